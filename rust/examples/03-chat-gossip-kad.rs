@@ -162,6 +162,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
           SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(event)) => {
             process_kad_events(&swarm.behaviour_mut().kademlia, event);
           }
+          SwarmEvent::Behaviour(MyBehaviourEvent::Autonat(autonat::Event::StatusChanged {
+              new,
+              ..
+          })) => {
+              println!("NAT status changed to {:?}", new);
+          }
           _ => {}
         }
     }
@@ -56,15 +56,51 @@
 //!
 //! Note: The client does not need to be directly connected to the providing
 //! peer, as long as both are connected to some node on the same DHT.
+//!
+//! Providing nodes answer DHT requests once AutoNAT reports them as publicly
+//! reachable; all other commands query the DHT as a Kademlia client. Pass
+//! `--kad-mode client|server|auto` to override this, e.g. `server` for nodes
+//! on a local network.
+//!
+//! A private DHT is kept apart from the public one by running all of its
+//! nodes with `--kad-protocol /lab/kad/1.0.0`, optionally together with
+//! `--check-kad-protocol`.
+//!
+//! Whole directories are shared with `provide-dir --path <dir> --name <name>`
+//! and mirrored with `get --name <name> --output <dir>`, `get` recognizing
+//! the name of a shared directory by its manifest.
+//!
+//! QUIC saves the round trips of the TCP, Noise and Yamux handshakes. Listen
+//! on a QUIC address, e.g. `--listen-address /ip4/0.0.0.0/udp/40839/quic`,
+//! and pass a QUIC address to `--peer` to dial over QUIC. `/quic` addresses
+//! are dialed with QUIC draft-29, `/quic-v1` addresses with QUIC v1.
+//!
+//! To let browser peers reach the provider, additionally listen on a secure
+//! WebSocket address, e.g. with the certificates shipped with the js-libp2p
+//! `01-transports` example:
+//!
+//!    ```
+//!    --listen-address /ip4/0.0.0.0/tcp/40837 \
+//!    --listen-address /ip4/0.0.0.0/tcp/40838/wss \
+//!    --ws-cert ../js-libp2p/01-transports/certs/cert.pem \
+//!    --ws-key ../js-libp2p/01-transports/certs/key.pem
+//!    ```
 use async_std::task::spawn;
 use clap::Parser;
 use futures::prelude::*;
 use libp2p::core::{Multiaddr, PeerId};
 use libp2p::multiaddr::Protocol;
+use libp2p_demo::authz::DenyReason;
+use libp2p_demo::catalog::{CatalogEntry, CatalogRequest};
+use libp2p_demo::kadmode::KademliaMode;
 use libp2p_demo::network;
+use libp2p_demo::quic;
+use libp2p_demo::transport::TransportConfig;
+use libp2p_demo::tree::{self, SharedTree};
 use std::error::Error;
 use std::io::Write;
 use std::path::PathBuf;
+use std::time::Instant;
 
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -72,85 +108,260 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let opt = Opt::parse();
 
-    let (mut network_client, mut network_events, network_event_loop) =
-        network::new(opt.secret_key_seed).await?;
+    let mut transport = TransportConfig::default();
+    if let (Some(cert), Some(key)) = (opt.ws_cert, opt.ws_key) {
+        transport.set_websocket_certificate(cert, key);
+    }
+    for cert in opt.ws_trust {
+        transport.add_websocket_trust(cert);
+    }
+
+    let mut config = network::Config::default();
+    config.set_transport(transport);
+    for relay in opt.relay {
+        config.add_relay(relay);
+    }
+    for peer in opt.peering {
+        config.add_peering(peer);
+    }
+    // One-off commands only query the DHT, they are gone before other nodes
+    // could make use of them in their routing tables.
+    config.set_kademlia_mode(opt.kad_mode.unwrap_or(match opt.argument {
+        CliArgument::Provide { .. } | CliArgument::ProvideDir { .. } => KademliaMode::Auto,
+        _ => KademliaMode::Client,
+    }));
+    if let Some(name) = opt.kad_protocol {
+        config.set_kademlia_protocol(name.into_bytes());
+    }
+    config.set_check_kademlia_protocol(opt.check_kad_protocol);
+
+    let (mut network_client, network_events, network_event_loop) =
+        network::new(opt.secret_key_seed, config).await?;
 
     // Spawn the network task for it to run in the background.
     spawn(network_event_loop.run());
 
-    // In case a listen address was provided use it, otherwise listen on any
-    // address.
-    match opt.listen_address {
-        Some(addr) => network_client
-            .start_listening(addr)
-            .await
-            .expect("Listening not to fail."),
-        None => network_client
+    // In case listen addresses were provided use them, e.g. a TCP and a
+    // `/wss` address, otherwise listen on any address.
+    if opt.listen_address.is_empty() {
+        network_client
             .start_listening("/ip4/0.0.0.0/tcp/0".parse()?)
             .await
-            .expect("Listening not to fail."),
-    };
-
-    // In case the user provided an address of a peer on the CLI, dial it.
-    if let Some(addr) = opt.peer {
-        let peer_id = match addr.iter().last() {
-            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).expect("Valid hash."),
-            _ => return Err("Expect peer multiaddr to contain peer ID.".into()),
-        };
+            .expect("Listening not to fail.");
+    }
+    for addr in opt.listen_address {
         network_client
-            .dial(peer_id, addr)
+            .start_listening(addr)
             .await
-            .expect("Dial to succeed");
+            .expect("Listening not to fail.");
     }
 
+    // In case the user provided an address of a peer on the CLI, dial it.
+    let peer_id = match opt.peer {
+        Some(addr) => {
+            let peer_id = match addr.iter().last() {
+                Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).expect("Valid hash."),
+                _ => return Err("Expect peer multiaddr to contain peer ID.".into()),
+            };
+            network_client
+                .dial(peer_id, addr)
+                .await
+                .expect("Dial to succeed");
+            Some(peer_id)
+        }
+        None => None,
+    };
+
     match opt.argument {
         // Providing a file.
-        CliArgument::Provide { path, name } => {
+        CliArgument::Provide {
+            path,
+            name,
+            description,
+        } => {
             // Advertise oneself as a provider of the file on the DHT.
             network_client.start_providing(name.clone()).await;
+            if let Err(e) = network_client
+                .index_keywords(name.clone(), description.as_deref().unwrap_or(""))
+                .await
+            {
+                eprintln!("Failed to index keywords of {}: {:?}", name, e);
+            }
+            network_client
+                .add_to_catalog(CatalogEntry::for_file(name.clone(), &path, description)?)
+                .await?;
 
-            loop {
-                match network_events.next().await {
-                    // Reply with the content of the file on incoming requests.
-                    Some(network::Event::InboundRequest { request, channel }) => {
-                        if request == name {
-                            network_client
-                                .respond_file(std::fs::read(&path)?, channel)
-                                .await;
-                        }
-                    }
-                    e => todo!("{:?}", e),
+            serve(network_client, network_events, |request| {
+                (request == name).then(|| std::fs::read(&path))
+            })
+            .await?;
+        }
+        // Providing a directory tree.
+        CliArgument::ProvideDir { path, name } => {
+            let tree = SharedTree::scan(&path, name)?;
+
+            // Advertise oneself as a provider of the manifest and every file.
+            for key in tree.keys() {
+                network_client.start_providing(key.to_string()).await;
+            }
+            for entry in tree.catalog_entries() {
+                network_client.add_to_catalog(entry).await?;
+            }
+
+            serve(network_client, network_events, |request| tree.read(request)).await?;
+        }
+        // Locating and getting a file or a directory tree.
+        CliArgument::Get { name, output } => {
+            // Nothing to react on while getting, keep the event stream drained.
+            spawn(network_events.for_each(|_| future::ready(())));
+
+            // Locate the providers and ask them in ranked order.
+            let (_, file_content) = network_client.fetch(name.clone()).await?;
+
+            match (tree::Manifest::parse(&file_content), output) {
+                // The name of a shared directory, mirror the whole tree.
+                (Some(manifest), Some(output)) => {
+                    tree::mirror_tree(&network_client, &manifest, &output, 8).await?;
+                    eprintln!(
+                        "Fetched {} files into {}.",
+                        manifest.entries.len(),
+                        output.display()
+                    );
+                }
+                (Some(_), None) => {
+                    return Err(
+                        format!("{} is a directory, pass --output <dir> to get it.", name).into(),
+                    );
                 }
+                (None, Some(output)) => std::fs::write(output, file_content)?,
+                (None, None) => std::io::stdout().write_all(&file_content)?,
+            }
+        }
+        // Searching by keywords.
+        CliArgument::Search { query } => {
+            spawn(network_events.for_each(|_| future::ready(())));
+
+            for hit in network_client.search(&query).await {
+                println!("{}\t{}", hit.key, hit.matched.join(","));
             }
         }
-        // Locating and getting a file.
-        CliArgument::Get { name } => {
-            // Locate all nodes providing the file.
-            let providers = network_client.get_providers(name.clone()).await;
-            if providers.is_empty() {
-                return Err(format!("Could not find provider for file {}.", name).into());
+        // Inspecting the routing table.
+        argument @ (CliArgument::Peers | CliArgument::Buckets) => {
+            spawn(network_events.for_each(|_| future::ready(())));
+
+            if let Err(e) = network_client.bootstrap().await {
+                eprintln!("Bootstrap failed: {:?}", e);
             }
+            let table = network_client.routing_table().await;
 
-            // Request the content of the file from each node.
-            let requests = providers.into_iter().map(|p| {
-                let mut network_client = network_client.clone();
-                let name = name.clone();
-                async move { network_client.request_file(p, name).await }.boxed()
-            });
+            if let CliArgument::Peers = argument {
+                for bucket in &table.buckets {
+                    for entry in &bucket.entries {
+                        let addresses: Vec<String> =
+                            entry.addresses.iter().map(ToString::to_string).collect();
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}",
+                            bucket.index,
+                            entry.peer,
+                            if entry.connected { "connected" } else { "disconnected" },
+                            age(entry.last_updated),
+                            addresses.join(",")
+                        );
+                    }
+                }
+            } else {
+                for bucket in &table.buckets {
+                    println!(
+                        "{}\t{} peers\t{} connected\t{}\tupdated {}",
+                        bucket.index,
+                        bucket.entries.len(),
+                        bucket.entries.iter().filter(|e| e.connected).count(),
+                        if bucket.has_pending { "pending" } else { "-" },
+                        age(bucket.last_updated)
+                    );
+                }
+            }
 
-            // Await the requests, ignore the remaining once a single one succeeds.
-            let file_content = futures::future::select_ok(requests)
-                .await
-                .map_err(|_| "None of the providers returned file.")?
-                .0;
+            let health = &table.health;
+            eprintln!(
+                "{} peers ({} connected) in {} buckets, {} full, {} never updated: {}",
+                health.peers,
+                health.connected,
+                health.non_empty_buckets,
+                health.full_buckets,
+                health.never_updated,
+                if health.is_healthy() { "healthy" } else { "unhealthy" }
+            );
+        }
+        // Listing everything the `--peer` node shares.
+        CliArgument::Browse { page_size } => {
+            spawn(network_events.for_each(|_| future::ready(())));
 
-            std::io::stdout().write_all(&file_content)?;
+            let peer_id = peer_id.ok_or("Browsing requires `--peer`.")?;
+            let mut request = CatalogRequest {
+                offset: 0,
+                limit: page_size,
+            };
+            loop {
+                let page = network_client.browse(peer_id, request.clone()).await?;
+                for entry in &page.entries {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}",
+                        entry.key,
+                        entry.size,
+                        entry.mime_type,
+                        entry.sha256,
+                        entry.description.as_deref().unwrap_or("")
+                    );
+                }
+                match page.next_offset(&request) {
+                    Some(offset) => request.offset = offset,
+                    None => break,
+                }
+            }
         }
     }
 
     Ok(())
 }
 
+/// Answer inbound requests with the content `lookup` returns for the
+/// requested name, denying unknown names.
+async fn serve(
+    mut network_client: network::Client,
+    mut network_events: impl Stream<Item = network::Event> + Unpin,
+    lookup: impl Fn(&str) -> Option<std::io::Result<Vec<u8>>>,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        match network_events.next().await {
+            // Reply with the content of the file on incoming requests.
+            Some(network::Event::InboundRequest {
+                request, channel, ..
+            }) => match lookup(&request) {
+                Some(content) => network_client.respond_file(content?, channel).await,
+                None => {
+                    network_client
+                        .deny_file(DenyReason::NotFound, channel)
+                        .await
+                }
+            },
+            Some(network::Event::NatStatusChanged { new, .. }) => {
+                eprintln!("NAT status changed to {:?}", new);
+            }
+            Some(network::Event::GossipMessage { .. }) => {}
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Time since `instant` in whole seconds, `-` if unknown.
+fn age(instant: Option<Instant>) -> String {
+    match instant {
+        Some(instant) => format!("{}s ago", instant.elapsed().as_secs()),
+        None => "-".to_string(),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[clap(name = "libp2p file sharing example")]
 struct Opt {
@@ -158,11 +369,45 @@ struct Opt {
     #[clap(long)]
     secret_key_seed: Option<u8>,
 
-    #[clap(long)]
+    #[clap(long, value_parser = quic::parse_multiaddr)]
     peer: Option<Multiaddr>,
 
+    #[clap(long, value_parser = quic::parse_multiaddr)]
+    listen_address: Vec<Multiaddr>,
+
+    /// PEM certificate chain served on `/wss` listen addresses.
+    #[clap(long, requires = "ws_key")]
+    ws_cert: Option<PathBuf>,
+
+    /// PEM private key matching `--ws-cert`.
+    #[clap(long, requires = "ws_cert")]
+    ws_key: Option<PathBuf>,
+
+    /// Additional PEM certificate to trust when dialing `/wss` addresses.
+    #[clap(long)]
+    ws_trust: Vec<PathBuf>,
+
+    /// Relay to listen through in case the node turns out to be private.
+    #[clap(long, value_parser = quic::parse_multiaddr)]
+    relay: Vec<Multiaddr>,
+
+    /// Peer to stay connected to, re-dialed whenever the connection drops.
+    #[clap(long, value_parser = quic::parse_multiaddr)]
+    peering: Vec<Multiaddr>,
+
+    /// Kademlia mode, `client`, `server` or `auto`. Defaults to `auto` when
+    /// providing and to `client` otherwise.
+    #[clap(long)]
+    kad_mode: Option<KademliaMode>,
+
+    /// Kademlia protocol name of a private DHT, e.g. `/lab/kad/1.0.0`.
+    #[clap(long)]
+    kad_protocol: Option<String>,
+
+    /// Drop peers from the routing table that do not announce the Kademlia
+    /// protocol via identify.
     #[clap(long)]
-    listen_address: Option<Multiaddr>,
+    check_kad_protocol: bool,
 
     #[clap(subcommand)]
     argument: CliArgument,
@@ -175,9 +420,39 @@ enum CliArgument {
         path: PathBuf,
         #[clap(long)]
         name: String,
+        /// Description listed in the catalog.
+        #[clap(long)]
+        description: Option<String>,
     },
+    /// Get the file or the directory shared under the given name.
     Get {
         #[clap(long)]
         name: String,
+        /// File to write to instead of stdout, or the directory to mirror a
+        /// shared directory into.
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Share a directory recursively under the given name.
+    ProvideDir {
+        #[clap(long)]
+        path: PathBuf,
+        #[clap(long)]
+        name: String,
+    },
+    /// Find keys by keywords of their name and description.
+    Search {
+        #[clap(long)]
+        query: String,
+    },
+    /// List the catalog of the `--peer` node.
+    Browse {
+        #[clap(long, default_value_t = 50)]
+        page_size: u32,
     },
+    /// Bootstrap via `--peer` and list the peers of the routing table.
+    Peers,
+    /// Bootstrap via `--peer` and summarize the k-buckets of the routing
+    /// table.
+    Buckets,
 }
@@ -2,6 +2,9 @@
 //!
 //! You can pass as parameter a base58 peer ID to search for. If you don't pass any parameter, a
 //! peer ID will be generated randomly.
+//!
+//! The routing table is saved to `ipfs-kad-peers.json` once the query finished. Later runs start
+//! from the saved peers and only fall back to the bootnodes if there are none.
 
 use async_std::task;
 use futures::StreamExt;
@@ -12,7 +15,8 @@ use libp2p::{
   swarm::{Swarm, SwarmEvent},
   Multiaddr, PeerId,
 };
-use std::{env, error::Error, str::FromStr, time::Duration};
+use libp2p_demo::peerstore::PeerStore;
+use std::{env, error::Error, path::Path, str::FromStr, time::Duration};
 
 const BOOTNODES: [&str; 4] = [
   "QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
@@ -21,6 +25,8 @@ const BOOTNODES: [&str; 4] = [
   "QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
 ];
 
+const PEER_STORE: &str = "ipfs-kad-peers.json";
+
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
   env_logger::init();
@@ -40,12 +46,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let store = MemoryStore::new(local_peer_id);
     let mut behaviour = Kademlia::with_config(local_peer_id, store, cfg);
 
-    // Add the bootnodes to the local routing table. `libp2p-dns` built
-    // into the `transport` resolves the `dnsaddr` when Kademlia tries
-    // to dial these nodes.
-    let bootaddr = Multiaddr::from_str("/dnsaddr/bootstrap.libp2p.io")?;
-    for peer in &BOOTNODES {
-      behaviour.add_address(&PeerId::from_str(peer)?, bootaddr.clone());
+    // Add the peers known from the last run to the local routing table,
+    // or else the bootnodes. `libp2p-dns` built into the `transport`
+    // resolves the `dnsaddr` when Kademlia tries to dial these nodes.
+    let known_peers = PeerStore::load(Path::new(PEER_STORE))?;
+    if known_peers.is_empty() {
+      let bootaddr = Multiaddr::from_str("/dnsaddr/bootstrap.libp2p.io")?;
+      for peer in &BOOTNODES {
+        behaviour.add_address(&PeerId::from_str(peer)?, bootaddr.clone());
+      }
+    } else {
+      println!(
+        "Starting from {} known peers",
+        known_peers.restore(&mut behaviour)
+      );
     }
 
     Swarm::new(transport, behaviour, local_peer_id)
@@ -95,6 +109,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
       }
     }
 
+    let mut known_peers = PeerStore::default();
+    known_peers.snapshot_routing_table(swarm.behaviour_mut());
+    if let Err(e) = known_peers.save(Path::new(PEER_STORE)).await {
+      eprintln!("Failed to save known peers: {}", e);
+    }
+
     Ok(())
   })
 }
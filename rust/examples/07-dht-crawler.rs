@@ -0,0 +1,109 @@
+//! Crawls a DHT and prints a snapshot of its peers.
+//!
+//! Starting from the bootstrap peers the crawler asks every peer it
+//! discovers for the peers it knows, for random keys across its buckets,
+//! counting it as reachable if it answers, and identifies the ones it
+//! connects to. The snapshot lists each peer with its addresses, protocols
+//! and agent version, as JSON or CSV.
+//!
+//! Crawling a network of `05-file-sharing` nodes:
+//!
+//! ```
+//! cargo run --example 07-dht-crawler -- \
+//!           --bootstrap /ip4/127.0.0.1/tcp/40837/p2p/12D3KooWPjceQrSwdWXPyLLeABRXmuqt69Rg3sBYbU1Nft9HyQ6X \
+//!           --format csv --output dht.csv
+//! ```
+//!
+//! A private DHT running Kademlia under its own protocol name is crawled by
+//! additionally passing `--protocol-name /lab/kad/1.0.0`.
+
+use clap::{Parser, ValueEnum};
+use libp2p::Multiaddr;
+use libp2p_demo::crawler::{self, CrawlerConfig};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[async_std::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+  env_logger::init();
+
+  let opt = Opt::parse();
+
+  let mut config = CrawlerConfig::default();
+  for addr in opt.bootstrap {
+    config.add_bootstrap(addr);
+  }
+  if let Some(name) = opt.protocol_name {
+    config.set_protocol_name(name.into_bytes());
+  }
+  config
+    .set_parallelism(opt.parallelism)
+    .set_keys_per_peer(opt.keys_per_peer)
+    .set_idle_rounds(opt.idle_rounds)
+    .set_query_timeout(Duration::from_secs(opt.query_timeout));
+
+  let snapshot = crawler::crawl(config).await?;
+  eprintln!(
+    "Found {} peers, {} reachable, in {} rounds",
+    snapshot.peers.len(),
+    snapshot.reachable(),
+    snapshot.rounds
+  );
+
+  let mut out: Box<dyn Write> = match opt.output {
+    Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+    None => Box::new(io::stdout()),
+  };
+  match opt.format {
+    Format::Json => snapshot.write_json(&mut out)?,
+    Format::Csv => snapshot.write_csv(&mut out)?,
+  }
+  out.flush()?;
+
+  Ok(())
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+  Json,
+  Csv,
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "libp2p DHT crawler")]
+struct Opt {
+  /// Peer to start crawling from, including `/p2p/<peer-id>`.
+  #[clap(long, required = true)]
+  bootstrap: Vec<Multiaddr>,
+
+  /// Kademlia protocol name of a private DHT.
+  #[clap(long)]
+  protocol_name: Option<String>,
+
+  #[clap(long, value_enum, default_value_t = Format::Json)]
+  format: Format,
+
+  /// File to write the snapshot to instead of stdout.
+  #[clap(long)]
+  output: Option<PathBuf>,
+
+  /// Lookups run concurrently.
+  #[clap(long, default_value_t = 8)]
+  parallelism: usize,
+
+  /// Random keys each peer is asked for, one per bucket from the most
+  /// distant one on.
+  #[clap(long, default_value_t = 8)]
+  keys_per_peer: u32,
+
+  /// Rounds without a new peer after which the crawl ends.
+  #[clap(long, default_value_t = 3)]
+  idle_rounds: usize,
+
+  /// Timeout of a single lookup in seconds.
+  #[clap(long, default_value_t = 30)]
+  query_timeout: u64,
+}
@@ -0,0 +1,23 @@
+//! Decode arbitrary bytes as a file exchange request. Decoding must never
+//! panic, and whatever decodes has to survive an encode/decode round trip.
+#![no_main]
+use futures::executor::block_on;
+use futures::io::Cursor;
+use libfuzzer_sys::fuzz_target;
+use libp2p::request_response::RequestResponseCodec;
+use libp2p_demo::exchange::{FileExchangeCodec, FileExchangeProtocol};
+
+fuzz_target!(|data: &[u8]| {
+  let mut codec = FileExchangeCodec();
+
+  for protocol in FileExchangeProtocol::ALL {
+    if let Ok(request) = block_on(codec.read_request(&protocol, &mut Cursor::new(data))) {
+      let mut encoded = Cursor::new(Vec::new());
+      block_on(codec.write_request(&protocol, &mut encoded, request.clone()))
+        .expect("Decoded request to be encodable.");
+      let decoded = block_on(codec.read_request(&protocol, &mut Cursor::new(encoded.into_inner())))
+        .expect("Encoded request to be decodable.");
+      assert_eq!(request, decoded);
+    }
+  }
+});
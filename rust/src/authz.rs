@@ -0,0 +1,250 @@
+//! Authorization of inbound file requests.
+//!
+//! Every inbound request passes the configured [`RequestPolicy`] before it is
+//! handed to the application. Denied and rate-limited requests are answered
+//! by the network layer itself, the requester receiving them as
+//! [`FileRequestError::Denied`](crate::network::FileRequestError::Denied) and
+//! [`FileRequestError::RateLimited`](crate::network::FileRequestError::RateLimited).
+//! Catalog requests pass the same policy, see
+//! [`RequestPolicy::authorize_browse`].
+use libp2p::core::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Outcome of a [`RequestPolicy`] check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+  /// Hand the request to the application.
+  Allow,
+  /// Refuse the request.
+  Deny(DenyReason),
+  /// Refuse the request for now, the requester may retry after the given
+  /// duration.
+  RateLimited { retry_after: Duration },
+}
+
+/// Reason code sent to the requester along with a denial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DenyReason {
+  /// The requester is not allowed to fetch the key.
+  Unauthorized,
+  /// The key is not shared.
+  NotFound,
+  /// Application defined reason.
+  Other(u8),
+}
+
+impl DenyReason {
+  /// Wire representation of the reason. Application defined codes follow a
+  /// zero byte, so that they never collide with the predefined reasons.
+  pub fn encode(&self) -> Vec<u8> {
+    match self {
+      DenyReason::Unauthorized => vec![1],
+      DenyReason::NotFound => vec![2],
+      DenyReason::Other(code) => vec![0, *code],
+    }
+  }
+
+  pub fn decode(bytes: &[u8]) -> Option<Self> {
+    match bytes {
+      [1] => Some(DenyReason::Unauthorized),
+      [2] => Some(DenyReason::NotFound),
+      [0, code] => Some(DenyReason::Other(*code)),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for DenyReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DenyReason::Unauthorized => write!(f, "unauthorized"),
+      DenyReason::NotFound => write!(f, "not found"),
+      DenyReason::Other(code) => write!(f, "denied with code {}", code),
+    }
+  }
+}
+
+/// Decides whether an inbound file request is served.
+///
+/// Called on the network task for every inbound request, so implementations
+/// must not block.
+pub trait RequestPolicy: fmt::Debug + Send + Sync {
+  fn authorize(&self, peer: &PeerId, key: &str) -> Decision;
+
+  /// Decides whether `peer` may browse the catalog, see
+  /// [`crate::catalog`]. Defaults to the decision for the empty key, so that
+  /// per-peer policies and rate limits cover browsing as well.
+  fn authorize_browse(&self, peer: &PeerId) -> Decision {
+    self.authorize(peer, "")
+  }
+}
+
+/// Policy allowing every request, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl RequestPolicy for AllowAll {
+  fn authorize(&self, _: &PeerId, _: &str) -> Decision {
+    Decision::Allow
+  }
+}
+
+/// Policy allowing only the given peers, denying everybody else as
+/// [`DenyReason::Unauthorized`].
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+  peers: Vec<PeerId>,
+}
+
+impl AllowList {
+  pub fn new(peers: impl IntoIterator<Item = PeerId>) -> Self {
+    Self {
+      peers: peers.into_iter().collect(),
+    }
+  }
+}
+
+impl RequestPolicy for AllowList {
+  fn authorize(&self, peer: &PeerId, _: &str) -> Decision {
+    if self.peers.contains(peer) {
+      Decision::Allow
+    } else {
+      Decision::Deny(DenyReason::Unauthorized)
+    }
+  }
+}
+
+/// Limits every peer to `max_requests` per `window` on top of an inner
+/// policy. Only requests allowed by the inner policy are counted.
+#[derive(Debug)]
+pub struct RateLimit {
+  inner: Arc<dyn RequestPolicy>,
+  max_requests: u32,
+  window: Duration,
+  windows: Mutex<HashMap<PeerId, (Instant, u32)>>,
+}
+
+impl RateLimit {
+  pub fn new(inner: Arc<dyn RequestPolicy>, max_requests: u32, window: Duration) -> Self {
+    Self {
+      inner,
+      max_requests,
+      window,
+      windows: Default::default(),
+    }
+  }
+}
+
+impl RequestPolicy for RateLimit {
+  fn authorize(&self, peer: &PeerId, key: &str) -> Decision {
+    let decision = self.inner.authorize(peer, key);
+    if decision != Decision::Allow {
+      return decision;
+    }
+
+    let now = Instant::now();
+    let mut windows = self.windows.lock().expect("Lock not to be poisoned.");
+    // Forget peers whose window is over to keep the table bounded.
+    windows.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+
+    let (start, count) = windows.entry(*peer).or_insert((now, 0));
+    if *count >= self.max_requests {
+      return Decision::RateLimited {
+        retry_after: self.window - now.duration_since(*start),
+      };
+    }
+    *count += 1;
+    Decision::Allow
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn deny_reason_roundtrip() {
+    for reason in [
+      DenyReason::Unauthorized,
+      DenyReason::NotFound,
+      DenyReason::Other(1),
+      DenyReason::Other(2),
+    ] {
+      assert_eq!(DenyReason::decode(&reason.encode()), Some(reason));
+    }
+  }
+
+  #[test]
+  fn deny_reason_rejects_unknown_codes() {
+    assert_eq!(DenyReason::decode(&[]), None);
+    assert_eq!(DenyReason::decode(&[3]), None);
+    assert_eq!(DenyReason::decode(&[0]), None);
+    assert_eq!(DenyReason::decode(&[0, 1, 2]), None);
+  }
+
+  #[test]
+  fn allow_list() {
+    let allowed = PeerId::random();
+    let policy = AllowList::new([allowed]);
+    assert_eq!(policy.authorize(&allowed, "key"), Decision::Allow);
+    assert_eq!(
+      policy.authorize(&PeerId::random(), "key"),
+      Decision::Deny(DenyReason::Unauthorized)
+    );
+    assert_eq!(
+      policy.authorize_browse(&PeerId::random()),
+      Decision::Deny(DenyReason::Unauthorized)
+    );
+  }
+
+  #[test]
+  fn rate_limit_per_peer() {
+    let policy = RateLimit::new(Arc::new(AllowAll), 2, Duration::from_secs(60));
+    let peer = PeerId::random();
+    assert_eq!(policy.authorize(&peer, "a"), Decision::Allow);
+    assert_eq!(policy.authorize(&peer, "b"), Decision::Allow);
+    match policy.authorize(&peer, "c") {
+      Decision::RateLimited { retry_after } => {
+        assert!(retry_after <= Duration::from_secs(60))
+      }
+      decision => panic!("Unexpected decision {:?}", decision),
+    }
+    // Other peers have their own window.
+    assert_eq!(policy.authorize(&PeerId::random(), "a"), Decision::Allow);
+  }
+
+  #[test]
+  fn rate_limit_window_expires() {
+    let policy = RateLimit::new(Arc::new(AllowAll), 1, Duration::from_millis(10));
+    let peer = PeerId::random();
+    assert_eq!(policy.authorize(&peer, "a"), Decision::Allow);
+    assert!(matches!(
+      policy.authorize(&peer, "a"),
+      Decision::RateLimited { .. }
+    ));
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(policy.authorize(&peer, "a"), Decision::Allow);
+  }
+
+  #[test]
+  fn rate_limit_does_not_count_denied_requests() {
+    let allowed = PeerId::random();
+    let denied = PeerId::random();
+    let policy = RateLimit::new(
+      Arc::new(AllowList::new([allowed])),
+      1,
+      Duration::from_secs(60),
+    );
+    for _ in 0..3 {
+      assert_eq!(
+        policy.authorize(&denied, "a"),
+        Decision::Deny(DenyReason::Unauthorized)
+      );
+    }
+    assert_eq!(policy.authorize(&allowed, "a"), Decision::Allow);
+  }
+}
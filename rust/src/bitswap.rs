@@ -0,0 +1,995 @@
+//! Want-list based block exchange, modelled on Bitswap.
+//!
+//! Peers send each other [`BitswapMessage`]s over `/block-exchange/1`, one
+//! way, every message being acknowledged with an empty response. A message
+//! carries want-list entries, blocks and block presences:
+//!
+//! - A node looking for a block sends a want-have to every connected peer and
+//!   a want-block to the first peer reporting to have it. Peers that fail to
+//!   deliver within [`BLOCK_REQUEST_TIMEOUT`] are replaced by the next peer
+//!   that has the block. Once no peer is left to ask, every connected peer
+//!   is sent a want-have again.
+//! - Once the block arrives, from whichever peer, it is verified against its
+//!   CID and the remaining wants are cancelled.
+//! - Wants a node cannot serve yet are kept in the peer's want-list, up to
+//!   [`MAX_PEER_WANTS`] entries, and answered as soon as the block is added
+//!   to the store.
+//!
+//! [`BlockExchange`] holds the protocol state of the network layer and keeps
+//! a [`Ledger`] per peer as well as the overall [`Stat`].
+//!
+//! A message is the concatenation of three lists, each prefixed by its varint
+//! length: want-list entries (CID, flags byte), blocks (CID, data) and
+//! presences (CID, presence byte). CIDs and block data are varint length
+//! prefixed. Decoding never trusts the peer, see [`crate::exchange`].
+use crate::blockstore::{BlockStore, MAX_BLOCK_SIZE};
+use async_trait::async_trait;
+use cid::Cid;
+use futures::channel::oneshot;
+use futures::prelude::*;
+use libp2p::core::upgrade::{read_varint, write_varint, ProtocolName};
+use libp2p::core::PeerId;
+use libp2p::request_response::RequestResponseCodec;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Maximum size of a message, one block plus framing and control entries.
+pub const MAX_MESSAGE_SIZE: usize = MAX_BLOCK_SIZE + 256 * 1024;
+
+/// Maximum number of want-list entries respectively presences per message.
+pub const MAX_ENTRIES: usize = 1024;
+
+/// Maximum number of blocks a peer may want from the local node at a time.
+/// Further wants are dropped until earlier ones are served or cancelled.
+pub const MAX_PEER_WANTS: usize = MAX_ENTRIES;
+
+/// Time a peer has to deliver a requested block before it is asked of the
+/// next peer that has it.
+pub const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum size of an encoded CID.
+const MAX_CID_SIZE: usize = 128;
+
+// Flags of a want-list entry.
+const FLAG_CANCEL: u8 = 0b001;
+const FLAG_WANT_BLOCK: u8 = 0b010;
+const FLAG_SEND_DONT_HAVE: u8 = 0b100;
+
+const PRESENCE_HAVE: u8 = 0;
+const PRESENCE_DONT_HAVE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WantType {
+  /// Ask whether the peer has the block.
+  Have,
+  /// Ask the peer to send the block.
+  Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WantlistEntry {
+  pub cid: Cid,
+  pub want_type: WantType,
+  /// Withdraw an earlier want for `cid`.
+  pub cancel: bool,
+  /// Ask for an explicit [`Presence::DontHave`] if the peer lacks the block.
+  pub send_dont_have: bool,
+}
+
+impl WantlistEntry {
+  fn have(cid: Cid) -> Self {
+    Self {
+      cid,
+      want_type: WantType::Have,
+      cancel: false,
+      send_dont_have: true,
+    }
+  }
+
+  fn block(cid: Cid) -> Self {
+    Self {
+      cid,
+      want_type: WantType::Block,
+      cancel: false,
+      send_dont_have: true,
+    }
+  }
+
+  fn cancel(cid: Cid) -> Self {
+    Self {
+      cid,
+      want_type: WantType::Block,
+      cancel: true,
+      send_dont_have: false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+  Have,
+  DontHave,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BitswapMessage {
+  pub wantlist: Vec<WantlistEntry>,
+  pub blocks: Vec<(Cid, Vec<u8>)>,
+  pub presences: Vec<(Cid, Presence)>,
+}
+
+/// Data exchanged with a single peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ledger {
+  pub bytes_sent: u64,
+  pub bytes_received: u64,
+  pub blocks_sent: u64,
+  pub blocks_received: u64,
+}
+
+impl Ledger {
+  /// Bytes sent per byte received, high for peers taking more than they
+  /// give.
+  pub fn debt_ratio(&self) -> f64 {
+    self.bytes_sent as f64 / (self.bytes_received as f64 + 1.0)
+  }
+
+  /// Blocks exchanged in either direction.
+  pub fn exchanged(&self) -> u64 {
+    self.blocks_sent + self.blocks_received
+  }
+}
+
+/// Overall block exchange statistics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stat {
+  pub blocks_received: u64,
+  pub data_received: u64,
+  pub blocks_sent: u64,
+  pub data_sent: u64,
+  /// Blocks received although they were in the store already.
+  pub dup_blocks_received: u64,
+  pub dup_data_received: u64,
+  /// Blocks wanted by the local node.
+  pub wantlist: Vec<Cid>,
+  /// Peers taking part in the exchange.
+  pub peers: Vec<PeerId>,
+}
+
+/// A block wanted by the local node.
+#[derive(Debug, Default)]
+struct LocalWant {
+  waiters: Vec<oneshot::Sender<Vec<u8>>>,
+  /// Peers the want was sent to.
+  sent_to: HashSet<PeerId>,
+  /// Peers reporting to have the block, in the order they answered.
+  haves: VecDeque<PeerId>,
+  /// Peer the block was requested from.
+  requested_from: Option<PeerId>,
+  /// When the block was requested from `requested_from`.
+  requested_at: Option<Instant>,
+}
+
+/// Messages to send, control entries being merged per peer.
+#[derive(Default)]
+struct Outbox {
+  control: HashMap<PeerId, BitswapMessage>,
+  blocks: Vec<(PeerId, BitswapMessage)>,
+}
+
+impl Outbox {
+  fn want(&mut self, peer: PeerId, entry: WantlistEntry) {
+    self.control.entry(peer).or_default().wantlist.push(entry);
+  }
+
+  fn presence(&mut self, peer: PeerId, cid: Cid, presence: Presence) {
+    self
+      .control
+      .entry(peer)
+      .or_default()
+      .presences
+      .push((cid, presence));
+  }
+
+  /// Blocks travel in messages of their own to stay below the size limit.
+  fn block(&mut self, peer: PeerId, cid: Cid, data: Vec<u8>) {
+    let message = BitswapMessage {
+      blocks: vec![(cid, data)],
+      ..Default::default()
+    };
+    self.blocks.push((peer, message));
+  }
+}
+
+/// State of the block exchange with all connected peers.
+#[derive(Debug, Default)]
+pub struct BlockExchange {
+  wants: HashMap<Cid, LocalWant>,
+  /// Want-lists of connected peers.
+  peers: HashMap<PeerId, HashMap<Cid, WantType>>,
+  ledgers: HashMap<PeerId, Ledger>,
+  stat: Stat,
+  invalid_senders: Vec<PeerId>,
+}
+
+impl BlockExchange {
+  /// Want `cid`, `waiter` receiving the block once it arrives.
+  pub fn want(
+    &mut self,
+    cid: Cid,
+    waiter: oneshot::Sender<Vec<u8>>,
+  ) -> Vec<(PeerId, BitswapMessage)> {
+    let mut outbox = Outbox::default();
+    let want = self.wants.entry(cid).or_default();
+    if want.waiters.is_empty() {
+      ask_connected(&cid, want, &self.peers, &mut outbox);
+    }
+    want.waiters.push(waiter);
+    self.flush(outbox)
+  }
+
+  /// Stop wanting `cid`, dropping everyone waiting for it.
+  pub fn cancel(&mut self, cid: &Cid) -> Vec<(PeerId, BitswapMessage)> {
+    let mut outbox = Outbox::default();
+    if let Some(want) = self.wants.remove(cid) {
+      for peer in want.sent_to {
+        outbox.want(peer, WantlistEntry::cancel(*cid));
+      }
+    }
+    self.flush(outbox)
+  }
+
+  /// Cancel wants whose waiters all went away.
+  pub fn cancel_abandoned(&mut self) -> Vec<(PeerId, BitswapMessage)> {
+    let abandoned: Vec<Cid> = self
+      .wants
+      .iter_mut()
+      .filter_map(|(cid, want)| {
+        want.waiters.retain(|w| !w.is_canceled());
+        want.waiters.is_empty().then_some(*cid)
+      })
+      .collect();
+    abandoned.iter().flat_map(|cid| self.cancel(cid)).collect()
+  }
+
+  /// Ask someone else for blocks not delivered within
+  /// [`BLOCK_REQUEST_TIMEOUT`], cancelling the request at the slow peer.
+  pub fn expire_requests(&mut self, now: Instant) -> Vec<(PeerId, BitswapMessage)> {
+    let mut outbox = Outbox::default();
+    for (cid, want) in &mut self.wants {
+      let expired = want
+        .requested_at
+        .is_some_and(|at| now.saturating_duration_since(at) >= BLOCK_REQUEST_TIMEOUT);
+      if !expired {
+        continue;
+      }
+      if let Some(peer) = want.requested_from.take() {
+        debug!("bitswap: {} did not deliver {} in time", peer, cid);
+        want.sent_to.remove(&peer);
+        outbox.want(peer, WantlistEntry::cancel(*cid));
+      }
+      want.requested_at = None;
+      request_block(cid, want, &mut outbox);
+      if want.requested_from.is_none() {
+        ask_connected(cid, want, &self.peers, &mut outbox);
+      }
+    }
+    self.flush(outbox)
+  }
+
+  /// Repeat the want-list entries of a message to `peer` that was lost, e.g.
+  /// timed out, while the peer stays connected. Entries overtaken since are
+  /// dropped, a lost want-block moves on to the next peer having the block.
+  pub fn message_failed(
+    &mut self,
+    peer: PeerId,
+    wantlist: Vec<WantlistEntry>,
+  ) -> Vec<(PeerId, BitswapMessage)> {
+    let mut outbox = Outbox::default();
+    if !self.peers.contains_key(&peer) {
+      return self.flush(outbox);
+    }
+    for entry in wantlist {
+      let want = self.wants.get_mut(&entry.cid);
+      let asked = want
+        .as_ref()
+        .is_some_and(|want| want.sent_to.contains(&peer));
+      match (want, entry.cancel, entry.want_type) {
+        (_, true, _) if !asked => outbox.want(peer, entry),
+        (Some(want), false, WantType::Have) if asked && want.requested_from != Some(peer) => {
+          outbox.want(peer, entry)
+        }
+        (Some(want), false, WantType::Block) if want.requested_from == Some(peer) => {
+          want.requested_from = None;
+          want.requested_at = None;
+          want.haves.push_back(peer);
+          request_block(&entry.cid, want, &mut outbox);
+        }
+        _ => {}
+      }
+    }
+    self.flush(outbox)
+  }
+
+  /// Send the local want-list to a newly connected peer.
+  pub fn peer_connected(&mut self, peer: PeerId) -> Vec<(PeerId, BitswapMessage)> {
+    let mut outbox = Outbox::default();
+    self.peers.entry(peer).or_default();
+    for (cid, want) in &mut self.wants {
+      if want.sent_to.insert(peer) {
+        outbox.want(peer, WantlistEntry::have(*cid));
+      }
+    }
+    self.flush(outbox)
+  }
+
+  /// Forget the want-list of `peer`, asking someone else for blocks
+  /// requested from it. Its ledger is kept.
+  pub fn peer_disconnected(&mut self, peer: &PeerId) -> Vec<(PeerId, BitswapMessage)> {
+    let mut outbox = Outbox::default();
+    self.peers.remove(peer);
+    for (cid, want) in &mut self.wants {
+      want.sent_to.remove(peer);
+      want.haves.retain(|p| p != peer);
+      if want.requested_from == Some(*peer) {
+        want.requested_from = None;
+        want.requested_at = None;
+        request_block(cid, want, &mut outbox);
+      }
+    }
+    self.flush(outbox)
+  }
+
+  /// Handle a message received from `peer`, storing the wanted blocks it
+  /// carries in `store`.
+  pub fn handle_message(
+    &mut self,
+    peer: PeerId,
+    message: BitswapMessage,
+    store: &mut BlockStore,
+  ) -> Vec<(PeerId, BitswapMessage)> {
+    let mut outbox = Outbox::default();
+
+    for entry in message.wantlist {
+      let peer_wants = self.peers.entry(peer).or_default();
+      if entry.cancel {
+        peer_wants.remove(&entry.cid);
+        continue;
+      }
+      match (store.get(&entry.cid), entry.want_type) {
+        (Some(data), WantType::Block) => {
+          peer_wants.remove(&entry.cid);
+          outbox.block(peer, entry.cid, data.to_vec());
+        }
+        (Some(_), WantType::Have) => {
+          peer_wants.remove(&entry.cid);
+          outbox.presence(peer, entry.cid, Presence::Have);
+        }
+        (None, want_type) => {
+          if peer_wants.len() >= MAX_PEER_WANTS && !peer_wants.contains_key(&entry.cid) {
+            debug!(
+              "bitswap: want-list of {} is full, dropping {}",
+              peer, entry.cid
+            );
+          } else {
+            peer_wants.insert(entry.cid, want_type);
+          }
+          if entry.send_dont_have {
+            outbox.presence(peer, entry.cid, Presence::DontHave);
+          }
+        }
+      }
+    }
+
+    for (cid, presence) in message.presences {
+      let want = match self.wants.get_mut(&cid) {
+        Some(want) => want,
+        None => continue,
+      };
+      match presence {
+        Presence::Have => {
+          if want.requested_from != Some(peer) && !want.haves.contains(&peer) {
+            want.haves.push_back(peer);
+          }
+        }
+        Presence::DontHave => {
+          want.haves.retain(|p| *p != peer);
+          if want.requested_from == Some(peer) {
+            want.requested_from = None;
+            want.requested_at = None;
+          }
+        }
+      }
+      request_block(&cid, want, &mut outbox);
+    }
+
+    for (cid, data) in message.blocks {
+      let size = data.len() as u64;
+      let ledger = self.ledgers.entry(peer).or_default();
+      ledger.bytes_received += size;
+      ledger.blocks_received += 1;
+
+      if store.contains(&cid) {
+        self.stat.dup_blocks_received += 1;
+        self.stat.dup_data_received += size;
+        continue;
+      }
+      if !self.wants.contains_key(&cid) {
+        debug!("bitswap: dropping unwanted block {} from {}", cid, peer);
+        continue;
+      }
+      if let Err(e) = store.put(cid, data) {
+        warn!("bitswap: invalid block from {}: {}", peer, e);
+        self.invalid_senders.push(peer);
+        continue;
+      }
+      self.stat.blocks_received += 1;
+      self.stat.data_received += size;
+      self.added(cid, store, &mut outbox);
+    }
+
+    self.flush(outbox)
+  }
+
+  /// Serve peers and local waiters wanting the newly stored block `cid`.
+  pub fn block_added(&mut self, cid: Cid, store: &BlockStore) -> Vec<(PeerId, BitswapMessage)> {
+    let mut outbox = Outbox::default();
+    self.added(cid, store, &mut outbox);
+    self.flush(outbox)
+  }
+
+  fn added(&mut self, cid: Cid, store: &BlockStore, outbox: &mut Outbox) {
+    let data = match store.get(&cid) {
+      Some(data) => data,
+      None => return,
+    };
+
+    if let Some(want) = self.wants.remove(&cid) {
+      for waiter in want.waiters {
+        let _ = waiter.send(data.to_vec());
+      }
+      for peer in want.sent_to {
+        outbox.want(peer, WantlistEntry::cancel(cid));
+      }
+    }
+
+    for (peer, peer_wants) in &mut self.peers {
+      match peer_wants.remove(&cid) {
+        Some(WantType::Block) => outbox.block(*peer, cid, data.to_vec()),
+        Some(WantType::Have) => outbox.presence(*peer, cid, Presence::Have),
+        None => {}
+      }
+    }
+  }
+
+  /// Account for the blocks about to be sent.
+  fn flush(&mut self, outbox: Outbox) -> Vec<(PeerId, BitswapMessage)> {
+    for (peer, message) in &outbox.blocks {
+      let size: u64 = message
+        .blocks
+        .iter()
+        .map(|(_, data)| data.len() as u64)
+        .sum();
+      let ledger = self.ledgers.entry(*peer).or_default();
+      ledger.bytes_sent += size;
+      ledger.blocks_sent += message.blocks.len() as u64;
+      self.stat.data_sent += size;
+      self.stat.blocks_sent += message.blocks.len() as u64;
+    }
+    let mut messages = Vec::new();
+    for (peer, mut message) in outbox.control {
+      // Split control entries into messages the codec accepts.
+      loop {
+        let rest = BitswapMessage {
+          wantlist: message
+            .wantlist
+            .split_off(message.wantlist.len().min(MAX_ENTRIES)),
+          presences: message
+            .presences
+            .split_off(message.presences.len().min(MAX_ENTRIES)),
+          blocks: Vec::new(),
+        };
+        messages.push((peer, message));
+        if rest.wantlist.is_empty() && rest.presences.is_empty() {
+          break;
+        }
+        message = rest;
+      }
+    }
+    messages.extend(outbox.blocks);
+    messages
+  }
+
+  /// Peers that sent blocks not matching their CID since the last call.
+  pub fn take_invalid_senders(&mut self) -> Vec<PeerId> {
+    std::mem::take(&mut self.invalid_senders)
+  }
+
+  /// Blocks wanted by the local node, or by `peer` as far as known.
+  pub fn wantlist(&self, peer: Option<&PeerId>) -> Vec<Cid> {
+    let mut cids: Vec<Cid> = match peer {
+      None => self.wants.keys().copied().collect(),
+      Some(peer) => self
+        .peers
+        .get(peer)
+        .map(|wants| wants.keys().copied().collect())
+        .unwrap_or_default(),
+    };
+    cids.sort();
+    cids
+  }
+
+  pub fn ledger(&self, peer: &PeerId) -> Ledger {
+    self.ledgers.get(peer).cloned().unwrap_or_default()
+  }
+
+  pub fn stat(&self) -> Stat {
+    let mut peers: Vec<PeerId> = self.peers.keys().copied().collect();
+    peers.sort();
+    Stat {
+      wantlist: self.wantlist(None),
+      peers,
+      ..self.stat.clone()
+    }
+  }
+}
+
+/// Send a want-have for `cid` to every connected peer.
+fn ask_connected(
+  cid: &Cid,
+  want: &mut LocalWant,
+  peers: &HashMap<PeerId, HashMap<Cid, WantType>>,
+  outbox: &mut Outbox,
+) {
+  for peer in peers.keys() {
+    want.sent_to.insert(*peer);
+    outbox.want(*peer, WantlistEntry::have(*cid));
+  }
+}
+
+/// Ask the next peer that has the block unless a request is in flight.
+fn request_block(cid: &Cid, want: &mut LocalWant, outbox: &mut Outbox) {
+  if want.requested_from.is_some() {
+    return;
+  }
+  if let Some(peer) = want.haves.pop_front() {
+    want.requested_from = Some(peer);
+    want.requested_at = Some(Instant::now());
+    outbox.want(peer, WantlistEntry::block(*cid));
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct BitswapProtocol();
+
+impl ProtocolName for BitswapProtocol {
+  fn protocol_name(&self) -> &[u8] {
+    "/block-exchange/1".as_bytes()
+  }
+}
+
+#[derive(Clone)]
+pub struct BitswapCodec();
+
+/// Read a field announced by a varint length prefix of at most `max_size`
+/// bytes.
+async fn read_field<T>(io: &mut T, max_size: usize, what: &str) -> io::Result<Vec<u8>>
+where
+  T: AsyncRead + Unpin + Send,
+{
+  let len = read_varint(io).await?;
+  if len > max_size {
+    return Err(invalid_data(format!(
+      "{} of {} bytes exceeds the maximum of {} bytes",
+      what, len, max_size
+    )));
+  }
+
+  let mut field = Vec::new();
+  io.take(len as u64).read_to_end(&mut field).await?;
+  if field.len() != len {
+    return Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      format!("{} truncated after {} of {} bytes", what, field.len(), len),
+    ));
+  }
+  Ok(field)
+}
+
+async fn read_count<T>(io: &mut T, max: usize, what: &str) -> io::Result<usize>
+where
+  T: AsyncRead + Unpin + Send,
+{
+  let count = read_varint(io).await?;
+  if count > max {
+    return Err(invalid_data(format!(
+      "{} {} exceed the maximum of {}",
+      count, what, max
+    )));
+  }
+  Ok(count)
+}
+
+async fn read_cid<T>(io: &mut T) -> io::Result<Cid>
+where
+  T: AsyncRead + Unpin + Send,
+{
+  let bytes = read_field(io, MAX_CID_SIZE, "CID").await?;
+  Cid::try_from(bytes.as_slice()).map_err(|e| invalid_data(format!("invalid CID: {}", e)))
+}
+
+async fn read_byte<T>(io: &mut T) -> io::Result<u8>
+where
+  T: AsyncRead + Unpin + Send,
+{
+  let mut byte = [0u8];
+  io.read_exact(&mut byte).await?;
+  Ok(byte[0])
+}
+
+async fn write_field<T>(io: &mut T, field: &[u8]) -> io::Result<()>
+where
+  T: AsyncWrite + Unpin + Send,
+{
+  write_varint(io, field.len()).await?;
+  io.write_all(field).await
+}
+
+fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[async_trait]
+impl RequestResponseCodec for BitswapCodec {
+  type Protocol = BitswapProtocol;
+  type Request = BitswapMessage;
+  type Response = ();
+
+  async fn read_request<T>(&mut self, _: &BitswapProtocol, io: &mut T) -> io::Result<Self::Request>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    let mut io = io.take(MAX_MESSAGE_SIZE as u64);
+    let mut message = BitswapMessage::default();
+
+    for _ in 0..read_count(&mut io, MAX_ENTRIES, "want-list entries").await? {
+      let cid = read_cid(&mut io).await?;
+      let flags = read_byte(&mut io).await?;
+      if flags & !(FLAG_CANCEL | FLAG_WANT_BLOCK | FLAG_SEND_DONT_HAVE) != 0 {
+        return Err(invalid_data(format!(
+          "unknown want-list flags {:#b}",
+          flags
+        )));
+      }
+      message.wantlist.push(WantlistEntry {
+        cid,
+        want_type: if flags & FLAG_WANT_BLOCK != 0 {
+          WantType::Block
+        } else {
+          WantType::Have
+        },
+        cancel: flags & FLAG_CANCEL != 0,
+        send_dont_have: flags & FLAG_SEND_DONT_HAVE != 0,
+      });
+    }
+
+    for _ in 0..read_count(&mut io, MAX_ENTRIES, "blocks").await? {
+      let cid = read_cid(&mut io).await?;
+      let data = read_field(&mut io, MAX_BLOCK_SIZE, "block").await?;
+      message.blocks.push((cid, data));
+    }
+
+    for _ in 0..read_count(&mut io, MAX_ENTRIES, "presences").await? {
+      let cid = read_cid(&mut io).await?;
+      let presence = match read_byte(&mut io).await? {
+        PRESENCE_HAVE => Presence::Have,
+        PRESENCE_DONT_HAVE => Presence::DontHave,
+        other => return Err(invalid_data(format!("unknown presence {}", other))),
+      };
+      message.presences.push((cid, presence));
+    }
+
+    Ok(message)
+  }
+
+  async fn read_response<T>(&mut self, _: &BitswapProtocol, _: &mut T) -> io::Result<Self::Response>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    Ok(())
+  }
+
+  async fn write_request<T>(
+    &mut self,
+    _: &BitswapProtocol,
+    io: &mut T,
+    message: BitswapMessage,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    if message.wantlist.len() > MAX_ENTRIES || message.presences.len() > MAX_ENTRIES {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "message has too many entries",
+      ));
+    }
+
+    write_varint(io, message.wantlist.len()).await?;
+    for entry in &message.wantlist {
+      let mut flags = 0;
+      if entry.cancel {
+        flags |= FLAG_CANCEL;
+      }
+      if entry.want_type == WantType::Block {
+        flags |= FLAG_WANT_BLOCK;
+      }
+      if entry.send_dont_have {
+        flags |= FLAG_SEND_DONT_HAVE;
+      }
+      write_field(io, &entry.cid.to_bytes()).await?;
+      io.write_all(&[flags]).await?;
+    }
+
+    write_varint(io, message.blocks.len()).await?;
+    for (cid, data) in &message.blocks {
+      write_field(io, &cid.to_bytes()).await?;
+      write_field(io, data).await?;
+    }
+
+    write_varint(io, message.presences.len()).await?;
+    for (cid, presence) in &message.presences {
+      let presence = match presence {
+        Presence::Have => PRESENCE_HAVE,
+        Presence::DontHave => PRESENCE_DONT_HAVE,
+      };
+      write_field(io, &cid.to_bytes()).await?;
+      io.write_all(&[presence]).await?;
+    }
+    io.close().await?;
+
+    Ok(())
+  }
+
+  async fn write_response<T>(&mut self, _: &BitswapProtocol, io: &mut T, _: ()) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    io.close().await?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blockstore::{cid_of, RAW};
+  use futures::io::Cursor;
+
+  fn presence(cid: Cid, presence: Presence) -> BitswapMessage {
+    BitswapMessage {
+      presences: vec![(cid, presence)],
+      ..Default::default()
+    }
+  }
+
+  /// Want-list entries sent to `peer`.
+  fn wants_to(messages: &[(PeerId, BitswapMessage)], peer: &PeerId) -> Vec<WantlistEntry> {
+    messages
+      .iter()
+      .filter(|(p, _)| p == peer)
+      .flat_map(|(_, message)| message.wantlist.clone())
+      .collect()
+  }
+
+  #[test]
+  fn block_requested_from_first_peer_having_it() {
+    let mut exchange = BlockExchange::default();
+    let mut store = BlockStore::default();
+    let (a, b) = (PeerId::random(), PeerId::random());
+    exchange.peer_connected(a);
+    exchange.peer_connected(b);
+    let data = b"block".to_vec();
+    let cid = cid_of(RAW, &data);
+
+    let (waiter, block) = oneshot::channel();
+    let messages = exchange.want(cid, waiter);
+    assert_eq!(wants_to(&messages, &a), [WantlistEntry::have(cid)]);
+    assert_eq!(wants_to(&messages, &b), [WantlistEntry::have(cid)]);
+
+    let messages = exchange.handle_message(a, presence(cid, Presence::Have), &mut store);
+    assert_eq!(wants_to(&messages, &a), [WantlistEntry::block(cid)]);
+    let messages = exchange.handle_message(b, presence(cid, Presence::Have), &mut store);
+    assert!(messages.is_empty());
+
+    let message = BitswapMessage {
+      blocks: vec![(cid, data.clone())],
+      ..Default::default()
+    };
+    let messages = exchange.handle_message(a, message, &mut store);
+    assert_eq!(wants_to(&messages, &a), [WantlistEntry::cancel(cid)]);
+    assert_eq!(wants_to(&messages, &b), [WantlistEntry::cancel(cid)]);
+    assert_eq!(block.now_or_never(), Some(Ok(data)));
+    assert!(exchange.wantlist(None).is_empty());
+    assert_eq!(exchange.ledger(&a).blocks_received, 1);
+  }
+
+  #[test]
+  fn invalid_block_reports_sender() {
+    let mut exchange = BlockExchange::default();
+    let mut store = BlockStore::default();
+    let peer = PeerId::random();
+    let cid = cid_of(RAW, b"block");
+    let (waiter, _block) = oneshot::channel();
+    exchange.want(cid, waiter);
+
+    let message = BitswapMessage {
+      blocks: vec![(cid, b"forged".to_vec())],
+      ..Default::default()
+    };
+    exchange.handle_message(peer, message, &mut store);
+    assert!(!store.contains(&cid));
+    assert_eq!(exchange.take_invalid_senders(), [peer]);
+    assert_eq!(exchange.wantlist(None), [cid]);
+  }
+
+  #[test]
+  fn expired_request_goes_to_next_peer() {
+    let mut exchange = BlockExchange::default();
+    let mut store = BlockStore::default();
+    let (a, b) = (PeerId::random(), PeerId::random());
+    exchange.peer_connected(a);
+    exchange.peer_connected(b);
+    let cid = cid_of(RAW, b"block");
+    let (waiter, _block) = oneshot::channel();
+    exchange.want(cid, waiter);
+    exchange.handle_message(a, presence(cid, Presence::Have), &mut store);
+    exchange.handle_message(b, presence(cid, Presence::Have), &mut store);
+
+    assert!(exchange.expire_requests(Instant::now()).is_empty());
+
+    let messages = exchange.expire_requests(Instant::now() + BLOCK_REQUEST_TIMEOUT);
+    assert_eq!(wants_to(&messages, &a), [WantlistEntry::cancel(cid)]);
+    assert_eq!(wants_to(&messages, &b), [WantlistEntry::block(cid)]);
+
+    // Nobody else is known to have the block, every connected peer is asked
+    // again.
+    let messages = exchange.expire_requests(Instant::now() + BLOCK_REQUEST_TIMEOUT);
+    assert_eq!(
+      wants_to(&messages, &b),
+      [WantlistEntry::cancel(cid), WantlistEntry::have(cid)]
+    );
+    assert_eq!(wants_to(&messages, &a), [WantlistEntry::have(cid)]);
+    assert!(exchange
+      .expire_requests(Instant::now() + BLOCK_REQUEST_TIMEOUT)
+      .is_empty());
+
+    let messages = exchange.handle_message(a, presence(cid, Presence::Have), &mut store);
+    assert_eq!(wants_to(&messages, &a), [WantlistEntry::block(cid)]);
+    assert_eq!(exchange.wantlist(None), [cid]);
+  }
+
+  #[test]
+  fn lost_wants_are_repeated() {
+    let mut exchange = BlockExchange::default();
+    let mut store = BlockStore::default();
+    let (a, b) = (PeerId::random(), PeerId::random());
+    exchange.peer_connected(a);
+    exchange.peer_connected(b);
+    let cid = cid_of(RAW, b"block");
+    let (waiter, _block) = oneshot::channel();
+    let messages = exchange.want(cid, waiter);
+
+    let lost = wants_to(&messages, &a);
+    let messages = exchange.message_failed(a, lost.clone());
+    assert_eq!(wants_to(&messages, &a), lost);
+
+    // A lost want-block moves on to the next peer having the block.
+    exchange.handle_message(a, presence(cid, Presence::Have), &mut store);
+    exchange.handle_message(b, presence(cid, Presence::Have), &mut store);
+    let messages = exchange.message_failed(a, vec![WantlistEntry::block(cid)]);
+    assert_eq!(wants_to(&messages, &b), [WantlistEntry::block(cid)]);
+
+    // Nothing is repeated for disconnected peers or ended wants.
+    exchange.peer_disconnected(&a);
+    assert!(exchange.message_failed(a, lost).is_empty());
+    exchange.cancel(&cid);
+    let messages = exchange.message_failed(b, vec![WantlistEntry::have(cid)]);
+    assert!(messages.is_empty());
+    let messages = exchange.message_failed(b, vec![WantlistEntry::cancel(cid)]);
+    assert_eq!(wants_to(&messages, &b), [WantlistEntry::cancel(cid)]);
+  }
+
+  #[test]
+  fn control_messages_are_split() {
+    let mut exchange = BlockExchange::default();
+    let peer = PeerId::random();
+    let cids: Vec<Cid> = (0..MAX_ENTRIES + 1)
+      .map(|i| cid_of(RAW, &i.to_le_bytes()))
+      .collect();
+    for cid in &cids {
+      exchange.want(*cid, oneshot::channel().0);
+    }
+
+    let messages = exchange.peer_connected(peer);
+    let sizes = messages
+      .iter()
+      .map(|(_, message)| message.wantlist.len())
+      .collect::<Vec<_>>();
+    assert_eq!(sizes, [MAX_ENTRIES, 1]);
+    assert_eq!(wants_to(&messages, &peer).len(), cids.len());
+  }
+
+  #[test]
+  fn peer_wants_are_capped() {
+    let mut exchange = BlockExchange::default();
+    let mut store = BlockStore::default();
+    let peer = PeerId::random();
+    let cids: Vec<Cid> = (0..MAX_PEER_WANTS + 1)
+      .map(|i| cid_of(RAW, &i.to_le_bytes()))
+      .collect();
+    let wants = |cids: &[Cid]| BitswapMessage {
+      wantlist: cids
+        .iter()
+        .map(|cid| WantlistEntry {
+          send_dont_have: false,
+          ..WantlistEntry::have(*cid)
+        })
+        .collect(),
+      ..Default::default()
+    };
+
+    exchange.handle_message(peer, wants(&cids[..MAX_PEER_WANTS]), &mut store);
+    exchange.handle_message(peer, wants(&cids[MAX_PEER_WANTS..]), &mut store);
+    assert_eq!(exchange.wantlist(Some(&peer)).len(), MAX_PEER_WANTS);
+    assert!(!exchange
+      .wantlist(Some(&peer))
+      .contains(&cids[MAX_PEER_WANTS]));
+
+    // Cancelling a want makes room for another one.
+    let cancel = BitswapMessage {
+      wantlist: vec![WantlistEntry::cancel(cids[0])],
+      ..Default::default()
+    };
+    exchange.handle_message(peer, cancel, &mut store);
+    exchange.handle_message(peer, wants(&cids[MAX_PEER_WANTS..]), &mut store);
+    assert!(exchange
+      .wantlist(Some(&peer))
+      .contains(&cids[MAX_PEER_WANTS]));
+  }
+
+  #[async_std::test]
+  async fn codec_roundtrip() {
+    let cid = cid_of(RAW, b"block");
+    let message = BitswapMessage {
+      wantlist: vec![WantlistEntry::have(cid), WantlistEntry::cancel(cid)],
+      blocks: vec![(cid, b"block".to_vec())],
+      presences: vec![(cid, Presence::Have), (cid, Presence::DontHave)],
+    };
+    let mut bytes = Cursor::new(Vec::new());
+    BitswapCodec()
+      .write_request(&BitswapProtocol(), &mut bytes, message.clone())
+      .await
+      .unwrap();
+    let decoded = BitswapCodec()
+      .read_request(&BitswapProtocol(), &mut Cursor::new(bytes.into_inner()))
+      .await
+      .unwrap();
+    assert_eq!(decoded, message);
+  }
+
+  #[async_std::test]
+  async fn codec_rejects_unknown_flags() {
+    let cid = cid_of(RAW, b"block").to_bytes();
+    let mut bytes = vec![1, cid.len() as u8];
+    bytes.extend(&cid);
+    bytes.extend([0b1000, 0, 0]);
+    let error = BitswapCodec()
+      .read_request(&BitswapProtocol(), &mut Cursor::new(bytes))
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+}
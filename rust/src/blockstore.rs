@@ -0,0 +1,266 @@
+//! Content-addressed block store.
+//!
+//! Blocks are addressed by their [`Cid`], which commits to the block's
+//! content through a multihash. Blocks from untrusted sources enter the
+//! store through [`BlockStore::put`], which verifies the hash first; blocks
+//! created locally are hashed with SHA-256 by [`BlockStore::insert`].
+//!
+//! [`BlockStore::walk`] traverses the DAG below a root, following the links
+//! of the codecs returned by [`links`].
+use crate::ipld::{self, DAG_CBOR, DAG_JSON};
+use cid::Cid;
+use libp2p::multihash::{Code, MultihashDigest};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Multicodec of opaque bytes.
+pub const RAW: u64 = 0x55;
+
+/// Multicodec of protobuf encoded UnixFS nodes.
+pub const DAG_PB: u64 = 0x70;
+
+/// Maximum size of a single block, as enforced by IPFS implementations.
+pub const MAX_BLOCK_SIZE: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+  /// The CID uses a hash function the store cannot verify.
+  UnsupportedHash {
+    cid: Cid,
+    code: u64,
+  },
+  /// The content does not hash to the CID.
+  HashMismatch {
+    cid: Cid,
+  },
+  TooLarge {
+    cid: Cid,
+    size: usize,
+  },
+  /// The block is not in the store.
+  Missing {
+    cid: Cid,
+  },
+  /// Links cannot be extracted from blocks of this codec.
+  UnsupportedCodec {
+    cid: Cid,
+    codec: u64,
+  },
+  /// The block does not decode according to its codec.
+  Malformed {
+    cid: Cid,
+    reason: String,
+  },
+}
+
+impl fmt::Display for BlockError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BlockError::UnsupportedHash { cid, code } => {
+        write!(
+          f,
+          "block {} uses unsupported hash function {:#x}",
+          cid, code
+        )
+      }
+      BlockError::HashMismatch { cid } => write!(f, "content does not match block {}", cid),
+      BlockError::TooLarge { cid, size } => write!(
+        f,
+        "block {} of {} bytes exceeds the maximum of {} bytes",
+        cid, size, MAX_BLOCK_SIZE
+      ),
+      BlockError::Missing { cid } => write!(f, "block {} is missing", cid),
+      BlockError::UnsupportedCodec { cid, codec } => {
+        write!(f, "block {} uses unsupported codec {:#x}", cid, codec)
+      }
+      BlockError::Malformed { cid, reason } => write!(f, "block {} is malformed: {}", cid, reason),
+    }
+  }
+}
+
+impl std::error::Error for BlockError {}
+
+/// CIDv1 of `data` encoded with `codec`, hashed with SHA-256.
+pub fn cid_of(codec: u64, data: &[u8]) -> Cid {
+  Cid::new_v1(codec, Code::Sha2_256.digest(data))
+}
+
+/// Check that `data` is the content of `cid`.
+pub fn verify(cid: &Cid, data: &[u8]) -> Result<(), BlockError> {
+  if data.len() > MAX_BLOCK_SIZE {
+    return Err(BlockError::TooLarge {
+      cid: *cid,
+      size: data.len(),
+    });
+  }
+  let code = cid.hash().code();
+  let hasher = Code::try_from(code).map_err(|_| BlockError::UnsupportedHash { cid: *cid, code })?;
+  if hasher.digest(data) == *cid.hash() {
+    Ok(())
+  } else {
+    Err(BlockError::HashMismatch { cid: *cid })
+  }
+}
+
+/// CIDs linked from the block `cid` with content `data`, in order.
+pub fn links(cid: &Cid, data: &[u8]) -> Result<Vec<Cid>, BlockError> {
+  match cid.codec() {
+    RAW => Ok(Vec::new()),
+    DAG_PB => dag_pb_links(data).ok_or_else(|| BlockError::Malformed {
+      cid: *cid,
+      reason: "invalid dag-pb node".to_string(),
+    }),
+    codec @ (DAG_CBOR | DAG_JSON) => {
+      ipld::decode(codec, data)
+        .map(|ipld| ipld.links())
+        .map_err(|e| BlockError::Malformed {
+          cid: *cid,
+          reason: e.to_string(),
+        })
+    }
+    codec => Err(BlockError::UnsupportedCodec { cid: *cid, codec }),
+  }
+}
+
+/// Hashes of the `Links` (field 2) of a `PBNode`, each being a `PBLink`
+/// whose `Hash` is field 1.
+fn dag_pb_links(mut data: &[u8]) -> Option<Vec<Cid>> {
+  let mut links = Vec::new();
+  while !data.is_empty() {
+    let (field, value) = protobuf_field(&mut data)?;
+    if field != 2 {
+      continue;
+    }
+    let mut link = value?;
+    while !link.is_empty() {
+      if let (1, Some(hash)) = protobuf_field(&mut link)? {
+        links.push(Cid::try_from(hash).ok()?);
+      }
+    }
+  }
+  Some(links)
+}
+
+/// Take the next protobuf field off `data`, returning its number and, for
+/// length-delimited fields, its content.
+fn protobuf_field<'a>(data: &mut &'a [u8]) -> Option<(u64, Option<&'a [u8]>)> {
+  let key = take_uvarint(data)?;
+  let skip = match key & 0b111 {
+    0 => {
+      take_uvarint(data)?;
+      return Some((key >> 3, None));
+    }
+    1 => 8,
+    2 => {
+      let len = usize::try_from(take_uvarint(data)?).ok()?;
+      let value = data.get(..len)?;
+      *data = &data[len..];
+      return Some((key >> 3, Some(value)));
+    }
+    5 => 4,
+    _ => return None,
+  };
+  *data = data.get(skip..)?;
+  Some((key >> 3, None))
+}
+
+/// Take an unsigned LEB128 varint off the front of `data`.
+pub(crate) fn take_uvarint(data: &mut &[u8]) -> Option<u64> {
+  let mut value = 0u64;
+  for (i, byte) in data.iter().enumerate().take(10) {
+    value |= u64::from(byte & 0x7f) << (7 * i);
+    if byte & 0x80 == 0 {
+      *data = &data[i + 1..];
+      return Some(value);
+    }
+  }
+  None
+}
+
+/// Blocks held in memory.
+#[derive(Debug, Clone, Default)]
+pub struct BlockStore {
+  blocks: HashMap<Cid, Vec<u8>>,
+  size: usize,
+}
+
+impl BlockStore {
+  /// Store `data` as a raw block, returning its CID.
+  pub fn insert(&mut self, data: Vec<u8>) -> Cid {
+    self.insert_with_codec(RAW, data)
+  }
+
+  /// Store `data` encoded with `codec`, returning its CID.
+  pub fn insert_with_codec(&mut self, codec: u64, data: Vec<u8>) -> Cid {
+    let cid = cid_of(codec, &data);
+    self.store(cid, data);
+    cid
+  }
+
+  /// Store `data` under `cid` after verifying that it hashes to it.
+  /// Returns whether the block is new to the store.
+  pub fn put(&mut self, cid: Cid, data: Vec<u8>) -> Result<bool, BlockError> {
+    verify(&cid, &data)?;
+    Ok(self.store(cid, data))
+  }
+
+  /// Store a block already verified against `cid`.
+  pub(crate) fn store(&mut self, cid: Cid, data: Vec<u8>) -> bool {
+    if self.blocks.contains_key(&cid) {
+      return false;
+    }
+    self.size += data.len();
+    self.blocks.insert(cid, data);
+    true
+  }
+
+  pub fn get(&self, cid: &Cid) -> Option<&[u8]> {
+    self.blocks.get(cid).map(Vec::as_slice)
+  }
+
+  pub fn contains(&self, cid: &Cid) -> bool {
+    self.blocks.contains_key(cid)
+  }
+
+  pub fn remove(&mut self, cid: &Cid) -> Option<Vec<u8>> {
+    let data = self.blocks.remove(cid)?;
+    self.size -= data.len();
+    Some(data)
+  }
+
+  pub fn cids(&self) -> impl Iterator<Item = &Cid> {
+    self.blocks.keys()
+  }
+
+  pub fn len(&self) -> usize {
+    self.blocks.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.blocks.is_empty()
+  }
+
+  /// Total size of all blocks in bytes.
+  pub fn size(&self) -> usize {
+    self.size
+  }
+
+  /// CIDs of the DAG below `root`, in depth-first order starting with
+  /// `root` and without duplicates. Fails if a block is missing or cannot
+  /// be decoded.
+  pub fn walk(&self, root: &Cid) -> Result<Vec<Cid>, BlockError> {
+    let mut seen = HashSet::new();
+    let mut order = Vec::new();
+    let mut stack = vec![*root];
+
+    while let Some(cid) = stack.pop() {
+      if !seen.insert(cid) {
+        continue;
+      }
+      let data = self.get(&cid).ok_or(BlockError::Missing { cid })?;
+      stack.extend(links(&cid, data)?.into_iter().rev());
+      order.push(cid);
+    }
+    Ok(order)
+  }
+}
@@ -0,0 +1,339 @@
+//! CAR (content addressable archive) import and export.
+//!
+//! A CARv1 archive is a varint length-prefixed DAG-CBOR header listing the
+//! root CIDs, followed by sections of a varint length, a CID and the block
+//! content. A CARv2 archive wraps a CARv1 payload behind a fixed pragma and
+//! header, its index is not used here.
+//!
+//! [`import`] verifies every block against its CID before anything is added
+//! to the store. [`export`] writes the DAG below a root as CARv1, so that the
+//! archive can be read by other IPFS tooling, e.g. `ipfs dag import`.
+use crate::blockstore::{self, take_uvarint, BlockError, BlockStore};
+use crate::ipld::{self, Ipld};
+use cid::Cid;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Maximum size of the CARv1 header.
+pub const MAX_HEADER_SIZE: usize = 1024 * 1024;
+
+/// Size of the CARv2 header following the pragma.
+const V2_HEADER_SIZE: usize = 40;
+
+/// A block read from an archive, its CID and content.
+pub type Block = (Cid, Vec<u8>);
+
+#[derive(Debug)]
+pub enum CarError {
+  Io(io::Error),
+  InvalidHeader(String),
+  UnsupportedVersion(u64),
+  InvalidSection { offset: usize, reason: String },
+  Block(BlockError),
+}
+
+impl fmt::Display for CarError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CarError::Io(e) => write!(f, "{}", e),
+      CarError::InvalidHeader(reason) => write!(f, "invalid CAR header: {}", reason),
+      CarError::UnsupportedVersion(version) => write!(f, "unsupported CAR version {}", version),
+      CarError::InvalidSection { offset, reason } => {
+        write!(f, "invalid CAR section at offset {}: {}", offset, reason)
+      }
+      CarError::Block(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for CarError {}
+
+impl From<io::Error> for CarError {
+  fn from(e: io::Error) -> Self {
+    CarError::Io(e)
+  }
+}
+
+impl From<BlockError> for CarError {
+  fn from(e: BlockError) -> Self {
+    CarError::Block(e)
+  }
+}
+
+/// Import the CARv1 or CARv2 archive `car` into `store`, returning its
+/// roots. Nothing is imported unless every block matches its CID.
+pub fn import(store: &mut BlockStore, car: &[u8]) -> Result<Vec<Cid>, CarError> {
+  let (roots, blocks) = read(car)?;
+  for (cid, data) in blocks {
+    store.store(cid, data);
+  }
+  Ok(roots)
+}
+
+/// Parse and verify the archive `car`, returning its roots and blocks.
+pub fn read(car: &[u8]) -> Result<(Vec<Cid>, Vec<Block>), CarError> {
+  let mut data = car;
+  let (version, roots) = read_header(&mut data)?;
+  match version {
+    1 => read_v1_sections(car.len() - data.len(), data).map(|blocks| (roots, blocks)),
+    2 => {
+      let header = data
+        .get(..V2_HEADER_SIZE)
+        .ok_or_else(|| CarError::InvalidHeader("truncated CARv2 header".to_string()))?;
+      let u64_at = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&header[offset..offset + 8]);
+        usize::try_from(u64::from_le_bytes(bytes)).unwrap_or(usize::MAX)
+      };
+      // The first 16 bytes are characteristics, followed by the offset and
+      // size of the payload and the offset of the index.
+      let (offset, size) = (u64_at(16), u64_at(24));
+      let payload = offset
+        .checked_add(size)
+        .and_then(|end| car.get(offset..end))
+        .ok_or_else(|| CarError::InvalidHeader("CARv2 payload out of bounds".to_string()))?;
+
+      let mut inner = payload;
+      match read_header(&mut inner)? {
+        (1, roots) => {
+          let blocks = read_v1_sections(offset + payload.len() - inner.len(), inner)?;
+          Ok((roots, blocks))
+        }
+        (version, _) => Err(CarError::UnsupportedVersion(version)),
+      }
+    }
+    version => Err(CarError::UnsupportedVersion(version)),
+  }
+}
+
+/// Read the varint length-prefixed header off `data`, returning the
+/// version and, for CARv1, the roots.
+fn read_header(data: &mut &[u8]) -> Result<(u64, Vec<Cid>), CarError> {
+  let invalid = |reason: &str| CarError::InvalidHeader(reason.to_string());
+
+  let len = take_uvarint(data)
+    .and_then(|len| usize::try_from(len).ok())
+    .ok_or_else(|| invalid("invalid length"))?;
+  if len > MAX_HEADER_SIZE {
+    return Err(invalid("header too large"));
+  }
+  let header = data.get(..len).ok_or_else(|| invalid("truncated"))?;
+  *data = &data[len..];
+
+  let header = match ipld::decode_dag_cbor(header) {
+    Ok(Ipld::Map(header)) => header,
+    Ok(_) => return Err(invalid("not a map")),
+    Err(e) => return Err(CarError::InvalidHeader(e.to_string())),
+  };
+  if let Some(key) = header
+    .keys()
+    .find(|key| !["roots", "version"].contains(&key.as_str()))
+  {
+    return Err(CarError::InvalidHeader(format!("unknown key {:?}", key)));
+  }
+  let version = match header.get("version") {
+    Some(Ipld::Integer(version)) => {
+      u64::try_from(*version).map_err(|_| invalid("invalid version"))?
+    }
+    Some(_) => return Err(invalid("invalid version")),
+    None => return Err(invalid("missing version")),
+  };
+  let roots = match header.get("roots") {
+    Some(Ipld::List(roots)) => roots
+      .iter()
+      .map(|root| match root {
+        Ipld::Link(cid) => Ok(*cid),
+        _ => Err(invalid("root is not a CID")),
+      })
+      .collect::<Result<_, _>>()?,
+    Some(_) => return Err(invalid("roots is not a list")),
+    None => Vec::new(),
+  };
+
+  match version {
+    1 if roots.is_empty() => Err(invalid("no roots")),
+    version => Ok((version, roots)),
+  }
+}
+
+/// Read and verify the sections of a CARv1 payload found at `offset`.
+fn read_v1_sections(offset: usize, mut data: &[u8]) -> Result<Vec<Block>, CarError> {
+  let total = data.len();
+  let mut blocks = Vec::new();
+
+  while !data.is_empty() {
+    let position = offset + total - data.len();
+    let invalid = |reason: &str| CarError::InvalidSection {
+      offset: position,
+      reason: reason.to_string(),
+    };
+
+    let len = take_uvarint(&mut data)
+      .and_then(|len| usize::try_from(len).ok())
+      .ok_or_else(|| invalid("invalid length"))?;
+    // Some writers pad the payload with zeros, which ends the archive.
+    if len == 0 {
+      break;
+    }
+    let mut section = data.get(..len).ok_or_else(|| invalid("truncated"))?;
+    data = &data[len..];
+
+    let cid = Cid::read_bytes(&mut section).map_err(|e| invalid(&e.to_string()))?;
+    blockstore::verify(&cid, section)?;
+    blocks.push((cid, section.to_vec()));
+  }
+  Ok(blocks)
+}
+
+/// Export the DAG below `root` from `store` as a CARv1 archive to `writer`.
+/// Fails before writing anything if a block of the DAG is missing.
+pub fn export(store: &BlockStore, root: &Cid, mut writer: impl Write) -> Result<(), CarError> {
+  let cids = store.walk(root)?;
+
+  let header = encode_header(root);
+  write_uvarint(&mut writer, header.len() as u64)?;
+  writer.write_all(&header)?;
+
+  for cid in cids {
+    let data = store.get(&cid).expect("Walked block to be stored.");
+    let cid = cid.to_bytes();
+    write_uvarint(&mut writer, (cid.len() + data.len()) as u64)?;
+    writer.write_all(&cid)?;
+    writer.write_all(data)?;
+  }
+  writer.flush()?;
+  Ok(())
+}
+
+/// DAG-CBOR encoding of `{"roots": [root], "version": 1}`.
+fn encode_header(root: &Cid) -> Vec<u8> {
+  let header = BTreeMap::from([
+    ("roots".to_string(), Ipld::List(vec![Ipld::Link(*root)])),
+    ("version".to_string(), Ipld::Integer(1)),
+  ]);
+  ipld::encode_dag_cbor(&Ipld::Map(header)).expect("Header to be encodable.")
+}
+
+fn write_uvarint(writer: &mut impl Write, mut value: u64) -> io::Result<()> {
+  let mut buf = Vec::with_capacity(10);
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if value == 0 {
+      buf.push(byte);
+      break;
+    }
+    buf.push(byte | 0x80);
+  }
+  writer.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ipld::DAG_CBOR;
+
+  /// A root linking two raw leaves, with the store holding all three.
+  fn dag() -> (BlockStore, Cid) {
+    let mut store = BlockStore::default();
+    let a = store.insert(b"leaf a".to_vec());
+    let b = store.insert(b"leaf b".to_vec());
+    let root = Ipld::List(vec![Ipld::Link(a), Ipld::Link(b)]);
+    let root = store.insert_with_codec(DAG_CBOR, ipld::encode_dag_cbor(&root).unwrap());
+    (store, root)
+  }
+
+  fn export_v1(store: &BlockStore, root: &Cid) -> Vec<u8> {
+    let mut car = Vec::new();
+    export(store, root, &mut car).unwrap();
+    car
+  }
+
+  /// Wrap the CARv1 archive `v1` in a CARv2 archive, padded after the
+  /// payload as writers do before the index.
+  fn wrap_v2(v1: &[u8]) -> Vec<u8> {
+    let pragma = ipld::encode_dag_cbor(&Ipld::Map(BTreeMap::from([(
+      "version".to_string(),
+      Ipld::Integer(2),
+    )])))
+    .unwrap();
+    let mut car = Vec::new();
+    write_uvarint(&mut car, pragma.len() as u64).unwrap();
+    car.extend(&pragma);
+    let offset = (car.len() + V2_HEADER_SIZE) as u64;
+    car.extend([0; 16]);
+    car.extend(offset.to_le_bytes());
+    car.extend((v1.len() as u64).to_le_bytes());
+    car.extend(0u64.to_le_bytes());
+    car.extend(v1);
+    car.extend([0; 8]);
+    car
+  }
+
+  #[test]
+  fn v1_roundtrip() {
+    let (store, root) = dag();
+    let car = export_v1(&store, &root);
+
+    let mut imported = BlockStore::default();
+    assert_eq!(import(&mut imported, &car).unwrap(), [root]);
+    assert_eq!(imported.len(), store.len());
+    for cid in store.cids() {
+      assert_eq!(imported.get(cid), store.get(cid));
+    }
+    assert_eq!(export_v1(&imported, &root), car);
+  }
+
+  #[test]
+  fn v2_roundtrip() {
+    let (store, root) = dag();
+    let v1 = export_v1(&store, &root);
+
+    let (roots, blocks) = read(&wrap_v2(&v1)).unwrap();
+    assert_eq!(roots, [root]);
+    assert_eq!(blocks, read(&v1).unwrap().1);
+    assert_eq!(blocks.len(), 3);
+  }
+
+  #[test]
+  fn corrupt_block_imports_nothing() {
+    let (store, root) = dag();
+    let mut car = export_v1(&store, &root);
+    let last = car.len() - 1;
+    car[last] ^= 0xff;
+
+    let mut imported = BlockStore::default();
+    assert!(matches!(
+      import(&mut imported, &car),
+      Err(CarError::Block(BlockError::HashMismatch { .. }))
+    ));
+    assert!(imported.is_empty());
+  }
+
+  #[test]
+  fn rejects_invalid_archives() {
+    let (store, root) = dag();
+    let car = export_v1(&store, &root);
+
+    assert!(matches!(
+      read(&car[..car.len() - 1]),
+      Err(CarError::InvalidSection { .. })
+    ));
+    assert!(matches!(read(&[]), Err(CarError::InvalidHeader(_))));
+
+    let mut v2 = wrap_v2(&car);
+    v2.truncate(v2.len() - 16);
+    assert!(matches!(read(&v2), Err(CarError::InvalidHeader(_))));
+
+    let header = ipld::encode_dag_cbor(&Ipld::Map(BTreeMap::from([(
+      "version".to_string(),
+      Ipld::Integer(3),
+    )])))
+    .unwrap();
+    let mut v3 = Vec::new();
+    write_uvarint(&mut v3, header.len() as u64).unwrap();
+    v3.extend(&header);
+    assert!(matches!(read(&v3), Err(CarError::UnsupportedVersion(3))));
+  }
+}
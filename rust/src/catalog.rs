@@ -0,0 +1,305 @@
+//! Catalog protocol: ask a peer which keys it shares.
+//!
+//! The catalog is kept by the network layer, which answers `/file-catalog/1`
+//! requests on its own, provided the configured
+//! [`RequestPolicy`](crate::authz::RequestPolicy) lets the peer browse.
+//! Requests and responses are length-prefixed JSON, responses being
+//! paginated by `offset` and `limit`. Peers the policy refuses receive a
+//! [`CatalogResponse::Denied`] or [`CatalogResponse::RateLimited`] instead of
+//! a page.
+//!
+//! Entries are bounded when added to the catalog, see
+//! [`CatalogEntry::bounded`], so that a full page always fits into
+//! [`MAX_RESPONSE_SIZE`].
+use crate::authz::DenyReason;
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::RequestResponseCodec;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::Duration;
+use std::{fmt, io};
+
+/// Maximum number of entries returned in a single page.
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+/// Maximum size of an encoded request.
+pub const MAX_REQUEST_SIZE: usize = 1_024;
+
+/// Maximum size of an encoded page.
+pub const MAX_RESPONSE_SIZE: usize = 1_000_000;
+
+/// Maximum length of a description in bytes, longer ones are cut.
+pub const MAX_DESCRIPTION_LEN: usize = 1_000;
+
+/// Maximum size of an encoded entry. A page of [`MAX_PAGE_SIZE`] entries
+/// stays below [`MAX_RESPONSE_SIZE`], even with descriptions of
+/// [`MAX_DESCRIPTION_LEN`] bytes that grow sixfold when escaped.
+pub const MAX_ENTRY_SIZE: usize = 9_000;
+
+/// A key shared by a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+  pub key: String,
+  pub size: u64,
+  /// Hex encoded SHA-256 of the content.
+  pub sha256: String,
+  pub mime_type: String,
+  pub description: Option<String>,
+}
+
+impl CatalogEntry {
+  /// Describe the file at `path` shared under `key`, guessing the MIME type
+  /// from the file extension.
+  pub fn for_file(key: String, path: &Path, description: Option<String>) -> io::Result<Self> {
+    let content = std::fs::read(path)?;
+    Ok(Self {
+      key,
+      size: content.len() as u64,
+      sha256: format!("{:x}", Sha256::digest(&content)),
+      mime_type: mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string(),
+      description,
+    })
+  }
+
+  /// Cut the description to [`MAX_DESCRIPTION_LEN`] bytes, failing if the
+  /// entry is still larger than [`MAX_ENTRY_SIZE`] when encoded, e.g.
+  /// because of an overly long key.
+  pub fn bounded(mut self) -> Result<Self, EntryTooLarge> {
+    if let Some(description) = &mut self.description {
+      if description.len() > MAX_DESCRIPTION_LEN {
+        let mut end = MAX_DESCRIPTION_LEN;
+        while !description.is_char_boundary(end) {
+          end -= 1;
+        }
+        description.truncate(end);
+      }
+    }
+
+    let size = serde_json::to_vec(&self).map_or(usize::MAX, |encoded| encoded.len());
+    if size > MAX_ENTRY_SIZE {
+      return Err(EntryTooLarge {
+        key: self.key,
+        size,
+      });
+    }
+    Ok(self)
+  }
+}
+
+/// A catalog entry exceeding [`MAX_ENTRY_SIZE`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryTooLarge {
+  pub key: String,
+  pub size: usize,
+}
+
+impl fmt::Display for EntryTooLarge {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "catalog entry of {} bytes exceeds the maximum of {} bytes",
+      self.size, MAX_ENTRY_SIZE
+    )
+  }
+}
+
+impl std::error::Error for EntryTooLarge {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogRequest {
+  pub offset: u32,
+  pub limit: u32,
+}
+
+/// One page of a peer's catalog, ordered by key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogPage {
+  pub entries: Vec<CatalogEntry>,
+  /// Total number of entries in the catalog.
+  pub total: u32,
+}
+
+impl CatalogPage {
+  /// Offset of the following page, `None` on the last page.
+  pub fn next_offset(&self, request: &CatalogRequest) -> Option<u32> {
+    let next = request.offset.saturating_add(self.entries.len() as u32);
+    (!self.entries.is_empty() && next < self.total).then_some(next)
+  }
+}
+
+/// Answer to a [`CatalogRequest`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CatalogResponse {
+  Page(CatalogPage),
+  /// The requester may not browse the catalog.
+  Denied(DenyReason),
+  /// The requester may not browse the catalog for now.
+  RateLimited {
+    retry_after: Duration,
+  },
+}
+
+#[derive(Debug, Clone)]
+pub struct CatalogProtocol();
+
+impl ProtocolName for CatalogProtocol {
+  fn protocol_name(&self) -> &[u8] {
+    "/file-catalog/1".as_bytes()
+  }
+}
+
+#[derive(Clone)]
+pub struct CatalogCodec();
+
+#[async_trait]
+impl RequestResponseCodec for CatalogCodec {
+  type Protocol = CatalogProtocol;
+  type Request = CatalogRequest;
+  type Response = CatalogResponse;
+
+  async fn read_request<T>(&mut self, _: &CatalogProtocol, io: &mut T) -> io::Result<Self::Request>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    let vec = read_length_prefixed(io, MAX_REQUEST_SIZE).await?;
+    serde_json::from_slice(&vec).map_err(Into::into)
+  }
+
+  async fn read_response<T>(
+    &mut self,
+    _: &CatalogProtocol,
+    io: &mut T,
+  ) -> io::Result<Self::Response>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    let vec = read_length_prefixed(io, MAX_RESPONSE_SIZE).await?;
+    serde_json::from_slice(&vec).map_err(Into::into)
+  }
+
+  async fn write_request<T>(
+    &mut self,
+    _: &CatalogProtocol,
+    io: &mut T,
+    request: CatalogRequest,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    write_length_prefixed(io, serde_json::to_vec(&request)?).await?;
+    io.close().await?;
+
+    Ok(())
+  }
+
+  async fn write_response<T>(
+    &mut self,
+    _: &CatalogProtocol,
+    io: &mut T,
+    response: CatalogResponse,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    write_length_prefixed(io, serde_json::to_vec(&response)?).await?;
+    io.close().await?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::io::Cursor;
+
+  fn entry(key: &str, description: Option<String>) -> CatalogEntry {
+    CatalogEntry {
+      key: key.to_string(),
+      size: 0,
+      sha256: String::new(),
+      mime_type: "text/plain".to_string(),
+      description,
+    }
+  }
+
+  async fn roundtrip(response: CatalogResponse) -> CatalogResponse {
+    let mut bytes = Vec::new();
+    CatalogCodec()
+      .write_response(&CatalogProtocol(), &mut bytes, response)
+      .await
+      .unwrap();
+    CatalogCodec()
+      .read_response(&CatalogProtocol(), &mut Cursor::new(bytes))
+      .await
+      .unwrap()
+  }
+
+  #[async_std::test]
+  async fn response_roundtrip() {
+    for response in [
+      CatalogResponse::Page(CatalogPage {
+        entries: vec![entry("a", Some("first".to_string()))],
+        total: 3,
+      }),
+      CatalogResponse::Denied(DenyReason::Unauthorized),
+      CatalogResponse::Denied(DenyReason::Other(7)),
+      CatalogResponse::RateLimited {
+        retry_after: Duration::from_millis(1_500),
+      },
+    ] {
+      assert_eq!(roundtrip(response.clone()).await, response);
+    }
+  }
+
+  #[async_std::test]
+  async fn oversized_request_is_rejected() {
+    let mut bytes = Vec::new();
+    write_length_prefixed(&mut bytes, vec![b' '; MAX_REQUEST_SIZE + 1])
+      .await
+      .unwrap();
+    assert!(CatalogCodec()
+      .read_request(&CatalogProtocol(), &mut Cursor::new(bytes))
+      .await
+      .is_err());
+  }
+
+  #[test]
+  fn next_offset() {
+    let page = CatalogPage {
+      entries: vec![entry("a", None), entry("b", None)],
+      total: 5,
+    };
+    let request = |offset| CatalogRequest { offset, limit: 2 };
+    assert_eq!(page.next_offset(&request(0)), Some(2));
+    assert_eq!(page.next_offset(&request(3)), None);
+
+    let empty = CatalogPage {
+      entries: Vec::new(),
+      total: 5,
+    };
+    assert_eq!(empty.next_offset(&request(0)), None);
+  }
+
+  #[test]
+  fn bounded_cuts_description_at_char_boundary() {
+    let description = "é".repeat(MAX_DESCRIPTION_LEN);
+    let bounded = entry("a", Some(description)).bounded().unwrap();
+    let description = bounded.description.unwrap();
+    assert!(description.len() <= MAX_DESCRIPTION_LEN);
+    assert!(description.len() > MAX_DESCRIPTION_LEN - 2);
+  }
+
+  #[test]
+  fn bounded_rejects_long_keys() {
+    let error = entry(&"k".repeat(MAX_ENTRY_SIZE), None)
+      .bounded()
+      .unwrap_err();
+    assert!(error.size > MAX_ENTRY_SIZE);
+  }
+}
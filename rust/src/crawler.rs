@@ -0,0 +1,861 @@
+//! DHT crawler.
+//!
+//! [`crawl`] runs a dedicated node that joins a DHT through the configured
+//! bootstrap peers and maps it: every peer discovered is sent `FIND_NODE`
+//! requests for random keys spread over its buckets, asking it for the peers
+//! it knows, and counts as reachable if it answers. Once no peers are left to
+//! ask, the crawler runs Kademlia lookups for random keys until a number of
+//! rounds in a row turns up nothing new. Peers the crawler connects to are identified, which yields
+//! their protocols and agent versions.
+//!
+//! The result is a [`DhtSnapshot`] that can be exported as JSON or CSV. The
+//! crawler runs over the same transports as the network layer, i.e. over the
+//! in-memory transport against a local test swarm, or over TCP against a
+//! private DHT, see [`CrawlerConfig::set_protocol_name`].
+use crate::transport::{self, TransportConfig};
+use async_std::future::timeout;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use libp2p::core::connection::{ConnectedPoint, ConnectionId};
+use libp2p::core::upgrade::{DeniedUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo};
+use libp2p::identity;
+use libp2p::kad::kbucket::Key;
+use libp2p::kad::protocol::{KadPeer, KadRequestMsg, KadResponseMsg, KademliaProtocolConfig};
+use libp2p::kad::record::store::MemoryStore;
+use libp2p::kad::{
+  GetClosestPeersError, GetClosestPeersOk, Kademlia, KademliaConfig, KademliaEvent, QueryId,
+  QueryResult,
+};
+use libp2p::multiaddr::Protocol;
+use libp2p::relay::v2::client as relay;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::swarm::{
+  NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, OneShotHandler, OneShotHandlerConfig,
+  PollParameters, SubstreamProtocol, SwarmBuilder, SwarmEvent,
+};
+use libp2p::{NetworkBehaviour, Swarm};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io::{self, Write};
+use std::iter;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use void::Void;
+
+/// Configuration of a crawl.
+#[derive(Debug, Clone)]
+pub struct CrawlerConfig {
+  transport: TransportConfig,
+  bootstrap: Vec<Multiaddr>,
+  protocol_name: Option<Cow<'static, [u8]>>,
+  parallelism: usize,
+  keys_per_peer: u32,
+  max_rounds: usize,
+  idle_rounds: usize,
+  query_timeout: Duration,
+  identify_grace: Duration,
+}
+
+impl Default for CrawlerConfig {
+  fn default() -> Self {
+    Self {
+      transport: Default::default(),
+      bootstrap: Default::default(),
+      protocol_name: None,
+      parallelism: 8,
+      keys_per_peer: 8,
+      max_rounds: 1000,
+      idle_rounds: 3,
+      query_timeout: Duration::from_secs(30),
+      identify_grace: Duration::from_secs(5),
+    }
+  }
+}
+
+impl CrawlerConfig {
+  pub fn set_transport(&mut self, transport: TransportConfig) -> &mut Self {
+    self.transport = transport;
+    self
+  }
+
+  /// Start crawling from the peer at `addr`, which has to end with
+  /// `/p2p/<peer-id>`.
+  pub fn add_bootstrap(&mut self, addr: Multiaddr) -> &mut Self {
+    self.bootstrap.push(addr);
+    self
+  }
+
+  /// Crawl a DHT speaking the Kademlia protocol `name` instead of the default
+  /// `/ipfs/kad/1.0.0`.
+  pub fn set_protocol_name(&mut self, name: impl Into<Cow<'static, [u8]>>) -> &mut Self {
+    self.protocol_name = Some(name.into());
+    self
+  }
+
+  /// Lookups run concurrently per round.
+  pub fn set_parallelism(&mut self, parallelism: usize) -> &mut Self {
+    self.parallelism = parallelism.max(1);
+    self
+  }
+
+  /// Number of keys each discovered peer is asked for. The `i`-th key shares
+  /// the first `i` bits with the peer's own key, so that the answers come
+  /// from its buckets of the most distant peers on. Defaults to 8.
+  pub fn set_keys_per_peer(&mut self, keys: u32) -> &mut Self {
+    self.keys_per_peer = keys.clamp(1, 16);
+    self
+  }
+
+  /// Upper bound on the number of rounds, as a safeguard against a DHT
+  /// that keeps growing while it is crawled.
+  pub fn set_max_rounds(&mut self, max_rounds: usize) -> &mut Self {
+    self.max_rounds = max_rounds;
+    self
+  }
+
+  /// Rounds of random lookups without a new peer after which the crawl
+  /// ends.
+  pub fn set_idle_rounds(&mut self, idle_rounds: usize) -> &mut Self {
+    self.idle_rounds = idle_rounds;
+    self
+  }
+
+  pub fn set_query_timeout(&mut self, query_timeout: Duration) -> &mut Self {
+    self.query_timeout = query_timeout;
+    self
+  }
+
+  /// Time given to outstanding identify exchanges after the last round.
+  pub fn set_identify_grace(&mut self, identify_grace: Duration) -> &mut Self {
+    self.identify_grace = identify_grace;
+    self
+  }
+}
+
+/// A peer found while crawling.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CrawledPeer {
+  pub addresses: Vec<Multiaddr>,
+  /// Protocols reported via identify, empty if the peer was not identified.
+  pub protocols: Vec<String>,
+  pub agent_version: Option<String>,
+  pub protocol_version: Option<String>,
+  /// Whether the peer answered the crawler's `FIND_NODE` request.
+  pub reachable: bool,
+}
+
+/// The peers of a DHT as seen by the crawler.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DhtSnapshot {
+  pub peers: BTreeMap<PeerId, CrawledPeer>,
+  /// Lookup rounds the crawl took.
+  pub rounds: usize,
+}
+
+/// Export representation, peer IDs and addresses in their string form.
+#[derive(Serialize)]
+struct ExportedPeer<'a> {
+  peer_id: String,
+  reachable: bool,
+  agent_version: Option<&'a str>,
+  protocol_version: Option<&'a str>,
+  addresses: Vec<String>,
+  protocols: &'a [String],
+}
+
+impl DhtSnapshot {
+  /// Number of peers that answered the crawler.
+  pub fn reachable(&self) -> usize {
+    self.peers.values().filter(|p| p.reachable).count()
+  }
+
+  fn exported(&self) -> impl Iterator<Item = ExportedPeer<'_>> {
+    self.peers.iter().map(|(peer, crawled)| ExportedPeer {
+      peer_id: peer.to_base58(),
+      reachable: crawled.reachable,
+      agent_version: crawled.agent_version.as_deref(),
+      protocol_version: crawled.protocol_version.as_deref(),
+      addresses: crawled.addresses.iter().map(ToString::to_string).collect(),
+      protocols: &crawled.protocols,
+    })
+  }
+
+  /// Write the snapshot as a pretty-printed JSON array of peers.
+  pub fn write_json(&self, writer: impl Write) -> io::Result<()> {
+    let peers: Vec<ExportedPeer> = self.exported().collect();
+    serde_json::to_writer_pretty(writer, &peers)?;
+    Ok(())
+  }
+
+  /// Write the snapshot as CSV with a header row, one peer per row.
+  /// Addresses and protocols are separated by spaces within their column.
+  pub fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+    writeln!(
+      writer,
+      "peer_id,reachable,agent_version,protocol_version,addresses,protocols"
+    )?;
+    for peer in self.exported() {
+      writeln!(
+        writer,
+        "{},{},{},{},{},{}",
+        peer.peer_id,
+        peer.reachable,
+        csv_field(peer.agent_version.unwrap_or_default()),
+        csv_field(peer.protocol_version.unwrap_or_default()),
+        csv_field(&peer.addresses.join(" ")),
+        csv_field(&peer.protocols.join(" ")),
+      )?;
+    }
+    Ok(())
+  }
+}
+
+/// Quote `value` if needed, agent versions are free-form.
+fn csv_field(value: &str) -> Cow<'_, str> {
+  if value.contains([',', '"', '\n', '\r']) {
+    Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+  } else {
+    Cow::Borrowed(value)
+  }
+}
+
+/// Crawl the DHT reachable from the configured bootstrap peers.
+pub async fn crawl(config: CrawlerConfig) -> Result<DhtSnapshot, Box<dyn Error>> {
+  if config.bootstrap.is_empty() {
+    return Err("No bootstrap peers configured.".into());
+  }
+
+  let id_keys = identity::Keypair::generate_ed25519();
+  let peer_id = id_keys.public().to_peer_id();
+  let (relay_transport, relay_client) = relay::Client::new_transport_and_behaviour(peer_id);
+  let transport = transport::build(&id_keys, relay_transport, config.transport.clone()).await?;
+
+  let mut kad_config = KademliaConfig::default();
+  let mut kad_protocol = KademliaProtocolConfig::default();
+  kad_config.set_query_timeout(config.query_timeout);
+  if let Some(name) = &config.protocol_name {
+    kad_config.set_protocol_name(name.clone());
+    kad_protocol.set_protocol_name(name.clone());
+  }
+  let mut kademlia = Kademlia::with_config(peer_id, MemoryStore::new(peer_id), kad_config);
+
+  let mut crawler = Crawler {
+    local_peer_id: peer_id,
+    snapshot: DhtSnapshot::default(),
+    frontier: VecDeque::new(),
+    queries: HashSet::new(),
+    requests: HashSet::new(),
+  };
+  for addr in &config.bootstrap {
+    let mut addr = addr.clone();
+    match addr.pop() {
+      Some(Protocol::P2p(hash)) => {
+        let peer = PeerId::from_multihash(hash).map_err(|_| "Invalid peer ID.")?;
+        kademlia.add_address(&peer, addr.clone());
+        crawler.discovered(peer, Some(addr));
+      }
+      _ => return Err(format!("Bootstrap address {} lacks /p2p/<peer-id>.", addr).into()),
+    }
+  }
+
+  let mut swarm = SwarmBuilder::new(
+    transport,
+    CrawlerBehaviour {
+      kademlia,
+      identify: Identify::new(IdentifyConfig::new(
+        "/libp2p-demo-crawler/1.0.0".to_string(),
+        id_keys.public(),
+      )),
+      relay_client,
+      find_node: FindNode::new(kad_protocol, config.query_timeout, config.keys_per_peer),
+    },
+    peer_id,
+  )
+  .build();
+
+  let mut idle = 0;
+  while crawler.snapshot.rounds < config.max_rounds && idle < config.idle_rounds {
+    crawler.snapshot.rounds += 1;
+    let known = crawler.snapshot.peers.len();
+    let from_frontier = !crawler.frontier.is_empty();
+
+    for _ in 0..config.parallelism {
+      if from_frontier {
+        let peer = match crawler.frontier.pop_front() {
+          Some(peer) => peer,
+          None => break,
+        };
+        let addresses = crawler.snapshot.peers[&peer].addresses.clone();
+        swarm.behaviour_mut().find_node.send(peer, addresses);
+        crawler.requests.insert(peer);
+      } else {
+        let id = swarm
+          .behaviour_mut()
+          .kademlia
+          .get_closest_peers(PeerId::random());
+        crawler.queries.insert(id);
+      }
+    }
+
+    // Every query and request ends at the latest after the query timeout.
+    while !crawler.queries.is_empty() || !crawler.requests.is_empty() {
+      let event = swarm.select_next_some().await;
+      crawler.handle_event(&mut swarm, event);
+    }
+
+    debug!(
+      "crawler: round {} done, {} peers known, {} to look up",
+      crawler.snapshot.rounds,
+      crawler.snapshot.peers.len(),
+      crawler.frontier.len()
+    );
+    if from_frontier || crawler.snapshot.peers.len() > known {
+      idle = 0;
+    } else {
+      idle += 1;
+    }
+  }
+
+  // Give identify a chance to complete on the connections of the last round.
+  let deadline = Instant::now() + config.identify_grace;
+  while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+    match timeout(remaining, swarm.select_next_some()).await {
+      Ok(event) => {
+        crawler.handle_event(&mut swarm, event);
+      }
+      Err(_) => break,
+    }
+  }
+
+  Ok(crawler.snapshot)
+}
+
+struct Crawler {
+  local_peer_id: PeerId,
+  snapshot: DhtSnapshot,
+  /// Discovered peers not yet asked.
+  frontier: VecDeque<PeerId>,
+  /// Random lookups of the current round.
+  queries: HashSet<QueryId>,
+  /// Peers asked in the current round.
+  requests: HashSet<PeerId>,
+}
+
+impl Crawler {
+  /// Record `peer`, queueing it for a request if it is new.
+  fn discovered(&mut self, peer: PeerId, addr: Option<Multiaddr>) -> &mut CrawledPeer {
+    let frontier = &mut self.frontier;
+    let crawled = self.snapshot.peers.entry(peer).or_insert_with(|| {
+      frontier.push_back(peer);
+      CrawledPeer::default()
+    });
+    if let Some(addr) = addr {
+      if !crawled.addresses.contains(&addr) {
+        crawled.addresses.push(addr);
+      }
+    }
+    crawled
+  }
+
+  /// Handle a swarm event, marking completed queries and requests.
+  fn handle_event<E>(
+    &mut self,
+    swarm: &mut Swarm<CrawlerBehaviour>,
+    event: SwarmEvent<CrawlerEvent, E>,
+  ) {
+    match event {
+      SwarmEvent::Behaviour(CrawlerEvent::Kademlia(KademliaEvent::RoutingUpdated {
+        peer,
+        addresses,
+        ..
+      })) => {
+        for addr in addresses.into_vec() {
+          self.discovered(peer, Some(addr));
+        }
+      }
+      SwarmEvent::Behaviour(CrawlerEvent::Kademlia(KademliaEvent::OutboundQueryCompleted {
+        id,
+        result: QueryResult::GetClosestPeers(result),
+        ..
+      })) => {
+        let peers = match result {
+          Ok(GetClosestPeersOk { peers, .. }) => peers,
+          Err(GetClosestPeersError::Timeout { peers, .. }) => peers,
+        };
+        for peer in peers {
+          if peer != self.local_peer_id {
+            self.discovered(peer, None);
+          }
+        }
+        self.queries.remove(&id);
+      }
+      SwarmEvent::Behaviour(CrawlerEvent::FindNode(FindNodeEvent::Response {
+        peer,
+        closer_peers,
+      })) => {
+        self.requests.remove(&peer);
+        self.discovered(peer, None).reachable = true;
+        for KadPeer {
+          node_id,
+          multiaddrs,
+          ..
+        } in closer_peers
+        {
+          if node_id == self.local_peer_id {
+            continue;
+          }
+          self.discovered(node_id, None);
+          for addr in multiaddrs {
+            swarm
+              .behaviour_mut()
+              .kademlia
+              .add_address(&node_id, addr.clone());
+            self.discovered(node_id, Some(addr));
+          }
+        }
+      }
+      SwarmEvent::Behaviour(CrawlerEvent::FindNode(FindNodeEvent::Failed { peer })) => {
+        trace!("crawler: {} did not answer", peer);
+        self.requests.remove(&peer);
+      }
+      SwarmEvent::Behaviour(CrawlerEvent::RelayClient(event)) => {
+        trace!("crawler: {:?}", event);
+      }
+      SwarmEvent::Behaviour(CrawlerEvent::Identify(IdentifyEvent::Received {
+        peer_id,
+        info:
+          IdentifyInfo {
+            protocol_version,
+            agent_version,
+            listen_addrs,
+            protocols,
+            ..
+          },
+      })) => {
+        for addr in &listen_addrs {
+          swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&peer_id, addr.clone());
+        }
+        let crawled = self.discovered(peer_id, None);
+        for addr in listen_addrs {
+          if !crawled.addresses.contains(&addr) {
+            crawled.addresses.push(addr);
+          }
+        }
+        crawled.protocols = protocols;
+        crawled.agent_version = Some(agent_version);
+        crawled.protocol_version = Some(protocol_version);
+      }
+      SwarmEvent::OutgoingConnectionError {
+        peer_id: Some(peer_id),
+        error,
+      } => {
+        trace!("crawler: dialing {} failed: {}", peer_id, error);
+      }
+      _ => {}
+    }
+  }
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "CrawlerEvent")]
+struct CrawlerBehaviour {
+  kademlia: Kademlia<MemoryStore>,
+  identify: Identify,
+  relay_client: relay::Client,
+  find_node: FindNode,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+enum CrawlerEvent {
+  Kademlia(KademliaEvent),
+  Identify(IdentifyEvent),
+  RelayClient(relay::Event),
+  FindNode(FindNodeEvent),
+}
+
+impl From<KademliaEvent> for CrawlerEvent {
+  fn from(event: KademliaEvent) -> Self {
+    CrawlerEvent::Kademlia(event)
+  }
+}
+
+impl From<IdentifyEvent> for CrawlerEvent {
+  fn from(event: IdentifyEvent) -> Self {
+    CrawlerEvent::Identify(event)
+  }
+}
+
+impl From<relay::Event> for CrawlerEvent {
+  fn from(event: relay::Event) -> Self {
+    CrawlerEvent::RelayClient(event)
+  }
+}
+
+impl From<FindNodeEvent> for CrawlerEvent {
+  fn from(event: FindNodeEvent) -> Self {
+    CrawlerEvent::FindNode(event)
+  }
+}
+
+/// A `FIND_NODE` request for `key` sent on its own substream.
+#[derive(Debug, Clone)]
+struct FindNodeRequest {
+  protocol: KademliaProtocolConfig,
+  key: Vec<u8>,
+}
+
+/// Peers closest to the requested key, as known by the asked peer.
+#[derive(Debug)]
+struct FindNodeResponse(Vec<KadPeer>);
+
+impl From<Void> for FindNodeResponse {
+  fn from(v: Void) -> Self {
+    void::unreachable(v)
+  }
+}
+
+impl UpgradeInfo for FindNodeRequest {
+  type Info = Cow<'static, [u8]>;
+  type InfoIter = iter::Once<Self::Info>;
+
+  fn protocol_info(&self) -> Self::InfoIter {
+    self.protocol.protocol_info()
+  }
+}
+
+impl<C> OutboundUpgrade<C> for FindNodeRequest
+where
+  C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  type Output = FindNodeResponse;
+  type Error = io::Error;
+  type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+  fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+    async move {
+      let mut stream = self.protocol.upgrade_outbound(socket, info).await?;
+      stream
+        .send(KadRequestMsg::FindNode { key: self.key })
+        .await?;
+      match stream.next().await {
+        Some(Ok(KadResponseMsg::FindNode { closer_peers })) => Ok(FindNodeResponse(closer_peers)),
+        Some(Ok(response)) => Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("unexpected response {:?}", response),
+        )),
+        Some(Err(e)) => Err(e),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+      }
+    }
+    .boxed()
+  }
+}
+
+type FindNodeHandler = OneShotHandler<DeniedUpgrade, FindNodeRequest, FindNodeResponse>;
+
+#[derive(Debug)]
+enum FindNodeEvent {
+  /// `peer` answered at least one request, with the peers it knows closest
+  /// to the keys asked for.
+  Response {
+    peer: PeerId,
+    closer_peers: Vec<KadPeer>,
+  },
+  /// `peer` could not be reached or did not answer.
+  Failed { peer: PeerId },
+}
+
+/// Sends `FIND_NODE` requests to given peers, unlike Kademlia lookups which
+/// walk the DHT towards a key. Peers not connected to are dialed.
+struct FindNode {
+  protocol: KademliaProtocolConfig,
+  timeout: Duration,
+  keys: u32,
+  connected: HashSet<PeerId>,
+  /// Peers asked along with the requests not answered yet and the peers
+  /// received so far.
+  pending: HashMap<PeerId, (u32, Vec<KadPeer>)>,
+  actions: VecDeque<NetworkBehaviourAction<FindNodeEvent, FindNodeHandler>>,
+}
+
+impl FindNode {
+  fn new(protocol: KademliaProtocolConfig, timeout: Duration, keys: u32) -> Self {
+    Self {
+      protocol,
+      timeout,
+      keys,
+      connected: Default::default(),
+      pending: Default::default(),
+      actions: Default::default(),
+    }
+  }
+
+  /// Ask `peer`, reachable at `addresses`, for the peers closest to random
+  /// keys spread over its buckets, see [`bucket_keys`]. The outcome is
+  /// reported as a [`FindNodeEvent`] once all requests are answered or the
+  /// connection fails.
+  fn send(&mut self, peer: PeerId, addresses: Vec<Multiaddr>) {
+    if self.pending.contains_key(&peer) {
+      return;
+    }
+    self.pending.insert(peer, (self.keys, Vec::new()));
+    let protocol = self.protocol.clone();
+    let requests = bucket_keys(&peer, self.keys).map(move |key| FindNodeRequest {
+      protocol: protocol.clone(),
+      key,
+    });
+    if self.connected.contains(&peer) {
+      for request in requests {
+        self
+          .actions
+          .push_back(NetworkBehaviourAction::NotifyHandler {
+            peer_id: peer,
+            handler: NotifyHandler::Any,
+            event: request,
+          });
+      }
+    } else {
+      let mut handler = self.new_handler();
+      requests.for_each(|request| handler.send_request(request));
+      self.actions.push_back(NetworkBehaviourAction::Dial {
+        opts: DialOpts::peer_id(peer)
+          .condition(PeerCondition::Always)
+          .addresses(addresses)
+          .extend_addresses_through_behaviour()
+          .build(),
+        handler,
+      });
+    }
+  }
+
+  /// Report `peer` as failed, or with the peers received so far if it
+  /// answered any request.
+  fn failed(&mut self, peer: PeerId) {
+    if let Some((unanswered, closer_peers)) = self.pending.remove(&peer) {
+      let event = if unanswered < self.keys {
+        FindNodeEvent::Response { peer, closer_peers }
+      } else {
+        FindNodeEvent::Failed { peer }
+      };
+      self
+        .actions
+        .push_back(NetworkBehaviourAction::GenerateEvent(event));
+    }
+  }
+}
+
+impl NetworkBehaviour for FindNode {
+  type ConnectionHandler = FindNodeHandler;
+  type OutEvent = FindNodeEvent;
+
+  fn new_handler(&mut self) -> Self::ConnectionHandler {
+    OneShotHandler::new(
+      SubstreamProtocol::new(DeniedUpgrade, ()),
+      OneShotHandlerConfig {
+        outbound_substream_timeout: self.timeout,
+        ..Default::default()
+      },
+    )
+  }
+
+  fn inject_connection_established(
+    &mut self,
+    peer: &PeerId,
+    _: &ConnectionId,
+    _: &ConnectedPoint,
+    _: Option<&Vec<Multiaddr>>,
+    _: usize,
+  ) {
+    self.connected.insert(*peer);
+  }
+
+  fn inject_connection_closed(
+    &mut self,
+    peer: &PeerId,
+    _: &ConnectionId,
+    _: &ConnectedPoint,
+    handler: FindNodeHandler,
+    remaining_established: usize,
+  ) {
+    if remaining_established == 0 {
+      self.connected.remove(peer);
+    }
+    // Failed requests are not taken off the handler's count.
+    if handler.pending_requests() > 0 {
+      self.failed(*peer);
+    }
+  }
+
+  fn inject_dial_failure(
+    &mut self,
+    peer: Option<PeerId>,
+    handler: FindNodeHandler,
+    _: &libp2p::swarm::DialError,
+  ) {
+    if let Some(peer) = peer {
+      if handler.pending_requests() > 0 {
+        self.failed(peer);
+      }
+    }
+  }
+
+  fn inject_event(&mut self, peer: PeerId, _: ConnectionId, response: FindNodeResponse) {
+    if let Some((unanswered, closer_peers)) = self.pending.get_mut(&peer) {
+      *unanswered -= 1;
+      closer_peers.extend(response.0);
+      if *unanswered == 0 {
+        let (_, closer_peers) = self.pending.remove(&peer).expect("Peer to be pending.");
+        self
+          .actions
+          .push_back(NetworkBehaviourAction::GenerateEvent(
+            FindNodeEvent::Response { peer, closer_peers },
+          ));
+      }
+    }
+  }
+
+  fn poll(
+    &mut self,
+    _: &mut Context<'_>,
+    _: &mut impl PollParameters,
+  ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+    match self.actions.pop_front() {
+      Some(action) => Poll::Ready(action),
+      None => Poll::Pending,
+    }
+  }
+}
+
+/// `count` random keys for `FIND_NODE` requests to `peer`, the `i`-th of
+/// them sharing exactly the first `i` bits with the peer's key, i.e. falling
+/// into its `i`-th most distant bucket. Each further bit doubles the expected
+/// number of keys drawn, so `count` should stay small.
+fn bucket_keys(peer: &PeerId, count: u32) -> impl Iterator<Item = Vec<u8>> {
+  let target = Key::from(*peer);
+  (0..count).map(move |shared| loop {
+    let candidate = PeerId::random();
+    if target.distance(&Key::from(candidate)).ilog2() == Some(255 - shared) {
+      break candidate.to_bytes();
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn snapshot() -> (PeerId, DhtSnapshot) {
+    let peer = PeerId::random();
+    let mut snapshot = DhtSnapshot::default();
+    snapshot.peers.insert(
+      peer,
+      CrawledPeer {
+        addresses: vec![
+          "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+          "/ip4/127.0.0.1/tcp/2".parse().unwrap(),
+        ],
+        protocols: vec!["/ipfs/kad/1.0.0".to_string(), "/ipfs/id/1.0.0".to_string()],
+        agent_version: Some("agent \"a\", 1.0".to_string()),
+        protocol_version: Some("/ipfs/0.1.0".to_string()),
+        reachable: true,
+      },
+    );
+    snapshot
+      .peers
+      .insert(PeerId::random(), CrawledPeer::default());
+    (peer, snapshot)
+  }
+
+  #[test]
+  fn csv_field_quotes_when_needed() {
+    assert_eq!(csv_field("plain"), "plain");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+  }
+
+  #[test]
+  fn write_csv() {
+    let (peer, snapshot) = snapshot();
+    let mut csv = Vec::new();
+    snapshot.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+
+    let mut lines = csv.lines();
+    assert_eq!(
+      lines.next(),
+      Some("peer_id,reachable,agent_version,protocol_version,addresses,protocols")
+    );
+    assert!(lines.any(|line| line
+      == format!(
+        "{},true,\"agent \"\"a\"\", 1.0\",/ipfs/0.1.0,\
+         /ip4/127.0.0.1/tcp/1 /ip4/127.0.0.1/tcp/2,/ipfs/kad/1.0.0 /ipfs/id/1.0.0",
+        peer
+      )));
+    assert_eq!(csv.lines().count(), 3);
+  }
+
+  #[test]
+  fn write_json() {
+    let (peer, snapshot) = snapshot();
+    let mut json = Vec::new();
+    snapshot.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+
+    let peers = json.as_array().unwrap();
+    assert_eq!(peers.len(), 2);
+    let exported = peers
+      .iter()
+      .find(|p| p["peer_id"] == peer.to_base58())
+      .unwrap();
+    assert_eq!(exported["reachable"], true);
+    assert_eq!(exported["addresses"][1], "/ip4/127.0.0.1/tcp/2");
+    assert_eq!(snapshot.reachable(), 1);
+  }
+
+  #[test]
+  fn discovered_peers_are_queued_once() {
+    let mut crawler = Crawler {
+      local_peer_id: PeerId::random(),
+      snapshot: DhtSnapshot::default(),
+      frontier: VecDeque::new(),
+      queries: HashSet::new(),
+      requests: HashSet::new(),
+    };
+    let peer = PeerId::random();
+    let addr: Multiaddr = "/memory/1".parse().unwrap();
+    crawler.discovered(peer, Some(addr.clone()));
+    crawler.discovered(peer, Some(addr.clone()));
+    crawler.discovered(peer, None);
+
+    assert_eq!(crawler.frontier, [peer]);
+    assert_eq!(crawler.snapshot.peers[&peer].addresses, [addr]);
+    assert!(!crawler.snapshot.peers[&peer].reachable);
+  }
+
+  #[test]
+  fn bucket_keys_cover_distant_buckets() {
+    let peer = PeerId::random();
+    let distances = bucket_keys(&peer, 4)
+      .map(|key| Key::from(peer).distance(&Key::new(key)).ilog2())
+      .collect::<Vec<_>>();
+    assert_eq!(distances, [Some(255), Some(254), Some(253), Some(252)]);
+  }
+
+  #[async_std::test]
+  async fn crawl_requires_bootstrap_peer_ids() {
+    assert!(crawl(CrawlerConfig::default()).await.is_err());
+
+    let mut config = CrawlerConfig::default();
+    config.add_bootstrap("/memory/1".parse().unwrap());
+    assert!(crawl(config).await.is_err());
+  }
+}
@@ -0,0 +1,416 @@
+//! Simple file exchange protocol.
+//!
+//! A request is the length-prefixed UTF-8 name of the requested file. In
+//! `/file-exchange/2` a response is a length-prefixed frame whose first byte
+//! is a status, followed by the file content, the [`DenyReason`] of a denial
+//! or the duration to wait after being rate limited (big endian `u64`
+//! seconds and `u32` nanoseconds).
+//!
+//! `/file-exchange/1` is still spoken with peers that do not support
+//! version 2. Its responses are the bare file content, so denials and rate
+//! limits cannot be sent to those peers, their stream being closed instead.
+//!
+//! Decoding never trusts the peer: invalid UTF-8, frames above the size
+//! limits, empty frames, unknown statuses and truncated streams are all
+//! reported as `io::Error`s.
+use crate::authz::DenyReason;
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::core::upgrade::{read_varint, write_varint, ProtocolName};
+use libp2p::request_response::RequestResponseCodec;
+use std::io;
+use std::time::Duration;
+
+/// Maximum size of a request frame, i.e. of a file name.
+pub const MAX_REQUEST_SIZE: usize = 1_000_000;
+
+/// Maximum size of a response frame, i.e. of a file plus the status byte.
+pub const MAX_RESPONSE_SIZE: usize = 500_000_000;
+
+// Status byte preceding the payload of a response.
+const STATUS_FILE: u8 = 0;
+const STATUS_DENIED: u8 = 1;
+const STATUS_RATE_LIMITED: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileExchangeProtocol {
+  V1,
+  /// Prefixes responses with a status byte.
+  V2,
+}
+
+impl FileExchangeProtocol {
+  /// Supported versions, the preferred one first.
+  pub const ALL: [FileExchangeProtocol; 2] = [FileExchangeProtocol::V2, FileExchangeProtocol::V1];
+}
+
+#[derive(Clone)]
+pub struct FileExchangeCodec();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRequest(pub String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileResponse {
+  File(Vec<u8>),
+  Denied(DenyReason),
+  RateLimited { retry_after: Duration },
+}
+
+impl ProtocolName for FileExchangeProtocol {
+  fn protocol_name(&self) -> &[u8] {
+    match self {
+      FileExchangeProtocol::V1 => b"/file-exchange/1",
+      FileExchangeProtocol::V2 => b"/file-exchange/2",
+    }
+  }
+}
+
+/// Read a frame announced by a varint length prefix of at most `max_size`
+/// bytes, without allocating more than what was actually received.
+async fn read_frame<T>(io: &mut T, max_size: usize, what: &str) -> io::Result<Vec<u8>>
+where
+  T: AsyncRead + Unpin + Send,
+{
+  let len = read_varint(io).await?;
+  if len == 0 {
+    return Err(invalid_data(format!("empty {}", what)));
+  }
+  if len > max_size {
+    return Err(invalid_data(format!(
+      "{} of {} bytes exceeds the maximum of {} bytes",
+      what, len, max_size
+    )));
+  }
+
+  let mut frame = Vec::new();
+  io.take(len as u64).read_to_end(&mut frame).await?;
+  if frame.len() != len {
+    return Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      format!("{} truncated after {} of {} bytes", what, frame.len(), len),
+    ));
+  }
+  Ok(frame)
+}
+
+/// Write `prefix` and `payload` as one length-prefixed frame and close the
+/// stream.
+async fn write_frame<T>(io: &mut T, prefix: &[u8], payload: &[u8]) -> io::Result<()>
+where
+  T: AsyncWrite + Unpin + Send,
+{
+  let len = prefix.len() + payload.len();
+  if len > MAX_RESPONSE_SIZE {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      format!(
+        "file of {} bytes exceeds the transfer maximum",
+        payload.len()
+      ),
+    ));
+  }
+
+  write_varint(io, len).await?;
+  io.write_all(prefix).await?;
+  io.write_all(payload).await?;
+  io.close().await?;
+
+  Ok(())
+}
+
+fn encode_duration(duration: Duration) -> Vec<u8> {
+  let mut bytes = duration.as_secs().to_be_bytes().to_vec();
+  bytes.extend(duration.subsec_nanos().to_be_bytes());
+  bytes
+}
+
+fn decode_duration(bytes: &[u8]) -> Option<Duration> {
+  let secs = u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?);
+  let nanos = u32::from_be_bytes(bytes.get(8..)?.try_into().ok()?);
+  (nanos < 1_000_000_000).then(|| Duration::new(secs, nanos))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[async_trait]
+impl RequestResponseCodec for FileExchangeCodec {
+  type Protocol = FileExchangeProtocol;
+  type Request = FileRequest;
+  type Response = FileResponse;
+
+  async fn read_request<T>(
+    &mut self,
+    _: &FileExchangeProtocol,
+    io: &mut T,
+  ) -> io::Result<Self::Request>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    let frame = read_frame(io, MAX_REQUEST_SIZE, "request").await?;
+    let name = String::from_utf8(frame)
+      .map_err(|e| invalid_data(format!("request is not valid UTF-8: {}", e)))?;
+
+    Ok(FileRequest(name))
+  }
+
+  async fn read_response<T>(
+    &mut self,
+    protocol: &FileExchangeProtocol,
+    io: &mut T,
+  ) -> io::Result<Self::Response>
+  where
+    T: AsyncRead + Unpin + Send,
+  {
+    let mut frame = read_frame(io, MAX_RESPONSE_SIZE, "response").await?;
+    if *protocol == FileExchangeProtocol::V1 {
+      return Ok(FileResponse::File(frame));
+    }
+    let status = frame[0];
+
+    match (status, &frame[1..]) {
+      (STATUS_FILE, _) => {
+        frame.remove(0);
+        Ok(FileResponse::File(frame))
+      }
+      (STATUS_DENIED, payload) => DenyReason::decode(payload)
+        .map(FileResponse::Denied)
+        .ok_or_else(|| invalid_data(format!("invalid deny reason {:?}", payload))),
+      (STATUS_RATE_LIMITED, payload) => decode_duration(payload)
+        .map(|retry_after| FileResponse::RateLimited { retry_after })
+        .ok_or_else(|| invalid_data(format!("invalid retry duration {:?}", payload))),
+      _ => Err(invalid_data(format!(
+        "response has unknown status {}",
+        status
+      ))),
+    }
+  }
+
+  async fn write_request<T>(
+    &mut self,
+    _: &FileExchangeProtocol,
+    io: &mut T,
+    FileRequest(name): FileRequest,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    if name.is_empty() || name.len() > MAX_REQUEST_SIZE {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("request of {} bytes cannot be sent", name.len()),
+      ));
+    }
+
+    write_varint(io, name.len()).await?;
+    io.write_all(name.as_bytes()).await?;
+    io.close().await?;
+
+    Ok(())
+  }
+
+  async fn write_response<T>(
+    &mut self,
+    protocol: &FileExchangeProtocol,
+    io: &mut T,
+    response: FileResponse,
+  ) -> io::Result<()>
+  where
+    T: AsyncWrite + Unpin + Send,
+  {
+    let (status, payload) = match (protocol, response) {
+      (FileExchangeProtocol::V1, FileResponse::File(data)) if !data.is_empty() => {
+        return write_frame(io, &[], &data).await;
+      }
+      (FileExchangeProtocol::V1, response) => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!("{:?} cannot be sent with /file-exchange/1", response),
+        ));
+      }
+      (_, FileResponse::File(data)) => (STATUS_FILE, data),
+      (_, FileResponse::Denied(reason)) => (STATUS_DENIED, reason.encode()),
+      (_, FileResponse::RateLimited { retry_after }) => {
+        (STATUS_RATE_LIMITED, encode_duration(retry_after))
+      }
+    };
+    write_frame(io, &[status], &payload).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::io::Cursor;
+
+  async fn encode_frame(len: usize, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_varint(&mut bytes, len).await.unwrap();
+    bytes.extend(payload);
+    bytes
+  }
+
+  async fn read_request(bytes: Vec<u8>) -> io::Result<FileRequest> {
+    FileExchangeCodec()
+      .read_request(&FileExchangeProtocol::V2, &mut Cursor::new(bytes))
+      .await
+  }
+
+  async fn read_response(
+    protocol: FileExchangeProtocol,
+    bytes: Vec<u8>,
+  ) -> io::Result<FileResponse> {
+    FileExchangeCodec()
+      .read_response(&protocol, &mut Cursor::new(bytes))
+      .await
+  }
+
+  async fn roundtrip(protocol: FileExchangeProtocol, response: FileResponse) -> FileResponse {
+    let mut bytes = Vec::new();
+    FileExchangeCodec()
+      .write_response(&protocol, &mut bytes, response)
+      .await
+      .unwrap();
+    read_response(protocol, bytes).await.unwrap()
+  }
+
+  #[async_std::test]
+  async fn request_roundtrip() {
+    let mut bytes = Vec::new();
+    FileExchangeCodec()
+      .write_request(
+        &FileExchangeProtocol::V2,
+        &mut bytes,
+        FileRequest("report.pdf".to_string()),
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      read_request(bytes).await.unwrap(),
+      FileRequest("report.pdf".to_string())
+    );
+  }
+
+  #[async_std::test]
+  async fn request_with_invalid_utf8() {
+    let bytes = encode_frame(2, &[0xff, 0xfe]).await;
+    let error = read_request(bytes).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[async_std::test]
+  async fn oversized_frames() {
+    let bytes = encode_frame(MAX_REQUEST_SIZE + 1, b"a").await;
+    let error = read_request(bytes).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    let bytes = encode_frame(MAX_RESPONSE_SIZE + 1, &[STATUS_FILE]).await;
+    let error = read_response(FileExchangeProtocol::V2, bytes)
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[async_std::test]
+  async fn empty_frames() {
+    let error = read_request(encode_frame(0, &[]).await).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    for protocol in FileExchangeProtocol::ALL {
+      let error = read_response(protocol, encode_frame(0, &[]).await)
+        .await
+        .unwrap_err();
+      assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    let mut bytes = Vec::new();
+    let error = FileExchangeCodec()
+      .write_request(
+        &FileExchangeProtocol::V2,
+        &mut bytes,
+        FileRequest(String::new()),
+      )
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+  }
+
+  #[async_std::test]
+  async fn truncated_frames() {
+    let error = read_request(encode_frame(5, b"ab").await)
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+    let bytes = encode_frame(5, &[STATUS_FILE, 1, 2]).await;
+    let error = read_response(FileExchangeProtocol::V2, bytes)
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+    // A length prefix cut in the middle of its varint.
+    assert!(read_request(vec![0x80]).await.is_err());
+  }
+
+  #[async_std::test]
+  async fn invalid_responses() {
+    let bytes = encode_frame(2, &[7, 0]).await;
+    let error = read_response(FileExchangeProtocol::V2, bytes)
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    // Nanoseconds of a retry duration must stay below one second.
+    let mut payload = vec![STATUS_RATE_LIMITED];
+    payload.extend(0u64.to_be_bytes());
+    payload.extend(1_000_000_000u32.to_be_bytes());
+    let bytes = encode_frame(payload.len(), &payload).await;
+    let error = read_response(FileExchangeProtocol::V2, bytes)
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[async_std::test]
+  async fn response_roundtrips() {
+    let responses = [
+      FileResponse::File(b"content".to_vec()),
+      FileResponse::Denied(DenyReason::Unauthorized),
+      FileResponse::Denied(DenyReason::NotFound),
+      FileResponse::Denied(DenyReason::Other(1)),
+      FileResponse::Denied(DenyReason::Other(2)),
+      FileResponse::RateLimited {
+        retry_after: Duration::new(3, 500_000_000),
+      },
+    ];
+    for response in responses {
+      assert_eq!(
+        roundtrip(FileExchangeProtocol::V2, response.clone()).await,
+        response
+      );
+    }
+
+    let file = FileResponse::File(b"content".to_vec());
+    assert_eq!(
+      roundtrip(FileExchangeProtocol::V1, file.clone()).await,
+      file
+    );
+  }
+
+  #[async_std::test]
+  async fn v1_cannot_send_denials() {
+    let mut bytes = Vec::new();
+    let error = FileExchangeCodec()
+      .write_response(
+        &FileExchangeProtocol::V1,
+        &mut bytes,
+        FileResponse::Denied(DenyReason::NotFound),
+      )
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    assert!(bytes.is_empty());
+  }
+}
@@ -0,0 +1,631 @@
+//! IPLD data model with the dag-cbor and dag-json codecs.
+//!
+//! [`Ipld`] values are stored as blocks whose CID names the codec, links to
+//! other blocks being CIDs. [`put`] publishes a value through the block
+//! store of the network layer, [`resolve`] follows a path like
+//! `<cid>/a/b/0` through map keys and list indices, fetching every linked
+//! block on the way from the local store or the connected peers.
+//!
+//! dag-cbor follows the strict IPLD variant, decoding rejects anything else:
+//! no indefinite lengths, no tags other than 42 for links, only 64-bit
+//! floats, integers and lengths in their shortest form and map keys unique
+//! and sorted by length first. dag-json encodes links as `{"/": "<cid>"}` and
+//! bytes as `{"/": {"bytes": "<base64>"}}`, the `"/"` key being reserved for
+//! both. It is written with sorted keys and without whitespace, decoding
+//! accepts either.
+use crate::blockstore::RAW;
+use crate::network::Client;
+use async_std::future::timeout;
+use cid::Cid;
+use multibase::Base;
+use serde_json::{Map, Number, Value};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Multicodec of dag-cbor.
+pub const DAG_CBOR: u64 = 0x71;
+
+/// Multicodec of dag-json.
+pub const DAG_JSON: u64 = 0x0129;
+
+/// Nesting depth up to which untrusted data is decoded.
+pub const MAX_DEPTH: usize = 128;
+
+/// CBOR tag of a CID.
+const CBOR_TAG_CID: u64 = 42;
+
+/// A value of the IPLD data model.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ipld {
+  Null,
+  Bool(bool),
+  /// Integers within the range of CBOR, i.e. `-2^64..2^64`.
+  Integer(i128),
+  Float(f64),
+  String(String),
+  Bytes(Vec<u8>),
+  List(Vec<Ipld>),
+  Map(BTreeMap<String, Ipld>),
+  Link(Cid),
+}
+
+impl Ipld {
+  /// The child at `segment` of a path: a map entry or, for lists, the
+  /// element at the index `segment`.
+  pub fn get(&self, segment: &str) -> Option<&Ipld> {
+    match self {
+      Ipld::Map(map) => map.get(segment),
+      Ipld::List(list) => segment.parse::<usize>().ok().and_then(|i| list.get(i)),
+      _ => None,
+    }
+  }
+
+  /// All links within the value, in order.
+  pub fn links(&self) -> Vec<Cid> {
+    let mut links = Vec::new();
+    let mut stack = vec![self];
+    while let Some(ipld) = stack.pop() {
+      match ipld {
+        Ipld::Link(cid) => links.push(*cid),
+        Ipld::List(list) => stack.extend(list.iter().rev()),
+        Ipld::Map(map) => stack.extend(map.values().rev()),
+        _ => {}
+      }
+    }
+    links
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpldError {
+  UnsupportedCodec(u64),
+  Encode(String),
+  Decode(String),
+}
+
+impl fmt::Display for IpldError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      IpldError::UnsupportedCodec(codec) => write!(f, "unsupported codec {:#x}", codec),
+      IpldError::Encode(reason) => write!(f, "cannot encode: {}", reason),
+      IpldError::Decode(reason) => write!(f, "cannot decode: {}", reason),
+    }
+  }
+}
+
+impl std::error::Error for IpldError {}
+
+fn encode_error(reason: impl Into<String>) -> IpldError {
+  IpldError::Encode(reason.into())
+}
+
+fn decode_error(reason: impl Into<String>) -> IpldError {
+  IpldError::Decode(reason.into())
+}
+
+/// Encode `ipld` with `codec`, one of [`DAG_CBOR`] and [`DAG_JSON`].
+pub fn encode(codec: u64, ipld: &Ipld) -> Result<Vec<u8>, IpldError> {
+  match codec {
+    DAG_CBOR => encode_dag_cbor(ipld),
+    DAG_JSON => encode_dag_json(ipld),
+    codec => Err(IpldError::UnsupportedCodec(codec)),
+  }
+}
+
+/// Decode a block encoded with `codec`. Raw blocks decode to
+/// [`Ipld::Bytes`].
+pub fn decode(codec: u64, data: &[u8]) -> Result<Ipld, IpldError> {
+  match codec {
+    DAG_CBOR => decode_dag_cbor(data),
+    DAG_JSON => decode_dag_json(data),
+    RAW => Ok(Ipld::Bytes(data.to_vec())),
+    codec => Err(IpldError::UnsupportedCodec(codec)),
+  }
+}
+
+pub fn encode_dag_cbor(ipld: &Ipld) -> Result<Vec<u8>, IpldError> {
+  let mut out = Vec::new();
+  write_cbor(&mut out, ipld)?;
+  Ok(out)
+}
+
+fn write_cbor(out: &mut Vec<u8>, ipld: &Ipld) -> Result<(), IpldError> {
+  match ipld {
+    Ipld::Null => out.push(0xf6),
+    Ipld::Bool(false) => out.push(0xf4),
+    Ipld::Bool(true) => out.push(0xf5),
+    Ipld::Integer(i) => {
+      let (major, value) = if *i >= 0 { (0, *i) } else { (1, -1 - *i) };
+      let value =
+        u64::try_from(value).map_err(|_| encode_error(format!("integer {} out of range", i)))?;
+      cbor_head(out, major, value);
+    }
+    Ipld::Float(f) => {
+      if !f.is_finite() {
+        return Err(encode_error(format!("float {} is not finite", f)));
+      }
+      out.push(0xfb);
+      out.extend(f.to_bits().to_be_bytes());
+    }
+    Ipld::String(s) => {
+      cbor_head(out, 3, s.len() as u64);
+      out.extend(s.as_bytes());
+    }
+    Ipld::Bytes(bytes) => {
+      cbor_head(out, 2, bytes.len() as u64);
+      out.extend(bytes);
+    }
+    Ipld::List(list) => {
+      cbor_head(out, 4, list.len() as u64);
+      for item in list {
+        write_cbor(out, item)?;
+      }
+    }
+    Ipld::Map(map) => {
+      let mut entries: Vec<_> = map.iter().collect();
+      entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+      cbor_head(out, 5, entries.len() as u64);
+      for (key, value) in entries {
+        cbor_head(out, 3, key.len() as u64);
+        out.extend(key.as_bytes());
+        write_cbor(out, value)?;
+      }
+    }
+    Ipld::Link(cid) => {
+      // CIDs are prefixed by the multibase identity prefix.
+      let bytes = cid.to_bytes();
+      cbor_head(out, 6, CBOR_TAG_CID);
+      cbor_head(out, 2, bytes.len() as u64 + 1);
+      out.push(0);
+      out.extend(bytes);
+    }
+  }
+  Ok(())
+}
+
+/// Append the shortest head of a CBOR item of major type `major`.
+fn cbor_head(out: &mut Vec<u8>, major: u8, value: u64) {
+  let major = major << 5;
+  match value {
+    0..=23 => out.push(major | value as u8),
+    24..=0xff => out.extend([major | 24, value as u8]),
+    0x100..=0xffff => {
+      out.push(major | 25);
+      out.extend((value as u16).to_be_bytes());
+    }
+    0x1_0000..=0xffff_ffff => {
+      out.push(major | 26);
+      out.extend((value as u32).to_be_bytes());
+    }
+    _ => {
+      out.push(major | 27);
+      out.extend(value.to_be_bytes());
+    }
+  }
+}
+
+pub fn decode_dag_cbor(mut data: &[u8]) -> Result<Ipld, IpldError> {
+  let ipld = read_cbor(&mut data, 0)?;
+  if !data.is_empty() {
+    return Err(decode_error(format!("{} trailing bytes", data.len())));
+  }
+  Ok(ipld)
+}
+
+/// Take the head of a CBOR item off `data`, returning its major type, its
+/// additional information and its argument.
+fn read_cbor_head(data: &mut &[u8]) -> Result<(u8, u8, u64), IpldError> {
+  let (&initial, rest) = data
+    .split_first()
+    .ok_or_else(|| decode_error("unexpected end of data"))?;
+  let (major, info) = (initial >> 5, initial & 0x1f);
+  let size = match info {
+    0..=23 => {
+      *data = rest;
+      return Ok((major, info, info.into()));
+    }
+    24 => 1,
+    25 => 2,
+    26 => 4,
+    27 => 8,
+    31 => return Err(decode_error("indefinite lengths are not allowed")),
+    _ => {
+      return Err(decode_error(format!(
+        "reserved additional information {}",
+        info
+      )))
+    }
+  };
+  let bytes = rest
+    .get(..size)
+    .ok_or_else(|| decode_error("unexpected end of data"))?;
+  *data = &rest[size..];
+  let value = bytes
+    .iter()
+    .fold(0, |value, byte| value << 8 | u64::from(*byte));
+  // Floats and simple values of major type 7 have fixed sizes.
+  let minimal = match info {
+    24 => value >= 24,
+    25 => value > 0xff,
+    26 => value > 0xffff,
+    _ => value > 0xffff_ffff,
+  };
+  if major != 7 && !minimal {
+    return Err(decode_error(format!(
+      "{} is not encoded in its shortest form",
+      value
+    )));
+  }
+  Ok((major, info, value))
+}
+
+fn take_bytes<'a>(data: &mut &'a [u8], len: u64) -> Result<&'a [u8], IpldError> {
+  let bytes = usize::try_from(len)
+    .ok()
+    .and_then(|len| data.get(..len))
+    .ok_or_else(|| decode_error("unexpected end of data"))?;
+  *data = &data[bytes.len()..];
+  Ok(bytes)
+}
+
+fn take_string(data: &mut &[u8], len: u64) -> Result<String, IpldError> {
+  let bytes = take_bytes(data, len)?;
+  String::from_utf8(bytes.to_vec()).map_err(|_| decode_error("string is not valid UTF-8"))
+}
+
+fn read_cbor(data: &mut &[u8], depth: usize) -> Result<Ipld, IpldError> {
+  if depth > MAX_DEPTH {
+    return Err(decode_error("nested too deeply"));
+  }
+
+  let (major, info, value) = read_cbor_head(data)?;
+  match major {
+    0 => Ok(Ipld::Integer(value.into())),
+    1 => Ok(Ipld::Integer(-1 - i128::from(value))),
+    2 => Ok(Ipld::Bytes(take_bytes(data, value)?.to_vec())),
+    3 => Ok(Ipld::String(take_string(data, value)?)),
+    4 => {
+      // Every item takes at least one byte, which bounds the length.
+      let mut list = Vec::new();
+      for _ in 0..value {
+        list.push(read_cbor(data, depth + 1)?);
+      }
+      Ok(Ipld::List(list))
+    }
+    5 => {
+      let mut map = BTreeMap::new();
+      let mut previous: Option<String> = None;
+      for _ in 0..value {
+        let key = match read_cbor_head(data)? {
+          (3, _, len) => take_string(data, len)?,
+          _ => return Err(decode_error("map keys must be strings")),
+        };
+        if let Some(previous) = &previous {
+          match (previous.len(), previous).cmp(&(key.len(), &key)) {
+            Ordering::Less => {}
+            Ordering::Equal => return Err(decode_error("duplicate map key")),
+            Ordering::Greater => return Err(decode_error("map keys are not sorted")),
+          }
+        }
+        let item = read_cbor(data, depth + 1)?;
+        map.insert(key.clone(), item);
+        previous = Some(key);
+      }
+      Ok(Ipld::Map(map))
+    }
+    6 if value == CBOR_TAG_CID => match read_cbor_head(data)? {
+      (2, _, len) => match take_bytes(data, len)? {
+        [0, cid @ ..] => Cid::try_from(cid)
+          .map(Ipld::Link)
+          .map_err(|e| decode_error(format!("invalid CID: {}", e))),
+        _ => Err(decode_error("CID lacks the identity multibase prefix")),
+      },
+      _ => Err(decode_error("CID is not a byte string")),
+    },
+    6 => Err(decode_error(format!("tag {} is not allowed", value))),
+    _ => match (info, value) {
+      (20, _) => Ok(Ipld::Bool(false)),
+      (21, _) => Ok(Ipld::Bool(true)),
+      (22, _) => Ok(Ipld::Null),
+      (27, bits) => match f64::from_bits(bits) {
+        f if f.is_finite() => Ok(Ipld::Float(f)),
+        f => Err(decode_error(format!("float {} is not finite", f))),
+      },
+      (25, _) | (26, _) => Err(decode_error("floats must be 64 bit")),
+      (info, _) => Err(decode_error(format!(
+        "simple value {} is not allowed",
+        info
+      ))),
+    },
+  }
+}
+
+pub fn encode_dag_json(ipld: &Ipld) -> Result<Vec<u8>, IpldError> {
+  serde_json::to_vec(&to_json(ipld)?).map_err(|e| encode_error(e.to_string()))
+}
+
+fn to_json(ipld: &Ipld) -> Result<Value, IpldError> {
+  let reserved = |value: Value| Value::Object(Map::from_iter([("/".to_string(), value)]));
+  Ok(match ipld {
+    Ipld::Null => Value::Null,
+    Ipld::Bool(b) => Value::Bool(*b),
+    Ipld::Integer(i) => match (i64::try_from(*i), u64::try_from(*i)) {
+      (Ok(i), _) => Value::Number(i.into()),
+      (_, Ok(u)) => Value::Number(u.into()),
+      _ => return Err(encode_error(format!("integer {} out of range", i))),
+    },
+    Ipld::Float(f) => Value::Number(
+      Number::from_f64(*f).ok_or_else(|| encode_error(format!("float {} is not finite", f)))?,
+    ),
+    Ipld::String(s) => Value::String(s.clone()),
+    Ipld::Bytes(bytes) => reserved(Value::Object(Map::from_iter([(
+      "bytes".to_string(),
+      Value::String(Base::Base64.encode(bytes)),
+    )]))),
+    Ipld::List(list) => Value::Array(list.iter().map(to_json).collect::<Result<_, _>>()?),
+    Ipld::Map(map) => {
+      if map.contains_key("/") {
+        return Err(encode_error("the map key \"/\" is reserved"));
+      }
+      Value::Object(
+        map
+          .iter()
+          .map(|(key, value)| Ok((key.clone(), to_json(value)?)))
+          .collect::<Result<_, IpldError>>()?,
+      )
+    }
+    Ipld::Link(cid) => reserved(Value::String(cid.to_string())),
+  })
+}
+
+pub fn decode_dag_json(data: &[u8]) -> Result<Ipld, IpldError> {
+  // `serde_json` limits the nesting depth on its own.
+  let value: Value = serde_json::from_slice(data).map_err(|e| decode_error(e.to_string()))?;
+  from_json(value)
+}
+
+fn from_json(value: Value) -> Result<Ipld, IpldError> {
+  Ok(match value {
+    Value::Null => Ipld::Null,
+    Value::Bool(b) => Ipld::Bool(b),
+    Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+      (Some(i), _, _) => Ipld::Integer(i.into()),
+      (_, Some(u), _) => Ipld::Integer(u.into()),
+      (_, _, Some(f)) => Ipld::Float(f),
+      _ => return Err(decode_error(format!("invalid number {}", n))),
+    },
+    Value::String(s) => Ipld::String(s),
+    Value::Array(list) => Ipld::List(list.into_iter().map(from_json).collect::<Result<_, _>>()?),
+    Value::Object(mut map) => match map.remove("/") {
+      None => Ipld::Map(
+        map
+          .into_iter()
+          .map(|(key, value)| Ok((key, from_json(value)?)))
+          .collect::<Result<_, IpldError>>()?,
+      ),
+      Some(Value::String(cid)) if map.is_empty() => Cid::try_from(cid.as_str())
+        .map(Ipld::Link)
+        .map_err(|e| decode_error(format!("invalid CID: {}", e)))?,
+      Some(Value::Object(mut inner)) if map.is_empty() && inner.len() == 1 => {
+        match inner.remove("bytes") {
+          Some(Value::String(bytes)) => Base::Base64
+            .decode(bytes)
+            .map(Ipld::Bytes)
+            .map_err(|e| decode_error(format!("invalid base64: {}", e)))?,
+          _ => return Err(decode_error("the map key \"/\" is reserved")),
+        }
+      }
+      Some(_) => return Err(decode_error("the map key \"/\" is reserved")),
+    },
+  })
+}
+
+/// Split a path like `<cid>/a/b/0`, optionally prefixed by `/ipfs/` or
+/// `/ipld/`, into its root and segments.
+pub fn parse_path(path: &str) -> Result<(Cid, Vec<String>), ResolveError> {
+  let path = path.trim_start_matches('/');
+  let path = path
+    .strip_prefix("ipfs/")
+    .or_else(|| path.strip_prefix("ipld/"))
+    .unwrap_or(path);
+  let mut segments = path.split('/').filter(|s| !s.is_empty());
+  let root = segments
+    .next()
+    .and_then(|root| Cid::try_from(root).ok())
+    .ok_or_else(|| ResolveError::InvalidPath(path.to_string()))?;
+  Ok((root, segments.map(ToString::to_string).collect()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveError {
+  InvalidPath(String),
+  /// The block could not be fetched in time.
+  Unavailable(Cid),
+  Decode {
+    cid: Box<Cid>,
+    error: IpldError,
+  },
+  /// Nothing is found at the given path prefix.
+  NotFound(String),
+}
+
+impl fmt::Display for ResolveError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ResolveError::InvalidPath(path) => write!(f, "invalid path {:?}", path),
+      ResolveError::Unavailable(cid) => write!(f, "block {} is not available", cid),
+      ResolveError::Decode { cid, error } => write!(f, "block {}: {}", cid, error),
+      ResolveError::NotFound(path) => write!(f, "nothing found at {:?}", path),
+    }
+  }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// Store `ipld` encoded with `codec` in the local block store, making it
+/// available to peers. Returns the CID of the block.
+pub async fn put(client: &mut Client, codec: u64, ipld: &Ipld) -> Result<Cid, IpldError> {
+  let data = encode(codec, ipld)?;
+  Ok(client.put_block_with_codec(codec, data).await)
+}
+
+/// Get and decode the block `cid`, waiting at most `limit` for it to arrive.
+pub async fn get(client: &mut Client, cid: Cid, limit: Duration) -> Result<Ipld, ResolveError> {
+  let data = timeout(limit, client.get_block(cid))
+    .await
+    .ok()
+    .flatten()
+    .ok_or(ResolveError::Unavailable(cid))?;
+  decode(cid.codec(), &data).map_err(|error| ResolveError::Decode {
+    cid: Box::new(cid),
+    error,
+  })
+}
+
+/// Resolve `path`, following links into other blocks, each of which is
+/// waited for at most `limit`. A link at the end of the path is returned as
+/// is.
+pub async fn resolve(
+  client: &mut Client,
+  path: &str,
+  limit: Duration,
+) -> Result<Ipld, ResolveError> {
+  let (root, segments) = parse_path(path)?;
+  let mut node = get(client, root, limit).await?;
+
+  for (i, segment) in segments.iter().enumerate() {
+    if let Ipld::Link(cid) = node {
+      node = get(client, cid, limit).await?;
+    }
+    node = node
+      .get(segment)
+      .cloned()
+      .ok_or_else(|| ResolveError::NotFound(format!("{}/{}", root, segments[..=i].join("/"))))?;
+  }
+  Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::blockstore::cid_of;
+
+  fn value() -> Ipld {
+    Ipld::Map(BTreeMap::from([
+      ("name".to_string(), Ipld::String("leaf".to_string())),
+      ("size".to_string(), Ipld::Integer(-1_000)),
+      ("big".to_string(), Ipld::Integer(u64::MAX.into())),
+      ("ratio".to_string(), Ipld::Float(0.5)),
+      ("data".to_string(), Ipld::Bytes(vec![0, 1, 2])),
+      (
+        "list".to_string(),
+        Ipld::List(vec![Ipld::Null, Ipld::Bool(true), Ipld::Bool(false)]),
+      ),
+      ("link".to_string(), Ipld::Link(cid_of(RAW, b"leaf"))),
+    ]))
+  }
+
+  fn rejects(data: &[u8], reason: &str) {
+    match decode_dag_cbor(data) {
+      Err(IpldError::Decode(e)) => assert!(e.contains(reason), "{:?}: {}", data, e),
+      other => panic!("{:?} decoded to {:?}", data, other),
+    }
+  }
+
+  #[test]
+  fn dag_cbor_roundtrip() {
+    let encoded = encode_dag_cbor(&value()).unwrap();
+    assert_eq!(decode_dag_cbor(&encoded).unwrap(), value());
+    assert_eq!(value().links(), [cid_of(RAW, b"leaf")]);
+  }
+
+  #[test]
+  fn dag_cbor_canonical_encoding() {
+    let map = Ipld::Map(BTreeMap::from([
+      ("aa".to_string(), Ipld::Integer(24)),
+      ("b".to_string(), Ipld::Integer(-1)),
+    ]));
+    // Keys sorted by length first, integers in their shortest form.
+    assert_eq!(
+      encode_dag_cbor(&map).unwrap(),
+      [0xa2, 0x61, b'b', 0x20, 0x62, b'a', b'a', 0x18, 24]
+    );
+    assert_eq!(
+      encode_dag_cbor(&Ipld::Float(1.0)).unwrap(),
+      [0xfb, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0]
+    );
+    assert!(encode_dag_cbor(&Ipld::Float(f64::NAN)).is_err());
+    assert!(encode_dag_cbor(&Ipld::Integer(i128::from(u64::MAX) + 1)).is_err());
+  }
+
+  #[test]
+  fn dag_cbor_rejects_non_canonical_data() {
+    rejects(&[0x18, 5], "shortest form");
+    rejects(&[0x19, 0, 0xff], "shortest form");
+    rejects(&[0x62, 0x61], "unexpected end");
+    rejects(&[0x9f, 0xff], "indefinite");
+    rejects(&[0xa2, 0x62, b'a', b'a', 0, 0x61, b'b', 0], "not sorted");
+    rejects(&[0xa2, 0x61, b'b', 0, 0x61, b'a', 0], "not sorted");
+    rejects(&[0xa2, 0x61, b'a', 0, 0x61, b'a', 0], "duplicate");
+    rejects(&[0xa1, 0, 0], "keys must be strings");
+    rejects(&[0xc1, 0], "tag 1");
+    rejects(&[0xfa, 0x3f, 0x80, 0, 0], "64 bit");
+    rejects(&[0xf7], "simple value");
+    rejects(&[0, 0], "trailing");
+    rejects(&[0x81; MAX_DEPTH + 2], "nested too deeply");
+    // A length-first order differs from the byte order of the keys.
+    assert!(decode_dag_cbor(&[0xa2, 0x61, b'b', 0, 0x62, b'a', b'a', 0]).is_ok());
+  }
+
+  #[test]
+  fn dag_json_roundtrip() {
+    let encoded = encode_dag_json(&value()).unwrap();
+    assert_eq!(decode_dag_json(&encoded).unwrap(), value());
+
+    let link = cid_of(RAW, b"leaf");
+    assert_eq!(
+      String::from_utf8(encode_dag_json(&Ipld::List(vec![Ipld::Link(link)])).unwrap()).unwrap(),
+      format!("[{{\"/\":\"{}\"}}]", link)
+    );
+    assert_eq!(
+      encode_dag_json(&Ipld::Bytes(b"hi".to_vec())).unwrap(),
+      br#"{"/":{"bytes":"aGk"}}"#
+    );
+  }
+
+  #[test]
+  fn dag_json_reserves_slash_key() {
+    let map = Ipld::Map(BTreeMap::from([("/".to_string(), Ipld::Null)]));
+    assert!(encode_dag_json(&map).is_err());
+    assert!(decode_dag_json(br#"{"/": 1}"#).is_err());
+    assert!(decode_dag_json(br#"{"/": "not a cid"}"#).is_err());
+    assert!(decode_dag_json(br#"{"/": "x", "a": 1}"#).is_err());
+    assert!(decode_dag_json(br#"{"/": {"bytes": "!"}}"#).is_err());
+    assert!(decode_dag_json(b"[1,").is_err());
+  }
+
+  #[test]
+  fn parse_paths() {
+    let cid = cid_of(RAW, b"leaf");
+    for path in [
+      format!("{}/a/0", cid),
+      format!("/ipfs/{}/a/0", cid),
+      format!("/ipld/{}//a/0/", cid),
+    ] {
+      assert_eq!(
+        parse_path(&path).unwrap(),
+        (cid, vec!["a".to_string(), "0".to_string()])
+      );
+    }
+    assert!(matches!(
+      parse_path("/ipfs/nocid/a"),
+      Err(ResolveError::InvalidPath(_))
+    ));
+    assert_eq!(
+      value().get("list").and_then(|l| l.get("1")),
+      Some(&Ipld::Bool(true))
+    );
+  }
+}
@@ -1,161 +1,344 @@
+//! Typed outcomes of Kademlia events.
+//!
+//! [`process_kad_events`] turns every [`KademliaEvent`] into a [`KadOutcome`],
+//! logging it on the way. The network layer hands these out as a stream, see
+//! [`Client::kad_outcomes`](crate::network::Client::kad_outcomes), so that
+//! applications and tests can follow bootstrap progress, routing table
+//! changes and query results instead of reading logs.
+use futures::channel::mpsc;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::kad::record::store::RecordStore;
 use libp2p::kad::{
-  store::MemoryStore,
   AddProviderError, AddProviderOk, BootstrapError, BootstrapOk, GetClosestPeersError,
-  GetClosestPeersOk, GetProvidersError, GetProvidersOk, GetRecordError, GetRecordOk, Kademlia,
-  KademliaEvent::{self, *},
-  PutRecordError, PutRecordOk,
-  QueryResult::*,
+  GetClosestPeersOk, GetProvidersError, GetProvidersOk, GetRecordError, GetRecordOk,
+  InboundRequest, Kademlia, KademliaEvent, PutRecordError, PutRecordOk, QueryId,
+  QueryResult::{self, *},
+  QueryStats,
 };
 
-// modified from `rust-ipfs`
-pub fn process_kad_events(kademlia: &Kademlia<MemoryStore>, event: KademliaEvent) {
-  match event {
-    InboundRequest { request } => {
-      trace!("kad: inbound {:?} request handled", request);
-    }
-    OutboundQueryCompleted { result, id, .. } => {
-      // make sure the query is exhausted
-      if kademlia.query(&id).is_none() {
-        match result {
-          // these subscriptions return actual values
-          GetClosestPeers(_) | GetProviders(_) | GetRecord(_) => {}
-          // we want to return specific errors for the following
-          Bootstrap(Err(_)) | StartProviding(Err(_)) | PutRecord(Err(_)) => {}
-          // and the rest can just return a general KadResult::Complete
-          _ => {
-            trace!("kad: query {:?} completed", id);
-          }
-        }
-      }
+/// Stream of the outcomes of a node's Kademlia events.
+pub type KadOutcomes = mpsc::UnboundedReceiver<KadOutcome>;
 
-      match result {
-        Bootstrap(Ok(BootstrapOk {
-          peer,
-          num_remaining,
-        })) => {
-          debug!(
-            "kad: bootstrapped with {}, {} peers remain",
-            peer, num_remaining
-          );
-        }
-        Bootstrap(Err(BootstrapError::Timeout { .. })) => {
-          warn!("kad: timed out while trying to bootstrap");
+/// What a [`KademliaEvent`] means to the application.
+#[derive(Debug, Clone)]
+pub enum KadOutcome {
+  /// An inbound request was answered.
+  InboundRequest(InboundRequest),
+  /// A bootstrap step finished, `num_remaining` buckets are still to be
+  /// refreshed.
+  BootstrapProgress {
+    id: QueryId,
+    peer: PeerId,
+    num_remaining: u32,
+    stats: QueryStats,
+  },
+  /// A query other than a successful bootstrap step finished.
+  QueryCompleted {
+    id: QueryId,
+    result: QueryResult,
+    stats: QueryStats,
+    /// Whether the query is exhausted, no further results follow.
+    finished: bool,
+  },
+  /// A peer was added to or updated in the routing table, possibly evicting
+  /// `old_peer`.
+  RoutingUpdated {
+    peer: PeerId,
+    is_new_peer: bool,
+    addresses: Vec<Multiaddr>,
+    old_peer: Option<PeerId>,
+  },
+  /// A peer connected but none of its addresses is known.
+  UnroutablePeer { peer: PeerId },
+  /// A peer connected at a known address but was not added to the routing
+  /// table. The network layer only adds peers that run a DHT server.
+  RoutablePeer { peer: PeerId, address: Multiaddr },
+  /// A peer connected and waits for a slot in its full bucket.
+  PendingRoutablePeer { peer: PeerId, address: Multiaddr },
+}
 
-          if kademlia.query(&id).is_none() {
-            trace!("kad: timed out while trying to bootstrap: {:?}", id);
-          }
-        }
-        GetClosestPeers(Ok(GetClosestPeersOk { key: _, peers: _ })) => {
-          if kademlia.query(&id).is_none() {
-            trace!("kad: got lots of peers");
-          }
-        }
-        GetClosestPeers(Err(GetClosestPeersError::Timeout { key: _, peers: _ })) => {
-          // don't mention the key here, as this is just the id of our node
-          warn!("kad: timed out while trying to find all closest peers");
-        }
-        GetProviders(Ok(GetProvidersOk {
-          key: _,
-          providers: _,
-          closest_peers: _,
-        })) => {
-          trace!("kad: got lots of providers");
-        }
-        GetProviders(Err(GetProvidersError::Timeout { key: _, .. })) => {
-          trace!("timed out while trying to get providers for the given key");
-        }
-        StartProviding(Ok(AddProviderOk { key })) => {
-          debug!("kad: providing {:?}", key);
-        }
-        StartProviding(Err(AddProviderError::Timeout { key: _ })) => {
-          trace!("kad: timed out while trying to provide the record");
-        }
-        RepublishProvider(Ok(AddProviderOk { key })) => {
-          debug!("kad: republished provider {:?}", key);
-        }
-        RepublishProvider(Err(AddProviderError::Timeout { key })) => {
-          warn!(
-            "kad: timed out while trying to republish provider {:?}",
-            key
-          );
-        }
-        GetRecord(Ok(GetRecordOk { records: _, .. })) => {
-          trace!("kad: got lots of records");
-        }
-        GetRecord(Err(GetRecordError::NotFound {
-          key,
-          closest_peers: _,
-        })) => {
-          warn!("kad: couldn't find record {:?}", key);
-        }
-        GetRecord(Err(GetRecordError::QuorumFailed {
-          key,
-          records: _,
-          quorum,
-        })) => {
-          warn!(
-            "kad: quorum failed {:?} when trying to get key {:?}",
-            quorum, key
-          );
-        }
-        GetRecord(Err(GetRecordError::Timeout {
-          key,
-          records: _,
-          quorum: _,
-        })) => {
-          warn!("kad: timed out while trying to get key {:?}", key);
-        }
-        PutRecord(Ok(PutRecordOk { key })) | RepublishRecord(Ok(PutRecordOk { key })) => {
-          debug!("kad: successfully put record {:?}", key);
-        }
-        PutRecord(Err(PutRecordError::QuorumFailed {
-          key,
-          success: _,
-          quorum,
-        }))
-        | RepublishRecord(Err(PutRecordError::QuorumFailed {
-          key,
-          success: _,
-          quorum,
-        })) => {
-          warn!(
-            "kad: quorum failed ({:?}) when trying to put record {:?}",
-            quorum, key
-          );
-        }
-        PutRecord(Err(PutRecordError::Timeout {
-          key,
-          success: _,
-          quorum: _,
-        })) => {
-          warn!("kad: timed out while trying to put record {:?}", key);
-        }
-        RepublishRecord(Err(PutRecordError::Timeout {
-          key,
-          success: _,
-          quorum: _,
-        })) => {
-          warn!("kad: timed out while trying to republish record {:?}", key);
-        }
-      }
+impl KadOutcome {
+  /// Whether the outcome reports a failed query.
+  pub fn is_error(&self) -> bool {
+    match self {
+      KadOutcome::QueryCompleted { result, .. } => matches!(
+        result,
+        Bootstrap(Err(_))
+          | GetClosestPeers(Err(_))
+          | GetProviders(Err(_))
+          | StartProviding(Err(_))
+          | RepublishProvider(Err(_))
+          | GetRecord(Err(_))
+          | PutRecord(Err(_))
+          | RepublishRecord(Err(_))
+      ),
+      _ => false,
     }
-    RoutingUpdated {
+  }
+}
+
+// modified from `rust-ipfs`
+pub fn process_kad_events<TStore>(kademlia: &Kademlia<TStore>, event: KademliaEvent) -> KadOutcome
+where
+  for<'a> TStore: RecordStore<'a>,
+  TStore: Send + 'static,
+{
+  let outcome = match event {
+    KademliaEvent::InboundRequest { request } => KadOutcome::InboundRequest(request),
+    KademliaEvent::OutboundQueryCompleted { id, result, stats } => match result {
+      Bootstrap(Ok(BootstrapOk {
+        peer,
+        num_remaining,
+      })) => KadOutcome::BootstrapProgress {
+        id,
+        peer,
+        num_remaining,
+        stats,
+      },
+      result => KadOutcome::QueryCompleted {
+        id,
+        result,
+        stats,
+        // make sure the query is exhausted
+        finished: kademlia.query(&id).is_none(),
+      },
+    },
+    KademliaEvent::RoutingUpdated {
       peer,
-      is_new_peer: _,
+      is_new_peer,
       addresses,
-      bucket_range: _,
-      old_peer: _,
+      old_peer,
+      ..
+    } => KadOutcome::RoutingUpdated {
+      peer,
+      is_new_peer,
+      addresses: addresses.into_vec(),
+      old_peer,
+    },
+    KademliaEvent::UnroutablePeer { peer } => KadOutcome::UnroutablePeer { peer },
+    KademliaEvent::RoutablePeer { peer, address } => KadOutcome::RoutablePeer { peer, address },
+    KademliaEvent::PendingRoutablePeer { peer, address } => {
+      KadOutcome::PendingRoutablePeer { peer, address }
+    }
+  };
+  log_outcome(&outcome);
+  outcome
+}
+
+fn log_outcome(outcome: &KadOutcome) {
+  let (id, result) = match outcome {
+    KadOutcome::InboundRequest(request) => {
+      trace!("kad: inbound {:?} request handled", request);
+      return;
+    }
+    KadOutcome::BootstrapProgress {
+      peer,
+      num_remaining,
+      ..
+    } => {
+      debug!(
+        "kad: bootstrapped with {}, {} peers remain",
+        peer, num_remaining
+      );
+      return;
+    }
+    KadOutcome::RoutingUpdated {
+      peer, addresses, ..
     } => {
       trace!("kad: routing updated; {}: {:?}", peer, addresses);
+      return;
     }
-    UnroutablePeer { peer } => {
+    KadOutcome::UnroutablePeer { peer } => {
       trace!("kad: peer {} is unroutable", peer);
+      return;
     }
-    RoutablePeer { peer, address } => {
+    KadOutcome::RoutablePeer { peer, address } => {
       trace!("kad: peer {} ({}) is routable", peer, address);
+      return;
     }
-    PendingRoutablePeer { peer, address } => {
+    KadOutcome::PendingRoutablePeer { peer, address } => {
       trace!("kad: pending routable peer {} ({})", peer, address);
+      return;
+    }
+    KadOutcome::QueryCompleted { id, result, .. } => (id, result),
+  };
+
+  match result {
+    Bootstrap(Ok(_)) => {}
+    Bootstrap(Err(BootstrapError::Timeout { .. })) => {
+      warn!("kad: timed out while trying to bootstrap: {:?}", id);
+    }
+    GetClosestPeers(Ok(GetClosestPeersOk { .. })) => {
+      trace!("kad: got lots of peers");
+    }
+    GetClosestPeers(Err(GetClosestPeersError::Timeout { .. })) => {
+      // don't mention the key here, as this is just the id of our node
+      warn!("kad: timed out while trying to find all closest peers");
+    }
+    GetProviders(Ok(GetProvidersOk { .. })) => {
+      trace!("kad: got lots of providers");
+    }
+    GetProviders(Err(GetProvidersError::Timeout { .. })) => {
+      trace!("timed out while trying to get providers for the given key");
+    }
+    StartProviding(Ok(AddProviderOk { key })) => {
+      debug!("kad: providing {:?}", key);
+    }
+    StartProviding(Err(AddProviderError::Timeout { .. })) => {
+      trace!("kad: timed out while trying to provide the record");
+    }
+    RepublishProvider(Ok(AddProviderOk { key })) => {
+      debug!("kad: republished provider {:?}", key);
+    }
+    RepublishProvider(Err(AddProviderError::Timeout { key })) => {
+      warn!(
+        "kad: timed out while trying to republish provider {:?}",
+        key
+      );
+    }
+    GetRecord(Ok(GetRecordOk { .. })) => {
+      trace!("kad: got lots of records");
     }
+    GetRecord(Err(GetRecordError::NotFound { key, .. })) => {
+      warn!("kad: couldn't find record {:?}", key);
+    }
+    GetRecord(Err(GetRecordError::QuorumFailed { key, quorum, .. })) => {
+      warn!(
+        "kad: quorum failed {:?} when trying to get key {:?}",
+        quorum, key
+      );
+    }
+    GetRecord(Err(GetRecordError::Timeout { key, .. })) => {
+      warn!("kad: timed out while trying to get key {:?}", key);
+    }
+    PutRecord(Ok(PutRecordOk { key })) | RepublishRecord(Ok(PutRecordOk { key })) => {
+      debug!("kad: successfully put record {:?}", key);
+    }
+    PutRecord(Err(PutRecordError::QuorumFailed { key, quorum, .. }))
+    | RepublishRecord(Err(PutRecordError::QuorumFailed { key, quorum, .. })) => {
+      warn!(
+        "kad: quorum failed ({:?}) when trying to put record {:?}",
+        quorum, key
+      );
+    }
+    PutRecord(Err(PutRecordError::Timeout { key, .. })) => {
+      warn!("kad: timed out while trying to put record {:?}", key);
+    }
+    RepublishRecord(Err(PutRecordError::Timeout { key, .. })) => {
+      warn!("kad: timed out while trying to republish record {:?}", key);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::kad::kbucket::Distance;
+  use libp2p::kad::store::MemoryStore;
+  use libp2p::kad::Addresses;
+
+  fn completed(id: QueryId, result: QueryResult) -> KademliaEvent {
+    KademliaEvent::OutboundQueryCompleted {
+      id,
+      result,
+      stats: QueryStats::empty(),
+    }
+  }
+
+  #[test]
+  fn query_outcomes() {
+    let local = PeerId::random();
+    let mut kademlia = Kademlia::new(local, MemoryStore::new(local));
+    let peer = PeerId::random();
+    kademlia.add_address(&peer, "/memory/1".parse().unwrap());
+    let bootstrap = kademlia.bootstrap().unwrap();
+    let lookup = kademlia.get_closest_peers(PeerId::random());
+
+    let step = Bootstrap(Ok(BootstrapOk {
+      peer,
+      num_remaining: 3,
+    }));
+    match process_kad_events(&kademlia, completed(bootstrap, step)) {
+      KadOutcome::BootstrapProgress {
+        id,
+        peer: p,
+        num_remaining,
+        stats,
+      } => {
+        assert_eq!(id, bootstrap);
+        assert_eq!(p, peer);
+        assert_eq!(num_remaining, 3);
+        assert_eq!(stats.num_requests(), 0);
+      }
+      outcome => panic!("Unexpected outcome {:?}", outcome),
+    }
+
+    let found = GetClosestPeers(Ok(GetClosestPeersOk {
+      key: Vec::new(),
+      peers: vec![peer],
+    }));
+    let outcome = process_kad_events(&kademlia, completed(lookup, found));
+    assert!(!outcome.is_error());
+    match outcome {
+      KadOutcome::QueryCompleted {
+        id,
+        result: GetClosestPeers(Ok(ok)),
+        finished,
+        ..
+      } => {
+        assert_eq!(id, lookup);
+        assert_eq!(ok.peers, vec![peer]);
+        assert!(!finished, "The lookup is still running.");
+      }
+      outcome => panic!("Unexpected outcome {:?}", outcome),
+    }
+
+    kademlia.query_mut(&lookup).unwrap().finish();
+    let timeout = GetClosestPeers(Err(GetClosestPeersError::Timeout {
+      key: Vec::new(),
+      peers: Vec::new(),
+    }));
+    let outcome = process_kad_events(&kademlia, completed(lookup, timeout));
+    assert!(outcome.is_error());
+    assert!(matches!(
+      outcome,
+      KadOutcome::QueryCompleted { finished: true, .. }
+    ));
+  }
+
+  #[test]
+  fn routing_outcomes() {
+    let local = PeerId::random();
+    let kademlia = Kademlia::new(local, MemoryStore::new(local));
+    let peer = PeerId::random();
+    let address: Multiaddr = "/memory/1".parse().unwrap();
+
+    let updated = KademliaEvent::RoutingUpdated {
+      peer,
+      is_new_peer: true,
+      addresses: Addresses::new(address.clone()),
+      bucket_range: (Distance::default(), Distance::default()),
+      old_peer: None,
+    };
+    match process_kad_events(&kademlia, updated) {
+      KadOutcome::RoutingUpdated {
+        peer: p,
+        is_new_peer,
+        addresses,
+        old_peer,
+      } => {
+        assert_eq!(p, peer);
+        assert!(is_new_peer);
+        assert_eq!(addresses, vec![address]);
+        assert_eq!(old_peer, None);
+      }
+      outcome => panic!("Unexpected outcome {:?}", outcome),
+    }
+
+    let unroutable = KademliaEvent::UnroutablePeer { peer };
+    assert!(matches!(
+      process_kad_events(&kademlia, unroutable),
+      KadOutcome::UnroutablePeer { peer: p } if p == peer
+    ));
   }
 }
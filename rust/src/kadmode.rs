@@ -0,0 +1,479 @@
+use libp2p::core::connection::{ConnectedPoint, ConnectionId, ListenerId};
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::identify::{Identify, IdentifyEvent};
+use libp2p::kad::handler::{
+  KademliaHandlerConfig, KademliaHandlerEvent, KademliaHandlerProto, KademliaHandlerQueryErr,
+};
+use libp2p::kad::protocol::KademliaProtocolConfig;
+use libp2p::kad::record::store::RecordStore;
+use libp2p::kad::{Kademlia, KademliaEvent, QueryId};
+use libp2p::swarm::{
+  ConnectionHandler, ConnectionHandlerUpgrErr, DialError, IntoConnectionHandler, NetworkBehaviour,
+  NetworkBehaviourAction, PollParameters,
+};
+use std::collections::HashSet;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{fmt, io};
+
+/// Connection idle timeout used by `KademliaConfig::default()`.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which Kademlia mode a node runs in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KademliaMode {
+  /// Query the DHT without answering requests, e.g. for short-lived
+  /// command line invocations that would leave unreachable entries in the
+  /// routing tables of other nodes.
+  Client,
+  /// Answer requests and get added to the routing tables of other nodes.
+  Server,
+  /// Server while AutoNAT reports the node as public, client otherwise,
+  /// including before AutoNAT has a verdict.
+  #[default]
+  Auto,
+}
+
+impl fmt::Display for KademliaMode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      KademliaMode::Client => write!(f, "client"),
+      KademliaMode::Server => write!(f, "server"),
+      KademliaMode::Auto => write!(f, "auto"),
+    }
+  }
+}
+
+impl FromStr for KademliaMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "client" => Ok(KademliaMode::Client),
+      "server" => Ok(KademliaMode::Server),
+      "auto" => Ok(KademliaMode::Auto),
+      _ => Err(format!(
+        "unknown Kademlia mode {:?}, expected client, server or auto",
+        s
+      )),
+    }
+  }
+}
+
+/// A [`Kademlia`] behaviour that can run either as a DHT server or as a
+/// client.
+///
+/// In client mode new connections do not accept inbound Kademlia substreams,
+/// so the node can still query the DHT but does not answer requests and
+/// remote peers stop treating it as a routable DHT server. A mode change only
+/// applies to connections established afterwards.
+pub struct ModalKademlia<TStore> {
+  inner: Kademlia<TStore>,
+  protocol_config: KademliaProtocolConfig,
+  idle_timeout: Duration,
+  server: bool,
+  timed_out_peers: Vec<PeerId>,
+}
+
+impl<TStore> ModalKademlia<TStore> {
+  /// Wraps `inner`, starting in server mode.
+  ///
+  /// `protocol_config` and `idle_timeout` must match the configuration
+  /// `inner` was created with, since the wrapper builds the connection
+  /// handlers itself.
+  pub fn new(
+    inner: Kademlia<TStore>,
+    protocol_config: KademliaProtocolConfig,
+    idle_timeout: Duration,
+  ) -> Self {
+    Self {
+      inner,
+      protocol_config,
+      idle_timeout,
+      server: true,
+      timed_out_peers: Vec::new(),
+    }
+  }
+
+  /// Whether inbound Kademlia requests are answered on new connections.
+  pub fn is_server(&self) -> bool {
+    self.server
+  }
+
+  /// Switch between server (`true`) and client (`false`) mode.
+  pub fn set_server(&mut self, server: bool) {
+    if self.server != server {
+      debug!(
+        "kad: switching to {} mode",
+        if server { "server" } else { "client" }
+      );
+      self.server = server;
+    }
+  }
+
+  /// Peers whose Kademlia requests timed out since the last call.
+  pub fn take_timed_out_peers(&mut self) -> Vec<PeerId> {
+    std::mem::take(&mut self.timed_out_peers)
+  }
+}
+
+impl<TStore> Deref for ModalKademlia<TStore> {
+  type Target = Kademlia<TStore>;
+
+  fn deref(&self) -> &Self::Target {
+    &self.inner
+  }
+}
+
+impl<TStore> DerefMut for ModalKademlia<TStore> {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.inner
+  }
+}
+
+type KadHandler = <KademliaHandlerProto<QueryId> as IntoConnectionHandler>::Handler;
+
+impl<TStore> NetworkBehaviour for ModalKademlia<TStore>
+where
+  for<'a> TStore: RecordStore<'a>,
+  TStore: Send + 'static,
+{
+  type ConnectionHandler = KademliaHandlerProto<QueryId>;
+  type OutEvent = KademliaEvent;
+
+  fn new_handler(&mut self) -> Self::ConnectionHandler {
+    KademliaHandlerProto::new(KademliaHandlerConfig {
+      protocol_config: self.protocol_config.clone(),
+      allow_listening: self.server,
+      idle_timeout: self.idle_timeout,
+    })
+  }
+
+  fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+    self.inner.addresses_of_peer(peer_id)
+  }
+
+  fn inject_connection_established(
+    &mut self,
+    peer_id: &PeerId,
+    connection_id: &ConnectionId,
+    endpoint: &ConnectedPoint,
+    failed_addresses: Option<&Vec<Multiaddr>>,
+    other_established: usize,
+  ) {
+    self.inner.inject_connection_established(
+      peer_id,
+      connection_id,
+      endpoint,
+      failed_addresses,
+      other_established,
+    )
+  }
+
+  fn inject_connection_closed(
+    &mut self,
+    peer_id: &PeerId,
+    connection_id: &ConnectionId,
+    endpoint: &ConnectedPoint,
+    handler: KadHandler,
+    remaining_established: usize,
+  ) {
+    self.inner.inject_connection_closed(
+      peer_id,
+      connection_id,
+      endpoint,
+      handler,
+      remaining_established,
+    )
+  }
+
+  fn inject_address_change(
+    &mut self,
+    peer_id: &PeerId,
+    connection_id: &ConnectionId,
+    old: &ConnectedPoint,
+    new: &ConnectedPoint,
+  ) {
+    self
+      .inner
+      .inject_address_change(peer_id, connection_id, old, new)
+  }
+
+  fn inject_event(
+    &mut self,
+    peer_id: PeerId,
+    connection: ConnectionId,
+    event: <KadHandler as ConnectionHandler>::OutEvent,
+  ) {
+    if let KademliaHandlerEvent::QueryError { error, .. } = &event {
+      let timed_out = match error {
+        KademliaHandlerQueryErr::Upgrade(ConnectionHandlerUpgrErr::Timeout) => true,
+        KademliaHandlerQueryErr::Io(e) => e.kind() == io::ErrorKind::TimedOut,
+        _ => false,
+      };
+      if timed_out {
+        self.timed_out_peers.push(peer_id);
+      }
+    }
+    self.inner.inject_event(peer_id, connection, event)
+  }
+
+  fn inject_dial_failure(
+    &mut self,
+    peer_id: Option<PeerId>,
+    handler: Self::ConnectionHandler,
+    error: &DialError,
+  ) {
+    self.inner.inject_dial_failure(peer_id, handler, error)
+  }
+
+  fn inject_new_listen_addr(&mut self, id: ListenerId, addr: &Multiaddr) {
+    self.inner.inject_new_listen_addr(id, addr)
+  }
+
+  fn inject_expired_listen_addr(&mut self, id: ListenerId, addr: &Multiaddr) {
+    self.inner.inject_expired_listen_addr(id, addr)
+  }
+
+  fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+    self.inner.inject_new_external_addr(addr)
+  }
+
+  fn poll(
+    &mut self,
+    cx: &mut Context<'_>,
+    params: &mut impl PollParameters,
+  ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+    match self.inner.poll(cx, params) {
+      // Dials requested by the inner behaviour carry a handler built with the
+      // default configuration, replace it with one honouring the mode.
+      Poll::Ready(NetworkBehaviourAction::Dial { opts, .. }) => {
+        Poll::Ready(NetworkBehaviourAction::Dial {
+          opts,
+          handler: self.new_handler(),
+        })
+      }
+      other => other,
+    }
+  }
+}
+
+/// An [`Identify`] behaviour announcing the Kademlia protocol only while the
+/// node runs as a DHT server.
+///
+/// A swarm collects the protocols it supports once, when it is built, so
+/// [`ModalKademlia`] has to be built in server mode for its protocol to be
+/// listed at all. This wrapper hides the protocol from the identify
+/// information of a client and pushes the new information to all connected
+/// peers whenever the mode changes.
+pub struct ModalIdentify {
+  inner: Identify,
+  kademlia_protocol: Vec<u8>,
+  announce: bool,
+  connected: HashSet<PeerId>,
+}
+
+impl ModalIdentify {
+  /// Wraps `inner`, not announcing `kademlia_protocol` until
+  /// [`ModalIdentify::set_announce_kademlia`] is called.
+  pub fn new(inner: Identify, kademlia_protocol: Vec<u8>) -> Self {
+    Self {
+      inner,
+      kademlia_protocol,
+      announce: false,
+      connected: HashSet::new(),
+    }
+  }
+
+  /// Whether the Kademlia protocol is announced.
+  pub fn announces_kademlia(&self) -> bool {
+    self.announce
+  }
+
+  /// Announce the Kademlia protocol or stop doing so, telling the connected
+  /// peers right away.
+  pub fn set_announce_kademlia(&mut self, announce: bool) {
+    if self.announce != announce {
+      self.announce = announce;
+      self.inner.push(self.connected.iter().copied());
+    }
+  }
+}
+
+/// Poll parameters listing the supported protocols but `hidden`.
+struct AnnouncedProtocols<'a, P> {
+  params: &'a mut P,
+  hidden: Option<&'a [u8]>,
+}
+
+impl<P: PollParameters> PollParameters for AnnouncedProtocols<'_, P> {
+  type SupportedProtocolsIter = std::vec::IntoIter<Vec<u8>>;
+  type ListenedAddressesIter = P::ListenedAddressesIter;
+  type ExternalAddressesIter = P::ExternalAddressesIter;
+
+  fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+    self
+      .params
+      .supported_protocols()
+      .filter(|protocol| Some(protocol.as_slice()) != self.hidden)
+      .collect::<Vec<_>>()
+      .into_iter()
+  }
+
+  fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+    self.params.listened_addresses()
+  }
+
+  fn external_addresses(&self) -> Self::ExternalAddressesIter {
+    self.params.external_addresses()
+  }
+
+  fn local_peer_id(&self) -> &PeerId {
+    self.params.local_peer_id()
+  }
+}
+
+impl NetworkBehaviour for ModalIdentify {
+  type ConnectionHandler = <Identify as NetworkBehaviour>::ConnectionHandler;
+  type OutEvent = IdentifyEvent;
+
+  fn new_handler(&mut self) -> Self::ConnectionHandler {
+    self.inner.new_handler()
+  }
+
+  fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+    self.inner.addresses_of_peer(peer_id)
+  }
+
+  fn inject_connection_established(
+    &mut self,
+    peer_id: &PeerId,
+    connection_id: &ConnectionId,
+    endpoint: &ConnectedPoint,
+    failed_addresses: Option<&Vec<Multiaddr>>,
+    other_established: usize,
+  ) {
+    self.connected.insert(*peer_id);
+    self.inner.inject_connection_established(
+      peer_id,
+      connection_id,
+      endpoint,
+      failed_addresses,
+      other_established,
+    )
+  }
+
+  fn inject_connection_closed(
+    &mut self,
+    peer_id: &PeerId,
+    connection_id: &ConnectionId,
+    endpoint: &ConnectedPoint,
+    handler: <Self::ConnectionHandler as IntoConnectionHandler>::Handler,
+    remaining_established: usize,
+  ) {
+    if remaining_established == 0 {
+      self.connected.remove(peer_id);
+    }
+    self.inner.inject_connection_closed(
+      peer_id,
+      connection_id,
+      endpoint,
+      handler,
+      remaining_established,
+    )
+  }
+
+  fn inject_event(
+    &mut self,
+    peer_id: PeerId,
+    connection: ConnectionId,
+    event: <<Self::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::OutEvent,
+  ) {
+    self.inner.inject_event(peer_id, connection, event)
+  }
+
+  fn inject_dial_failure(
+    &mut self,
+    peer_id: Option<PeerId>,
+    handler: Self::ConnectionHandler,
+    error: &DialError,
+  ) {
+    self.inner.inject_dial_failure(peer_id, handler, error)
+  }
+
+  fn inject_new_listen_addr(&mut self, id: ListenerId, addr: &Multiaddr) {
+    self.inner.inject_new_listen_addr(id, addr)
+  }
+
+  fn inject_expired_listen_addr(&mut self, id: ListenerId, addr: &Multiaddr) {
+    self.inner.inject_expired_listen_addr(id, addr)
+  }
+
+  fn poll(
+    &mut self,
+    cx: &mut Context<'_>,
+    params: &mut impl PollParameters,
+  ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+    let hidden = (!self.announce).then_some(self.kademlia_protocol.as_slice());
+    self
+      .inner
+      .poll(cx, &mut AnnouncedProtocols { params, hidden })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::core::upgrade::{ProtocolName, UpgradeInfo};
+  use libp2p::kad::record::store::MemoryStore;
+
+  fn modal() -> ModalKademlia<MemoryStore> {
+    let local = PeerId::random();
+    ModalKademlia::new(
+      Kademlia::new(local, MemoryStore::new(local)),
+      KademliaProtocolConfig::default(),
+      DEFAULT_IDLE_TIMEOUT,
+    )
+  }
+
+  fn inbound_protocols(kademlia: &mut ModalKademlia<MemoryStore>) -> Vec<Vec<u8>> {
+    kademlia
+      .new_handler()
+      .inbound_protocol()
+      .protocol_info()
+      .map(|info| info.protocol_name().to_vec())
+      .collect()
+  }
+
+  #[test]
+  fn mode_roundtrip() {
+    assert_eq!(KademliaMode::default(), KademliaMode::Auto);
+    for mode in [
+      KademliaMode::Client,
+      KademliaMode::Server,
+      KademliaMode::Auto,
+    ] {
+      assert_eq!(mode.to_string().parse(), Ok(mode));
+    }
+    assert!("Server".parse::<KademliaMode>().is_err());
+  }
+
+  #[test]
+  fn client_mode_denies_inbound_requests() {
+    let mut kademlia = modal();
+    assert!(kademlia.is_server());
+    assert_eq!(
+      inbound_protocols(&mut kademlia),
+      [b"/ipfs/kad/1.0.0".to_vec()]
+    );
+
+    kademlia.set_server(false);
+    assert!(!kademlia.is_server());
+    assert!(inbound_protocols(&mut kademlia).is_empty());
+
+    kademlia.set_server(true);
+    assert_eq!(inbound_protocols(&mut kademlia).len(), 1);
+  }
+}
@@ -1,5 +1,28 @@
+pub mod authz;
+pub mod bitswap;
+pub mod blockstore;
+pub mod car;
+pub mod catalog;
+pub mod crawler;
+pub mod exchange;
+pub mod ipld;
 pub mod kadevents;
+pub mod kadmode;
+pub mod lookup;
 pub mod network;
+pub mod peering;
+pub mod peerstore;
+pub mod quic;
+pub mod ranking;
+pub mod reputation;
+pub mod retry;
+pub mod routing;
+pub mod search;
+#[cfg(feature = "test-support")]
+pub mod testing;
+pub mod tls;
+pub mod transport;
+pub mod tree;
 
 #[macro_use]
 extern crate tracing;
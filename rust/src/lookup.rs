@@ -0,0 +1,88 @@
+//! Streaming DHT lookups.
+//!
+//! Provider and record lookups hand out their results as a [`Lookup`] stream:
+//! every provider or record is emitted as [`LookupEvent::Found`] once it is
+//! known, followed by exactly one [`LookupEvent::Finished`] with the final
+//! status. Dropping the stream finishes the underlying Kademlia query, though
+//! only on the next periodic housekeeping of the network event loop, within a
+//! second.
+//!
+//! Providers stored locally are emitted right away. Remote results are
+//! emitted as Kademlia reports them, which for this Kademlia version is when
+//! the query completes.
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::fmt;
+
+/// Stream of the results of a DHT lookup.
+pub type Lookup<T> = mpsc::UnboundedReceiver<LookupEvent<T>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupEvent<T> {
+  /// A provider or record was discovered.
+  Found(T),
+  /// The lookup is over, no further events follow.
+  Finished(LookupStatus),
+}
+
+/// How a lookup ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupStatus {
+  /// The query completed and found at least one result.
+  Complete,
+  /// The query timed out, results found before are still valid.
+  Timeout,
+  /// The query completed without finding anything.
+  NotFound,
+}
+
+impl fmt::Display for LookupStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LookupStatus::Complete => write!(f, "lookup complete"),
+      LookupStatus::Timeout => write!(f, "lookup timed out"),
+      LookupStatus::NotFound => write!(f, "nothing found"),
+    }
+  }
+}
+
+impl std::error::Error for LookupStatus {}
+
+/// Drain `lookup`, returning everything found and the final status. A stream
+/// ending without status, i.e. the network shutting down, counts as a
+/// timeout.
+pub async fn collect<T>(mut lookup: Lookup<T>) -> (Vec<T>, LookupStatus) {
+  let mut found = Vec::new();
+  while let Some(event) = lookup.next().await {
+    match event {
+      LookupEvent::Found(item) => found.push(item),
+      LookupEvent::Finished(status) => return (found, status),
+    }
+  }
+  (found, LookupStatus::Timeout)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[async_std::test]
+  async fn collect_until_finished() {
+    let (sender, lookup) = mpsc::unbounded();
+    sender.unbounded_send(LookupEvent::Found(1)).unwrap();
+    sender.unbounded_send(LookupEvent::Found(2)).unwrap();
+    sender
+      .unbounded_send(LookupEvent::Finished(LookupStatus::Timeout))
+      .unwrap();
+    sender.unbounded_send(LookupEvent::Found(3)).unwrap();
+    assert_eq!(collect(lookup).await, (vec![1, 2], LookupStatus::Timeout));
+  }
+
+  #[async_std::test]
+  async fn closed_stream_counts_as_timeout() {
+    let (sender, lookup) = mpsc::unbounded();
+    sender.unbounded_send(LookupEvent::Found(1)).unwrap();
+    drop(sender);
+    assert_eq!(collect(lookup).await, (vec![1], LookupStatus::Timeout));
+  }
+}
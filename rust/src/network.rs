@@ -1,25 +1,75 @@
-use async_std::io;
-use async_trait::async_trait;
+use crate::authz::{AllowAll, Decision, DenyReason, RequestPolicy};
+use crate::bitswap::{
+    self, BitswapCodec, BitswapMessage, BitswapProtocol, BlockExchange, WantlistEntry,
+};
+use crate::blockstore::{BlockStore, RAW};
+use crate::car::{self, CarError};
+use crate::catalog::{
+    CatalogCodec, CatalogEntry, CatalogPage, CatalogProtocol, CatalogRequest, CatalogResponse,
+    EntryTooLarge,
+    MAX_PAGE_SIZE,
+};
+use crate::exchange::{FileExchangeCodec, FileExchangeProtocol, FileRequest};
+use crate::kadevents::{process_kad_events, KadOutcome, KadOutcomes};
+use crate::kadmode::{self, KademliaMode, ModalIdentify, ModalKademlia};
+use crate::lookup::{self, Lookup, LookupEvent, LookupStatus};
+use crate::peering::{Peering, PeeringState};
+use crate::peerstore::{PeerStore, MAX_AGE};
+use crate::ranking::{PeerStats, RankingStrategy, Weighted};
+use crate::reputation::{
+    self, MessageValidator, Offense, PeerReputation, ReputationConfig, ReputationTracker,
+};
+use crate::retry::{Attempt, RetryError, RetryPolicy};
+use crate::routing::RoutingTable;
+use crate::search::{self, SearchHit};
+use crate::transport::{self, TransportConfig};
+use async_std::future::timeout;
+use async_std::stream::{interval, Interval};
+use async_std::task::{self, sleep, JoinHandle};
+use cid::Cid;
 use futures::channel::{mpsc, oneshot};
 /// The network module, encapsulating all network related logic.
 use futures::prelude::*;
-use libp2p::core::either::EitherError;
-use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use futures::stream::{Fuse, FuturesUnordered};
+use libp2p::autonat::{self, NatStatus};
+use libp2p::core::connection::ListenerId;
 use libp2p::core::{Multiaddr, PeerId};
+use libp2p::gossipsub::{
+    Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic,
+    MessageAcceptance, MessageAuthenticity,
+};
+use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
 use libp2p::identity;
 use libp2p::identity::ed25519;
-use libp2p::kad::record::store::MemoryStore;
-use libp2p::kad::{GetProvidersOk, Kademlia, KademliaEvent, QueryId, QueryResult};
+use libp2p::kad::protocol::KademliaProtocolConfig;
+use libp2p::kad::record::store::{MemoryStore, RecordStore};
+use libp2p::kad::record::Key;
+use libp2p::kad::{
+    BootstrapOk, GetProvidersError, GetProvidersOk, GetRecordError, GetRecordOk, Kademlia,
+    KademliaBucketInserts, KademliaConfig, KademliaEvent, PeerRecord, QueryId, QueryResult, Quorum, Record,
+};
 use libp2p::multiaddr::Protocol;
+use libp2p::ping;
+use libp2p::relay::v2::client as relay;
 use libp2p::request_response::{
-    ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec, RequestResponseEvent,
-    RequestResponseMessage, ResponseChannel,
+    OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+    RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+};
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::swarm::{
+    ConnectionHandler, IntoConnectionHandler, NetworkBehaviour, SwarmBuilder, SwarmEvent,
 };
-use libp2p::swarm::{ConnectionHandlerUpgrErr, SwarmBuilder, SwarmEvent};
 use libp2p::{NetworkBehaviour, Swarm};
-use std::collections::{hash_map, HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
 use std::error::Error;
-use std::iter;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt, iter};
+
+pub use crate::exchange::FileResponse;
 
 /// Creates the network components, namely:
 ///
@@ -31,6 +81,7 @@ use std::iter;
 /// - The network task driving the network itself.
 pub async fn new(
     secret_key_seed: Option<u8>,
+    config: Config,
 ) -> Result<(Client, impl Stream<Item = Event>, EventLoop), Box<dyn Error>> {
     // Create a public/private key pair, either random or based on a seed.
     let id_keys = match secret_key_seed {
@@ -46,40 +97,331 @@ pub async fn new(
     };
     let peer_id = id_keys.public().to_peer_id();
 
+    // The relay client transport allows listening via relays once AutoNAT
+    // reports the node as not publicly reachable.
+    let (relay_transport, relay_client) = relay::Client::new_transport_and_behaviour(peer_id);
+    let transport = transport::build(&id_keys, relay_transport, config.transport.clone()).await?;
+
+    // Nodes only talk to peers speaking the same Kademlia protocol, which
+    // keeps separate DHTs from merging.
+    let mut kad_config = KademliaConfig::default();
+    // Peers are only added to the routing table once they are known to run
+    // a DHT server, see `EventLoop::kademlia_peers`.
+    kad_config.set_kbucket_inserts(KademliaBucketInserts::Manual);
+    kad_config.set_query_timeout(config.kademlia_query_timeout);
+    let mut kad_protocol = KademliaProtocolConfig::default();
+    if let Some(name) = &config.kademlia_protocol {
+        kad_config.set_protocol_name(name.clone());
+        kad_protocol.set_protocol_name(name.clone());
+    }
+
+    // Warm up the routing table with the peers known from the last run.
+    let mut kademlia = Kademlia::with_config(peer_id, MemoryStore::new(peer_id), kad_config);
+    // A damaged store is no reason not to start, it is overwritten on the
+    // next save.
+    let known_peers = match &config.peer_store {
+        Some(path) => PeerStore::load(path).unwrap_or_else(|e| {
+            warn!("Ignoring peer store {}: {}", path.display(), e);
+            PeerStore::default()
+        }),
+        None => PeerStore::default(),
+    };
+    if !known_peers.is_empty() {
+        debug!("Restored {} known peers", known_peers.restore(&mut kademlia));
+    }
+
+    let mut peering = HashMap::<PeerId, Vec<Multiaddr>>::new();
+    for addr in &config.peering {
+        config.transport.check_address(addr)?;
+        let mut addr = addr.clone();
+        match addr.pop() {
+            Some(Protocol::P2p(hash)) => {
+                let peer = PeerId::from_multihash(hash).map_err(|_| "Invalid peer ID.")?;
+                peering.entry(peer).or_default().push(addr);
+            }
+            _ => return Err(format!("Peering address {} lacks /p2p/<peer-id>.", addr).into()),
+        }
+    }
+
+    // The protocols announced via identify are taken from the behaviour when
+    // the swarm is built, so Kademlia is built as a server and hidden by
+    // `ModalIdentify` while the node is a client. The actual mode is set once
+    // the swarm is built.
+    let kademlia_protocol = kad_protocol.protocol_name().to_vec();
+    let mut kademlia = ModalKademlia::new(kademlia, kad_protocol, kadmode::DEFAULT_IDLE_TIMEOUT);
+    kademlia.set_server(true);
+
+    let mut request_response_config = RequestResponseConfig::default();
+    request_response_config.set_request_timeout(config.request_timeout);
+
     // Build the Swarm, connecting the lower layer transport logic with the
     // higher layer network behaviour logic.
-    let swarm = SwarmBuilder::new(
-        libp2p::development_transport(id_keys).await?,
+    let mut swarm = SwarmBuilder::new(
+        transport,
         ComposedBehaviour {
-            kademlia: Kademlia::new(peer_id, MemoryStore::new(peer_id)),
+            kademlia,
             request_response: RequestResponse::new(
                 FileExchangeCodec(),
-                iter::once((FileExchangeProtocol(), ProtocolSupport::Full)),
-                Default::default(),
+                FileExchangeProtocol::ALL.map(|protocol| (protocol, ProtocolSupport::Full)),
+                request_response_config.clone(),
+            ),
+            catalog: RequestResponse::new(
+                CatalogCodec(),
+                iter::once((CatalogProtocol(), ProtocolSupport::Full)),
+                request_response_config.clone(),
+            ),
+            bitswap: RequestResponse::new(
+                BitswapCodec(),
+                iter::once((BitswapProtocol(), ProtocolSupport::Full)),
+                request_response_config,
             ),
+            autonat: autonat::Behaviour::new(peer_id, Default::default()),
+            relay_client,
+            identify: ModalIdentify::new(
+                Identify::new(IdentifyConfig::new(
+                    "/libp2p-demo/1.0.0".to_string(),
+                    id_keys.public(),
+                )),
+                kademlia_protocol.clone(),
+            ),
+            gossipsub: Gossipsub::new(
+                MessageAuthenticity::Signed(id_keys),
+                // Messages are only forwarded once validated, see
+                // `Config::set_message_validator`.
+                GossipsubConfigBuilder::default()
+                    .validate_messages()
+                    .build()?,
+            )?,
+            ping: ping::Behaviour::new(ping::Config::new()),
+            peering: Peering::new(peering),
         },
         peer_id,
     )
     .build();
 
+    // Only a node that is known to be a server answers DHT requests right
+    // away, in auto mode the event loop decides once AutoNAT has a verdict.
+    let server = config.kademlia_mode == KademliaMode::Server;
+    swarm.behaviour_mut().kademlia.set_server(server);
+    swarm.behaviour_mut().identify.set_announce_kademlia(server);
+
     let (command_sender, command_receiver) = mpsc::channel(0);
     let (event_sender, event_receiver) = mpsc::channel(0);
 
     Ok((
         Client {
+            peer_id,
             sender: command_sender,
+            fetch_parallelism: config.fetch_parallelism,
+            fetch_timeout: config.fetch_timeout,
         },
         event_receiver,
-        EventLoop::new(swarm, command_receiver, event_sender),
+        EventLoop::new(
+            swarm,
+            command_receiver,
+            event_sender,
+            config,
+            known_peers,
+            kademlia_protocol,
+        ),
     ))
 }
 
+/// Configuration of the network layer created by [`new`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    transport: TransportConfig,
+    relays: Vec<Multiaddr>,
+    request_policy: Arc<dyn RequestPolicy>,
+    ranking: Arc<dyn RankingStrategy>,
+    fetch_parallelism: usize,
+    fetch_timeout: Duration,
+    request_timeout: Duration,
+    reputation: ReputationConfig,
+    message_validator: Arc<dyn MessageValidator>,
+    peer_store: Option<PathBuf>,
+    peer_store_interval: Duration,
+    peering: Vec<Multiaddr>,
+    kademlia_mode: KademliaMode,
+    kademlia_protocol: Option<Cow<'static, [u8]>>,
+    check_kademlia_protocol: bool,
+    kademlia_query_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            transport: Default::default(),
+            relays: Default::default(),
+            request_policy: Arc::new(AllowAll),
+            ranking: Arc::new(Weighted::default()),
+            fetch_parallelism: 2,
+            fetch_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(60),
+            reputation: Default::default(),
+            message_validator: Arc::new(reputation::AcceptAll),
+            peer_store: None,
+            peer_store_interval: Duration::from_secs(5 * 60),
+            peering: Default::default(),
+            kademlia_mode: Default::default(),
+            kademlia_protocol: None,
+            check_kademlia_protocol: false,
+            kademlia_query_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Config {
+    /// Transport stack settings, e.g. security and multiplexing, or the
+    /// in-memory transport for tests.
+    pub fn set_transport(&mut self, transport: TransportConfig) -> &mut Self {
+        self.transport = transport;
+        self
+    }
+
+    #[cfg(feature = "test-support")]
+    pub(crate) fn transport_mut(&mut self) -> &mut TransportConfig {
+        &mut self.transport
+    }
+
+    /// Add a relay to listen through while AutoNAT reports the local node as
+    /// private. The address has to end with the relay's `/p2p/<peer-id>`.
+    pub fn add_relay(&mut self, addr: Multiaddr) -> &mut Self {
+        self.relays.push(addr);
+        self
+    }
+
+    /// Policy deciding which inbound file requests reach the application as
+    /// [`Event::InboundRequest`]. Defaults to [`AllowAll`].
+    pub fn set_request_policy(&mut self, policy: Arc<dyn RequestPolicy>) -> &mut Self {
+        self.request_policy = policy;
+        self
+    }
+
+    /// Strategy ordering the providers returned by
+    /// [`Client::get_ranked_providers`]. Defaults to [`Weighted`].
+    pub fn set_ranking_strategy(&mut self, ranking: Arc<dyn RankingStrategy>) -> &mut Self {
+        self.ranking = ranking;
+        self
+    }
+
+    /// Number of providers [`Client::fetch`] asks at the same time.
+    ///
+    /// Values below one are treated as one.
+    pub fn set_fetch_parallelism(&mut self, parallelism: usize) -> &mut Self {
+        self.fetch_parallelism = parallelism.max(1);
+        self
+    }
+
+    /// How long [`Client::fetch`] waits for a single provider.
+    pub fn set_fetch_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.fetch_timeout = timeout;
+        self
+    }
+
+    /// How long a file, catalog or block request may take at the protocol
+    /// level before it fails with [`FileRequestError::Outbound`]. Defaults to
+    /// one minute.
+    ///
+    /// Per-call limits, e.g. of [`Client::request_file_with_timeout`] or
+    /// [`RetryPolicy::attempt_timeout`], only take effect up to this timeout.
+    pub fn set_request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Penalties, decay and thresholds of peer reputation, see
+    /// [`Client::reputation`].
+    pub fn set_reputation(&mut self, reputation: ReputationConfig) -> &mut Self {
+        self.reputation = reputation;
+        self
+    }
+
+    /// Validator deciding which gossipsub messages are forwarded and reach
+    /// the application as [`Event::GossipMessage`]. Defaults to accepting
+    /// every message.
+    pub fn set_message_validator(&mut self, validator: Arc<dyn MessageValidator>) -> &mut Self {
+        self.message_validator = validator;
+        self
+    }
+
+    /// File to persist known peer addresses and the routing table in. It is
+    /// loaded into the routing table by [`new`] and saved periodically and
+    /// when the event loop shuts down. A [`Client::bootstrap`] right after
+    /// startup then joins the DHT through the restored peers. A file that
+    /// cannot be read is logged and replaced on the next save.
+    pub fn set_peer_store(&mut self, path: PathBuf) -> &mut Self {
+        self.peer_store = Some(path);
+        self
+    }
+
+    /// How often the peer store is saved, see [`Config::set_peer_store`].
+    pub fn set_peer_store_interval(&mut self, interval: Duration) -> &mut Self {
+        self.peer_store_interval = interval;
+        self
+    }
+
+    /// Add a peer to stay connected to, e.g. a seed node or a partner
+    /// provider. The address has to end with the peer's `/p2p/<peer-id>`,
+    /// several addresses of the same peer may be added.
+    ///
+    /// Peering peers are dialed at startup, re-dialed with backoff after a
+    /// disconnect and never banned for a bad reputation.
+    pub fn add_peering(&mut self, addr: Multiaddr) -> &mut Self {
+        self.peering.push(addr);
+        self
+    }
+
+    /// Whether the node answers DHT requests, see [`KademliaMode`].
+    /// Defaults to [`KademliaMode::Auto`].
+    pub fn set_kademlia_mode(&mut self, mode: KademliaMode) -> &mut Self {
+        self.kademlia_mode = mode;
+        self
+    }
+
+    /// Kademlia protocol name, `/ipfs/kad/1.0.0` by default. Nodes of a
+    /// private DHT should share a name of their own, e.g. `/lab/kad/1.0.0`,
+    /// so that the DHT never merges with the public one.
+    pub fn set_kademlia_protocol(&mut self, name: impl Into<Cow<'static, [u8]>>) -> &mut Self {
+        self.kademlia_protocol = Some(name.into());
+        self
+    }
+
+    /// Only admit peers to the routing table once their identify information
+    /// listed the Kademlia protocol, see [`Config::set_kademlia_protocol`],
+    /// and drop peers whose information does not from the peer store. Peers
+    /// that identify without the protocol are kept out of the routing table
+    /// either way.
+    ///
+    /// The check only looks at the protocols a peer advertises. A peer
+    /// listing the protocol is not tested for actually speaking it.
+    pub fn set_check_kademlia_protocol(&mut self, check: bool) -> &mut Self {
+        self.check_kademlia_protocol = check;
+        self
+    }
+
+    /// Time after which a DHT query gives up, reporting what it found so far,
+    /// e.g. [`LookupStatus::Timeout`] for lookups. Defaults to 60 seconds.
+    pub fn set_kademlia_query_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.kademlia_query_timeout = timeout;
+        self
+    }
+}
+
 #[derive(Clone)]
 pub struct Client {
+    peer_id: PeerId,
     sender: mpsc::Sender<Command>,
+    fetch_parallelism: usize,
+    fetch_timeout: Duration,
 }
 
 impl Client {
+    /// The peer ID of the local node.
+    pub fn local_peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
     /// Listen for incoming connections on the given address.
     pub async fn start_listening(&mut self, addr: Multiaddr) -> Result<(), Box<dyn Error + Send>> {
         let (sender, receiver) = oneshot::channel();
@@ -90,7 +432,8 @@ impl Client {
         receiver.await.expect("Sender not to be dropped.")
     }
 
-    /// Dial the given peer at the given address.
+    /// Dial the given peer at the given address. While a dial to the peer is
+    /// in progress, the call waits for its outcome instead of dialing again.
     pub async fn dial(
         &mut self,
         peer_id: PeerId,
@@ -120,20 +463,93 @@ impl Client {
 
     /// Find the providers for the given file on the DHT.
     pub async fn get_providers(&mut self, file_name: String) -> HashSet<PeerId> {
-        let (sender, receiver) = oneshot::channel();
+        let (providers, _) = lookup::collect(self.get_providers_stream(file_name).await).await;
+        providers.into_iter().collect()
+    }
+
+    /// Find the providers for the given file on the DHT. Providers stored
+    /// locally are emitted right away, remote ones once the query completes
+    /// or times out, see [`crate::lookup`]. Dropping the stream ends the
+    /// lookup.
+    pub async fn get_providers_stream(&mut self, file_name: String) -> Lookup<PeerId> {
+        let (sender, receiver) = mpsc::unbounded();
         self.sender
             .send(Command::GetProviders { file_name, sender })
             .await
             .expect("Command receiver not to be dropped.");
+        receiver
+    }
+
+    /// Find the providers for the given file on the DHT, ordered by the
+    /// configured [`RankingStrategy`].
+    pub async fn get_ranked_providers(&mut self, file_name: String) -> Vec<PeerId> {
+        let providers = self.get_providers(file_name).await;
+        self.rank_providers(providers.into_iter().collect()).await
+    }
+
+    /// Order the given providers by the configured [`RankingStrategy`].
+    pub async fn rank_providers(&mut self, providers: Vec<PeerId>) -> Vec<PeerId> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::RankProviders { providers, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
         receiver.await.expect("Sender not to be dropped.")
     }
 
+    /// Fetch the given file from its providers, returning the file and the
+    /// provider that sent it.
+    ///
+    /// Providers are asked in ranked order, at most as many at a time as
+    /// configured via [`Config::set_fetch_parallelism`]. The next provider is
+    /// only asked once a request in flight failed.
+    pub async fn fetch(&mut self, file_name: String) -> Result<(PeerId, Vec<u8>), RetryError> {
+        let mut providers = self
+            .get_ranked_providers(file_name.clone())
+            .await
+            .into_iter();
+        let mut in_flight = FuturesUnordered::new();
+        let mut attempts = Vec::new();
+
+        loop {
+            while in_flight.len() < self.fetch_parallelism {
+                let peer = match providers.next() {
+                    Some(peer) => peer,
+                    None => break,
+                };
+                let mut client = self.clone();
+                let file_name = file_name.clone();
+                let limit = self.fetch_timeout;
+                in_flight.push(async move {
+                    let result = client
+                        .request_file_with_timeout(peer, file_name, limit)
+                        .await;
+                    (peer, result)
+                });
+            }
+
+            match in_flight.next().await {
+                Some((peer, Ok(file))) => return Ok((peer, file)),
+                Some((peer, Err(error))) => {
+                    debug!("Fetching {:?} from {} failed: {}", file_name, peer, error);
+                    attempts.push(Attempt { peer, error });
+                }
+                None => {
+                    return Err(RetryError {
+                        file_name,
+                        attempts,
+                    })
+                }
+            }
+        }
+    }
+
     /// Request the content of the given file from the given peer.
     pub async fn request_file(
         &mut self,
         peer: PeerId,
         file_name: String,
-    ) -> Result<Vec<u8>, Box<dyn Error + Send>> {
+    ) -> Result<Vec<u8>, FileRequestError> {
         let (sender, receiver) = oneshot::channel();
         self.sender
             .send(Command::RequestFile {
@@ -146,6 +562,219 @@ impl Client {
         receiver.await.expect("Sender not be dropped.")
     }
 
+    /// Like [`Client::request_file`], giving up with
+    /// [`FileRequestError::Timeout`] if no response arrived within `limit`.
+    /// Limits beyond [`Config::set_request_timeout`] end with
+    /// [`FileRequestError::Outbound`] instead.
+    pub async fn request_file_with_timeout(
+        &mut self,
+        peer: PeerId,
+        file_name: String,
+        limit: Duration,
+    ) -> Result<Vec<u8>, FileRequestError> {
+        timeout(limit, self.request_file(peer, file_name))
+            .await
+            .unwrap_or(Err(FileRequestError::Timeout(limit)))
+    }
+
+    /// Request the given file from the given providers according to `policy`,
+    /// returning the file and the provider that sent it.
+    ///
+    /// Attempts rotate through the providers in the given order. Providers
+    /// denying the request are not asked again, rate-limited ones not before
+    /// the duration they asked for.
+    pub async fn request_file_with_retry(
+        &mut self,
+        providers: impl IntoIterator<Item = PeerId>,
+        file_name: String,
+        policy: &RetryPolicy,
+    ) -> Result<(PeerId, Vec<u8>), RetryError> {
+        let mut candidates: Vec<PeerId> = Vec::new();
+        for peer in providers {
+            if !candidates.contains(&peer) {
+                candidates.push(peer);
+            }
+        }
+
+        let mut attempts: Vec<Attempt> = Vec::new();
+        let mut next = 0;
+        while attempts.len() < policy.max_attempts() && !candidates.is_empty() {
+            next %= candidates.len();
+            let peer = candidates[next];
+
+            if let Some(last) = attempts.last() {
+                let mut delay = policy.backoff(attempts.len() - 1);
+                if let FileRequestError::RateLimited { retry_after } = last.error {
+                    if last.peer == peer {
+                        delay = delay.max(retry_after);
+                    }
+                }
+                sleep(delay).await;
+            }
+
+            match self
+                .request_file_with_timeout(peer, file_name.clone(), policy.attempt_timeout())
+                .await
+            {
+                Ok(file) => return Ok((peer, file)),
+                Err(error) => {
+                    let attempt = Attempt { peer, error };
+                    debug!(
+                        "Attempt {} for {:?} to {} failed: {}",
+                        attempts.len() + 1,
+                        file_name,
+                        peer,
+                        attempt.error
+                    );
+                    if attempt.is_retryable() {
+                        next += 1;
+                    } else {
+                        candidates.remove(next);
+                    }
+                    attempts.push(attempt);
+                }
+            }
+        }
+
+        Err(RetryError {
+            file_name,
+            attempts,
+        })
+    }
+
+    /// Store the given record on the DHT.
+    pub async fn put_record(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+    ) -> Result<(), Box<dyn Error + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PutRecord { key, value, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Look up the value of the given record on the DHT.
+    pub async fn get_record(&mut self, key: String) -> Result<Vec<u8>, Box<dyn Error + Send>> {
+        let (records, status) = lookup::collect(self.get_record_stream(key).await).await;
+        match records.into_iter().next() {
+            Some(PeerRecord { record, .. }) => Ok(record.value),
+            None => Err(Box::new(status)),
+        }
+    }
+
+    /// Look up the given record on the DHT, emitting each copy found together
+    /// with the peer that stored it. Dropping the stream ends the lookup.
+    pub async fn get_record_stream(&mut self, key: String) -> Lookup<PeerRecord> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.sender
+            .send(Command::GetRecord { key, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver
+    }
+
+    /// Index `key` under the keywords of its name and `metadata`, making it
+    /// discoverable through [`Client::search`].
+    ///
+    /// Fails with [`search::KeywordFull`] if a keyword record has no room for
+    /// `key`, and if a keyword record could not be looked up, so that a
+    /// failed lookup never overwrites the keys indexed so far.
+    pub async fn index_keywords(
+        &mut self,
+        key: String,
+        metadata: &str,
+    ) -> Result<(), Box<dyn Error + Send>> {
+        for keyword in search::keywords(&key, metadata) {
+            let record_key = search::record_key(&keyword);
+            let (records, status) =
+                lookup::collect(self.get_record_stream(record_key.clone()).await).await;
+            let mut keys = match records.into_iter().next() {
+                Some(PeerRecord { record, .. }) => search::decode_keys(&record.value),
+                // A missing record simply means nothing is indexed yet.
+                None if status == LookupStatus::NotFound => Default::default(),
+                None => return Err(Box::new(status)),
+            };
+            if keys.insert(key.clone()) {
+                let value = match search::encode_keys(&keyword, &keys) {
+                    Ok(value) => value,
+                    Err(e) => return Err(Box::new(e)),
+                };
+                self.put_record(record_key, value).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up the keywords of `query` on the DHT and return the matching
+    /// content keys, best matches first.
+    pub async fn search(&mut self, query: &str) -> Vec<SearchHit> {
+        let lookups = search::tokenize(query).into_iter().map(|keyword| {
+            let mut client = self.clone();
+            async move {
+                let keys = match client.get_record(search::record_key(&keyword)).await {
+                    Ok(value) => search::decode_keys(&value),
+                    Err(_) => Default::default(),
+                };
+                (keyword, keys)
+            }
+        });
+        search::rank(query, future::join_all(lookups).await)
+    }
+
+    /// Populate the routing table by looking up the local node and refreshing
+    /// all buckets, starting from the peers known so far.
+    pub async fn bootstrap(&mut self) -> Result<(), Box<dyn Error + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Bootstrap { sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Subscribe to the given gossipsub topic. Messages are reported as
+    /// [`Event::GossipMessage`].
+    pub async fn subscribe(&mut self, topic: String) -> Result<(), Box<dyn Error + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Subscribe { topic, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Publish a message on the given gossipsub topic.
+    pub async fn publish(
+        &mut self,
+        topic: String,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn Error + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Publish {
+                topic,
+                data,
+                sender,
+            })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Current NAT status as determined by AutoNAT, together with the
+    /// confidence in it (number of probes confirming the status).
+    pub async fn nat_status(&mut self) -> (NatStatus, usize) {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::NatStatus { sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
     /// Respond with the provided file content to the given request.
     pub async fn respond_file(&mut self, file: Vec<u8>, channel: ResponseChannel<FileResponse>) {
         self.sender
@@ -153,17 +782,285 @@ impl Client {
             .await
             .expect("Command receiver not to be dropped.");
     }
+
+    /// Refuse the given request, the requester receiving
+    /// [`FileRequestError::Denied`].
+    pub async fn deny_file(&mut self, reason: DenyReason, channel: ResponseChannel<FileResponse>) {
+        self.sender
+            .send(Command::DenyFile { reason, channel })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// List the given entry in the catalog served to other peers, replacing
+    /// any entry with the same key. The entry is bounded first, see
+    /// [`CatalogEntry::bounded`].
+    pub async fn add_to_catalog(&mut self, entry: CatalogEntry) -> Result<(), EntryTooLarge> {
+        let entry = entry.bounded()?;
+        self.sender
+            .send(Command::AddToCatalog { entry })
+            .await
+            .expect("Command receiver not to be dropped.");
+        Ok(())
+    }
+
+    /// Remove the entry with the given key from the served catalog.
+    pub async fn remove_from_catalog(&mut self, key: String) {
+        self.sender
+            .send(Command::RemoveFromCatalog { key })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Reputation of every peer that misbehaved since the node started.
+    pub async fn reputation(&mut self) -> Vec<(PeerId, PeerReputation)> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Reputation { sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// State of the connection to every configured peering peer, see
+    /// [`Config::add_peering`].
+    pub async fn peering(&mut self) -> Vec<(PeerId, PeeringState)> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Peering { sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Snapshot of the Kademlia routing table with a summary of its health.
+    pub async fn routing_table(&mut self) -> RoutingTable {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::RoutingTable { sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Number of Kademlia queries still running.
+    #[cfg(feature = "test-support")]
+    pub(crate) async fn running_queries(&mut self) -> usize {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::RunningQueries { sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Subscribe to the outcomes of all Kademlia events from now on, e.g.
+    /// bootstrap progress, routing table updates and query results. The
+    /// stream is unbounded, drop it when no longer interested.
+    pub async fn kad_outcomes(&mut self) -> KadOutcomes {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::KadOutcomes { sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Penalize the given peer for misbehaviour detected by the application,
+    /// e.g. [`Offense::InvalidFileResponse`] for content failing a hash check.
+    pub async fn report_peer(&mut self, peer: PeerId, offense: Offense) {
+        self.sender
+            .send(Command::ReportPeer { peer, offense })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Add `data` to the local block store as a raw block and serve it to
+    /// peers wanting it.
+    pub async fn put_block(&mut self, data: Vec<u8>) -> Cid {
+        self.put_block_with_codec(RAW, data).await
+    }
+
+    /// Add `data` encoded with `codec` to the local block store and serve it
+    /// to peers wanting it.
+    pub async fn put_block_with_codec(&mut self, codec: u64, data: Vec<u8>) -> Cid {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::PutBlock {
+                codec,
+                data,
+                sender,
+            })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Get the block `cid` from the local block store or else from the
+    /// connected peers, waiting until one of them has it. Returns `None` if
+    /// the want is cancelled via [`Client::cancel_want`]. Dropping the
+    /// future cancels the want as well.
+    pub async fn get_block(&mut self, cid: Cid) -> Option<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::GetBlock { cid, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.ok()
+    }
+
+    /// Stop wanting the block `cid`.
+    pub async fn cancel_want(&mut self, cid: Cid) {
+        self.sender
+            .send(Command::CancelWant { cid })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Blocks wanted by the local node, or by `peer` as far as it told us.
+    pub async fn wantlist(&mut self, peer: Option<PeerId>) -> Vec<Cid> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Wantlist { peer, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Blocks and bytes exchanged with `peer`.
+    pub async fn ledger(&mut self, peer: PeerId) -> bitswap::Ledger {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Ledger { peer, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Statistics of the block exchange.
+    pub async fn bitswap_stat(&mut self) -> bitswap::Stat {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::BitswapStat { sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Import the blocks of a CARv1 or CARv2 archive into the local block
+    /// store, returning the roots of the archive. Peers wanting any of the
+    /// blocks are served right away.
+    pub async fn import_car(&mut self, car: Vec<u8>) -> Result<Vec<Cid>, CarError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::ImportCar { car, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Export the DAG below `root` from the local block store as a CARv1
+    /// archive.
+    pub async fn export_car(&mut self, root: Cid) -> Result<Vec<u8>, CarError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::ExportCar { root, sender })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+
+    /// Request one page of the given peer's catalog. Pages hold at most
+    /// [`MAX_PAGE_SIZE`] entries. Peers refusing to be browsed answer with
+    /// [`FileRequestError::Denied`] or [`FileRequestError::RateLimited`].
+    pub async fn browse(
+        &mut self,
+        peer: PeerId,
+        request: CatalogRequest,
+    ) -> Result<CatalogPage, FileRequestError> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Command::Browse {
+                peer,
+                request,
+                sender,
+            })
+            .await
+            .expect("Command receiver not to be dropped.");
+        receiver.await.expect("Sender not to be dropped.")
+    }
+}
+
+/// A pending provider lookup and the providers it emitted so far.
+struct ProviderLookup {
+    sender: mpsc::UnboundedSender<LookupEvent<PeerId>>,
+    found: HashSet<PeerId>,
+}
+
+impl ProviderLookup {
+    fn found(&mut self, peer: PeerId) {
+        if self.found.insert(peer) {
+            let _ = self.sender.unbounded_send(LookupEvent::Found(peer));
+        }
+    }
 }
 
+/// Interval of the event loop's periodic maintenance.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval of pruning state kept about peers, see [`EventLoop::prune`].
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time after which the stats of a peer not heard of are forgotten.
+const PEER_STATS_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Reports the outcome of a command that has nothing to return.
+type ResultSender = oneshot::Sender<Result<(), Box<dyn Error + Send>>>;
+
 pub struct EventLoop {
     swarm: Swarm<ComposedBehaviour>,
     command_receiver: mpsc::Receiver<Command>,
     event_sender: mpsc::Sender<Event>,
-    pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), Box<dyn Error + Send>>>>,
+    transport: TransportConfig,
+    pending_dial: HashMap<PeerId, Vec<ResultSender>>,
     pending_start_providing: HashMap<QueryId, oneshot::Sender<()>>,
-    pending_get_providers: HashMap<QueryId, oneshot::Sender<HashSet<PeerId>>>,
-    pending_request_file:
-        HashMap<RequestId, oneshot::Sender<Result<Vec<u8>, Box<dyn Error + Send>>>>,
+    pending_get_providers: HashMap<QueryId, ProviderLookup>,
+    pending_request_file: HashMap<RequestId, oneshot::Sender<Result<Vec<u8>, FileRequestError>>>,
+    pending_browse: HashMap<RequestId, oneshot::Sender<Result<CatalogPage, FileRequestError>>>,
+    /// Want-list entries of bitswap messages not acknowledged yet.
+    pending_bitswap: HashMap<RequestId, Vec<WantlistEntry>>,
+    pending_put_record: HashMap<QueryId, ResultSender>,
+    pending_get_record: HashMap<QueryId, mpsc::UnboundedSender<LookupEvent<PeerRecord>>>,
+    pending_bootstrap: HashMap<QueryId, ResultSender>,
+    relays: Vec<Multiaddr>,
+    relay_listeners: Vec<ListenerId>,
+    kademlia_mode: KademliaMode,
+    kademlia_protocol: Vec<u8>,
+    check_kademlia_protocol: bool,
+    /// Whether connected peers announced the Kademlia protocol in their
+    /// identify information. Peers that did not are kept out of the routing
+    /// table, so that clients never end up there.
+    kademlia_peers: HashMap<PeerId, bool>,
+    request_policy: Arc<dyn RequestPolicy>,
+    catalog: BTreeMap<String, CatalogEntry>,
+    ranking: Arc<dyn RankingStrategy>,
+    /// Stats of every peer along with the time they were last updated.
+    peer_stats: HashMap<PeerId, (PeerStats, Instant)>,
+    reputation: ReputationTracker,
+    message_validator: Arc<dyn MessageValidator>,
+    housekeeping: Fuse<Interval>,
+    pruned: Instant,
+    known_peers: PeerStore,
+    peer_store: Option<PathBuf>,
+    peer_store_interval: Duration,
+    peer_store_saved: Instant,
+    peer_store_save: Option<JoinHandle<()>>,
+    peering: HashMap<PeerId, PeeringState>,
+    peering_backoff: RetryPolicy,
+    kad_subscribers: Vec<mpsc::UnboundedSender<KadOutcome>>,
+    routing_updated: HashMap<PeerId, Instant>,
+    blocks: BlockStore,
+    block_exchange: BlockExchange,
 }
 
 impl EventLoop {
@@ -171,38 +1068,113 @@ impl EventLoop {
         swarm: Swarm<ComposedBehaviour>,
         command_receiver: mpsc::Receiver<Command>,
         event_sender: mpsc::Sender<Event>,
+        config: Config,
+        known_peers: PeerStore,
+        kademlia_protocol: Vec<u8>,
     ) -> Self {
+        // Peering peers are dialed on the first housekeeping tick.
+        let now = Instant::now();
+        let peering = swarm
+            .behaviour()
+            .peering
+            .peers()
+            .map(|peer| {
+                let state = PeeringState::Disconnected {
+                    failures: 0,
+                    next_dial: now,
+                };
+                (*peer, state)
+            })
+            .collect();
+        let mut peering_backoff = RetryPolicy::default();
+        peering_backoff.set_backoff(Duration::from_secs(1), Duration::from_secs(5 * 60));
+
         Self {
             swarm,
             command_receiver,
             event_sender,
+            transport: config.transport,
             pending_dial: Default::default(),
             pending_start_providing: Default::default(),
             pending_get_providers: Default::default(),
             pending_request_file: Default::default(),
+            pending_browse: Default::default(),
+            pending_bitswap: Default::default(),
+            pending_put_record: Default::default(),
+            pending_get_record: Default::default(),
+            pending_bootstrap: Default::default(),
+            relays: config.relays,
+            relay_listeners: Default::default(),
+            kademlia_mode: config.kademlia_mode,
+            kademlia_protocol,
+            check_kademlia_protocol: config.check_kademlia_protocol,
+            kademlia_peers: Default::default(),
+            request_policy: config.request_policy,
+            catalog: Default::default(),
+            ranking: config.ranking,
+            peer_stats: Default::default(),
+            reputation: ReputationTracker::new(config.reputation),
+            message_validator: config.message_validator,
+            housekeeping: interval(HOUSEKEEPING_INTERVAL).fuse(),
+            pruned: Instant::now(),
+            known_peers,
+            peer_store: config.peer_store,
+            peer_store_interval: config.peer_store_interval,
+            peer_store_saved: Instant::now(),
+            peer_store_save: None,
+            peering,
+            peering_backoff,
+            kad_subscribers: Default::default(),
+            routing_updated: Default::default(),
+            blocks: Default::default(),
+            block_exchange: Default::default(),
         }
     }
 
     pub async fn run(mut self) {
         loop {
             futures::select! {
-                event = self.swarm.next() => self.handle_event(event.expect("Swarm stream to be infinite.")).await  ,
+                event = self.swarm.next() => {
+                    self.handle_event(event.expect("Swarm stream to be infinite.")).await;
+                    for peer in self.swarm.behaviour_mut().kademlia.take_timed_out_peers() {
+                        self.penalize(peer, Offense::KademliaTimeout);
+                    }
+                    for peer in self.block_exchange.take_invalid_senders() {
+                        self.penalize(peer, Offense::InvalidBlock);
+                    }
+                },
                 command = self.command_receiver.next() => match command {
                     Some(c) => self.handle_command(c).await,
                     // Command channel closed, thus shutting down the network event loop.
-                    None=>  return,
+                    None => {
+                        self.save_peer_store();
+                        if let Some(save) = self.peer_store_save.take() {
+                            save.await;
+                        }
+                        return;
+                    }
                 },
+                _ = self.housekeeping.next() => self.housekeeping().await,
             }
         }
     }
 
-    async fn handle_event(
-        &mut self,
-        event: SwarmEvent<
-            ComposedEvent,
-            EitherError<ConnectionHandlerUpgrErr<io::Error>, io::Error>,
-        >,
-    ) {
+    async fn handle_event(&mut self, event: SwarmEvent<ComposedEvent, ComposedHandlerErr>) {
+        if let SwarmEvent::Behaviour(ComposedEvent::Kademlia(event)) = &event {
+            if let KademliaEvent::RoutingUpdated { peer, old_peer, .. } = event {
+                self.routing_updated.insert(*peer, Instant::now());
+                if let Some(old_peer) = old_peer {
+                    self.routing_updated.remove(old_peer);
+                }
+            }
+            if !self.kad_subscribers.is_empty() {
+                let outcome =
+                    process_kad_events(&*self.swarm.behaviour().kademlia, event.clone());
+                self.kad_subscribers
+                    .retain(|subscriber| subscriber.unbounded_send(outcome.clone()).is_ok());
+            }
+        }
+
         match event {
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(
                 KademliaEvent::OutboundQueryCompleted {
@@ -220,56 +1192,395 @@ impl EventLoop {
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(
                 KademliaEvent::OutboundQueryCompleted {
                     id,
-                    result: QueryResult::GetProviders(Ok(GetProvidersOk { providers, .. })),
+                    result: QueryResult::GetProviders(result),
                     ..
                 },
             )) => {
-                let _ = self
-                    .pending_get_providers
-                    .remove(&id)
-                    .expect("Completed query to be previously pending.")
-                    .send(providers);
+                // Lookups whose stream was dropped are no longer pending.
+                if let Some(mut lookup) = self.pending_get_providers.remove(&id) {
+                    let status = match result {
+                        Ok(GetProvidersOk { providers, .. }) => {
+                            providers.into_iter().for_each(|peer| lookup.found(peer));
+                            if lookup.found.is_empty() {
+                                LookupStatus::NotFound
+                            } else {
+                                LookupStatus::Complete
+                            }
+                        }
+                        Err(GetProvidersError::Timeout { providers, .. }) => {
+                            providers.into_iter().for_each(|peer| lookup.found(peer));
+                            LookupStatus::Timeout
+                        }
+                    };
+                    let _ = lookup.sender.unbounded_send(LookupEvent::Finished(status));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryCompleted {
+                    id,
+                    result: QueryResult::PutRecord(result),
+                    ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_put_record.remove(&id) {
+                    let _ = match result {
+                        Ok(_) => sender.send(Ok(())),
+                        Err(e) => sender.send(Err(Box::new(e))),
+                    };
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryCompleted {
+                    id,
+                    result: QueryResult::GetRecord(result),
+                    ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_get_record.remove(&id) {
+                    let (records, status) = match result {
+                        Ok(GetRecordOk { records, .. }) => (records, LookupStatus::Complete),
+                        Err(GetRecordError::NotFound { .. }) => {
+                            (Vec::new(), LookupStatus::NotFound)
+                        }
+                        Err(GetRecordError::QuorumFailed { records, .. }) => {
+                            let status = if records.is_empty() {
+                                LookupStatus::NotFound
+                            } else {
+                                LookupStatus::Complete
+                            };
+                            (records, status)
+                        }
+                        Err(GetRecordError::Timeout { records, .. }) => {
+                            (records, LookupStatus::Timeout)
+                        }
+                    };
+                    for record in records {
+                        let _ = sender.unbounded_send(LookupEvent::Found(record));
+                    }
+                    let _ = sender.unbounded_send(LookupEvent::Finished(status));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryCompleted {
+                    id,
+                    result: QueryResult::Bootstrap(result),
+                    ..
+                },
+            )) => {
+                // A bootstrap reports once per refreshed bucket, resolve the
+                // caller once no refresh is remaining or on the first error.
+                match result {
+                    Ok(BootstrapOk { num_remaining, .. }) if num_remaining > 0 => {}
+                    Ok(_) => {
+                        if let Some(sender) = self.pending_bootstrap.remove(&id) {
+                            let _ = sender.send(Ok(()));
+                        }
+                    }
+                    Err(e) => {
+                        if let Some(sender) = self.pending_bootstrap.remove(&id) {
+                            let _ = sender.send(Err(Box::new(e)));
+                        }
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(KademliaEvent::RoutablePeer {
+                peer,
+                address,
+            })) => {
+                // Without the check, peers are admitted unless their
+                // identify information said otherwise.
+                let admit = match self.kademlia_peers.get(&peer) {
+                    Some(speaks_kademlia) => *speaks_kademlia,
+                    None => !self.check_kademlia_protocol,
+                };
+                if admit {
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer, address);
+                }
             }
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(_)) => {}
             SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
-                RequestResponseEvent::Message { message, .. },
+                RequestResponseEvent::Message { peer, message },
             )) => match message {
                 RequestResponseMessage::Request {
                     request, channel, ..
+                } => match self.request_policy.authorize(&peer, &request.0) {
+                    Decision::Allow => {
+                        self.event_sender
+                            .send(Event::InboundRequest {
+                                peer,
+                                request: request.0,
+                                channel,
+                            })
+                            .await
+                            .expect("Event receiver not to be dropped.");
+                    }
+                    Decision::Deny(reason) => {
+                        debug!("Denied request for {:?} from {}: {}", request.0, peer, reason);
+                        self.send_file_response(channel, FileResponse::Denied(reason));
+                    }
+                    Decision::RateLimited { retry_after } => {
+                        debug!("Rate limited request for {:?} from {}", request.0, peer);
+                        self.send_file_response(channel, FileResponse::RateLimited { retry_after });
+                    }
+                },
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
                 } => {
-                    self.event_sender
-                        .send(Event::InboundRequest {
-                            request: request.0,
-                            channel,
-                        })
-                        .await
-                        .expect("Event receiver not to be dropped.");
+                    if let FileResponse::File(_) = response {
+                        self.peer_stats_mut(peer).successes += 1;
+                    }
+                    let _ = self
+                        .pending_request_file
+                        .remove(&request_id)
+                        .expect("Request to still be pending.")
+                        .send(match response {
+                            FileResponse::File(file) => Ok(file),
+                            FileResponse::Denied(reason) => Err(FileRequestError::Denied(reason)),
+                            FileResponse::RateLimited { retry_after } => {
+                                Err(FileRequestError::RateLimited { retry_after })
+                            }
+                        });
+                }
+            },
+            SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
+                RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                },
+            )) => {
+                self.peer_stats_mut(peer).failures += 1;
+                self.penalize(peer, Offense::FailedFileResponse);
+                let _ = self
+                    .pending_request_file
+                    .remove(&request_id)
+                    .expect("Request to still be pending.")
+                    .send(Err(FileRequestError::Outbound(error)));
+            }
+            SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
+                RequestResponseEvent::InboundFailure { peer, error, .. },
+            )) => {
+                // E.g. a denial that a `/file-exchange/1` peer cannot receive.
+                debug!("Failed to answer the request of {}: {:?}", peer, error);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
+                RequestResponseEvent::ResponseSent { .. },
+            )) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Catalog(RequestResponseEvent::Message {
+                peer,
+                message,
+            })) => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = match self.request_policy.authorize_browse(&peer) {
+                        Decision::Allow => CatalogResponse::Page(self.catalog_page(&request)),
+                        Decision::Deny(reason) => {
+                            debug!("Denied catalog request from {}: {}", peer, reason);
+                            CatalogResponse::Denied(reason)
+                        }
+                        Decision::RateLimited { retry_after } => {
+                            debug!("Rate limited catalog request from {}", peer);
+                            CatalogResponse::RateLimited { retry_after }
+                        }
+                    };
+                    if self
+                        .swarm
+                        .behaviour_mut()
+                        .catalog
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        debug!("Requester disconnected before the catalog was sent.");
+                    }
                 }
                 RequestResponseMessage::Response {
                     request_id,
                     response,
                 } => {
                     let _ = self
-                        .pending_request_file
+                        .pending_browse
                         .remove(&request_id)
                         .expect("Request to still be pending.")
-                        .send(Ok(response.0));
+                        .send(match response {
+                            CatalogResponse::Page(page) => Ok(page),
+                            CatalogResponse::Denied(reason) => {
+                                Err(FileRequestError::Denied(reason))
+                            }
+                            CatalogResponse::RateLimited { retry_after } => {
+                                Err(FileRequestError::RateLimited { retry_after })
+                            }
+                        });
+                }
+            },
+            SwarmEvent::Behaviour(ComposedEvent::Catalog(
+                RequestResponseEvent::OutboundFailure {
+                    request_id, error, ..
+                },
+            )) => {
+                let _ = self
+                    .pending_browse
+                    .remove(&request_id)
+                    .expect("Request to still be pending.")
+                    .send(Err(FileRequestError::Outbound(error)));
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Catalog(_)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Bitswap(RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+            })) => {
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .bitswap
+                    .send_response(channel, ())
+                    .is_err()
+                {
+                    trace!("bitswap: {} disconnected before the ack was sent", peer);
+                }
+                let messages = self
+                    .block_exchange
+                    .handle_message(peer, request, &mut self.blocks);
+                self.send_bitswap(messages);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Bitswap(RequestResponseEvent::Message {
+                message: RequestResponseMessage::Response { request_id, .. },
+                ..
+            })) => {
+                self.pending_bitswap.remove(&request_id);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Bitswap(RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            })) => {
+                debug!("bitswap: sending to {} failed: {:?}", peer, error);
+                let wantlist = self.pending_bitswap.remove(&request_id).unwrap_or_default();
+                // Peers not speaking the protocol take no part in the exchange.
+                let messages = if let OutboundFailure::UnsupportedProtocols = error {
+                    self.block_exchange.peer_disconnected(&peer)
+                } else {
+                    self.block_exchange.message_failed(peer, wantlist)
+                };
+                self.send_bitswap(messages);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Bitswap(_)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Autonat(autonat::Event::StatusChanged {
+                old,
+                new,
+            })) => {
+                debug!("autonat: status changed from {:?} to {:?}", old, new);
+                self.apply_nat_status(&new);
+                self.event_sender
+                    .send(Event::NatStatusChanged { old, new })
+                    .await
+                    .expect("Event receiver not to be dropped.");
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Autonat(_)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::RelayClient(event)) => {
+                debug!("relay: {:?}", event);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(GossipsubEvent::Message {
+                propagation_source,
+                message_id,
+                message:
+                    GossipsubMessage {
+                        source,
+                        data,
+                        topic,
+                        ..
+                    },
+            })) => {
+                let valid = self
+                    .message_validator
+                    .validate(&propagation_source, topic.as_str(), &data);
+                let acceptance = if valid {
+                    MessageAcceptance::Accept
+                } else {
+                    MessageAcceptance::Reject
+                };
+                if let Err(e) = self
+                    .swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .report_message_validation_result(&message_id, &propagation_source, acceptance)
+                {
+                    debug!("gossipsub: failed to report validation result: {:?}", e);
+                }
+                if !valid {
+                    debug!(
+                        "gossipsub: rejected message {} from {}",
+                        message_id, propagation_source
+                    );
+                    self.penalize(propagation_source, Offense::GossipRejected);
+                    return;
+                }
+
+                self.event_sender
+                    .send(Event::GossipMessage {
+                        source,
+                        topic: topic.into_string(),
+                        data,
+                    })
+                    .await
+                    .expect("Event receiver not to be dropped.");
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(_)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Ping(ping::Event {
+                peer,
+                result: Ok(ping::Success::Ping { rtt }),
+            })) => {
+                self.peer_stats_mut(peer).rtt = Some(rtt);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Ping(ping::Event {
+                peer,
+                result: Err(e),
+            })) => {
+                debug!("ping: {} failed: {}", peer, e);
+                // Peers are free not to support ping.
+                if !matches!(e, ping::Failure::Unsupported) {
+                    self.penalize(peer, Offense::PingFailure);
                 }
-            },
-            SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
-                RequestResponseEvent::OutboundFailure {
-                    request_id, error, ..
-                },
-            )) => {
-                let _ = self
-                    .pending_request_file
-                    .remove(&request_id)
-                    .expect("Request to still be pending.")
-                    .send(Err(Box::new(error)));
             }
-            SwarmEvent::Behaviour(ComposedEvent::RequestResponse(
-                RequestResponseEvent::ResponseSent { .. },
-            )) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Ping(_)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Identify(IdentifyEvent::Received {
+                peer_id,
+                info,
+            })) => {
+                let speaks_kademlia = info
+                    .protocols
+                    .iter()
+                    .any(|p| p.as_bytes() == self.kademlia_protocol);
+                self.kademlia_peers.insert(peer_id, speaks_kademlia);
+                if speaks_kademlia {
+                    // Peers that dialed us are only routable once their
+                    // listen addresses are known.
+                    for addr in info.listen_addrs {
+                        self.swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .add_address(&peer_id, addr);
+                    }
+                } else {
+                    // Either a client, possibly one that was a server until
+                    // now, or a node of another DHT.
+                    debug!(
+                        "Dropping {} ({}), it does not announce our Kademlia protocol",
+                        peer_id, info.agent_version
+                    );
+                    self.swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+                    if self.check_kademlia_protocol {
+                        self.known_peers.remove(&peer_id);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Identify(_)) => {}
             SwarmEvent::NewListenAddr { address, .. } => {
                 let local_peer_id = *self.swarm.local_peer_id();
                 eprintln!(
@@ -279,31 +1590,329 @@ impl EventLoop {
             }
             SwarmEvent::IncomingConnection { .. } => {}
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
+                if let Some(state) = self.peering.get_mut(&peer_id) {
+                    *state = PeeringState::Connected;
+                }
+                self.known_peers.mark_seen(&peer_id);
+                if num_established.get() == 1 {
+                    let messages = self.block_exchange.peer_connected(peer_id);
+                    self.send_bitswap(messages);
+                }
                 if endpoint.is_dialer() {
-                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
+                    self.known_peers
+                        .add_address(peer_id, endpoint.get_remote_address().clone());
+                    for sender in self.pending_dial.remove(&peer_id).into_iter().flatten() {
                         let _ = sender.send(Ok(()));
                     }
                 }
             }
-            SwarmEvent::ConnectionClosed { .. } => {}
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                ..
+            } => {
+                if num_established == 0 {
+                    self.kademlia_peers.remove(&peer_id);
+                    let messages = self.block_exchange.peer_disconnected(&peer_id);
+                    self.send_bitswap(messages);
+                    if let Some(state) = self.peering.get_mut(&peer_id) {
+                        debug!("peering: {} disconnected", peer_id);
+                        *state = PeeringState::Disconnected {
+                            failures: 0,
+                            next_dial: Instant::now() + self.peering_backoff.backoff(0),
+                        };
+                    }
+                }
+            }
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                 if let Some(peer_id) = peer_id {
-                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
-                        let _ = sender.send(Err(Box::new(error)));
+                    if let Some(state) = self.peering.get_mut(&peer_id) {
+                        if let PeeringState::Connecting { failures } = *state {
+                            debug!("peering: dialing {} failed: {}", peer_id, error);
+                            *state = PeeringState::Disconnected {
+                                failures: failures + 1,
+                                next_dial: Instant::now()
+                                    + self.peering_backoff.backoff(failures as usize),
+                            };
+                        }
+                    }
+                    if let Some(senders) = self.pending_dial.remove(&peer_id) {
+                        // The error cannot be cloned, callers that joined the
+                        // dial later get its description.
+                        let message = error.to_string();
+                        let mut error: Option<Box<dyn Error + Send>> = Some(Box::new(error));
+                        for sender in senders {
+                            let error = error.take().unwrap_or_else(|| {
+                                Box::<dyn Error + Send + Sync>::from(message.clone())
+                            });
+                            let _ = sender.send(Err(error));
+                        }
                     }
                 }
             }
             SwarmEvent::IncomingConnectionError { .. } => {}
+            SwarmEvent::BannedPeer { peer_id, .. } => {
+                debug!("Refused connection of banned peer {}", peer_id);
+            }
             SwarmEvent::Dialing(peer_id) => eprintln!("Dialing {}", peer_id),
-            e => panic!("{:?}", e),
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                debug!("No longer listening on {:?}", address);
+            }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                reason,
+                ..
+            } => {
+                self.relay_listeners.retain(|id| *id != listener_id);
+                debug!("Listener {:?} closed: {:?}", listener_id, reason);
+            }
+            SwarmEvent::ListenerError { listener_id, error } => {
+                warn!("Listener {:?} failed: {}", listener_id, error);
+            }
+        }
+    }
+
+    /// Lower the reputation of `peer`, banning it once it drops below the
+    /// ban threshold.
+    fn penalize(&mut self, peer: PeerId, offense: Offense) {
+        trace!("reputation: {} penalized for {}", peer, offense);
+        if self.reputation.penalize(peer, offense, Instant::now())
+            && !self.peering.contains_key(&peer)
+        {
+            warn!("reputation: banning {} after {}", peer, offense);
+            self.swarm.ban_peer_id(peer);
+        }
+    }
+
+    /// Periodic maintenance, e.g. lifting expired bans.
+    async fn housekeeping(&mut self) {
+        for peer in self.reputation.expire_bans(Instant::now()) {
+            debug!("reputation: ban of {} expired", peer);
+            self.swarm.unban_peer_id(peer);
+        }
+        if self.peer_store_saved.elapsed() >= self.peer_store_interval {
+            self.save_peer_store();
+        }
+        if self.pruned.elapsed() >= PRUNE_INTERVAL {
+            self.prune();
+        }
+        self.dial_peering();
+        self.finish_abandoned_lookups();
+        let messages = self.block_exchange.cancel_abandoned();
+        self.send_bitswap(messages);
+        let messages = self.block_exchange.expire_requests(Instant::now());
+        self.send_bitswap(messages);
+    }
+
+    /// Stats of `peer`, marked as updated now.
+    fn peer_stats_mut(&mut self, peer: PeerId) -> &mut PeerStats {
+        let (stats, updated) = self
+            .peer_stats
+            .entry(peer)
+            .or_insert_with(|| (Default::default(), Instant::now()));
+        *updated = Instant::now();
+        stats
+    }
+
+    /// Forget state kept about peers that is no longer of use, so that it
+    /// does not grow with every peer ever seen.
+    fn prune(&mut self) {
+        self.pruned = Instant::now();
+        self.peer_stats.retain(|_, (_, updated)| updated.elapsed() < PEER_STATS_TTL);
+        self.reputation.prune(Instant::now());
+
+        // Peers leave the routing table without an event, e.g. when their
+        // last address is removed.
+        let in_table: HashSet<PeerId> = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| *entry.node.key.preimage())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        self.routing_updated.retain(|peer, _| in_table.contains(peer));
+    }
+
+    fn send_bitswap(&mut self, messages: Vec<(PeerId, BitswapMessage)>) {
+        for (peer, message) in messages {
+            let wantlist = message.wantlist.clone();
+            let request_id = self
+                .swarm
+                .behaviour_mut()
+                .bitswap
+                .send_request(&peer, message);
+            if !wantlist.is_empty() {
+                self.pending_bitswap.insert(request_id, wantlist);
+            }
+        }
+    }
+
+    /// Finish the queries of lookups whose stream was dropped.
+    fn finish_abandoned_lookups(&mut self) {
+        let abandoned: Vec<QueryId> = self
+            .pending_get_providers
+            .iter()
+            .filter(|(_, lookup)| lookup.sender.is_closed())
+            .map(|(id, _)| *id)
+            .chain(
+                self.pending_get_record
+                    .iter()
+                    .filter(|(_, sender)| sender.is_closed())
+                    .map(|(id, _)| *id),
+            )
+            .collect();
+        for id in abandoned {
+            trace!("kad: finishing abandoned lookup {:?}", id);
+            self.pending_get_providers.remove(&id);
+            self.pending_get_record.remove(&id);
+            if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(&id) {
+                query.finish();
+            }
+        }
+    }
+
+    /// Dial the disconnected peering peers whose backoff elapsed.
+    fn dial_peering(&mut self) {
+        let now = Instant::now();
+        for (peer, state) in self.peering.iter_mut() {
+            let failures = match *state {
+                PeeringState::Disconnected {
+                    failures,
+                    next_dial,
+                } if next_dial <= now => failures,
+                _ => continue,
+            };
+            if self.swarm.is_connected(peer) {
+                *state = PeeringState::Connected;
+                continue;
+            }
+
+            let opts = DialOpts::peer_id(*peer)
+                .condition(PeerCondition::Disconnected)
+                .build();
+            match self.swarm.dial(opts) {
+                Ok(()) => *state = PeeringState::Connecting { failures },
+                Err(e) => {
+                    debug!("peering: dialing {} failed: {}", peer, e);
+                    *state = PeeringState::Disconnected {
+                        failures: failures + 1,
+                        next_dial: now + self.peering_backoff.backoff(failures as usize),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Save the known peers together with the routing table in the
+    /// background, if a peer store is configured. The save starts once the
+    /// previous one finished, so that they do not overwrite each other.
+    fn save_peer_store(&mut self) {
+        self.peer_store_saved = Instant::now();
+        let path = match &self.peer_store {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        self.known_peers.prune(MAX_AGE);
+        let mut store = self.known_peers.clone();
+        store.snapshot_routing_table(&mut *self.swarm.behaviour_mut().kademlia);
+        let previous = self.peer_store_save.take();
+        self.peer_store_save = Some(task::spawn(async move {
+            if let Some(previous) = previous {
+                previous.await;
+            }
+            match store.save(&path).await {
+                Ok(()) => debug!("Saved {} peers to {}", store.len(), path.display()),
+                Err(e) => warn!("Failed to save peers to {}: {}", path.display(), e),
+            }
+        }));
+    }
+
+    fn catalog_page(&self, request: &CatalogRequest) -> CatalogPage {
+        CatalogPage {
+            entries: self
+                .catalog
+                .values()
+                .skip(request.offset as usize)
+                .take(request.limit.min(MAX_PAGE_SIZE) as usize)
+                .cloned()
+                .collect(),
+            total: self.catalog.len() as u32,
+        }
+    }
+
+    /// Answer a request on behalf of the application. The requester may have
+    /// disconnected in the meantime, which is not an error here.
+    fn send_file_response(&mut self, channel: ResponseChannel<FileResponse>, response: FileResponse) {
+        if self
+            .swarm
+            .behaviour_mut()
+            .request_response
+            .send_response(channel, response)
+            .is_err()
+        {
+            debug!("Requester disconnected before the response was sent.");
+        }
+    }
+
+    /// Let the other subsystems follow the NAT status: while private, only
+    /// query the DHT as a client unless configured otherwise, and listen via
+    /// the configured relays.
+    fn apply_nat_status(&mut self, status: &NatStatus) {
+        self.update_kademlia_mode();
+        match status {
+            NatStatus::Private => {
+                if self.relay_listeners.is_empty() {
+                    for relay in self.relays.clone() {
+                        match self.swarm.listen_on(relay.clone().with(Protocol::P2pCircuit)) {
+                            Ok(id) => self.relay_listeners.push(id),
+                            Err(e) => warn!("Failed to listen via relay {}: {:?}", relay, e),
+                        }
+                    }
+                }
+            }
+            NatStatus::Public(_) => {
+                for id in self.relay_listeners.drain(..) {
+                    self.swarm.remove_listener(id);
+                }
+            }
+            NatStatus::Unknown => {}
         }
     }
 
+    /// Switch the Kademlia mode according to [`KademliaMode`] and the NAT
+    /// status, announcing the Kademlia protocol only while a server.
+    fn update_kademlia_mode(&mut self) {
+        let server = match self.kademlia_mode {
+            KademliaMode::Client => false,
+            KademliaMode::Server => true,
+            KademliaMode::Auto => matches!(
+                self.swarm.behaviour().autonat.nat_status(),
+                NatStatus::Public(_)
+            ),
+        };
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.kademlia.set_server(server);
+        behaviour.identify.set_announce_kademlia(server);
+    }
+
     async fn handle_command(&mut self, command: Command) {
         match command {
             Command::StartListening { addr, sender } => {
+                if let Err(e) = self.transport.check_address(&addr) {
+                    let _ = sender.send(Err(Box::new(e)));
+                    return;
+                }
                 let _ = match self.swarm.listen_on(addr) {
                     Ok(_) => sender.send(Ok(())),
                     Err(e) => sender.send(Err(Box::new(e))),
@@ -314,24 +1923,28 @@ impl EventLoop {
                 peer_addr,
                 sender,
             } => {
-                if let hash_map::Entry::Vacant(e) = self.pending_dial.entry(peer_id) {
-                    self.swarm
-                        .behaviour_mut()
-                        .kademlia
-                        .add_address(&peer_id, peer_addr.clone());
-                    match self
+                if let Err(e) = self.transport.check_address(&peer_addr) {
+                    let _ = sender.send(Err(Box::new(e)));
+                    return;
+                }
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peer_id, peer_addr.clone());
+                match self.pending_dial.entry(peer_id) {
+                    // Wait for the outcome of the dial already in progress.
+                    hash_map::Entry::Occupied(mut e) => e.get_mut().push(sender),
+                    hash_map::Entry::Vacant(e) => match self
                         .swarm
                         .dial(peer_addr.with(Protocol::P2p(peer_id.into())))
                     {
                         Ok(()) => {
-                            e.insert(sender);
+                            e.insert(vec![sender]);
                         }
                         Err(e) => {
                             let _ = sender.send(Err(Box::new(e)));
                         }
-                    }
-                } else {
-                    todo!("Already dialing peer.");
+                    },
                 }
             }
             Command::StartProviding { file_name, sender } => {
@@ -344,12 +1957,23 @@ impl EventLoop {
                 self.pending_start_providing.insert(query_id, sender);
             }
             Command::GetProviders { file_name, sender } => {
-                let query_id = self
-                    .swarm
-                    .behaviour_mut()
-                    .kademlia
-                    .get_providers(file_name.into_bytes().into());
-                self.pending_get_providers.insert(query_id, sender);
+                let key: Key = file_name.into_bytes().into();
+                let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+                let local = kademlia.store_mut().providers(&key);
+                let query_id = kademlia.get_providers(key);
+
+                // Providers known locally do not have to wait for the query.
+                // The store only drops expired records periodically.
+                let mut lookup = ProviderLookup {
+                    sender,
+                    found: Default::default(),
+                };
+                let now = Instant::now();
+                local
+                    .into_iter()
+                    .filter(|record| !record.is_expired(now))
+                    .for_each(|record| lookup.found(record.provider));
+                self.pending_get_providers.insert(query_id, lookup);
             }
             Command::RequestFile {
                 file_name,
@@ -363,12 +1987,199 @@ impl EventLoop {
                     .send_request(&peer, FileRequest(file_name));
                 self.pending_request_file.insert(request_id, sender);
             }
+            Command::RankProviders { providers, sender } => {
+                let now = Instant::now();
+                let providers = providers
+                    .into_iter()
+                    .filter(|peer| !self.reputation.is_banned(peer, now))
+                    .map(|peer| {
+                        let mut stats = self
+                            .peer_stats
+                            .get(&peer)
+                            .map(|(stats, _)| stats.clone())
+                            .unwrap_or_default();
+                        stats.connected = self.swarm.is_connected(&peer);
+                        (peer, stats)
+                    })
+                    .collect();
+                // Peers with a bad reputation go last, whatever the strategy.
+                let (mut ranked, deprioritized): (Vec<_>, Vec<_>) = self
+                    .ranking
+                    .rank(providers)
+                    .into_iter()
+                    .partition(|peer| !self.reputation.is_deprioritized(peer, now));
+                ranked.extend(deprioritized);
+                let _ = sender.send(ranked);
+            }
+            Command::Reputation { sender } => {
+                let _ = sender.send(self.reputation.snapshot(Instant::now()));
+            }
+            Command::KadOutcomes { sender } => {
+                let (subscriber, outcomes) = mpsc::unbounded();
+                self.kad_subscribers.push(subscriber);
+                let _ = sender.send(outcomes);
+            }
+            Command::RoutingTable { sender } => {
+                let table = RoutingTable::snapshot(
+                    &mut *self.swarm.behaviour_mut().kademlia,
+                    &self.routing_updated,
+                );
+                let _ = sender.send(table);
+            }
+            #[cfg(feature = "test-support")]
+            Command::RunningQueries { sender } => {
+                let _ = sender.send(self.swarm.behaviour().kademlia.iter_queries().count());
+            }
+            Command::Peering { sender } => {
+                let _ = sender.send(
+                    self.peering
+                        .iter()
+                        .map(|(peer, state)| (*peer, state.clone()))
+                        .collect(),
+                );
+            }
+            Command::ReportPeer { peer, offense } => self.penalize(peer, offense),
+            Command::PutBlock {
+                codec,
+                data,
+                sender,
+            } => {
+                let cid = self.blocks.insert_with_codec(codec, data);
+                let messages = self.block_exchange.block_added(cid, &self.blocks);
+                self.send_bitswap(messages);
+                let _ = sender.send(cid);
+            }
+            Command::GetBlock { cid, sender } => match self.blocks.get(&cid) {
+                Some(data) => {
+                    let _ = sender.send(data.to_vec());
+                }
+                None => {
+                    let messages = self.block_exchange.want(cid, sender);
+                    self.send_bitswap(messages);
+                }
+            },
+            Command::CancelWant { cid } => {
+                let messages = self.block_exchange.cancel(&cid);
+                self.send_bitswap(messages);
+            }
+            Command::Wantlist { peer, sender } => {
+                let _ = sender.send(self.block_exchange.wantlist(peer.as_ref()));
+            }
+            Command::Ledger { peer, sender } => {
+                let _ = sender.send(self.block_exchange.ledger(&peer));
+            }
+            Command::BitswapStat { sender } => {
+                let _ = sender.send(self.block_exchange.stat());
+            }
+            Command::ImportCar { car, sender } => {
+                let result = car::read(&car).map(|(roots, blocks)| {
+                    for (cid, data) in blocks {
+                        if self.blocks.store(cid, data) {
+                            let messages = self.block_exchange.block_added(cid, &self.blocks);
+                            self.send_bitswap(messages);
+                        }
+                    }
+                    roots
+                });
+                let _ = sender.send(result);
+            }
+            Command::ExportCar { root, sender } => {
+                let mut out = Vec::new();
+                let result = car::export(&self.blocks, &root, &mut out).map(|()| out);
+                let _ = sender.send(result);
+            }
+            Command::PutRecord { key, value, sender } => {
+                let record = Record {
+                    key: Key::new(&key),
+                    value,
+                    publisher: None,
+                    expires: None,
+                };
+                match self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .put_record(record, Quorum::One)
+                {
+                    Ok(query_id) => {
+                        self.pending_put_record.insert(query_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(Box::new(e)));
+                    }
+                }
+            }
+            Command::GetRecord { key, sender } => {
+                let query_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .get_record(Key::new(&key), Quorum::One);
+                self.pending_get_record.insert(query_id, sender);
+            }
+            Command::Bootstrap { sender } => {
+                match self.swarm.behaviour_mut().kademlia.bootstrap() {
+                    Ok(query_id) => {
+                        self.pending_bootstrap.insert(query_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(Box::new(e)));
+                    }
+                }
+            }
+            Command::Subscribe { topic, sender } => {
+                let _ = match self
+                    .swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .subscribe(&IdentTopic::new(topic))
+                {
+                    Ok(_) => sender.send(Ok(())),
+                    Err(e) => sender.send(Err(Box::new(e))),
+                };
+            }
+            Command::Publish {
+                topic,
+                data,
+                sender,
+            } => {
+                let _ = match self
+                    .swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .publish(IdentTopic::new(topic), data)
+                {
+                    Ok(_) => sender.send(Ok(())),
+                    Err(e) => sender.send(Err(Box::new(e))),
+                };
+            }
+            Command::NatStatus { sender } => {
+                let autonat = &self.swarm.behaviour().autonat;
+                let _ = sender.send((autonat.nat_status(), autonat.confidence()));
+            }
             Command::RespondFile { file, channel } => {
-                self.swarm
+                self.send_file_response(channel, FileResponse::File(file));
+            }
+            Command::DenyFile { reason, channel } => {
+                self.send_file_response(channel, FileResponse::Denied(reason));
+            }
+            Command::AddToCatalog { entry } => {
+                self.catalog.insert(entry.key.clone(), entry);
+            }
+            Command::RemoveFromCatalog { key } => {
+                self.catalog.remove(&key);
+            }
+            Command::Browse {
+                peer,
+                request,
+                sender,
+            } => {
+                let request_id = self
+                    .swarm
                     .behaviour_mut()
-                    .request_response
-                    .send_response(channel, FileResponse(file))
-                    .expect("Connection to peer to be still open.");
+                    .catalog
+                    .send_request(&peer, request);
+                self.pending_browse.insert(request_id, sender);
             }
         }
     }
@@ -378,13 +2189,31 @@ impl EventLoop {
 #[behaviour(out_event = "ComposedEvent")]
 struct ComposedBehaviour {
     request_response: RequestResponse<FileExchangeCodec>,
-    kademlia: Kademlia<MemoryStore>,
+    catalog: RequestResponse<CatalogCodec>,
+    bitswap: RequestResponse<BitswapCodec>,
+    kademlia: ModalKademlia<MemoryStore>,
+    autonat: autonat::Behaviour,
+    relay_client: relay::Client,
+    identify: ModalIdentify,
+    gossipsub: Gossipsub,
+    ping: ping::Behaviour,
+    peering: Peering,
 }
 
+type ComposedHandlerErr = <<<ComposedBehaviour as NetworkBehaviour>::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::Error;
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 enum ComposedEvent {
     RequestResponse(RequestResponseEvent<FileRequest, FileResponse>),
+    Catalog(RequestResponseEvent<CatalogRequest, CatalogResponse>),
+    Bitswap(RequestResponseEvent<BitswapMessage, ()>),
     Kademlia(KademliaEvent),
+    Autonat(autonat::Event),
+    RelayClient(relay::Event),
+    Identify(IdentifyEvent),
+    Gossipsub(GossipsubEvent),
+    Ping(ping::Event),
 }
 
 impl From<RequestResponseEvent<FileRequest, FileResponse>> for ComposedEvent {
@@ -393,12 +2222,60 @@ impl From<RequestResponseEvent<FileRequest, FileResponse>> for ComposedEvent {
     }
 }
 
+impl From<RequestResponseEvent<CatalogRequest, CatalogResponse>> for ComposedEvent {
+    fn from(event: RequestResponseEvent<CatalogRequest, CatalogResponse>) -> Self {
+        ComposedEvent::Catalog(event)
+    }
+}
+
+impl From<RequestResponseEvent<BitswapMessage, ()>> for ComposedEvent {
+    fn from(event: RequestResponseEvent<BitswapMessage, ()>) -> Self {
+        ComposedEvent::Bitswap(event)
+    }
+}
+
 impl From<KademliaEvent> for ComposedEvent {
     fn from(event: KademliaEvent) -> Self {
         ComposedEvent::Kademlia(event)
     }
 }
 
+impl From<autonat::Event> for ComposedEvent {
+    fn from(event: autonat::Event) -> Self {
+        ComposedEvent::Autonat(event)
+    }
+}
+
+impl From<relay::Event> for ComposedEvent {
+    fn from(event: relay::Event) -> Self {
+        ComposedEvent::RelayClient(event)
+    }
+}
+
+impl From<IdentifyEvent> for ComposedEvent {
+    fn from(event: IdentifyEvent) -> Self {
+        ComposedEvent::Identify(event)
+    }
+}
+
+impl From<GossipsubEvent> for ComposedEvent {
+    fn from(event: GossipsubEvent) -> Self {
+        ComposedEvent::Gossipsub(event)
+    }
+}
+
+impl From<ping::Event> for ComposedEvent {
+    fn from(event: ping::Event) -> Self {
+        ComposedEvent::Ping(event)
+    }
+}
+
+impl From<Infallible> for ComposedEvent {
+    fn from(event: Infallible) -> Self {
+        match event {}
+    }
+}
+
 #[derive(Debug)]
 enum Command {
     StartListening {
@@ -416,113 +2293,164 @@ enum Command {
     },
     GetProviders {
         file_name: String,
-        sender: oneshot::Sender<HashSet<PeerId>>,
+        sender: mpsc::UnboundedSender<LookupEvent<PeerId>>,
     },
     RequestFile {
         file_name: String,
         peer: PeerId,
-        sender: oneshot::Sender<Result<Vec<u8>, Box<dyn Error + Send>>>,
+        sender: oneshot::Sender<Result<Vec<u8>, FileRequestError>>,
+    },
+    RankProviders {
+        providers: Vec<PeerId>,
+        sender: oneshot::Sender<Vec<PeerId>>,
+    },
+    Reputation {
+        sender: oneshot::Sender<Vec<(PeerId, PeerReputation)>>,
+    },
+    Peering {
+        sender: oneshot::Sender<Vec<(PeerId, PeeringState)>>,
+    },
+    KadOutcomes {
+        sender: oneshot::Sender<KadOutcomes>,
+    },
+    RoutingTable {
+        sender: oneshot::Sender<RoutingTable>,
+    },
+    #[cfg(feature = "test-support")]
+    RunningQueries {
+        sender: oneshot::Sender<usize>,
+    },
+    ReportPeer {
+        peer: PeerId,
+        offense: Offense,
+    },
+    PutBlock {
+        codec: u64,
+        data: Vec<u8>,
+        sender: oneshot::Sender<Cid>,
+    },
+    GetBlock {
+        cid: Cid,
+        sender: oneshot::Sender<Vec<u8>>,
+    },
+    CancelWant {
+        cid: Cid,
+    },
+    Wantlist {
+        peer: Option<PeerId>,
+        sender: oneshot::Sender<Vec<Cid>>,
+    },
+    Ledger {
+        peer: PeerId,
+        sender: oneshot::Sender<bitswap::Ledger>,
+    },
+    BitswapStat {
+        sender: oneshot::Sender<bitswap::Stat>,
+    },
+    ImportCar {
+        car: Vec<u8>,
+        sender: oneshot::Sender<Result<Vec<Cid>, CarError>>,
+    },
+    ExportCar {
+        root: Cid,
+        sender: oneshot::Sender<Result<Vec<u8>, CarError>>,
+    },
+    PutRecord {
+        key: String,
+        value: Vec<u8>,
+        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+    },
+    GetRecord {
+        key: String,
+        sender: mpsc::UnboundedSender<LookupEvent<PeerRecord>>,
+    },
+    Bootstrap {
+        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+    },
+    Subscribe {
+        topic: String,
+        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+    },
+    Publish {
+        topic: String,
+        data: Vec<u8>,
+        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+    },
+    NatStatus {
+        sender: oneshot::Sender<(NatStatus, usize)>,
     },
     RespondFile {
         file: Vec<u8>,
         channel: ResponseChannel<FileResponse>,
     },
+    DenyFile {
+        reason: DenyReason,
+        channel: ResponseChannel<FileResponse>,
+    },
+    AddToCatalog {
+        entry: CatalogEntry,
+    },
+    RemoveFromCatalog {
+        key: String,
+    },
+    Browse {
+        peer: PeerId,
+        request: CatalogRequest,
+        sender: oneshot::Sender<Result<CatalogPage, FileRequestError>>,
+    },
 }
 
 #[derive(Debug)]
 pub enum Event {
+    /// A file request that passed the request policy. Answer it with
+    /// [`Client::respond_file`] or [`Client::deny_file`].
     InboundRequest {
+        peer: PeerId,
         request: String,
         channel: ResponseChannel<FileResponse>,
     },
+    NatStatusChanged {
+        old: NatStatus,
+        new: NatStatus,
+    },
+    GossipMessage {
+        source: Option<PeerId>,
+        topic: String,
+        data: Vec<u8>,
+    },
 }
 
-// Simple file exchange protocol
-#[derive(Debug, Clone)]
-struct FileExchangeProtocol();
-
-#[derive(Clone)]
-struct FileExchangeCodec();
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct FileRequest(String);
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct FileResponse(Vec<u8>);
-
-impl ProtocolName for FileExchangeProtocol {
-    fn protocol_name(&self) -> &[u8] {
-        "/file-exchange/1".as_bytes()
-    }
+/// Why [`Client::request_file`] did not return the file.
+#[derive(Debug)]
+pub enum FileRequestError {
+    /// The request could not be delivered or no response arrived.
+    Outbound(OutboundFailure),
+    /// The provider refused the request.
+    Denied(DenyReason),
+    /// The provider refused the request for now.
+    RateLimited { retry_after: Duration },
+    /// No response arrived within the given duration.
+    Timeout(Duration),
 }
 
-#[async_trait]
-impl RequestResponseCodec for FileExchangeCodec {
-    type Protocol = FileExchangeProtocol;
-    type Request = FileRequest;
-    type Response = FileResponse;
-
-    async fn read_request<T>(
-        &mut self,
-        _: &FileExchangeProtocol,
-        io: &mut T,
-    ) -> io::Result<Self::Request>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let vec = read_length_prefixed(io, 1_000_000).await?;
-
-        if vec.is_empty() {
-            return Err(io::ErrorKind::UnexpectedEof.into());
+impl fmt::Display for FileRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileRequestError::Outbound(e) => write!(f, "request failed: {}", e),
+            FileRequestError::Denied(reason) => write!(f, "request denied: {}", reason),
+            FileRequestError::RateLimited { retry_after } => {
+                write!(f, "request rate limited, retry after {:?}", retry_after)
+            }
+            FileRequestError::Timeout(limit) => write!(f, "request timed out after {:?}", limit),
         }
-
-        Ok(FileRequest(String::from_utf8(vec).unwrap()))
     }
+}
 
-    async fn read_response<T>(
-        &mut self,
-        _: &FileExchangeProtocol,
-        io: &mut T,
-    ) -> io::Result<Self::Response>
-    where
-        T: AsyncRead + Unpin + Send,
-    {
-        let vec = read_length_prefixed(io, 500_000_000).await?; // update transfer maximum
-
-        if vec.is_empty() {
-            return Err(io::ErrorKind::UnexpectedEof.into());
+impl Error for FileRequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FileRequestError::Outbound(e) => Some(e),
+            _ => None,
         }
-
-        Ok(FileResponse(vec))
-    }
-
-    async fn write_request<T>(
-        &mut self,
-        _: &FileExchangeProtocol,
-        io: &mut T,
-        FileRequest(data): FileRequest,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        write_length_prefixed(io, data).await?;
-        io.close().await?;
-
-        Ok(())
-    }
-
-    async fn write_response<T>(
-        &mut self,
-        _: &FileExchangeProtocol,
-        io: &mut T,
-        FileResponse(data): FileResponse,
-    ) -> io::Result<()>
-    where
-        T: AsyncWrite + Unpin + Send,
-    {
-        write_length_prefixed(io, data).await?;
-        io.close().await?;
-
-        Ok(())
     }
 }
@@ -0,0 +1,152 @@
+//! Peering: persistent connections to configured peers.
+//!
+//! Connections to peering peers are kept alive even when no protocol uses
+//! them, and the event loop re-dials peering peers with exponential backoff
+//! whenever they disconnect. The state of every peering is reported as
+//! [`PeeringState`], see [`Client::peering`](crate::network::Client::peering).
+use libp2p::core::connection::{ConnectedPoint, ConnectionId};
+use libp2p::core::upgrade::DeniedUpgrade;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::swarm::handler::DummyConnectionHandler;
+use libp2p::swarm::{
+  ConnectionHandler, IntoConnectionHandler, KeepAlive, NetworkBehaviour, NetworkBehaviourAction,
+  PollParameters,
+};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// State of the connection to a peering peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeeringState {
+  /// A dial is in progress.
+  Connecting {
+    /// Dials failed in a row before this one.
+    failures: u32,
+  },
+  Connected,
+  /// The peer is not connected, the next dial is scheduled.
+  Disconnected {
+    /// Dials failed in a row since the peer was last connected.
+    failures: u32,
+    next_dial: Instant,
+  },
+}
+
+/// Provides the addresses of peering peers and keeps their connections
+/// alive. Dialing is up to the event loop.
+pub struct Peering {
+  addresses: HashMap<PeerId, Vec<Multiaddr>>,
+  peers: Arc<HashSet<PeerId>>,
+}
+
+impl Peering {
+  pub fn new(addresses: HashMap<PeerId, Vec<Multiaddr>>) -> Self {
+    let peers = Arc::new(addresses.keys().copied().collect());
+    Self { addresses, peers }
+  }
+
+  pub fn is_peering(&self, peer: &PeerId) -> bool {
+    self.peers.contains(peer)
+  }
+
+  pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+    self.peers.iter()
+  }
+}
+
+/// Builds handlers keeping connections to peering peers alive.
+pub struct PeeringHandlerProto {
+  peers: Arc<HashSet<PeerId>>,
+}
+
+impl IntoConnectionHandler for PeeringHandlerProto {
+  type Handler = DummyConnectionHandler;
+
+  fn into_handler(self, remote_peer_id: &PeerId, _: &ConnectedPoint) -> Self::Handler {
+    DummyConnectionHandler {
+      keep_alive: if self.peers.contains(remote_peer_id) {
+        KeepAlive::Yes
+      } else {
+        KeepAlive::No
+      },
+    }
+  }
+
+  fn inbound_protocol(&self) -> <Self::Handler as ConnectionHandler>::InboundProtocol {
+    DeniedUpgrade
+  }
+}
+
+impl NetworkBehaviour for Peering {
+  type ConnectionHandler = PeeringHandlerProto;
+  type OutEvent = Infallible;
+
+  fn new_handler(&mut self) -> Self::ConnectionHandler {
+    PeeringHandlerProto {
+      peers: self.peers.clone(),
+    }
+  }
+
+  fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+    self.addresses.get(peer_id).cloned().unwrap_or_default()
+  }
+
+  fn inject_event(
+    &mut self,
+    _: PeerId,
+    _: ConnectionId,
+    _: <DummyConnectionHandler as ConnectionHandler>::OutEvent,
+  ) {
+  }
+
+  fn poll(
+    &mut self,
+    _: &mut Context<'_>,
+    _: &mut impl PollParameters,
+  ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+    Poll::Pending
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::core::Endpoint;
+
+  fn endpoint() -> ConnectedPoint {
+    ConnectedPoint::Dialer {
+      address: "/memory/1".parse().unwrap(),
+      role_override: Endpoint::Dialer,
+    }
+  }
+
+  #[test]
+  fn provides_addresses_of_peering_peers() {
+    let peer = PeerId::random();
+    let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+    let mut peering = Peering::new([(peer, vec![addr.clone()])].into_iter().collect());
+
+    assert!(peering.is_peering(&peer));
+    assert!(!peering.is_peering(&PeerId::random()));
+    assert_eq!(peering.peers().collect::<Vec<_>>(), [&peer]);
+    assert_eq!(peering.addresses_of_peer(&peer), [addr]);
+    assert!(peering.addresses_of_peer(&PeerId::random()).is_empty());
+  }
+
+  #[test]
+  fn keeps_only_peering_connections_alive() {
+    let peer = PeerId::random();
+    let mut peering = Peering::new([(peer, Vec::new())].into_iter().collect());
+
+    let handler = peering.new_handler().into_handler(&peer, &endpoint());
+    assert_eq!(handler.connection_keep_alive(), KeepAlive::Yes);
+
+    let handler = peering
+      .new_handler()
+      .into_handler(&PeerId::random(), &endpoint());
+    assert_eq!(handler.connection_keep_alive(), KeepAlive::No);
+  }
+}
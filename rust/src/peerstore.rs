@@ -0,0 +1,348 @@
+//! Known peer addresses persisted across restarts.
+//!
+//! A [`PeerStore`] maps peers to the addresses they were reached at. It is
+//! filled from the Kademlia routing table and from established connections,
+//! saved as JSON and loaded back into the routing table at startup, so that a
+//! restarted node can bootstrap from the peers it knew instead of
+//! rediscovering the network from scratch.
+//!
+//! Every peer carries the time it was last connected to. Peers not seen for
+//! [`MAX_AGE`] are dropped by [`PeerStore::prune`], so that dead peers do
+//! not accumulate across restarts.
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::kad::kbucket::NodeStatus;
+use libp2p::kad::record::store::RecordStore;
+use libp2p::kad::Kademlia;
+use libp2p::multiaddr::Protocol;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Addresses kept per peer, the most recently added ones win.
+pub const MAX_ADDRESSES_PER_PEER: usize = 8;
+
+/// Time after which a peer not connected to is forgotten.
+pub const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Addresses of known peers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStore {
+  peers: BTreeMap<PeerId, KnownPeer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KnownPeer {
+  addresses: Vec<Multiaddr>,
+  last_seen: SystemTime,
+}
+
+/// On-disk representation, peer IDs and addresses in their string form,
+/// the last seen time in seconds since the Unix epoch. Stores written before
+/// the time was recorded count their peers as seen when loaded.
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+  peer_id: String,
+  addresses: Vec<String>,
+  #[serde(default)]
+  last_seen: Option<u64>,
+}
+
+impl PeerStore {
+  /// Load the store saved at `path`. A missing file yields an empty store,
+  /// entries that fail to parse and peers not seen for [`MAX_AGE`] are
+  /// skipped.
+  pub fn load(path: &Path) -> io::Result<Self> {
+    let content = match std::fs::read(path) {
+      Ok(content) => content,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(e) => return Err(e),
+    };
+    let stored: Vec<StoredPeer> = serde_json::from_slice(&content)?;
+
+    let mut store = Self::default();
+    for StoredPeer {
+      peer_id,
+      addresses,
+      last_seen,
+    } in stored
+    {
+      let peer = match peer_id.parse::<PeerId>() {
+        Ok(peer) => peer,
+        Err(_) => {
+          warn!("peerstore: skipping invalid peer ID {:?}", peer_id);
+          continue;
+        }
+      };
+      let last_seen = last_seen.map_or_else(SystemTime::now, |secs| {
+        UNIX_EPOCH + Duration::from_secs(secs)
+      });
+      for addr in addresses {
+        match addr.parse::<Multiaddr>() {
+          Ok(addr) => store.insert(peer, addr, last_seen),
+          Err(_) => warn!("peerstore: skipping invalid address {:?} of {}", addr, peer),
+        }
+      }
+    }
+    store.prune(MAX_AGE);
+    Ok(store)
+  }
+
+  /// Save the store to `path`, replacing the previous content atomically.
+  pub async fn save(&self, path: &Path) -> io::Result<()> {
+    let stored: Vec<StoredPeer> = self
+      .peers
+      .iter()
+      .map(|(peer, known)| StoredPeer {
+        peer_id: peer.to_base58(),
+        addresses: known.addresses.iter().map(ToString::to_string).collect(),
+        last_seen: known
+          .last_seen
+          .duration_since(UNIX_EPOCH)
+          .ok()
+          .map(|since| since.as_secs()),
+      })
+      .collect();
+
+    let tmp = path.with_extension("tmp");
+    async_std::fs::write(&tmp, serde_json::to_vec_pretty(&stored)?).await?;
+    async_std::fs::rename(&tmp, path).await
+  }
+
+  /// Remember `addr` as an address of `peer`, the peer being seen just now.
+  /// In-memory and relayed addresses are ignored, they do not outlive the
+  /// process respectively the relay reservation.
+  pub fn add_address(&mut self, peer: PeerId, addr: Multiaddr) {
+    self.insert(peer, addr, SystemTime::now());
+  }
+
+  /// Record that `peer` was just seen, if it is known.
+  pub fn mark_seen(&mut self, peer: &PeerId) {
+    if let Some(known) = self.peers.get_mut(peer) {
+      known.last_seen = SystemTime::now();
+    }
+  }
+
+  /// Forget the peers not seen for longer than `max_age`.
+  pub fn prune(&mut self, max_age: Duration) {
+    let now = SystemTime::now();
+    self.peers.retain(|_, known| {
+      now
+        .duration_since(known.last_seen)
+        .map_or(true, |age| age <= max_age)
+    });
+  }
+
+  /// Add `addr` to the addresses of `peer`, moving the last seen time of the
+  /// peer forward to `seen`.
+  fn insert(&mut self, peer: PeerId, mut addr: Multiaddr, seen: SystemTime) {
+    if addr
+      .iter()
+      .any(|p| matches!(p, Protocol::Memory(_) | Protocol::P2pCircuit))
+    {
+      return;
+    }
+    if let Some(Protocol::P2p(_)) = addr.iter().last() {
+      addr.pop();
+    }
+
+    let known = self.peers.entry(peer).or_insert(KnownPeer {
+      addresses: Vec::new(),
+      last_seen: seen,
+    });
+    known.last_seen = known.last_seen.max(seen);
+    let addrs = &mut known.addresses;
+    addrs.retain(|a| *a != addr);
+    addrs.push(addr);
+    if addrs.len() > MAX_ADDRESSES_PER_PEER {
+      addrs.remove(0);
+    }
+  }
+
+  /// Forget `peer` and all its addresses.
+  pub fn remove(&mut self, peer: &PeerId) {
+    self.peers.remove(peer);
+  }
+
+  pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &[Multiaddr])> {
+    self
+      .peers
+      .iter()
+      .map(|(peer, known)| (peer, known.addresses.as_slice()))
+  }
+
+  pub fn len(&self) -> usize {
+    self.peers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.peers.is_empty()
+  }
+
+  /// Add every peer of the routing table of `kademlia` with its addresses.
+  /// Peers already known keep their last seen time unless they are
+  /// connected.
+  pub fn snapshot_routing_table<TStore>(&mut self, kademlia: &mut Kademlia<TStore>)
+  where
+    for<'a> TStore: RecordStore<'a>,
+    TStore: Send + 'static,
+  {
+    let now = SystemTime::now();
+    for bucket in kademlia.kbuckets() {
+      for entry in bucket.iter() {
+        let peer = *entry.node.key.preimage();
+        let seen = match (entry.status, self.peers.get(&peer)) {
+          (NodeStatus::Disconnected, Some(known)) => known.last_seen,
+          _ => now,
+        };
+        for addr in entry.node.value.iter() {
+          self.insert(peer, addr.clone(), seen);
+        }
+      }
+    }
+  }
+
+  /// Add every stored address to the routing table of `kademlia`. Returns
+  /// the number of peers added.
+  pub fn restore<TStore>(&self, kademlia: &mut Kademlia<TStore>) -> usize
+  where
+    for<'a> TStore: RecordStore<'a>,
+    TStore: Send + 'static,
+  {
+    for (peer, known) in &self.peers {
+      for addr in &known.addresses {
+        kademlia.add_address(peer, addr.clone());
+      }
+    }
+    self.peers.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn addr(port: u16) -> Multiaddr {
+    format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+  }
+
+  fn scratch_file(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+  }
+
+  #[test]
+  fn add_address_skips_transient_addresses() {
+    let mut store = PeerStore::default();
+    let peer = PeerId::random();
+    store.add_address(peer, "/memory/1234".parse().unwrap());
+    store.add_address(
+      peer,
+      addr(1)
+        .with(Protocol::P2p(PeerId::random().into()))
+        .with(Protocol::P2pCircuit),
+    );
+    assert!(store.is_empty());
+
+    store.add_address(peer, addr(1).with(Protocol::P2p(peer.into())));
+    assert_eq!(store.peers().next(), Some((&peer, &[addr(1)][..])));
+  }
+
+  #[test]
+  fn addresses_are_capped_most_recent_first() {
+    let mut store = PeerStore::default();
+    let peer = PeerId::random();
+    for port in 0..MAX_ADDRESSES_PER_PEER as u16 + 2 {
+      store.add_address(peer, addr(port));
+    }
+    // Adding a known address again moves it to the back.
+    store.add_address(peer, addr(2));
+
+    let (_, addresses) = store.peers().next().unwrap();
+    assert_eq!(addresses.len(), MAX_ADDRESSES_PER_PEER);
+    assert_eq!(addresses.first(), Some(&addr(3)));
+    assert_eq!(addresses.last(), Some(&addr(2)));
+  }
+
+  #[test]
+  fn prune_drops_expired_peers() {
+    let mut store = PeerStore::default();
+    let old = PeerId::random();
+    let recent = PeerId::random();
+    let now = SystemTime::now();
+    store.insert(old, addr(1), now - Duration::from_secs(120));
+    store.insert(recent, addr(2), now - Duration::from_secs(30));
+
+    store.prune(Duration::from_secs(60));
+    assert_eq!(
+      store.peers().map(|(peer, _)| *peer).collect::<Vec<_>>(),
+      [recent]
+    );
+
+    store.mark_seen(&recent);
+    store.prune(Duration::from_secs(1));
+    assert_eq!(store.len(), 1);
+  }
+
+  #[async_std::test]
+  async fn save_and_load() {
+    let path = scratch_file("peerstore-roundtrip");
+    let mut store = PeerStore::default();
+    let peer = PeerId::random();
+    store.add_address(peer, addr(1));
+    store.add_address(peer, addr(2));
+    // Expired peers are dropped when loading.
+    store.insert(
+      PeerId::random(),
+      addr(3),
+      SystemTime::now() - MAX_AGE - Duration::from_secs(60),
+    );
+    store.save(&path).await.unwrap();
+
+    let loaded = PeerStore::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(
+      loaded.peers().next(),
+      Some((&peer, &[addr(1), addr(2)][..]))
+    );
+  }
+
+  #[test]
+  fn load_skips_invalid_entries() {
+    let path = scratch_file("peerstore-invalid");
+    let peer = PeerId::random();
+    std::fs::write(
+      &path,
+      format!(
+        r#"[
+          {{"peer_id": "invalid", "addresses": ["/ip4/127.0.0.1/tcp/1"]}},
+          {{"peer_id": "{}", "addresses": ["invalid", "/ip4/127.0.0.1/tcp/1"]}}
+        ]"#,
+        peer
+      ),
+    )
+    .unwrap();
+
+    let loaded = PeerStore::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // Stores without last seen time count their peers as seen now.
+    assert_eq!(
+      loaded.peers().collect::<Vec<_>>(),
+      [(&peer, &[addr(1)][..])]
+    );
+  }
+
+  #[test]
+  fn load_missing_and_corrupt_files() {
+    let path = scratch_file("peerstore-corrupt");
+    assert!(PeerStore::load(&path).unwrap().is_empty());
+
+    std::fs::write(&path, b"[{\"peer_id\": ").unwrap();
+    let error = PeerStore::load(&path).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+  }
+}
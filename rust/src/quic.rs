@@ -0,0 +1,622 @@
+//! QUIC transport, dialing and listening on `/ip4|ip6/<ip>/udp/<port>/quic`
+//! and `/quic-v1` addresses.
+//!
+//! Connections are secured with the libp2p TLS 1.3 handshake (see
+//! [`crate::tls`]) built into QUIC, and every libp2p substream is a QUIC
+//! bidirectional stream, so no further security or multiplexer upgrade is
+//! negotiated. That saves the round trips of multistream-select, Noise and
+//! Yamux a TCP connection needs before the first request.
+//!
+//! `/quic` addresses are dialed with QUIC draft-29, `/quic-v1` addresses with
+//! QUIC v1, listeners accept both and report `/quic` addresses. The
+//! `multiaddr` version in use has no `quic-v1` protocol, it is represented as
+//! `/quic/tls` instead, see [`parse_multiaddr`].
+use crate::tls;
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream};
+use futures::{AsyncRead, AsyncWrite, FutureExt, StreamExt};
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerEvent};
+use libp2p::core::transport::{ListenerEvent, TransportError};
+use libp2p::core::{identity, Multiaddr, PeerId, Transport};
+use libp2p::multiaddr::Protocol;
+use quinn::{Connecting, Connection, ConnectionError, Endpoint, RecvStream, SendStream, VarInt};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// QUIC version 1, RFC 9000.
+const QUIC_V1: u32 = 0x0000_0001;
+
+/// QUIC draft-29, spoken by libp2p implementations as `/quic`.
+const QUIC_DRAFT_29: u32 = 0xff00_001d;
+
+/// Server name sent by clients, peers are authenticated by their
+/// certificate's libp2p extension instead.
+const SERVER_NAME: &str = "l";
+
+/// Settings of QUIC connections.
+#[derive(Debug, Clone)]
+pub struct QuicConfig {
+  max_idle_timeout: Duration,
+  keep_alive_interval: Duration,
+  max_concurrent_streams: u32,
+}
+
+impl Default for QuicConfig {
+  fn default() -> Self {
+    Self {
+      max_idle_timeout: Duration::from_secs(30),
+      keep_alive_interval: Duration::from_secs(10),
+      max_concurrent_streams: 256,
+    }
+  }
+}
+
+impl QuicConfig {
+  /// Close connections without any traffic for `timeout`.
+  pub fn set_max_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+    self.max_idle_timeout = timeout;
+    self
+  }
+
+  /// Send keep-alive packets on connections idle for `interval`, which
+  /// should be well below the idle timeout.
+  pub fn set_keep_alive_interval(&mut self, interval: Duration) -> &mut Self {
+    self.keep_alive_interval = interval;
+    self
+  }
+
+  /// Maximum number of substreams the remote may have open at a time.
+  pub fn set_max_concurrent_streams(&mut self, streams: u32) -> &mut Self {
+    self.max_concurrent_streams = streams;
+    self
+  }
+
+  fn transport_config(&self) -> io::Result<Arc<quinn::TransportConfig>> {
+    let idle_timeout = quinn::IdleTimeout::try_from(self.max_idle_timeout)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut transport = quinn::TransportConfig::default();
+    transport
+      .max_idle_timeout(Some(idle_timeout))
+      .keep_alive_interval(Some(self.keep_alive_interval))
+      .max_concurrent_bidi_streams(VarInt::from_u32(self.max_concurrent_streams))
+      .max_concurrent_uni_streams(VarInt::from_u32(0));
+    Ok(Arc::new(transport))
+  }
+}
+
+/// QUIC transport yielding connections already authenticated and
+/// multiplexed.
+#[derive(Clone)]
+pub struct QuicTransport {
+  server: quinn::ServerConfig,
+  /// Client settings for `/quic` addresses.
+  draft_29: quinn::ClientConfig,
+  /// Client settings for `/quic-v1` addresses.
+  v1: quinn::ClientConfig,
+  /// Endpoints for outgoing connections, created on the first dial of the
+  /// respective IP version.
+  dialers: Arc<Mutex<Dialers>>,
+}
+
+#[derive(Default)]
+struct Dialers {
+  v4: Option<Endpoint>,
+  v6: Option<Endpoint>,
+}
+
+impl fmt::Debug for QuicTransport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("QuicTransport").finish_non_exhaustive()
+  }
+}
+
+impl QuicTransport {
+  /// Transport authenticating as `keypair`.
+  pub fn new(keypair: &identity::Keypair, config: &QuicConfig) -> io::Result<Self> {
+    let transport = config.transport_config()?;
+
+    let server_crypto = tls::server_config(keypair).map_err(io::Error::other)?;
+    let mut server = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+    server.transport_config(transport.clone());
+
+    let client_crypto = tls::client_config(keypair, None).map_err(io::Error::other)?;
+    let mut draft_29 = quinn::ClientConfig::new(Arc::new(client_crypto));
+    draft_29.transport_config(transport);
+    let mut v1 = draft_29.clone();
+    draft_29.version(QUIC_DRAFT_29);
+    v1.version(QUIC_V1);
+
+    Ok(Self {
+      server,
+      draft_29,
+      v1,
+      dialers: Default::default(),
+    })
+  }
+
+  fn dialer(&self, remote: &SocketAddr) -> io::Result<Endpoint> {
+    let mut dialers = self
+      .dialers
+      .lock()
+      .expect("Dialers lock not to be poisoned.");
+    let (slot, unspecified) = match remote {
+      SocketAddr::V4(_) => (&mut dialers.v4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+      SocketAddr::V6(_) => (&mut dialers.v6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+    if let Some(endpoint) = slot {
+      return Ok(endpoint.clone());
+    }
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+    let endpoint = Endpoint::new(
+      quinn::EndpointConfig::default(),
+      None,
+      socket,
+      quinn::AsyncStdRuntime,
+    )?;
+    *slot = Some(endpoint.clone());
+    Ok(endpoint)
+  }
+}
+
+impl Transport for QuicTransport {
+  type Output = (PeerId, QuicMuxer);
+  type Error = QuicError;
+  type Listener =
+    BoxStream<'static, Result<ListenerEvent<Self::ListenerUpgrade, QuicError>, QuicError>>;
+  type ListenerUpgrade = BoxFuture<'static, Result<Self::Output, QuicError>>;
+  type Dial = BoxFuture<'static, Result<Self::Output, QuicError>>;
+
+  fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<QuicError>> {
+    let socket_addr = match to_socket_addr(&addr) {
+      Some((socket_addr, _)) => socket_addr,
+      None => return Err(TransportError::MultiaddrNotSupported(addr)),
+    };
+    let socket = UdpSocket::bind(socket_addr).map_err(|e| TransportError::Other(e.into()))?;
+    let endpoint = Endpoint::new(
+      quinn::EndpointConfig::default(),
+      Some(self.server),
+      socket,
+      quinn::AsyncStdRuntime,
+    )
+    .map_err(|e| TransportError::Other(e.into()))?;
+    let local_addr = endpoint
+      .local_addr()
+      .map_err(|e| TransportError::Other(e.into()))?;
+
+    let addresses = listen_addresses(local_addr)
+      .into_iter()
+      .map(|addr| Ok(ListenerEvent::NewAddress(addr)));
+    let connections = stream::unfold(endpoint, move |endpoint| async move {
+      let connecting = endpoint.accept().await?;
+      let event = ListenerEvent::Upgrade {
+        local_addr: to_multiaddr(local_addr),
+        remote_addr: to_multiaddr(connecting.remote_address()),
+        upgrade: upgrade(connecting).boxed(),
+      };
+      Some((Ok(event), endpoint))
+    });
+    Ok(stream::iter(addresses).chain(connections).boxed())
+  }
+
+  fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<QuicError>> {
+    let (socket_addr, version) = match to_socket_addr(&addr) {
+      Some((socket_addr, version))
+        if !socket_addr.ip().is_unspecified() && socket_addr.port() != 0 =>
+      {
+        (socket_addr, version)
+      }
+      _ => return Err(TransportError::MultiaddrNotSupported(addr)),
+    };
+    let client = match version {
+      QUIC_V1 => self.v1.clone(),
+      _ => self.draft_29.clone(),
+    };
+    let endpoint = self
+      .dialer(&socket_addr)
+      .map_err(|e| TransportError::Other(e.into()))?;
+    let connecting = endpoint
+      .connect_with(client, socket_addr, SERVER_NAME)
+      .map_err(|e| TransportError::Other(QuicError::Connect(e)))?;
+    Ok(upgrade(connecting).boxed())
+  }
+
+  fn dial_as_listener(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<QuicError>> {
+    self.dial(addr)
+  }
+
+  fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+    // Outgoing connections do not use the listening port.
+    libp2p::core::address_translation(listen, observed)
+  }
+}
+
+/// Wait for the handshake of `connecting`, yielding the remote peer ID taken
+/// from its certificate.
+async fn upgrade(connecting: Connecting) -> Result<(PeerId, QuicMuxer), QuicError> {
+  let connection = connecting.await?;
+  let certificates = connection
+    .peer_identity()
+    .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+    .ok_or(QuicError::Certificate(webpki::Error::BadDer))?;
+  let peer_id = match certificates.as_slice() {
+    [certificate] => tls::parse(certificate)?.peer_id(),
+    _ => return Err(QuicError::Certificate(webpki::Error::BadDer)),
+  };
+  Ok((peer_id, QuicMuxer::new(connection)))
+}
+
+/// Parse a multiaddr, accepting `/quic-v1` for QUIC v1, which is represented
+/// as `/quic/tls`.
+pub fn parse_multiaddr(s: &str) -> Result<Multiaddr, libp2p::multiaddr::Error> {
+  s.split('/')
+    .map(|protocol| match protocol {
+      "quic-v1" => "quic/tls",
+      protocol => protocol,
+    })
+    .collect::<Vec<_>>()
+    .join("/")
+    .parse()
+}
+
+/// Socket address and QUIC version of `/ip4|ip6/<ip>/udp/<port>/quic`,
+/// draft-29, or `/ip4|ip6/<ip>/udp/<port>/quic/tls`, v1, optionally followed
+/// by `/p2p/<peer-id>`.
+fn to_socket_addr(addr: &Multiaddr) -> Option<(SocketAddr, u32)> {
+  let mut iter = addr.iter().peekable();
+  let ip = match iter.next()? {
+    Protocol::Ip4(ip) => IpAddr::V4(ip),
+    Protocol::Ip6(ip) => IpAddr::V6(ip),
+    _ => return None,
+  };
+  let port = match iter.next()? {
+    Protocol::Udp(port) => port,
+    _ => return None,
+  };
+  if iter.next()? != Protocol::Quic {
+    return None;
+  }
+  let version = match iter.next_if_eq(&Protocol::Tls) {
+    Some(_) => QUIC_V1,
+    None => QUIC_DRAFT_29,
+  };
+  match (iter.next(), iter.next()) {
+    (None, None) | (Some(Protocol::P2p(_)), None) => Some((SocketAddr::new(ip, port), version)),
+    _ => None,
+  }
+}
+
+fn to_multiaddr(addr: SocketAddr) -> Multiaddr {
+  Multiaddr::empty()
+    .with(addr.ip().into())
+    .with(Protocol::Udp(addr.port()))
+    .with(Protocol::Quic)
+}
+
+/// Addresses to report for a listener bound to `local_addr`: those of every
+/// interface of its IP version if bound to the unspecified address.
+fn listen_addresses(local_addr: SocketAddr) -> Vec<Multiaddr> {
+  if !local_addr.ip().is_unspecified() {
+    return vec![to_multiaddr(local_addr)];
+  }
+  let interfaces = match if_addrs::get_if_addrs() {
+    Ok(interfaces) => interfaces,
+    Err(e) => {
+      warn!("quic: failed to list network interfaces: {}", e);
+      return vec![to_multiaddr(local_addr)];
+    }
+  };
+  interfaces
+    .into_iter()
+    .map(|interface| interface.ip())
+    .filter(|ip| ip.is_ipv4() == local_addr.is_ipv4())
+    .map(|ip| to_multiaddr(SocketAddr::new(ip, local_addr.port())))
+    .collect()
+}
+
+/// Error of the QUIC transport.
+#[derive(Debug)]
+pub enum QuicError {
+  Io(io::Error),
+  /// A connection could not be initiated, e.g. for an invalid address.
+  Connect(quinn::ConnectError),
+  /// The handshake failed or the connection was lost.
+  Connection(ConnectionError),
+  /// The remote certificate is not a valid libp2p certificate.
+  Certificate(webpki::Error),
+}
+
+impl fmt::Display for QuicError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      QuicError::Io(e) => write!(f, "{}", e),
+      QuicError::Connect(e) => write!(f, "failed to connect: {}", e),
+      QuicError::Connection(e) => write!(f, "connection failed: {}", e),
+      QuicError::Certificate(e) => write!(f, "invalid peer certificate: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for QuicError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      QuicError::Io(e) => Some(e),
+      QuicError::Connect(e) => Some(e),
+      QuicError::Connection(e) => Some(e),
+      QuicError::Certificate(_) => None,
+    }
+  }
+}
+
+impl From<io::Error> for QuicError {
+  fn from(e: io::Error) -> Self {
+    QuicError::Io(e)
+  }
+}
+
+impl From<ConnectionError> for QuicError {
+  fn from(e: ConnectionError) -> Self {
+    QuicError::Connection(e)
+  }
+}
+
+impl From<webpki::Error> for QuicError {
+  fn from(e: webpki::Error) -> Self {
+    QuicError::Certificate(e)
+  }
+}
+
+type OpenStream = BoxFuture<'static, Result<(SendStream, RecvStream), ConnectionError>>;
+
+/// Stream muxer over a QUIC connection, every substream being a
+/// bidirectional QUIC stream.
+pub struct QuicMuxer {
+  connection: Connection,
+  incoming: Mutex<OpenStream>,
+}
+
+impl QuicMuxer {
+  fn new(connection: Connection) -> Self {
+    Self {
+      incoming: Mutex::new(accept_stream(connection.clone())),
+      connection,
+    }
+  }
+}
+
+fn accept_stream(connection: Connection) -> OpenStream {
+  async move { connection.accept_bi().await }.boxed()
+}
+
+/// A substream of a [`QuicMuxer`].
+pub struct Substream {
+  send: SendStream,
+  recv: RecvStream,
+}
+
+fn stream_error(e: ConnectionError) -> io::Error {
+  io::Error::new(io::ErrorKind::ConnectionAborted, e)
+}
+
+impl StreamMuxer for QuicMuxer {
+  type Substream = Substream;
+  type OutboundSubstream = OpenStream;
+  type Error = io::Error;
+
+  fn poll_event(
+    &self,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<StreamMuxerEvent<Self::Substream>, Self::Error>> {
+    let mut incoming = self
+      .incoming
+      .lock()
+      .expect("Muxer lock not to be poisoned.");
+    let (send, recv) = futures::ready!(incoming.poll_unpin(cx)).map_err(stream_error)?;
+    *incoming = accept_stream(self.connection.clone());
+    Poll::Ready(Ok(StreamMuxerEvent::InboundSubstream(Substream {
+      send,
+      recv,
+    })))
+  }
+
+  fn open_outbound(&self) -> Self::OutboundSubstream {
+    let connection = self.connection.clone();
+    async move { connection.open_bi().await }.boxed()
+  }
+
+  fn poll_outbound(
+    &self,
+    cx: &mut Context<'_>,
+    s: &mut Self::OutboundSubstream,
+  ) -> Poll<Result<Self::Substream, Self::Error>> {
+    let (send, recv) = futures::ready!(s.poll_unpin(cx)).map_err(stream_error)?;
+    Poll::Ready(Ok(Substream { send, recv }))
+  }
+
+  fn destroy_outbound(&self, _: Self::OutboundSubstream) {}
+
+  fn read_substream(
+    &self,
+    cx: &mut Context<'_>,
+    s: &mut Self::Substream,
+    buf: &mut [u8],
+  ) -> Poll<Result<usize, Self::Error>> {
+    Pin::new(&mut s.recv).poll_read(cx, buf)
+  }
+
+  fn write_substream(
+    &self,
+    cx: &mut Context<'_>,
+    s: &mut Self::Substream,
+    buf: &[u8],
+  ) -> Poll<Result<usize, Self::Error>> {
+    Pin::new(&mut s.send).poll_write(cx, buf)
+  }
+
+  fn flush_substream(
+    &self,
+    cx: &mut Context<'_>,
+    s: &mut Self::Substream,
+  ) -> Poll<Result<(), Self::Error>> {
+    Pin::new(&mut s.send).poll_flush(cx)
+  }
+
+  fn shutdown_substream(
+    &self,
+    cx: &mut Context<'_>,
+    s: &mut Self::Substream,
+  ) -> Poll<Result<(), Self::Error>> {
+    Pin::new(&mut s.send).poll_close(cx)
+  }
+
+  fn destroy_substream(&self, _: Self::Substream) {}
+
+  fn close(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.connection.close(VarInt::from_u32(0), b"");
+    Poll::Ready(Ok(()))
+  }
+
+  fn flush_all(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::io::{AsyncReadExt, AsyncWriteExt};
+  use libp2p::core::muxing::{event_from_ref_and_wrap, outbound_from_ref_and_wrap};
+
+  #[test]
+  fn parses_quic_addresses() {
+    let addr = "/ip4/127.0.0.1/udp/4001/quic".parse().unwrap();
+    assert_eq!(
+      to_socket_addr(&addr),
+      Some(("127.0.0.1:4001".parse().unwrap(), QUIC_DRAFT_29))
+    );
+    let addr = parse_multiaddr(
+      "/ip6/::1/udp/4001/quic-v1/p2p/12D3KooWPjceQrSwdWXPyLLeABRXmuqt69Rg3sBYbU1Nft9HyQ6X",
+    )
+    .unwrap();
+    assert_eq!(
+      to_socket_addr(&addr),
+      Some(("[::1]:4001".parse().unwrap(), QUIC_V1))
+    );
+    for addr in [
+      "/ip4/127.0.0.1/tcp/4001",
+      "/ip4/127.0.0.1/udp/4001",
+      "/ip4/127.0.0.1/udp/4001/quic/ws",
+      "/ip4/127.0.0.1/udp/4001/quic/tls/tls",
+      "/dns4/localhost/udp/4001/quic",
+    ] {
+      assert_eq!(to_socket_addr(&addr.parse().unwrap()), None, "{}", addr);
+    }
+  }
+
+  #[test]
+  fn parses_quic_v1() {
+    assert_eq!(
+      parse_multiaddr("/ip4/127.0.0.1/udp/4001/quic-v1").unwrap(),
+      "/ip4/127.0.0.1/udp/4001/quic/tls".parse().unwrap()
+    );
+    assert_eq!(
+      parse_multiaddr("/ip4/127.0.0.1/udp/4001/quic").unwrap(),
+      "/ip4/127.0.0.1/udp/4001/quic".parse().unwrap()
+    );
+    assert!(parse_multiaddr("/ip4/127.0.0.1/udp/4001/quic-v2").is_err());
+  }
+
+  #[async_std::test]
+  async fn connects_and_opens_substreams() {
+    let server_keys = identity::Keypair::generate_ed25519();
+    let client_keys = identity::Keypair::generate_ed25519();
+    let config = QuicConfig::default();
+    let server = QuicTransport::new(&server_keys, &config).unwrap();
+    let client = QuicTransport::new(&client_keys, &config).unwrap();
+
+    let mut listener = server
+      .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+      .unwrap();
+    let addr = match listener.next().await {
+      Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+      _ => panic!("Expected the listener to report its address."),
+    };
+    let accept = async {
+      match listener.next().await {
+        Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) => upgrade.await.unwrap(),
+        _ => panic!("Expected an incoming connection."),
+      }
+    };
+    let (accepted, dialed) = futures::join!(accept, client.dial(addr).unwrap());
+    let (client_peer, server_muxer) = accepted;
+    let (server_peer, client_muxer) = dialed.unwrap();
+    assert_eq!(client_peer, client_keys.public().to_peer_id());
+    assert_eq!(server_peer, server_keys.public().to_peer_id());
+
+    let server_muxer = Arc::new(server_muxer);
+    let client_muxer = Arc::new(client_muxer);
+    let mut outbound = outbound_from_ref_and_wrap(client_muxer.clone())
+      .await
+      .unwrap();
+    outbound.write_all(b"ping").await.unwrap();
+    outbound.flush().await.unwrap();
+    let mut inbound = match event_from_ref_and_wrap(server_muxer.clone()).await.unwrap() {
+      StreamMuxerEvent::InboundSubstream(substream) => substream,
+      StreamMuxerEvent::AddressChange(_) => panic!("Expected an inbound substream."),
+    };
+    let mut buf = [0; 4];
+    inbound.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    inbound.write_all(b"pong").await.unwrap();
+    inbound.close().await.unwrap();
+    outbound.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+  }
+
+  #[async_std::test]
+  async fn dials_draft_29_and_v1() {
+    let server_keys = identity::Keypair::generate_ed25519();
+    let config = QuicConfig::default();
+    let server = QuicTransport::new(&server_keys, &config).unwrap();
+    let client = QuicTransport::new(&identity::Keypair::generate_ed25519(), &config).unwrap();
+
+    let mut listener = server
+      .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+      .unwrap();
+    let addr = match listener.next().await {
+      Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+      _ => panic!("Expected the listener to report its address."),
+    };
+    for addr in [addr.clone(), addr.with(Protocol::Tls)] {
+      let accept = async {
+        match listener.next().await {
+          Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) => upgrade.await.unwrap(),
+          _ => panic!("Expected an incoming connection."),
+        }
+      };
+      let (_, dialed) = futures::join!(accept, client.clone().dial(addr.clone()).unwrap());
+      let (server_peer, _) = dialed.unwrap_or_else(|e| panic!("Dialing {} failed: {}", addr, e));
+      assert_eq!(server_peer, server_keys.public().to_peer_id());
+    }
+  }
+
+  #[async_std::test]
+  async fn refuses_unsupported_addresses() {
+    let keys = identity::Keypair::generate_ed25519();
+    let transport = QuicTransport::new(&keys, &QuicConfig::default()).unwrap();
+    assert!(matches!(
+      transport
+        .clone()
+        .dial("/ip4/127.0.0.1/tcp/4001".parse().unwrap()),
+      Err(TransportError::MultiaddrNotSupported(_))
+    ));
+    assert!(matches!(
+      transport.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()),
+      Err(TransportError::MultiaddrNotSupported(_))
+    ));
+  }
+}
@@ -0,0 +1,155 @@
+//! Ranking the providers of a key.
+//!
+//! The network layer keeps [`PeerStats`] for every peer it exchanged files
+//! with: the round-trip time last measured by the ping protocol, the outcome
+//! of past transfers and whether the peer is connected right now. Stats not
+//! updated for an hour are forgotten. A
+//! [`RankingStrategy`] turns these into an order to ask providers in, see
+//! [`Client::fetch`](crate::network::Client::fetch).
+use libp2p::core::PeerId;
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// What the local node knows about a peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+  /// Last round-trip time measured, if the peer was ever pinged.
+  pub rtt: Option<Duration>,
+  /// Transfers that returned the file.
+  pub successes: u32,
+  /// Transfers that failed on the way, e.g. timed out or were refused by the
+  /// connection. Denials by the peer do not count.
+  pub failures: u32,
+  pub connected: bool,
+}
+
+impl PeerStats {
+  /// Share of successful transfers, `None` if no transfer happened yet.
+  pub fn success_rate(&self) -> Option<f64> {
+    let total = self.successes + self.failures;
+    (total > 0).then(|| f64::from(self.successes) / f64::from(total))
+  }
+}
+
+/// Orders providers, the most promising first.
+pub trait RankingStrategy: Debug + Send + Sync {
+  /// Score of a provider, higher is better.
+  fn score(&self, peer: &PeerId, stats: &PeerStats) -> f64;
+
+  /// Sort `providers` by descending score. Ties keep their order.
+  fn rank(&self, providers: Vec<(PeerId, PeerStats)>) -> Vec<PeerId> {
+    let mut scored: Vec<(f64, PeerId)> = providers
+      .into_iter()
+      .map(|(peer, stats)| (self.score(&peer, &stats), peer))
+      .collect();
+    scored.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    scored.into_iter().map(|(_, peer)| peer).collect()
+  }
+}
+
+/// Weighted sum of the three signals, each normalized to `0.0..=1.0`:
+///
+/// - RTT: `1 / (1 + rtt / reference_rtt)`, `0.5` for unmeasured peers.
+/// - Success rate: the share of successful transfers, `0.5` for unknown
+///   peers so that new providers get a chance.
+/// - Connected: `1.0` if a connection is open, avoiding a dial.
+#[derive(Debug, Clone)]
+pub struct Weighted {
+  pub rtt: f64,
+  pub success_rate: f64,
+  pub connected: f64,
+  /// RTT scoring `0.5`.
+  pub reference_rtt: Duration,
+}
+
+impl Default for Weighted {
+  fn default() -> Self {
+    Self {
+      rtt: 1.0,
+      success_rate: 2.0,
+      connected: 1.0,
+      reference_rtt: Duration::from_millis(100),
+    }
+  }
+}
+
+impl RankingStrategy for Weighted {
+  fn score(&self, _: &PeerId, stats: &PeerStats) -> f64 {
+    let rtt = stats.rtt.map_or(0.5, |rtt| {
+      1.0 / (1.0 + rtt.as_secs_f64() / self.reference_rtt.as_secs_f64().max(f64::EPSILON))
+    });
+    let success_rate = stats.success_rate().unwrap_or(0.5);
+    let connected = if stats.connected { 1.0 } else { 0.0 };
+
+    self.rtt * rtt + self.success_rate * success_rate + self.connected * connected
+  }
+}
+
+/// Keeps the given order.
+#[derive(Debug, Clone, Default)]
+pub struct Unranked;
+
+impl RankingStrategy for Unranked {
+  fn score(&self, _: &PeerId, _: &PeerStats) -> f64 {
+    0.0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stats(rtt: Option<u64>, successes: u32, failures: u32, connected: bool) -> PeerStats {
+    PeerStats {
+      rtt: rtt.map(Duration::from_millis),
+      successes,
+      failures,
+      connected,
+    }
+  }
+
+  #[test]
+  fn success_rate() {
+    assert_eq!(PeerStats::default().success_rate(), None);
+    assert_eq!(stats(None, 3, 1, false).success_rate(), Some(0.75));
+  }
+
+  #[test]
+  fn weighted_score() {
+    let weighted = Weighted::default();
+    let peer = PeerId::random();
+    // Unknown peers score 0.5 on RTT and success rate.
+    assert_eq!(weighted.score(&peer, &PeerStats::default()), 1.5);
+    // An RTT of the reference RTT scores 0.5 as well.
+    assert_eq!(weighted.score(&peer, &stats(Some(100), 0, 0, false)), 1.5);
+    assert_eq!(weighted.score(&peer, &stats(Some(0), 1, 0, true)), 4.0);
+  }
+
+  #[test]
+  fn weighted_rank() {
+    let fast = PeerId::random();
+    let slow = PeerId::random();
+    let failing = PeerId::random();
+    let unknown = PeerId::random();
+    let ranked = Weighted::default().rank(vec![
+      (failing, stats(Some(10), 0, 4, false)),
+      (unknown, PeerStats::default()),
+      (slow, stats(Some(1_000), 4, 0, true)),
+      (fast, stats(Some(10), 4, 0, true)),
+    ]);
+    assert_eq!(ranked, [fast, slow, unknown, failing]);
+  }
+
+  #[test]
+  fn unranked_keeps_order() {
+    let peers = (0..5).map(|_| PeerId::random()).collect::<Vec<_>>();
+    let ranked = Unranked.rank(
+      peers
+        .iter()
+        .map(|peer| (*peer, stats(Some(10), 1, 0, true)))
+        .collect(),
+    );
+    assert_eq!(ranked, peers);
+  }
+}
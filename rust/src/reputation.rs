@@ -0,0 +1,369 @@
+//! Peer reputation across protocols.
+//!
+//! Misbehaviour observed on any protocol lowers a peer's score by the
+//! penalty of the [`Offense`]. Scores decay back towards zero with the
+//! configured half-life, so that peers recover from occasional failures.
+//! Peers scoring below [`ReputationConfig::set_deprioritize_threshold`] are
+//! asked last when fetching files, peers below
+//! [`ReputationConfig::set_ban_threshold`] are disconnected and refused for
+//! the ban duration. Peers whose score recovered are forgotten, see
+//! [`ReputationTracker::prune`].
+use libp2p::core::PeerId;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Score above which a peer that is not banned counts as recovered.
+const RECOVERED_SCORE: f64 = -0.5;
+
+/// Misbehaviour lowering a peer's score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offense {
+  /// A file request to the peer failed, e.g. timed out or the connection
+  /// closed.
+  FailedFileResponse,
+  /// The peer sent a file whose content turned out to be wrong.
+  InvalidFileResponse,
+  /// A Kademlia request to the peer timed out.
+  KademliaTimeout,
+  /// The peer forwarded a gossipsub message rejected by validation.
+  GossipRejected,
+  /// The peer did not answer a ping.
+  PingFailure,
+  /// The peer sent a block whose content does not match its CID.
+  InvalidBlock,
+}
+
+impl fmt::Display for Offense {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Offense::FailedFileResponse => write!(f, "failed file response"),
+      Offense::InvalidFileResponse => write!(f, "invalid file response"),
+      Offense::KademliaTimeout => write!(f, "kademlia timeout"),
+      Offense::GossipRejected => write!(f, "rejected gossip message"),
+      Offense::PingFailure => write!(f, "ping failure"),
+      Offense::InvalidBlock => write!(f, "invalid block"),
+    }
+  }
+}
+
+/// Penalties, thresholds and decay of the reputation tracker.
+#[derive(Debug, Clone)]
+pub struct ReputationConfig {
+  penalties: HashMap<Offense, f64>,
+  half_life: Duration,
+  deprioritize_threshold: f64,
+  ban_threshold: f64,
+  ban_duration: Duration,
+}
+
+impl Default for ReputationConfig {
+  fn default() -> Self {
+    Self {
+      penalties: [
+        (Offense::FailedFileResponse, 5.0),
+        (Offense::InvalidFileResponse, 50.0),
+        (Offense::KademliaTimeout, 2.0),
+        (Offense::GossipRejected, 20.0),
+        (Offense::PingFailure, 5.0),
+        (Offense::InvalidBlock, 50.0),
+      ]
+      .into_iter()
+      .collect(),
+      half_life: Duration::from_secs(10 * 60),
+      deprioritize_threshold: -20.0,
+      ban_threshold: -100.0,
+      ban_duration: Duration::from_secs(60 * 60),
+    }
+  }
+}
+
+impl ReputationConfig {
+  /// Sets the amount the score drops by for `offense`.
+  pub fn set_penalty(&mut self, offense: Offense, penalty: f64) -> &mut Self {
+    self.penalties.insert(offense, penalty.abs());
+    self
+  }
+
+  /// Sets the time after which a score has decayed to half its value.
+  pub fn set_half_life(&mut self, half_life: Duration) -> &mut Self {
+    self.half_life = half_life;
+    self
+  }
+
+  /// Sets the score below which peers are asked last for files.
+  pub fn set_deprioritize_threshold(&mut self, threshold: f64) -> &mut Self {
+    self.deprioritize_threshold = threshold;
+    self
+  }
+
+  /// Sets the score below which peers are banned.
+  pub fn set_ban_threshold(&mut self, threshold: f64) -> &mut Self {
+    self.ban_threshold = threshold;
+    self
+  }
+
+  /// Sets how long a ban lasts. The score is reset once it ends.
+  pub fn set_ban_duration(&mut self, duration: Duration) -> &mut Self {
+    self.ban_duration = duration;
+    self
+  }
+}
+
+/// Reputation of a single peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerReputation {
+  /// Current score, zero for well behaved peers and negative otherwise.
+  pub score: f64,
+  /// Offenses counted since the peer was last forgotten.
+  pub offenses: HashMap<Offense, u32>,
+  /// End of the current ban, if the peer is banned.
+  pub banned_until: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+  score: f64,
+  updated: Instant,
+  offenses: HashMap<Offense, u32>,
+  banned_until: Option<Instant>,
+}
+
+/// Scores of all peers that misbehaved.
+#[derive(Debug, Clone, Default)]
+pub struct ReputationTracker {
+  config: ReputationConfig,
+  peers: HashMap<PeerId, Entry>,
+}
+
+impl ReputationTracker {
+  pub fn new(config: ReputationConfig) -> Self {
+    Self {
+      config,
+      peers: Default::default(),
+    }
+  }
+
+  /// Lower the score of `peer` for `offense`. Returns `true` if the peer has
+  /// to be banned as a consequence.
+  pub fn penalize(&mut self, peer: PeerId, offense: Offense, now: Instant) -> bool {
+    let penalty = self.config.penalties.get(&offense).copied().unwrap_or(0.0);
+    let entry = self.peers.entry(peer).or_insert_with(|| Entry {
+      score: 0.0,
+      updated: now,
+      offenses: Default::default(),
+      banned_until: None,
+    });
+    let elapsed = now.saturating_duration_since(entry.updated);
+    entry.score = decay(entry.score, elapsed, self.config.half_life) - penalty;
+    entry.updated = now;
+    *entry.offenses.entry(offense).or_default() += 1;
+
+    if entry.banned_until.is_none() && entry.score < self.config.ban_threshold {
+      entry.banned_until = Some(now + self.config.ban_duration);
+      return true;
+    }
+    false
+  }
+
+  /// Current score of `peer`, zero for unknown peers.
+  pub fn score(&self, peer: &PeerId, now: Instant) -> f64 {
+    self.peers.get(peer).map_or(0.0, |entry| {
+      decay(
+        entry.score,
+        now.saturating_duration_since(entry.updated),
+        self.config.half_life,
+      )
+    })
+  }
+
+  /// Whether `peer` is to be asked last for files.
+  pub fn is_deprioritized(&self, peer: &PeerId, now: Instant) -> bool {
+    self.score(peer, now) < self.config.deprioritize_threshold
+  }
+
+  pub fn is_banned(&self, peer: &PeerId, now: Instant) -> bool {
+    self
+      .peers
+      .get(peer)
+      .and_then(|entry| entry.banned_until)
+      .is_some_and(|until| until > now)
+  }
+
+  /// End the bans that expired, resetting the scores of the affected peers.
+  /// Returns the peers to unban.
+  pub fn expire_bans(&mut self, now: Instant) -> Vec<PeerId> {
+    let mut expired = Vec::new();
+    for (peer, entry) in &mut self.peers {
+      if matches!(entry.banned_until, Some(until) if until <= now) {
+        entry.banned_until = None;
+        entry.score = 0.0;
+        entry.updated = now;
+        expired.push(*peer);
+      }
+    }
+    expired
+  }
+
+  /// Forget the peers that are not banned and whose score recovered, so
+  /// that only misbehaving peers are tracked. Their offense counts start
+  /// over when they misbehave again.
+  pub fn prune(&mut self, now: Instant) {
+    let half_life = self.config.half_life;
+    self.peers.retain(|_, entry| {
+      entry.banned_until.is_some()
+        || decay(
+          entry.score,
+          now.saturating_duration_since(entry.updated),
+          half_life,
+        ) < RECOVERED_SCORE
+    });
+  }
+
+  /// Reputation of every tracked peer, scores decayed to `now`.
+  pub fn snapshot(&self, now: Instant) -> Vec<(PeerId, PeerReputation)> {
+    self
+      .peers
+      .iter()
+      .map(|(peer, entry)| {
+        (
+          *peer,
+          PeerReputation {
+            score: self.score(peer, now),
+            offenses: entry.offenses.clone(),
+            banned_until: entry.banned_until,
+          },
+        )
+      })
+      .collect()
+  }
+}
+
+fn decay(score: f64, elapsed: Duration, half_life: Duration) -> f64 {
+  if half_life.is_zero() {
+    return 0.0;
+  }
+  score * 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64())
+}
+
+/// Decides whether a gossipsub message is forwarded and handed to the
+/// application. Peers forwarding rejected messages are penalized with
+/// [`Offense::GossipRejected`].
+pub trait MessageValidator: fmt::Debug + Send + Sync {
+  /// `propagation_source` is the peer the message was received from.
+  fn validate(&self, propagation_source: &PeerId, topic: &str, data: &[u8]) -> bool;
+}
+
+/// Accepts every message.
+#[derive(Debug, Clone, Default)]
+pub struct AcceptAll;
+
+impl MessageValidator for AcceptAll {
+  fn validate(&self, _: &PeerId, _: &str, _: &[u8]) -> bool {
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tracker() -> ReputationTracker {
+    let mut config = ReputationConfig::default();
+    config
+      .set_penalty(Offense::PingFailure, 10.0)
+      .set_half_life(Duration::from_secs(60))
+      .set_deprioritize_threshold(-15.0)
+      .set_ban_threshold(-25.0)
+      .set_ban_duration(Duration::from_secs(300));
+    ReputationTracker::new(config)
+  }
+
+  #[test]
+  fn penalties_accumulate() {
+    let mut tracker = tracker();
+    let peer = PeerId::random();
+    let now = Instant::now();
+
+    assert!(!tracker.penalize(peer, Offense::PingFailure, now));
+    assert_eq!(tracker.score(&peer, now), -10.0);
+    assert!(!tracker.is_deprioritized(&peer, now));
+
+    assert!(!tracker.penalize(peer, Offense::PingFailure, now));
+    assert_eq!(tracker.score(&peer, now), -20.0);
+    assert!(tracker.is_deprioritized(&peer, now));
+    assert_eq!(tracker.score(&PeerId::random(), now), 0.0);
+  }
+
+  #[test]
+  fn scores_decay_with_half_life() {
+    let mut tracker = tracker();
+    let peer = PeerId::random();
+    let now = Instant::now();
+
+    tracker.penalize(peer, Offense::PingFailure, now);
+    let later = now + Duration::from_secs(60);
+    assert!((tracker.score(&peer, later) + 5.0).abs() < 1e-9);
+    tracker.penalize(peer, Offense::PingFailure, later);
+    assert!((tracker.score(&peer, later) + 15.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn ban_and_expiry() {
+    let mut tracker = tracker();
+    let peer = PeerId::random();
+    let now = Instant::now();
+
+    for _ in 0..2 {
+      assert!(!tracker.penalize(peer, Offense::PingFailure, now));
+    }
+    assert!(tracker.penalize(peer, Offense::PingFailure, now));
+    assert!(tracker.is_banned(&peer, now));
+    // A banned peer is only reported once.
+    assert!(!tracker.penalize(peer, Offense::PingFailure, now));
+
+    assert!(tracker.expire_bans(now).is_empty());
+    let end = now + Duration::from_secs(300);
+    assert_eq!(tracker.expire_bans(end), [peer]);
+    assert!(!tracker.is_banned(&peer, end));
+    assert_eq!(tracker.score(&peer, end), 0.0);
+  }
+
+  #[test]
+  fn prune_forgets_recovered_peers() {
+    let mut tracker = tracker();
+    let recovered = PeerId::random();
+    let offending = PeerId::random();
+    let now = Instant::now();
+
+    tracker.penalize(recovered, Offense::PingFailure, now);
+    let later = now + Duration::from_secs(10 * 60);
+    tracker.penalize(offending, Offense::PingFailure, later);
+    tracker.prune(later);
+
+    let snapshot = tracker.snapshot(later);
+    assert_eq!(snapshot.len(), 1);
+    let (peer, reputation) = &snapshot[0];
+    assert_eq!(*peer, offending);
+    assert_eq!(reputation.score, -10.0);
+    assert_eq!(reputation.offenses[&Offense::PingFailure], 1);
+    assert_eq!(reputation.banned_until, None);
+  }
+
+  #[test]
+  fn negative_penalties_and_zero_half_life() {
+    let mut config = ReputationConfig::default();
+    config
+      .set_penalty(Offense::KademliaTimeout, -3.0)
+      .set_half_life(Duration::ZERO);
+    let mut tracker = ReputationTracker::new(config);
+    let peer = PeerId::random();
+    let now = Instant::now();
+
+    // Penalties are taken by absolute value.
+    tracker.penalize(peer, Offense::KademliaTimeout, now);
+    assert_eq!(tracker.snapshot(now)[0].1.offenses.len(), 1);
+    // Without half-life scores are forgotten right away.
+    assert_eq!(tracker.score(&peer, now), 0.0);
+  }
+}
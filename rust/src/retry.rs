@@ -0,0 +1,262 @@
+//! Retrying file requests.
+//!
+//! [`Client::request_file_with_retry`](crate::network::Client::request_file_with_retry)
+//! makes up to [`RetryPolicy::max_attempts`] attempts, each bounded by the
+//! per-attempt timeout. Attempts rotate through the given providers, so a
+//! retry may target another provider of the same key. Between attempts the
+//! caller backs off exponentially, with random jitter so that requesters
+//! failing at the same time do not retry in lockstep.
+use crate::authz::DenyReason;
+use crate::network::FileRequestError;
+use libp2p::core::PeerId;
+use rand::Rng;
+use std::fmt;
+use std::time::Duration;
+
+/// How often and how fast to retry a file request.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+  max_attempts: usize,
+  attempt_timeout: Duration,
+  initial_backoff: Duration,
+  max_backoff: Duration,
+  multiplier: u32,
+  jitter: f64,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      max_attempts: 3,
+      attempt_timeout: Duration::from_secs(10),
+      initial_backoff: Duration::from_millis(200),
+      max_backoff: Duration::from_secs(10),
+      multiplier: 2,
+      jitter: 0.5,
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// A policy making a single attempt bounded by `timeout`.
+  pub fn once(timeout: Duration) -> Self {
+    let mut policy = Self::default();
+    policy.set_max_attempts(1).set_attempt_timeout(timeout);
+    policy
+  }
+
+  /// Sets the total number of attempts, including the first one.
+  ///
+  /// Values below one are treated as one.
+  pub fn set_max_attempts(&mut self, max_attempts: usize) -> &mut Self {
+    self.max_attempts = max_attempts.max(1);
+    self
+  }
+
+  /// Sets how long a single attempt may take before it is given up.
+  pub fn set_attempt_timeout(&mut self, timeout: Duration) -> &mut Self {
+    self.attempt_timeout = timeout;
+    self
+  }
+
+  /// Sets the backoff before the first retry and the upper bound for all
+  /// later ones.
+  pub fn set_backoff(&mut self, initial: Duration, max: Duration) -> &mut Self {
+    self.initial_backoff = initial;
+    self.max_backoff = max.max(initial);
+    self
+  }
+
+  /// Sets the factor the backoff grows by after every retry.
+  pub fn set_multiplier(&mut self, multiplier: u32) -> &mut Self {
+    self.multiplier = multiplier.max(1);
+    self
+  }
+
+  /// Sets the fraction of the backoff that is randomized, between `0.0` (no
+  /// jitter) and `1.0` (anywhere between zero and the full backoff).
+  ///
+  /// Values outside that range are clamped, NaN is treated as `0.0`.
+  pub fn set_jitter(&mut self, jitter: f64) -> &mut Self {
+    self.jitter = if jitter.is_nan() {
+      0.0
+    } else {
+      jitter.clamp(0.0, 1.0)
+    };
+    self
+  }
+
+  pub fn max_attempts(&self) -> usize {
+    self.max_attempts
+  }
+
+  pub fn attempt_timeout(&self) -> Duration {
+    self.attempt_timeout
+  }
+
+  /// Backoff before retry number `retry`, starting at zero, jitter applied.
+  pub fn backoff(&self, retry: usize) -> Duration {
+    let factor = self
+      .multiplier
+      .checked_pow(retry.try_into().unwrap_or(u32::MAX))
+      .unwrap_or(u32::MAX);
+    let backoff = self
+      .initial_backoff
+      .checked_mul(factor)
+      .map_or(self.max_backoff, |b| b.min(self.max_backoff));
+
+    let jitter = rand::thread_rng().gen_range(0.0..=self.jitter);
+    backoff.mul_f64(1.0 - jitter)
+  }
+}
+
+/// A failed attempt of a retried request.
+#[derive(Debug)]
+pub struct Attempt {
+  pub peer: PeerId,
+  pub error: FileRequestError,
+}
+
+impl Attempt {
+  /// Whether the peer may answer differently when asked again. Denials are
+  /// final, the peer is not asked again.
+  pub fn is_retryable(&self) -> bool {
+    !matches!(self.error, FileRequestError::Denied(_))
+  }
+}
+
+/// Every attempt of a retried request failed.
+#[derive(Debug)]
+pub struct RetryError {
+  pub file_name: String,
+  /// The attempts in the order they were made.
+  pub attempts: Vec<Attempt>,
+}
+
+impl RetryError {
+  /// Whether every provider asked refused with [`DenyReason::NotFound`].
+  pub fn is_not_found(&self) -> bool {
+    !self.attempts.is_empty()
+      && self
+        .attempts
+        .iter()
+        .all(|a| matches!(a.error, FileRequestError::Denied(DenyReason::NotFound)))
+  }
+
+  /// The error of the last attempt, if any attempt was made.
+  pub fn last_error(&self) -> Option<&FileRequestError> {
+    self.attempts.last().map(|a| &a.error)
+  }
+}
+
+impl fmt::Display for RetryError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.attempts.is_empty() {
+      return write!(f, "no provider to request {:?} from", self.file_name);
+    }
+    write!(
+      f,
+      "requesting {:?} failed after {} attempt(s)",
+      self.file_name,
+      self.attempts.len()
+    )?;
+    for (i, attempt) in self.attempts.iter().enumerate() {
+      write!(f, "; #{} to {}: {}", i + 1, attempt.peer, attempt.error)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for RetryError {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::request_response::OutboundFailure;
+
+  fn attempt(error: FileRequestError) -> Attempt {
+    Attempt {
+      peer: PeerId::random(),
+      error,
+    }
+  }
+
+  #[test]
+  fn backoff_grows_up_to_max() {
+    let mut policy = RetryPolicy::default();
+    policy
+      .set_backoff(Duration::from_millis(100), Duration::from_secs(1))
+      .set_multiplier(3)
+      .set_jitter(0.0);
+    assert_eq!(policy.backoff(0), Duration::from_millis(100));
+    assert_eq!(policy.backoff(1), Duration::from_millis(300));
+    assert_eq!(policy.backoff(2), Duration::from_millis(900));
+    assert_eq!(policy.backoff(3), Duration::from_secs(1));
+    // Overflowing factors are capped as well.
+    assert_eq!(policy.backoff(100), Duration::from_secs(1));
+    assert_eq!(policy.backoff(usize::MAX), Duration::from_secs(1));
+  }
+
+  #[test]
+  fn backoff_jitter_stays_within_bounds() {
+    let mut policy = RetryPolicy::default();
+    policy
+      .set_backoff(Duration::from_millis(100), Duration::from_millis(100))
+      .set_jitter(0.5);
+    for retry in 0..100 {
+      let backoff = policy.backoff(retry);
+      assert!(backoff >= Duration::from_millis(50), "{:?}", backoff);
+      assert!(backoff <= Duration::from_millis(100), "{:?}", backoff);
+    }
+  }
+
+  #[test]
+  fn setters_clamp() {
+    let mut policy = RetryPolicy::default();
+    policy
+      .set_max_attempts(0)
+      .set_backoff(Duration::from_secs(2), Duration::from_secs(1))
+      .set_multiplier(0)
+      .set_jitter(f64::NAN);
+    assert_eq!(policy.max_attempts(), 1);
+    // The max is raised to the initial backoff, a multiplier of zero is one.
+    assert_eq!(policy.backoff(0), Duration::from_secs(2));
+    assert_eq!(policy.backoff(5), Duration::from_secs(2));
+
+    policy.set_jitter(7.0);
+    assert!(policy.backoff(0) <= Duration::from_secs(2));
+  }
+
+  #[test]
+  fn once() {
+    let policy = RetryPolicy::once(Duration::from_secs(3));
+    assert_eq!(policy.max_attempts(), 1);
+    assert_eq!(policy.attempt_timeout(), Duration::from_secs(3));
+  }
+
+  #[test]
+  fn denials_are_final() {
+    assert!(!attempt(FileRequestError::Denied(DenyReason::Unauthorized)).is_retryable());
+    assert!(attempt(FileRequestError::Timeout(Duration::from_secs(1))).is_retryable());
+    assert!(attempt(FileRequestError::RateLimited {
+      retry_after: Duration::from_secs(1)
+    })
+    .is_retryable());
+  }
+
+  #[test]
+  fn not_found_requires_every_attempt_to_be_not_found() {
+    let not_found = || attempt(FileRequestError::Denied(DenyReason::NotFound));
+    let error = |attempts| RetryError {
+      file_name: "a".to_string(),
+      attempts,
+    };
+    assert!(!error(Vec::new()).is_not_found());
+    assert!(error(vec![not_found(), not_found()]).is_not_found());
+    assert!(!error(vec![
+      not_found(),
+      attempt(FileRequestError::Outbound(OutboundFailure::Timeout))
+    ])
+    .is_not_found());
+  }
+}
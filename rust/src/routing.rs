@@ -0,0 +1,193 @@
+//! Routing table inspection.
+//!
+//! [`Client::routing_table`](crate::network::Client::routing_table) returns a
+//! [`RoutingTable`] snapshot of the Kademlia k-buckets, e.g. to debug why
+//! lookups fail. Update times are tracked by the network layer from routing
+//! updates since the node started; entries restored from a peer store and
+//! never updated since have none.
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::kad::kbucket::{Distance, NodeStatus};
+use libp2p::kad::record::store::RecordStore;
+use libp2p::kad::{Kademlia, K_VALUE};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Snapshot of the routing table, non-empty buckets only.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+  pub buckets: Vec<Bucket>,
+  pub health: TableHealth,
+}
+
+/// A k-bucket holding peers at a distance within `range`.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+  /// Index of the bucket, i.e. the base-2 logarithm of the lower end of its
+  /// distance range. Higher buckets hold more distant peers.
+  pub index: u32,
+  pub range: (Distance, Distance),
+  pub entries: Vec<BucketEntry>,
+  /// Whether a peer waits for a slot in the full bucket.
+  pub has_pending: bool,
+  /// Most recent update of any entry.
+  pub last_updated: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BucketEntry {
+  pub peer: PeerId,
+  pub addresses: Vec<Multiaddr>,
+  pub connected: bool,
+  pub last_updated: Option<Instant>,
+}
+
+/// Summary of the routing table's health.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableHealth {
+  pub peers: usize,
+  pub connected: usize,
+  pub non_empty_buckets: usize,
+  /// Buckets holding [`K_VALUE`] peers.
+  pub full_buckets: usize,
+  /// Entries without a known update since the node started.
+  pub never_updated: usize,
+}
+
+impl TableHealth {
+  /// Whether lookups can be expected to succeed: at least [`K_VALUE`]
+  /// peers, some of them connected.
+  pub fn is_healthy(&self) -> bool {
+    self.peers >= K_VALUE.get() && self.connected > 0
+  }
+}
+
+impl RoutingTable {
+  /// Snapshot the routing table of `kademlia`, taking update times from
+  /// `updated`.
+  pub fn snapshot<TStore>(
+    kademlia: &mut Kademlia<TStore>,
+    updated: &HashMap<PeerId, Instant>,
+  ) -> Self
+  where
+    for<'a> TStore: RecordStore<'a>,
+    TStore: Send + 'static,
+  {
+    let mut health = TableHealth::default();
+    let mut buckets = Vec::new();
+
+    for bucket in kademlia.kbuckets() {
+      let range = bucket.range();
+      let entries: Vec<BucketEntry> = bucket
+        .iter()
+        .map(|entry| {
+          let peer = *entry.node.key.preimage();
+          BucketEntry {
+            peer,
+            addresses: entry.node.value.iter().cloned().collect(),
+            connected: entry.status == NodeStatus::Connected,
+            last_updated: updated.get(&peer).copied(),
+          }
+        })
+        .collect();
+
+      health.peers += entries.len();
+      health.connected += entries.iter().filter(|e| e.connected).count();
+      health.never_updated += entries.iter().filter(|e| e.last_updated.is_none()).count();
+      health.non_empty_buckets += 1;
+      if entries.len() >= K_VALUE.get() {
+        health.full_buckets += 1;
+      }
+
+      buckets.push(Bucket {
+        index: range.0.ilog2().unwrap_or(0),
+        range,
+        last_updated: entries.iter().filter_map(|e| e.last_updated).max(),
+        has_pending: bucket.has_pending(),
+        entries,
+      });
+    }
+
+    Self { buckets, health }
+  }
+
+  /// Whether `peer` is in any bucket.
+  pub fn contains(&self, peer: &PeerId) -> bool {
+    self
+      .buckets
+      .iter()
+      .any(|bucket| bucket.entries.iter().any(|entry| entry.peer == *peer))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use libp2p::kad::kbucket::Key;
+  use libp2p::kad::store::MemoryStore;
+
+  fn kademlia(local: PeerId) -> Kademlia<MemoryStore> {
+    Kademlia::new(local, MemoryStore::new(local))
+  }
+
+  #[test]
+  fn empty_table() {
+    let table = RoutingTable::snapshot(&mut kademlia(PeerId::random()), &HashMap::new());
+    assert!(table.buckets.is_empty());
+    assert_eq!(table.health, TableHealth::default());
+    assert!(!table.health.is_healthy());
+  }
+
+  #[test]
+  fn snapshot_lists_peers_by_bucket() {
+    let local = PeerId::random();
+    let mut kademlia = kademlia(local);
+    let local = Key::from(local);
+    let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+    let peers = (0..10).map(|_| PeerId::random()).collect::<Vec<_>>();
+    for peer in &peers {
+      kademlia.add_address(peer, addr.clone());
+    }
+    let now = Instant::now();
+    let updated = [(peers[0], now)].into_iter().collect();
+
+    let table = RoutingTable::snapshot(&mut kademlia, &updated);
+    assert_eq!(
+      table.health,
+      TableHealth {
+        peers: 10,
+        connected: 0,
+        non_empty_buckets: table.buckets.len(),
+        full_buckets: 0,
+        never_updated: 9,
+      }
+    );
+    // Disconnected peers do not make a healthy table.
+    assert!(!table.health.is_healthy());
+
+    for bucket in &table.buckets {
+      for entry in &bucket.entries {
+        let distance = local.distance(&Key::from(entry.peer));
+        assert!(bucket.range.0 <= distance && distance <= bucket.range.1);
+        assert_eq!(bucket.index, distance.ilog2().unwrap());
+        assert_eq!(entry.addresses, std::slice::from_ref(&addr));
+        assert!(!entry.connected);
+      }
+    }
+    let bucket = table
+      .buckets
+      .iter()
+      .find(|bucket| bucket.entries.iter().any(|e| e.peer == peers[0]))
+      .unwrap();
+    assert_eq!(bucket.last_updated, Some(now));
+  }
+
+  #[test]
+  fn healthy_with_enough_connected_peers() {
+    let health = TableHealth {
+      peers: K_VALUE.get(),
+      connected: 1,
+      ..Default::default()
+    };
+    assert!(health.is_healthy());
+  }
+}
@@ -0,0 +1,188 @@
+//! Keyword index on top of DHT records.
+//!
+//! Every keyword maps to the record `/kw/<keyword>` whose value is a JSON
+//! array of the content keys indexed under it. Publishing merges the key into
+//! the current value (read, merge, write), so concurrent publishers may lose
+//! an update until one of them re-publishes. Searching looks up the record
+//! of every query keyword and ranks content keys by the number of keywords
+//! they matched.
+//!
+//! A keyword record holds at most [`MAX_RECORD_SIZE`] bytes. Once a keyword
+//! is full, indexing further keys under it fails with [`KeywordFull`].
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// Maximum size of an encoded keyword record. Kademlia messages are limited
+/// to 16 KiB, the remainder is left for the record key and message framing.
+pub const MAX_RECORD_SIZE: usize = 14 * 1024;
+
+const MIN_KEYWORD_LEN: usize = 2;
+
+const STOP_WORDS: &[&str] = &[
+  "a", "an", "and", "are", "as", "at", "be", "by", "for", "from", "in", "is", "it", "of", "on",
+  "or", "the", "to", "with",
+];
+
+/// A content key matching a search query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+  pub key: String,
+  /// Query keywords the key is indexed under.
+  pub matched: Vec<String>,
+}
+
+/// Lower-cased alphanumeric words of `text`, without stop words and words
+/// shorter than two characters.
+pub fn tokenize(text: &str) -> BTreeSet<String> {
+  text
+    .split(|c: char| !c.is_alphanumeric())
+    .map(str::to_lowercase)
+    .filter(|word| word.chars().count() >= MIN_KEYWORD_LEN && !STOP_WORDS.contains(&word.as_str()))
+    .collect()
+}
+
+/// Keywords to index a content key under: the words of the key itself and
+/// of its metadata, e.g. a description.
+pub fn keywords(key: &str, metadata: &str) -> BTreeSet<String> {
+  let mut keywords = tokenize(key);
+  keywords.extend(tokenize(metadata));
+  keywords
+}
+
+/// DHT record key of the given keyword.
+pub fn record_key(keyword: &str) -> String {
+  format!("/kw/{}", keyword)
+}
+
+/// Decode a keyword record, treating malformed values as empty.
+pub fn decode_keys(value: &[u8]) -> BTreeSet<String> {
+  serde_json::from_slice(value).unwrap_or_default()
+}
+
+/// Encode a keyword record, failing if it exceeds [`MAX_RECORD_SIZE`].
+pub fn encode_keys(keyword: &str, keys: &BTreeSet<String>) -> Result<Vec<u8>, KeywordFull> {
+  let value = serde_json::to_vec(keys).expect("Serializing strings not to fail.");
+  if value.len() > MAX_RECORD_SIZE {
+    return Err(KeywordFull {
+      keyword: keyword.to_string(),
+    });
+  }
+  Ok(value)
+}
+
+/// The record of a keyword has no room for another key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeywordFull {
+  pub keyword: String,
+}
+
+impl fmt::Display for KeywordFull {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "keyword {:?} is full, its record would exceed {} bytes",
+      self.keyword, MAX_RECORD_SIZE
+    )
+  }
+}
+
+impl std::error::Error for KeywordFull {}
+
+/// Rank the content keys found for each keyword of `query`: keys matching
+/// more keywords first, then keys containing the whole query, then by key.
+pub fn rank(query: &str, lookups: Vec<(String, BTreeSet<String>)>) -> Vec<SearchHit> {
+  let mut matches: HashMap<String, Vec<String>> = HashMap::new();
+  for (keyword, keys) in lookups {
+    for key in keys {
+      matches.entry(key).or_default().push(keyword.clone());
+    }
+  }
+
+  let query = query.to_lowercase();
+  let mut hits: Vec<SearchHit> = matches
+    .into_iter()
+    .map(|(key, mut matched)| {
+      matched.sort();
+      SearchHit { key, matched }
+    })
+    .collect();
+  hits.sort_by(|a, b| {
+    b.matched
+      .len()
+      .cmp(&a.matched.len())
+      .then_with(|| {
+        let a_contains = a.key.to_lowercase().contains(&query);
+        let b_contains = b.key.to_lowercase().contains(&query);
+        b_contains.cmp(&a_contains)
+      })
+      .then_with(|| a.key.cmp(&b.key))
+  });
+  hits
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn set(words: &[&str]) -> BTreeSet<String> {
+    words.iter().map(|w| w.to_string()).collect()
+  }
+
+  #[test]
+  fn tokenize_drops_stop_words_and_short_words() {
+    assert_eq!(
+      tokenize("The Rust-Book, 2nd edition: a guide to Rust"),
+      set(&["2nd", "book", "edition", "guide", "rust"])
+    );
+    assert_eq!(tokenize("a b-c"), BTreeSet::new());
+    assert_eq!(tokenize("Überblick"), set(&["überblick"]));
+  }
+
+  #[test]
+  fn keywords_of_key_and_metadata() {
+    assert_eq!(
+      keywords("notes/rust.md", "Notes on ownership"),
+      set(&["md", "notes", "ownership", "rust"])
+    );
+  }
+
+  #[test]
+  fn record_roundtrip() {
+    let keys = set(&["a.txt", "b.txt"]);
+    assert_eq!(decode_keys(&encode_keys("txt", &keys).unwrap()), keys);
+    assert_eq!(decode_keys(b"not json"), BTreeSet::new());
+  }
+
+  #[test]
+  fn encode_keys_rejects_full_record() {
+    let keys = (0..MAX_RECORD_SIZE / 8)
+      .map(|i| format!("key-{:04}", i))
+      .collect();
+    assert_eq!(
+      encode_keys("key", &keys),
+      Err(KeywordFull {
+        keyword: "key".to_string()
+      })
+    );
+  }
+
+  #[test]
+  fn rank_by_matched_keywords_then_query_then_key() {
+    let hits = rank(
+      "rust book",
+      vec![
+        ("rust".to_string(), set(&["b-rust", "rust-book", "a-rust"])),
+        ("book".to_string(), set(&["cook-book", "rust-book"])),
+      ],
+    );
+    let keys = hits.iter().map(|hit| hit.key.as_str()).collect::<Vec<_>>();
+    assert_eq!(keys, ["rust-book", "a-rust", "b-rust", "cook-book"]);
+    assert_eq!(hits[0].matched, ["book", "rust"]);
+
+    let hits = rank(
+      "b-rust",
+      vec![("rust".to_string(), set(&["a-rust", "b-rust"]))],
+    );
+    assert_eq!(hits[0].key, "b-rust");
+  }
+}
@@ -0,0 +1,674 @@
+//! Multi-node test harness, available with the `test-support` feature.
+//!
+//! [`TestNetwork::start`] runs a number of [`network`] nodes in the current
+//! process over the in-memory transport, connects them into one DHT and hands
+//! back their [`Client`]s. The `assert_*` methods exercise the common flows
+//! end to end and panic with a descriptive message when a flow fails.
+//!
+//! ```no_run
+//! # use libp2p_demo::testing::TestNetwork;
+//! # async_std::task::block_on(async {
+//! let mut net = TestNetwork::start(5).await;
+//! net.assert_file_exchange(0, 4, "report.pdf", b"content").await;
+//! net.assert_record_roundtrip(1, 3, "key", b"value").await;
+//! net.assert_gossip_propagates(2, "topic", b"hello").await;
+//! net.assert_block_exchange(3, 0, b"block").await;
+//! net.assert_dag_resolves(4, 1).await;
+//! net.assert_crawl_finds_all(0).await;
+//! # });
+//! ```
+use crate::crawler::{self, CrawlerConfig};
+use crate::ipld::{self, Ipld, DAG_CBOR, DAG_JSON};
+use crate::kadevents::KadOutcome;
+use crate::kadmode::KademliaMode;
+use crate::lookup::{Lookup, LookupEvent, LookupStatus};
+use crate::network::{self, Client, Event};
+use crate::transport::TransportConfig;
+use async_std::future::timeout;
+use async_std::task::{sleep, spawn};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::multiaddr::Protocol;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bound for every flow exercised by an assertion.
+pub const FLOW_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time given to identify and Kademlia to settle before asserting that a
+/// peer stays out of a routing table.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+static NEXT_MEMORY_PORT: AtomicU64 = AtomicU64::new(1);
+
+/// A node of a [`TestNetwork`].
+pub struct TestNode {
+  pub peer_id: PeerId,
+  pub addr: Multiaddr,
+  pub client: Client,
+  /// Events of the node. They are buffered without bound so that nodes whose
+  /// events are not looked at never stall their event loop.
+  pub events: mpsc::UnboundedReceiver<Event>,
+}
+
+/// A set of in-memory nodes forming one DHT.
+pub struct TestNetwork {
+  pub nodes: Vec<TestNode>,
+}
+
+impl TestNetwork {
+  /// Start `n` nodes with the default configuration, running as DHT
+  /// servers: AutoNAT never reports in-memory nodes as public.
+  pub async fn start(n: usize) -> Self {
+    let mut config = network::Config::default();
+    config.set_kademlia_mode(KademliaMode::Server);
+    Self::start_with_config(n, config).await
+  }
+
+  /// Start `n` nodes with the given configuration.
+  ///
+  /// The nodes always use the in-memory transport: TCP and WebSocket are
+  /// turned off in the configured [`TransportConfig`], whose multiplexer and
+  /// timeout settings still apply. WebSocket certificates are unused.
+  ///
+  /// The nodes are connected as a chain, node `i` dialing node `i - 1` and
+  /// node 0 dialing node 1. Once every node has its neighbours in its
+  /// routing table, each runs a Kademlia bootstrap, so that lookups have to
+  /// traverse several hops.
+  pub async fn start_with_config(n: usize, config: network::Config) -> Self {
+    let mut nodes: Vec<TestNode> = Vec::with_capacity(n);
+
+    for _ in 0..n {
+      let mut node = start_node(config.clone()).await;
+      if let Some(previous) = nodes.last() {
+        node
+          .client
+          .dial(previous.peer_id, previous.addr.clone())
+          .await
+          .expect("Dial to succeed.");
+      }
+      nodes.push(node);
+    }
+
+    if n > 1 {
+      // Node 0 only has an inbound connection to node 1, which does not put
+      // node 1 into its routing table. Dial back so its bootstrap has a peer
+      // to start from.
+      let (first, rest) = nodes.split_at_mut(1);
+      first[0]
+        .client
+        .dial(rest[0].peer_id, rest[0].addr.clone())
+        .await
+        .expect("Dial to succeed.");
+
+      // Peers that dialed a node are only routable once identify reported
+      // their listen addresses.
+      for index in 0..n {
+        let neighbours = [index.checked_sub(1), Some(index + 1).filter(|&i| i < n)]
+          .into_iter()
+          .flatten()
+          .map(|i| nodes[i].peer_id)
+          .collect::<Vec<_>>();
+        let mut client = nodes[index].client.clone();
+        timeout(FLOW_TIMEOUT, wait_routable(&mut client, &neighbours))
+          .await
+          .unwrap_or_else(|_| panic!("Neighbours of node {} did not become routable.", index));
+      }
+
+      for node in &mut nodes {
+        node
+          .client
+          .bootstrap()
+          .await
+          .expect("Bootstrap to succeed.");
+      }
+    }
+
+    Self { nodes }
+  }
+
+  /// Start one more node with the given configuration, connected to node
+  /// `neighbour` in both directions, and return its index. Unlike the nodes
+  /// started with the network it does not bootstrap.
+  pub async fn join(&mut self, config: network::Config, neighbour: usize) -> usize {
+    let mut node = start_node(config).await;
+    let neighbour = &mut self.nodes[neighbour];
+    node
+      .client
+      .dial(neighbour.peer_id, neighbour.addr.clone())
+      .await
+      .expect("Dial to succeed.");
+    neighbour
+      .client
+      .dial(node.peer_id, node.addr.clone())
+      .await
+      .expect("Dial to succeed.");
+    self.nodes.push(node);
+    self.nodes.len() - 1
+  }
+
+  /// Client of the node at `index`.
+  pub fn client(&self, index: usize) -> Client {
+    self.nodes[index].client.clone()
+  }
+
+  /// Node `provider` provides `name` serving `content`, node `requester`
+  /// finds it via `get_providers` and fetches it via `request_file`.
+  pub async fn assert_file_exchange(
+    &mut self,
+    provider: usize,
+    requester: usize,
+    name: &str,
+    content: &[u8],
+  ) {
+    let provider_id = self.nodes[provider].peer_id;
+    let mut provider_client = self.client(provider);
+    let mut requester_client = self.client(requester);
+
+    provider_client.start_providing(name.to_string()).await;
+
+    let fetch = async move {
+      let providers = requester_client.get_providers(name.to_string()).await;
+      assert!(
+        providers.contains(&provider_id),
+        "Expected {} among the providers of {:?}, got {:?}.",
+        provider_id,
+        name,
+        providers
+      );
+      requester_client
+        .request_file(provider_id, name.to_string())
+        .await
+    };
+
+    let events = &mut self.nodes[provider].events;
+    let serve = async move {
+      while let Some(event) = events.next().await {
+        if let Event::InboundRequest {
+          request, channel, ..
+        } = event
+        {
+          if request == name {
+            provider_client
+              .respond_file(content.to_vec(), channel)
+              .await;
+          }
+        }
+      }
+    };
+
+    futures::pin_mut!(fetch);
+    futures::pin_mut!(serve);
+    let result = match timeout(FLOW_TIMEOUT, future::select(fetch, serve)).await {
+      Ok(Either::Left((result, _))) => result,
+      Ok(Either::Right(((), _))) => panic!("Event stream of node {} ended.", provider),
+      Err(_) => panic!("Exchanging file {:?} timed out.", name),
+    };
+
+    match result {
+      Ok(received) => assert_eq!(received, content, "Received wrong content for {:?}.", name),
+      Err(e) => panic!("Requesting file {:?} failed: {:?}", name, e),
+    }
+  }
+
+  /// Node `writer` puts a record, node `reader` gets the same value back.
+  pub async fn assert_record_roundtrip(
+    &mut self,
+    writer: usize,
+    reader: usize,
+    key: &str,
+    value: &[u8],
+  ) {
+    let mut writer = self.client(writer);
+    let mut reader = self.client(reader);
+
+    let flow = async move {
+      writer
+        .put_record(key.to_string(), value.to_vec())
+        .await
+        .expect("Putting record to succeed.");
+      reader
+        .get_record(key.to_string())
+        .await
+        .expect("Getting record to succeed.")
+    };
+
+    let received = timeout(FLOW_TIMEOUT, flow)
+      .await
+      .unwrap_or_else(|_| panic!("Record roundtrip of {:?} timed out.", key));
+    assert_eq!(received, value, "Got wrong value for record {:?}.", key);
+  }
+
+  /// Node `requester` looks up the providers of a file node `provider`
+  /// provides and of a file nobody provides. The lookups report the provider
+  /// and then finish as complete, respectively finish as not found.
+  pub async fn assert_provider_lookup(&mut self, provider: usize, requester: usize) {
+    let provider_id = self.nodes[provider].peer_id;
+    let mut provider = self.client(provider);
+    let mut requester = self.client(requester);
+    let name = format!("provided-by-{}", provider_id);
+
+    timeout(FLOW_TIMEOUT, provider.start_providing(name.clone()))
+      .await
+      .expect("Providing to finish in time.");
+    let events = lookup_events(requester.get_providers_stream(name)).await;
+    assert_eq!(
+      events,
+      vec![
+        LookupEvent::Found(provider_id),
+        LookupEvent::Finished(LookupStatus::Complete)
+      ]
+    );
+
+    let events = lookup_events(requester.get_providers_stream("unprovided".to_string())).await;
+    assert_eq!(events, vec![LookupEvent::Finished(LookupStatus::NotFound)]);
+  }
+
+  /// Node `reader` looks up a record node `writer` put and a record nobody
+  /// put. The lookups report the record and then finish as complete,
+  /// respectively finish as not found.
+  pub async fn assert_record_lookup(&mut self, writer: usize, reader: usize) {
+    let mut writer = self.client(writer);
+    let mut reader = self.client(reader);
+    let key = format!("written-by-{}", writer.local_peer_id());
+
+    timeout(
+      FLOW_TIMEOUT,
+      writer.put_record(key.clone(), b"value".to_vec()),
+    )
+    .await
+    .expect("Putting record to finish in time.")
+    .expect("Putting record to succeed.");
+    let mut events = lookup_events(reader.get_record_stream(key)).await;
+    assert_eq!(
+      events.pop(),
+      Some(LookupEvent::Finished(LookupStatus::Complete))
+    );
+    assert!(!events.is_empty(), "The record was not reported.");
+    for event in events {
+      match event {
+        LookupEvent::Found(record) => assert_eq!(record.record.value, b"value"),
+        event => panic!("Unexpected event {:?} before the end.", event),
+      }
+    }
+
+    let events = lookup_events(reader.get_record_stream("unwritten".to_string())).await;
+    assert_eq!(events, vec![LookupEvent::Finished(LookupStatus::NotFound)]);
+  }
+
+  /// A node joining via node `neighbour`, whose DHT queries give up right
+  /// away, reports lookups as timed out. A provider lookup still reports the
+  /// node itself, stored locally.
+  pub async fn assert_lookups_time_out(&mut self, neighbour: usize) {
+    let mut config = network::Config::default();
+    config
+      .set_kademlia_mode(KademliaMode::Server)
+      .set_kademlia_query_timeout(Duration::ZERO);
+    let index = self.join(config, neighbour).await;
+    let neighbour = self.nodes[neighbour].peer_id;
+    let peer_id = self.nodes[index].peer_id;
+    let mut client = self.client(index);
+    timeout(FLOW_TIMEOUT, wait_routable(&mut client, &[neighbour]))
+      .await
+      .expect("Neighbour to become routable.");
+
+    let name = format!("provided-by-{}", peer_id);
+    timeout(FLOW_TIMEOUT, client.start_providing(name.clone()))
+      .await
+      .expect("Providing to finish in time.");
+    let events = lookup_events(client.get_providers_stream(name)).await;
+    assert_eq!(
+      events,
+      vec![
+        LookupEvent::Found(peer_id),
+        LookupEvent::Finished(LookupStatus::Timeout)
+      ]
+    );
+
+    let events = lookup_events(client.get_record_stream("unwritten".to_string())).await;
+    assert_eq!(events, vec![LookupEvent::Finished(LookupStatus::Timeout)]);
+  }
+
+  /// Lookups of node `index` whose stream is dropped stop their Kademlia
+  /// query on the next housekeeping tick.
+  ///
+  /// The lookups go via an extra neighbour that stops answering, so that
+  /// they do not complete on their own before.
+  pub async fn assert_dropped_lookups_finish(&mut self, index: usize) {
+    let mut client = self.client(index);
+    let mut config = network::Config::default();
+    config.set_kademlia_mode(KademliaMode::Server);
+    let (mut stalled, events, addr) = launch(config).await;
+    let stalled_id = stalled.local_peer_id();
+    stalled
+      .dial(self.nodes[index].peer_id, self.nodes[index].addr.clone())
+      .await
+      .expect("Dial to succeed.");
+    client
+      .dial(stalled_id, addr)
+      .await
+      .expect("Dial to succeed.");
+    timeout(FLOW_TIMEOUT, wait_routable(&mut client, &[stalled_id]))
+      .await
+      .expect("Stalled neighbour to become routable.");
+
+    // Nobody takes the inbound request events of the stalled node, its event
+    // loop blocks on reporting the second one.
+    for _ in 0..2 {
+      let mut client = client.clone();
+      spawn(async move { client.request_file(stalled_id, "stall".to_string()).await });
+    }
+    while timeout(Duration::from_millis(100), stalled.routing_table())
+      .await
+      .is_ok()
+    {}
+
+    drop(client.get_providers_stream("unprovided".to_string()).await);
+    drop(client.get_record_stream("unwritten".to_string()).await);
+
+    sleep(SETTLE_TIME).await;
+    assert_eq!(
+      client.running_queries().await,
+      0,
+      "Node {} still runs queries of dropped lookups.",
+      index
+    );
+    spawn(events.for_each(|_| future::ready(())));
+  }
+
+  /// All nodes subscribe to `topic`, node `publisher` publishes `data` and
+  /// every other node receives it.
+  pub async fn assert_gossip_propagates(&mut self, publisher: usize, topic: &str, data: &[u8]) {
+    for node in &mut self.nodes {
+      node
+        .client
+        .subscribe(topic.to_string())
+        .await
+        .expect("Subscribing to succeed.");
+    }
+
+    // Subscriptions have to reach the neighbours before publishing succeeds.
+    let mut publisher_client = self.client(publisher);
+    let publish = async move {
+      while publisher_client
+        .publish(topic.to_string(), data.to_vec())
+        .await
+        .is_err()
+      {
+        sleep(Duration::from_millis(100)).await;
+      }
+    };
+    timeout(FLOW_TIMEOUT, publish)
+      .await
+      .unwrap_or_else(|_| panic!("Publishing on {:?} timed out.", topic));
+
+    for (index, node) in self.nodes.iter_mut().enumerate() {
+      if index == publisher {
+        continue;
+      }
+      let events = &mut node.events;
+      let receive = async move {
+        while let Some(event) = events.next().await {
+          if let Event::GossipMessage {
+            topic: t, data: d, ..
+          } = event
+          {
+            if t == topic && d == data {
+              return;
+            }
+          }
+        }
+        panic!("Event stream of node {} ended.", index);
+      };
+      timeout(FLOW_TIMEOUT, receive)
+        .await
+        .unwrap_or_else(|_| panic!("Node {} did not receive gossip on {:?}.", index, topic));
+    }
+  }
+
+  /// Node `provider` stores a block, node `requester` wants it and gets it
+  /// via the block exchange, both ledgers accounting for it.
+  pub async fn assert_block_exchange(&mut self, provider: usize, requester: usize, data: &[u8]) {
+    let provider_id = self.nodes[provider].peer_id;
+    let requester_id = self.nodes[requester].peer_id;
+    let mut provider_client = self.client(provider);
+    let mut requester_client = self.client(requester);
+
+    // The block exchange runs between connected peers only.
+    let addr = self.nodes[provider].addr.clone();
+    requester_client
+      .dial(provider_id, addr)
+      .await
+      .expect("Dial to succeed.");
+
+    let cid = provider_client.put_block(data.to_vec()).await;
+    let received = timeout(FLOW_TIMEOUT, requester_client.get_block(cid))
+      .await
+      .unwrap_or_else(|_| panic!("Getting block {} timed out.", cid))
+      .unwrap_or_else(|| panic!("Want for block {} was cancelled.", cid));
+    assert_eq!(received, data, "Received wrong content for block {}.", cid);
+
+    let sent = provider_client.ledger(requester_id).await;
+    assert!(
+      sent.bytes_sent >= data.len() as u64,
+      "Ledger of node {} misses the block sent to node {}: {:?}",
+      provider,
+      requester,
+      sent
+    );
+    assert!(
+      requester_client.wantlist(None).await.is_empty(),
+      "Node {} still wants block {} after receiving it.",
+      requester,
+      cid
+    );
+  }
+
+  /// A dag-json block linking to a dag-cbor block, both published by node
+  /// `publisher`, resolves by path on node `resolver`.
+  pub async fn assert_dag_resolves(&mut self, publisher: usize, resolver: usize) {
+    let publisher_id = self.nodes[publisher].peer_id;
+    let mut publisher_client = self.client(publisher);
+    let mut resolver_client = self.client(resolver);
+
+    let addr = self.nodes[publisher].addr.clone();
+    resolver_client
+      .dial(publisher_id, addr)
+      .await
+      .expect("Dial to succeed.");
+
+    let leaf = Ipld::Map(BTreeMap::from([(
+      "values".to_string(),
+      Ipld::List(vec![Ipld::Integer(-1), Ipld::String("leaf".to_string())]),
+    )]));
+    let leaf_cid = ipld::put(&mut publisher_client, DAG_CBOR, &leaf)
+      .await
+      .expect("Leaf to be encodable.");
+    let root = Ipld::Map(BTreeMap::from([(
+      "child".to_string(),
+      Ipld::Link(leaf_cid),
+    )]));
+    let root_cid = ipld::put(&mut publisher_client, DAG_JSON, &root)
+      .await
+      .expect("Root to be encodable.");
+
+    let path = format!("{}/child/values/1", root_cid);
+    let resolved = ipld::resolve(&mut resolver_client, &path, FLOW_TIMEOUT)
+      .await
+      .unwrap_or_else(|e| panic!("Node {} failed to resolve {}: {}", resolver, path, e));
+    assert_eq!(
+      resolved,
+      Ipld::String("leaf".to_string()),
+      "Node {} resolved {} to the wrong value.",
+      resolver,
+      path
+    );
+  }
+
+  /// A bootstrap of node `index` is reported step by step on its
+  /// [`Client::kad_outcomes`] stream, up to the step refreshing the last
+  /// bucket.
+  pub async fn assert_bootstrap_reported(&mut self, index: usize) {
+    let mut client = self.client(index);
+    let mut outcomes = client.kad_outcomes().await;
+    timeout(FLOW_TIMEOUT, client.bootstrap())
+      .await
+      .expect("Bootstrap to finish in time.")
+      .expect("Bootstrap to succeed.");
+
+    // Outcomes are sent before the bootstrap result, so all of them are
+    // buffered by now.
+    let mut steps = Vec::new();
+    while let Ok(outcome) = outcomes.try_recv() {
+      match outcome {
+        KadOutcome::BootstrapProgress {
+          id, num_remaining, ..
+        } => steps.push((id, num_remaining)),
+        KadOutcome::QueryCompleted { result, .. } if outcome.is_error() => {
+          panic!("Node {} reported a failed query: {:?}", index, result)
+        }
+        _ => {}
+      }
+    }
+    assert!(
+      steps.windows(2).all(|w| w[0].0 == w[1].0),
+      "Node {} reported steps of several bootstraps: {:?}",
+      index,
+      steps
+    );
+    assert_eq!(
+      steps.last().map(|(_, remaining)| *remaining),
+      Some(0),
+      "Node {} did not report the last bootstrap step.",
+      index
+    );
+  }
+
+  /// Node `index` uses the DHT via node `neighbour`, but as a DHT client
+  /// never shows up in the routing table of any other node.
+  pub async fn assert_dht_client(&mut self, index: usize, neighbour: usize) {
+    let neighbour = self.nodes[neighbour].peer_id;
+    let mut client = self.client(index);
+    timeout(FLOW_TIMEOUT, wait_routable(&mut client, &[neighbour]))
+      .await
+      .unwrap_or_else(|_| panic!("Node {} did not learn about its neighbour.", index));
+    timeout(FLOW_TIMEOUT, client.bootstrap())
+      .await
+      .expect("Bootstrap to finish in time.")
+      .expect("Bootstrap to succeed.");
+
+    sleep(SETTLE_TIME).await;
+    let peer_id = self.nodes[index].peer_id;
+    for (other, node) in self.nodes.iter_mut().enumerate() {
+      assert!(
+        !node.client.routing_table().await.contains(&peer_id),
+        "Client node {} is in the routing table of node {}.",
+        index,
+        other
+      );
+    }
+  }
+
+  /// Nodes `a` and `b` are connected but never end up in each other's
+  /// routing table, e.g. because they use different Kademlia protocols.
+  pub async fn assert_separate_dhts(&mut self, a: usize, b: usize) {
+    sleep(SETTLE_TIME).await;
+    for (node, other) in [(a, b), (b, a)] {
+      let peer_id = self.nodes[other].peer_id;
+      assert!(
+        !self.nodes[node]
+          .client
+          .routing_table()
+          .await
+          .contains(&peer_id),
+        "Node {} is in the routing table of node {}.",
+        other,
+        node
+      );
+    }
+  }
+
+  /// A crawler bootstrapped from node `bootstrap` finds every node of the
+  /// network, all of them reachable.
+  pub async fn assert_crawl_finds_all(&self, bootstrap: usize) {
+    let mut transport = TransportConfig::default();
+    transport.set_memory(true);
+    let node = &self.nodes[bootstrap];
+    let mut config = CrawlerConfig::default();
+    config
+      .set_transport(transport)
+      .add_bootstrap(node.addr.clone().with(Protocol::P2p(node.peer_id.into())))
+      .set_idle_rounds(2)
+      .set_query_timeout(Duration::from_secs(5))
+      .set_identify_grace(Duration::from_secs(1));
+
+    let snapshot = timeout(FLOW_TIMEOUT, crawler::crawl(config))
+      .await
+      .expect("Crawl to finish in time.")
+      .expect("Crawl to succeed.");
+
+    for (index, node) in self.nodes.iter().enumerate() {
+      match snapshot.peers.get(&node.peer_id) {
+        Some(peer) => assert!(peer.reachable, "Crawler did not reach node {}.", index),
+        None => panic!("Crawler did not find node {}.", index),
+      }
+    }
+  }
+}
+
+/// All events of `lookup`, which has to end in time.
+async fn lookup_events<T>(lookup: impl Future<Output = Lookup<T>>) -> Vec<LookupEvent<T>> {
+  timeout(FLOW_TIMEOUT, async { lookup.await.collect().await })
+    .await
+    .expect("Lookup to end in time.")
+}
+
+/// Start a node listening on a fresh in-memory address.
+async fn start_node(config: network::Config) -> TestNode {
+  let (client, events, addr) = launch(config).await;
+
+  let (event_sender, event_receiver) = mpsc::unbounded();
+  spawn(events.map(Ok).forward(event_sender));
+
+  TestNode {
+    peer_id: client.local_peer_id(),
+    addr,
+    client,
+    events: event_receiver,
+  }
+}
+
+/// Run a node listening on a fresh in-memory address, handing out its
+/// events as they come from the network.
+async fn launch(mut config: network::Config) -> (Client, impl Stream<Item = Event>, Multiaddr) {
+  config.transport_mut().set_memory(true);
+
+  let (mut client, events, event_loop) = network::new(None, config)
+    .await
+    .expect("Network to be created.");
+  spawn(event_loop.run());
+
+  let addr = Multiaddr::empty().with(Protocol::Memory(
+    NEXT_MEMORY_PORT.fetch_add(1, Ordering::Relaxed),
+  ));
+  client
+    .start_listening(addr.clone())
+    .await
+    .expect("Listening not to fail.");
+
+  (client, events, addr)
+}
+
+/// Wait until all of `peers` are in the routing table of `client`.
+async fn wait_routable(client: &mut Client, peers: &[PeerId]) {
+  loop {
+    let table = client.routing_table().await;
+    if peers.iter().all(|peer| table.contains(peer)) {
+      return;
+    }
+    sleep(Duration::from_millis(50)).await;
+  }
+}
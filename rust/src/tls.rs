@@ -0,0 +1,670 @@
+//! TLS 1.3 security for libp2p connections, following the libp2p TLS spec
+//! (<https://github.com/libp2p/specs/blob/master/tls/tls.md>).
+//!
+//! Each side presents a self-signed certificate carrying the libp2p public
+//! key extension: the host's public key and its signature over the
+//! certificate key. Peers are authenticated by that extension instead of a
+//! certificate authority, the resulting peer ID is what the upgrade yields.
+//!
+//! Adapted from `libp2p-tls` 0.1, which requires a newer `libp2p-core` than
+//! the one this crate is built on.
+use futures::future::BoxFuture;
+use futures::{AsyncRead, AsyncWrite, FutureExt};
+use libp2p::core::{identity, InboundUpgrade, OutboundUpgrade, PeerId, UpgradeInfo};
+use rustls::cipher_suite::{
+  TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256,
+};
+use rustls::client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::internal::msgs::handshake::DigitallySignedStruct;
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+  Certificate, CommonState, DistinguishedNames, ServerName, SignatureScheme, SupportedCipherSuite,
+  SupportedProtocolVersion,
+};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::SystemTime;
+use x509_parser::prelude::*;
+use x509_parser::signature_algorithm::SignatureAlgorithm;
+
+pub use futures_rustls::TlsStream;
+
+/// Protocol name negotiated by multistream-select.
+pub const PROTOCOL_NAME: &[u8] = b"/tls/1.0.0";
+
+/// ALPN protocol, mandated by the spec for every libp2p TLS handshake.
+pub const P2P_ALPN: &[u8] = b"libp2p";
+
+/// Object identifier of the libp2p public key extension, allocated by IANA
+/// to the libp2p project.
+const P2P_EXT_OID: [u64; 9] = [1, 3, 6, 1, 4, 1, 53594, 1, 1];
+
+/// Prefix of the message signed with the host key, followed by the public
+/// key of the certificate.
+const P2P_SIGNING_PREFIX: &[u8] = b"libp2p-tls-handshake:";
+
+/// Certificate keys use the NamedCurve encoding, hashes are at least 256
+/// bits long as required by the spec.
+static P2P_SIGNATURE_ALGORITHM: &rcgen::SignatureAlgorithm = &rcgen::PKCS_ECDSA_P256_SHA256;
+
+/// Lower TLS versions must not be negotiated.
+static PROTOCOL_VERSIONS: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+static CIPHERSUITES: &[SupportedCipherSuite] = &[
+  TLS13_CHACHA20_POLY1305_SHA256,
+  TLS13_AES_256_GCM_SHA384,
+  TLS13_AES_128_GCM_SHA256,
+];
+
+/// Security upgrade securing a connection with TLS 1.3, for use with
+/// `authenticate` on a transport.
+#[derive(Clone)]
+pub struct TlsConfig {
+  server: Arc<rustls::ServerConfig>,
+  client: Arc<rustls::ClientConfig>,
+}
+
+impl fmt::Debug for TlsConfig {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("TlsConfig").finish_non_exhaustive()
+  }
+}
+
+impl TlsConfig {
+  /// Configuration presenting a fresh certificate signed with `keypair`.
+  pub fn new(keypair: &identity::Keypair) -> Result<Self, CertificateError> {
+    Ok(Self {
+      server: Arc::new(server_config(keypair)?),
+      client: Arc::new(client_config(keypair, None)?),
+    })
+  }
+}
+
+impl UpgradeInfo for TlsConfig {
+  type Info = &'static [u8];
+  type InfoIter = std::iter::Once<Self::Info>;
+
+  fn protocol_info(&self) -> Self::InfoIter {
+    std::iter::once(PROTOCOL_NAME)
+  }
+}
+
+impl<C> InboundUpgrade<C> for TlsConfig
+where
+  C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+  type Output = (PeerId, TlsStream<C>);
+  type Error = UpgradeError;
+  type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+  fn upgrade_inbound(self, socket: C, _: Self::Info) -> Self::Future {
+    async move {
+      let stream = futures_rustls::TlsAcceptor::from(self.server)
+        .accept(socket)
+        .await
+        .map_err(UpgradeError::Handshake)?;
+      let peer_id = remote_peer_id(stream.get_ref().1)?;
+      Ok((peer_id, stream.into()))
+    }
+    .boxed()
+  }
+}
+
+impl<C> OutboundUpgrade<C> for TlsConfig
+where
+  C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+  type Output = (PeerId, TlsStream<C>);
+  type Error = UpgradeError;
+  type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+  fn upgrade_outbound(self, socket: C, _: Self::Info) -> Self::Future {
+    async move {
+      // Clients must not send a server name indication, an unspecified IP
+      // address disables the extension.
+      let name = ServerName::IpAddress(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+      let stream = futures_rustls::TlsConnector::from(self.client)
+        .connect(name, socket)
+        .await
+        .map_err(UpgradeError::Handshake)?;
+      let peer_id = remote_peer_id(stream.get_ref().1)?;
+      Ok((peer_id, stream.into()))
+    }
+    .boxed()
+  }
+}
+
+/// Peer ID of the single certificate the verifiers let through.
+fn remote_peer_id(state: &CommonState) -> Result<PeerId, UpgradeError> {
+  match state.peer_certificates() {
+    Some([certificate]) => Ok(parse(certificate)?.peer_id()),
+    _ => Err(UpgradeError::Certificate(webpki::Error::BadDer)),
+  }
+}
+
+/// Error of the TLS upgrade.
+#[derive(Debug)]
+pub enum UpgradeError {
+  /// The handshake failed, e.g. the remote certificate was rejected.
+  Handshake(io::Error),
+  /// The remote certificate is not a valid libp2p certificate.
+  Certificate(webpki::Error),
+}
+
+impl fmt::Display for UpgradeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      UpgradeError::Handshake(e) => write!(f, "TLS handshake failed: {}", e),
+      UpgradeError::Certificate(e) => write!(f, "invalid peer certificate: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for UpgradeError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      UpgradeError::Handshake(e) => Some(e),
+      UpgradeError::Certificate(_) => None,
+    }
+  }
+}
+
+impl From<webpki::Error> for UpgradeError {
+  fn from(e: webpki::Error) -> Self {
+    UpgradeError::Certificate(e)
+  }
+}
+
+/// Failure to generate the local certificate.
+#[derive(Debug)]
+pub struct CertificateError(rcgen::RcgenError);
+
+impl fmt::Display for CertificateError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "failed to generate TLS certificate: {}", self.0)
+  }
+}
+
+impl std::error::Error for CertificateError {}
+
+impl From<rcgen::RcgenError> for CertificateError {
+  fn from(e: rcgen::RcgenError) -> Self {
+    CertificateError(e)
+  }
+}
+
+/// rustls client configuration for libp2p, checking the remote peer ID
+/// against `remote_peer_id` if given.
+pub fn client_config(
+  keypair: &identity::Keypair,
+  remote_peer_id: Option<PeerId>,
+) -> Result<rustls::ClientConfig, CertificateError> {
+  let (certificate, private_key) = generate(keypair)?;
+  let mut config = rustls::ClientConfig::builder()
+    .with_cipher_suites(CIPHERSUITES)
+    .with_safe_default_kx_groups()
+    .with_protocol_versions(PROTOCOL_VERSIONS)
+    .expect("Cipher suites and key exchange groups to be compatible.")
+    .with_custom_certificate_verifier(Arc::new(Verifier { remote_peer_id }))
+    .with_single_cert(vec![certificate], private_key)
+    .expect("Generated certificate to be valid.");
+  config.alpn_protocols = vec![P2P_ALPN.to_vec()];
+  Ok(config)
+}
+
+/// rustls server configuration for libp2p, requiring a client certificate.
+pub fn server_config(
+  keypair: &identity::Keypair,
+) -> Result<rustls::ServerConfig, CertificateError> {
+  let (certificate, private_key) = generate(keypair)?;
+  let mut config = rustls::ServerConfig::builder()
+    .with_cipher_suites(CIPHERSUITES)
+    .with_safe_default_kx_groups()
+    .with_protocol_versions(PROTOCOL_VERSIONS)
+    .expect("Cipher suites and key exchange groups to be compatible.")
+    .with_client_cert_verifier(Arc::new(Verifier {
+      remote_peer_id: None,
+    }))
+    .with_single_cert(vec![certificate], private_key)
+    .expect("Generated certificate to be valid.");
+  config.alpn_protocols = vec![P2P_ALPN.to_vec()];
+  Ok(config)
+}
+
+/// Generate a self-signed certificate carrying the libp2p extension signed
+/// with `keypair`. The certificate key itself is fresh and unrelated to the
+/// host key.
+pub fn generate(
+  keypair: &identity::Keypair,
+) -> Result<(rustls::Certificate, rustls::PrivateKey), rcgen::RcgenError> {
+  let certificate_keypair = rcgen::KeyPair::generate(P2P_SIGNATURE_ALGORITHM)?;
+  let private_key = rustls::PrivateKey(certificate_keypair.serialize_der());
+
+  let mut msg = P2P_SIGNING_PREFIX.to_vec();
+  msg.extend(certificate_keypair.public_key_der());
+  let signature = keypair
+    .sign(&msg)
+    .map_err(|_| rcgen::RcgenError::RingUnspecified)?;
+  // SignedKey ::= SEQUENCE { publicKey OCTET STRING, signature OCTET STRING }
+  let content = yasna::encode_der(&(keypair.public().to_protobuf_encoding(), signature));
+  let mut extension = rcgen::CustomExtension::from_oid_content(&P2P_EXT_OID, content);
+  extension.set_criticality(true);
+
+  let mut params = rcgen::CertificateParams::new(vec![]);
+  params.distinguished_name = rcgen::DistinguishedName::new();
+  params.custom_extensions.push(extension);
+  params.alg = P2P_SIGNATURE_ALGORITHM;
+  params.key_pair = Some(certificate_keypair);
+  let certificate = rcgen::Certificate::from_params(params)?;
+
+  Ok((
+    rustls::Certificate(certificate.serialize_der()?),
+    private_key,
+  ))
+}
+
+/// Parse and verify a libp2p certificate: it has to be currently valid,
+/// self-signed and carry a libp2p extension signed by the host key.
+pub fn parse(certificate: &Certificate) -> Result<P2pCertificate<'_>, webpki::Error> {
+  let certificate = parse_unverified(certificate.as_ref())?;
+  certificate.verify()?;
+  Ok(certificate)
+}
+
+/// An X.509 certificate carrying the libp2p public key extension.
+#[derive(Debug)]
+pub struct P2pCertificate<'a> {
+  certificate: X509Certificate<'a>,
+  public_key: identity::PublicKey,
+  signature: Vec<u8>,
+}
+
+fn parse_unverified(der: &[u8]) -> Result<P2pCertificate<'_>, webpki::Error> {
+  let (_, x509) = X509Certificate::from_der(der).map_err(|_| webpki::Error::BadDer)?;
+  let p2p_ext_oid =
+    x509_parser::der_parser::oid::Oid::from(&P2P_EXT_OID).expect("OID to be valid.");
+
+  let mut extension = None;
+  for ext in x509.extensions() {
+    if ext.oid == p2p_ext_oid {
+      if extension.is_some() {
+        return Err(webpki::Error::BadDer);
+      }
+      let (public_key, signature): (Vec<u8>, Vec<u8>) =
+        yasna::decode_der(ext.value).map_err(|_| webpki::Error::ExtensionValueInvalid)?;
+      let public_key = identity::PublicKey::from_protobuf_encoding(&public_key)
+        .map_err(|_| webpki::Error::UnknownIssuer)?;
+      extension = Some((public_key, signature));
+    } else if ext.critical {
+      // Unknown critical extensions abort the handshake, unknown
+      // non-critical ones are ignored.
+      return Err(webpki::Error::UnsupportedCriticalExtension);
+    }
+  }
+  let (public_key, signature) = extension.ok_or(webpki::Error::BadDer)?;
+
+  Ok(P2pCertificate {
+    certificate: x509,
+    public_key,
+    signature,
+  })
+}
+
+impl P2pCertificate<'_> {
+  /// Peer ID derived from the host key in the extension.
+  pub fn peer_id(&self) -> PeerId {
+    self.public_key.to_peer_id()
+  }
+
+  /// Check `signature` over `message` against the certificate key.
+  pub fn verify_signature(
+    &self,
+    scheme: SignatureScheme,
+    message: &[u8],
+    signature: &[u8],
+  ) -> Result<(), webpki::Error> {
+    self
+      .public_key(scheme)?
+      .verify(message, signature)
+      .map_err(|_| webpki::Error::InvalidSignatureForPublicKey)
+  }
+
+  fn public_key(
+    &self,
+    scheme: SignatureScheme,
+  ) -> Result<ring::signature::UnparsedPublicKey<&[u8]>, webpki::Error> {
+    use ring::signature;
+    use SignatureScheme::*;
+
+    if scheme != self.signature_scheme()? {
+      return Err(webpki::Error::UnsupportedSignatureAlgorithmForPublicKey);
+    }
+    let algorithm: &dyn signature::VerificationAlgorithm = match scheme {
+      RSA_PKCS1_SHA256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+      RSA_PKCS1_SHA384 => &signature::RSA_PKCS1_2048_8192_SHA384,
+      RSA_PKCS1_SHA512 => &signature::RSA_PKCS1_2048_8192_SHA512,
+      ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+      ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+      RSA_PSS_SHA256 => &signature::RSA_PSS_2048_8192_SHA256,
+      RSA_PSS_SHA384 => &signature::RSA_PSS_2048_8192_SHA384,
+      RSA_PSS_SHA512 => &signature::RSA_PSS_2048_8192_SHA512,
+      ED25519 => &signature::ED25519,
+      // P-521 and Ed448 are not supported by ring, SHA-1 must not be used.
+      _ => return Err(webpki::Error::UnsupportedSignatureAlgorithm),
+    };
+    Ok(signature::UnparsedPublicKey::new(
+      algorithm,
+      self
+        .certificate
+        .tbs_certificate
+        .subject_pki
+        .subject_public_key
+        .as_ref(),
+    ))
+  }
+
+  fn verify(&self) -> Result<(), webpki::Error> {
+    if !self.certificate.validity().is_valid() {
+      return Err(webpki::Error::InvalidCertValidity);
+    }
+
+    let scheme = self.signature_scheme()?;
+    self
+      .verify_signature(
+        scheme,
+        self.certificate.tbs_certificate.as_ref(),
+        self.certificate.signature_value.as_ref(),
+      )
+      .map_err(|_| webpki::Error::SignatureAlgorithmMismatch)?;
+
+    let mut msg = P2P_SIGNING_PREFIX.to_vec();
+    msg.extend(self.certificate.public_key().raw);
+    if !self.public_key.verify(&msg, &self.signature) {
+      return Err(webpki::Error::UnknownIssuer);
+    }
+    Ok(())
+  }
+
+  /// Signature scheme of the certificate, see RFC 8446 section 4.2.3.
+  fn signature_scheme(&self) -> Result<SignatureScheme, webpki::Error> {
+    use x509_parser::oid_registry::*;
+    use SignatureScheme::*;
+
+    let signature_algorithm = &self.certificate.signature_algorithm;
+    let pki_algorithm = &self.certificate.tbs_certificate.subject_pki.algorithm;
+
+    if pki_algorithm.algorithm == OID_PKCS1_RSAENCRYPTION {
+      if signature_algorithm.algorithm == OID_PKCS1_SHA256WITHRSA {
+        return Ok(RSA_PKCS1_SHA256);
+      }
+      if signature_algorithm.algorithm == OID_PKCS1_SHA384WITHRSA {
+        return Ok(RSA_PKCS1_SHA384);
+      }
+      if signature_algorithm.algorithm == OID_PKCS1_SHA512WITHRSA {
+        return Ok(RSA_PKCS1_SHA512);
+      }
+      if signature_algorithm.algorithm == OID_PKCS1_RSASSAPSS {
+        if let Ok(SignatureAlgorithm::RSASSA_PSS(params)) =
+          SignatureAlgorithm::try_from(signature_algorithm)
+        {
+          let hash = params.hash_algorithm_oid();
+          if hash == &OID_NIST_HASH_SHA256 {
+            return Ok(RSA_PSS_SHA256);
+          }
+          if hash == &OID_NIST_HASH_SHA384 {
+            return Ok(RSA_PSS_SHA384);
+          }
+          if hash == &OID_NIST_HASH_SHA512 {
+            return Ok(RSA_PSS_SHA512);
+          }
+        }
+      }
+      return Err(webpki::Error::UnsupportedSignatureAlgorithm);
+    }
+
+    if pki_algorithm.algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY {
+      let curve = pki_algorithm
+        .parameters
+        .as_ref()
+        .ok_or(webpki::Error::BadDer)?
+        .as_oid()
+        .map_err(|_| webpki::Error::BadDer)?;
+      if curve == OID_EC_P256 && signature_algorithm.algorithm == OID_SIG_ECDSA_WITH_SHA256 {
+        return Ok(ECDSA_NISTP256_SHA256);
+      }
+      if curve == OID_NIST_EC_P384 && signature_algorithm.algorithm == OID_SIG_ECDSA_WITH_SHA384 {
+        return Ok(ECDSA_NISTP384_SHA384);
+      }
+      return Err(webpki::Error::UnsupportedSignatureAlgorithm);
+    }
+
+    if signature_algorithm.algorithm == OID_SIG_ED25519 {
+      return Ok(ED25519);
+    }
+    Err(webpki::Error::UnsupportedSignatureAlgorithm)
+  }
+}
+
+/// Certificate verifier for both sides of the handshake: exactly one
+/// self-signed certificate with a valid libp2p extension is accepted.
+struct Verifier {
+  /// Peer the client intends to connect to, if known.
+  remote_peer_id: Option<PeerId>,
+}
+
+impl Verifier {
+  fn verify_presented(
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+  ) -> Result<PeerId, rustls::Error> {
+    if !intermediates.is_empty() {
+      return Err(rustls::Error::General(
+        "libp2p TLS requires exactly one certificate".into(),
+      ));
+    }
+    parse(end_entity)
+      .map(|certificate| certificate.peer_id())
+      .map_err(|e| {
+        rustls::Error::InvalidCertificateData(format!("invalid peer certificate: {}", e))
+      })
+  }
+
+  fn verify_signature(
+    cert: &Certificate,
+    dss: &DigitallySignedStruct,
+    message: &[u8],
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    parse(cert)
+      .and_then(|certificate| certificate.verify_signature(dss.scheme, message, dss.signature()))
+      .map_err(|e| match e {
+        webpki::Error::InvalidSignatureForPublicKey => rustls::Error::InvalidCertificateSignature,
+        webpki::Error::UnsupportedSignatureAlgorithm
+        | webpki::Error::UnsupportedSignatureAlgorithmForPublicKey => {
+          rustls::Error::InvalidCertificateSignatureType
+        }
+        e => rustls::Error::InvalidCertificateData(format!("invalid peer certificate: {}", e)),
+      })?;
+    Ok(HandshakeSignatureValid::assertion())
+  }
+
+  fn schemes() -> Vec<SignatureScheme> {
+    vec![
+      SignatureScheme::ECDSA_NISTP384_SHA384,
+      SignatureScheme::ECDSA_NISTP256_SHA256,
+      SignatureScheme::ED25519,
+      // RSA only if no elliptic curve algorithm is supported.
+      SignatureScheme::RSA_PSS_SHA512,
+      SignatureScheme::RSA_PSS_SHA384,
+      SignatureScheme::RSA_PSS_SHA256,
+      SignatureScheme::RSA_PKCS1_SHA512,
+      SignatureScheme::RSA_PKCS1_SHA384,
+      SignatureScheme::RSA_PKCS1_SHA256,
+    ]
+  }
+}
+
+impl ServerCertVerifier for Verifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    _server_name: &ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: SystemTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    let peer_id = Self::verify_presented(end_entity, intermediates)?;
+    match self.remote_peer_id {
+      Some(expected) if expected != peer_id => Err(rustls::Error::PeerMisbehavedError(
+        "wrong peer ID in libp2p extension".into(),
+      )),
+      _ => Ok(ServerCertVerified::assertion()),
+    }
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    _message: &[u8],
+    _cert: &Certificate,
+    _dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    Err(rustls::Error::PeerIncompatibleError(
+      "TLS 1.2 is not supported".into(),
+    ))
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &Certificate,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    Self::verify_signature(cert, dss, message)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    Self::schemes()
+  }
+}
+
+impl ClientCertVerifier for Verifier {
+  fn offer_client_auth(&self) -> bool {
+    true
+  }
+
+  fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+    Some(vec![])
+  }
+
+  fn verify_client_cert(
+    &self,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    _now: SystemTime,
+  ) -> Result<ClientCertVerified, rustls::Error> {
+    Self::verify_presented(end_entity, intermediates)?;
+    Ok(ClientCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    _message: &[u8],
+    _cert: &Certificate,
+    _dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    Err(rustls::Error::PeerIncompatibleError(
+      "TLS 1.2 is not supported".into(),
+    ))
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &Certificate,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    Self::verify_signature(cert, dss, message)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    Self::schemes()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::io::{AsyncReadExt, AsyncWriteExt};
+  use libp2p::core::transport::{MemoryTransport, Transport};
+
+  #[test]
+  fn generated_certificate_verifies() {
+    let keypair = identity::Keypair::generate_ed25519();
+    let (certificate, _) = generate(&keypair).unwrap();
+    let parsed = parse(&certificate).unwrap();
+    assert_eq!(parsed.peer_id(), keypair.public().to_peer_id());
+  }
+
+  #[test]
+  fn tampered_certificate_is_rejected() {
+    let keypair = identity::Keypair::generate_ed25519();
+    let (certificate, _) = generate(&keypair).unwrap();
+    let mut der = certificate.0;
+    // Corrupt the last byte of the certificate's self-signature.
+    let last = der.len() - 1;
+    der[last] ^= 0xff;
+    assert!(parse(&Certificate(der)).is_err());
+  }
+
+  #[async_std::test]
+  async fn handshake_authenticates_both_sides() {
+    let server_keys = identity::Keypair::generate_ed25519();
+    let client_keys = identity::Keypair::generate_ed25519();
+    let server_config = TlsConfig::new(&server_keys).unwrap();
+    let client_config = TlsConfig::new(&client_keys).unwrap();
+
+    let mut listener = MemoryTransport
+      .listen_on("/memory/0".parse().unwrap())
+      .unwrap();
+    let addr = match futures::StreamExt::next(&mut listener).await {
+      Some(Ok(libp2p::core::transport::ListenerEvent::NewAddress(addr))) => addr,
+      e => panic!("unexpected listener event {:?}", e.map(|e| e.map(|_| ()))),
+    };
+
+    let server = async_std::task::spawn(async move {
+      let upgrade = loop {
+        match futures::StreamExt::next(&mut listener).await {
+          Some(Ok(event)) => {
+            if let Some((upgrade, _)) = event.into_upgrade() {
+              break upgrade;
+            }
+          }
+          e => panic!("listener failed: {:?}", e.map(|e| e.map(|_| ()))),
+        }
+      };
+      let socket = upgrade.await.unwrap();
+      let (peer, mut stream) = server_config
+        .upgrade_inbound(socket, PROTOCOL_NAME)
+        .await
+        .unwrap();
+      let mut buf = [0; 5];
+      stream.read_exact(&mut buf).await.unwrap();
+      (peer, buf)
+    });
+
+    let socket = MemoryTransport.dial(addr).unwrap().await.unwrap();
+    let (peer, mut stream) = client_config
+      .upgrade_outbound(socket, PROTOCOL_NAME)
+      .await
+      .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.flush().await.unwrap();
+
+    let (server_seen, received) = server.await;
+    assert_eq!(peer, server_keys.public().to_peer_id());
+    assert_eq!(server_seen, client_keys.public().to_peer_id());
+    assert_eq!(&received, b"hello");
+  }
+}
@@ -0,0 +1,592 @@
+//! Construction of the transport stack used by the network layer.
+//!
+//! Connections are secured with Noise XX or TLS 1.3 (see [`crate::tls`]),
+//! both being offered by default.
+//!
+//! Supported transports are TCP, WebSocket over TCP (`/ws`, and `/wss` once
+//! a certificate is configured), QUIC (`/udp/<port>/quic` and `/quic-v1`,
+//! see [`crate::quic`]) and relayed connections. For tests the socket based
+//! transports can be replaced by libp2p's in-process `MemoryTransport`, see
+//! [`TransportConfig::set_memory`].
+use crate::quic::{QuicConfig, QuicTransport};
+use crate::tls::TlsConfig;
+use libp2p::core::either::EitherOutput;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::timeout::TransportTimeout;
+use libp2p::core::transport::{Boxed, MemoryTransport, OptionalTransport, OrTransport};
+use libp2p::core::upgrade::{
+  self, InboundUpgradeExt, OptionalUpgrade, OutboundUpgradeExt, SelectUpgrade,
+};
+use libp2p::core::{Multiaddr, PeerId, Transport};
+use libp2p::dns::DnsConfig;
+use libp2p::identity;
+use libp2p::mplex::MplexConfig;
+use libp2p::multiaddr::Protocol;
+use libp2p::noise;
+use libp2p::relay::v2::client::transport::ClientTransport;
+use libp2p::tcp::TcpConfig;
+use libp2p::websocket::{tls, WsConfig};
+use libp2p::yamux::YamuxConfig;
+use rustls_pemfile::Item;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Configuration of the transport stack.
+///
+/// By default both Noise and TLS are offered for security, and both Yamux
+/// and Mplex for multiplexing, Noise respectively Yamux being preferred
+/// when the remote supports both.
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+  noise: bool,
+  tls: bool,
+  yamux: Option<YamuxConfig>,
+  mplex: Option<MplexConfig>,
+  timeout: Duration,
+  tcp: bool,
+  quic: Option<QuicConfig>,
+  ws_certificate: Option<(PathBuf, PathBuf)>,
+  ws_trusted: Vec<PathBuf>,
+  memory: bool,
+}
+
+impl Default for TransportConfig {
+  fn default() -> Self {
+    Self {
+      noise: true,
+      tls: true,
+      yamux: Some(YamuxConfig::default()),
+      mplex: Some(MplexConfig::default()),
+      timeout: Duration::from_secs(20),
+      tcp: true,
+      quic: Some(QuicConfig::default()),
+      ws_certificate: None,
+      ws_trusted: Vec::new(),
+      memory: false,
+    }
+  }
+}
+
+impl TransportConfig {
+  /// Offer Noise XX for securing connections.
+  pub fn set_noise(&mut self, noise: bool) -> &mut Self {
+    self.noise = noise;
+    self
+  }
+
+  /// Offer TLS 1.3 for securing connections.
+  pub fn set_tls(&mut self, tls: bool) -> &mut Self {
+    self.tls = tls;
+    self
+  }
+
+  /// Offer Yamux with the given settings (window size, buffer size, maximum
+  /// number of streams), or stop offering it with `None`.
+  pub fn set_yamux(&mut self, yamux: Option<YamuxConfig>) -> &mut Self {
+    self.yamux = yamux;
+    self
+  }
+
+  /// Offer Mplex with the given settings, or stop offering it with `None`.
+  pub fn set_mplex(&mut self, mplex: Option<MplexConfig>) -> &mut Self {
+    self.mplex = mplex;
+    self
+  }
+
+  /// Timeout for the whole connection upgrade, i.e. security and
+  /// multiplexer negotiation.
+  pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Offer TCP and WebSocket over TCP.
+  pub fn set_tcp(&mut self, tcp: bool) -> &mut Self {
+    self.tcp = tcp;
+    self
+  }
+
+  /// Offer QUIC with the given settings, or stop offering it with `None`.
+  pub fn set_quic(&mut self, quic: Option<QuicConfig>) -> &mut Self {
+    self.quic = quic;
+    self
+  }
+
+  /// Certificate chain and private key (PKCS#8 or PKCS#1), both PEM encoded,
+  /// used to accept connections on `/wss` addresses.
+  pub fn set_websocket_certificate(&mut self, cert_chain: PathBuf, key: PathBuf) -> &mut Self {
+    self.ws_certificate = Some((cert_chain, key));
+    self
+  }
+
+  /// Trust the PEM encoded certificate(s) in addition to the web PKI roots
+  /// when dialing `/wss` addresses, e.g. for self-signed certificates.
+  pub fn add_websocket_trust(&mut self, cert: PathBuf) -> &mut Self {
+    self.ws_trusted.push(cert);
+    self
+  }
+
+  /// Use the in-process `MemoryTransport` instead of TCP, WebSocket and
+  /// QUIC.
+  ///
+  /// Nodes then listen on and dial `/memory/<port>` addresses, allowing many
+  /// nodes to run within a single process without opening any socket.
+  pub fn set_memory(&mut self, memory: bool) -> &mut Self {
+    self.memory = memory;
+    self
+  }
+
+  /// Check that `addr` can be dialed or listened on, failing for QUIC
+  /// addresses while QUIC is disabled, which would otherwise only be
+  /// refused deep within the swarm.
+  pub fn check_address(&self, addr: &Multiaddr) -> Result<(), UnsupportedAddress> {
+    let quic = self.quic.is_some() && !self.memory;
+    if !quic && addr.iter().any(|p| matches!(p, Protocol::Quic)) {
+      return Err(UnsupportedAddress(addr.clone()));
+    }
+    Ok(())
+  }
+
+  fn websocket_tls(&self) -> io::Result<tls::Config> {
+    let mut builder = tls::Config::builder();
+    if let Some((cert_chain, key)) = &self.ws_certificate {
+      builder
+        .server(read_private_key(key)?, read_certificates(cert_chain)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    for path in &self.ws_trusted {
+      for cert in read_certificates(path)? {
+        builder
+          .add_trust(&cert)
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+      }
+    }
+    Ok(builder.finish())
+  }
+}
+
+fn read_certificates(path: &Path) -> io::Result<Vec<tls::Certificate>> {
+  let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+  if certs.is_empty() {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("no certificate found in {}", path.display()),
+    ));
+  }
+  Ok(certs.into_iter().map(tls::Certificate::new).collect())
+}
+
+fn read_private_key(path: &Path) -> io::Result<tls::PrivateKey> {
+  rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))?
+    .into_iter()
+    .find_map(|item| match item {
+      Item::PKCS8Key(key) | Item::RSAKey(key) => Some(tls::PrivateKey::new(key)),
+      _ => None,
+    })
+    .ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "no PKCS#8 or PKCS#1 private key found in {}",
+          path.display()
+        ),
+      )
+    })
+}
+
+/// An address of a transport the stack does not offer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedAddress(pub Multiaddr);
+
+impl fmt::Display for UnsupportedAddress {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} uses QUIC, which is not enabled", self.0)
+  }
+}
+
+impl std::error::Error for UnsupportedAddress {}
+
+/// Build the transport: TCP (with DNS and WebSocket support) and QUIC, or
+/// in-memory, and relayed connections, secured and multiplexed as
+/// configured. QUIC brings its own security and multiplexing.
+pub async fn build(
+  id_keys: &identity::Keypair,
+  relay_transport: ClientTransport,
+  config: TransportConfig,
+) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+  if !config.noise && !config.tls {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      "at least one security protocol has to be enabled",
+    ));
+  }
+  let noise = if config.noise {
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+      .into_authentic(id_keys)
+      .expect("Signing libp2p-noise static DH keypair failed.");
+    OptionalUpgrade::some(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+  } else {
+    OptionalUpgrade::none()
+  };
+  let tls = if config.tls {
+    OptionalUpgrade::some(TlsConfig::new(id_keys).map_err(io::Error::other)?)
+  } else {
+    OptionalUpgrade::none()
+  };
+  let security = SelectUpgrade::new(noise, tls)
+    .map_inbound(either_security)
+    .map_outbound(either_security);
+
+  let base = {
+    let (sockets, memory) = if config.memory {
+      (
+        OptionalTransport::none(),
+        OptionalTransport::some(MemoryTransport),
+      )
+    } else if config.tcp {
+      let dns_tcp = DnsConfig::system(TcpConfig::new().nodelay(true)).await?;
+      let mut ws_dns_tcp = WsConfig::new(DnsConfig::system(TcpConfig::new().nodelay(true)).await?);
+      ws_dns_tcp.set_tls_config(config.websocket_tls()?);
+      // WebSocket goes first: it refuses other addresses right away, while
+      // DNS over TCP would accept `/dns4/<host>/tcp/<port>/ws` addresses and
+      // only fail once resolved.
+      (
+        OptionalTransport::some(ws_dns_tcp.or_transport(dns_tcp)),
+        OptionalTransport::none(),
+      )
+    } else {
+      (OptionalTransport::none(), OptionalTransport::none())
+    };
+    OrTransport::new(relay_transport, OrTransport::new(sockets, memory))
+  };
+
+  let authenticated = base.upgrade(upgrade::Version::V1).authenticate(security);
+
+  let upgraded = match (config.yamux, config.mplex) {
+    (Some(yamux), Some(mplex)) => authenticated
+      .multiplex(SelectUpgrade::new(yamux, mplex))
+      .timeout(config.timeout)
+      .boxed(),
+    (Some(yamux), None) => authenticated
+      .multiplex(yamux)
+      .timeout(config.timeout)
+      .boxed(),
+    (None, Some(mplex)) => authenticated
+      .multiplex(mplex)
+      .timeout(config.timeout)
+      .boxed(),
+    (None, None) => {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "at least one stream multiplexer has to be enabled",
+      ))
+    }
+  };
+
+  let quic = match &config.quic {
+    Some(quic) if !config.memory => OptionalTransport::some(
+      TransportTimeout::new(QuicTransport::new(id_keys, quic)?, config.timeout)
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer))),
+    ),
+    _ => OptionalTransport::none(),
+  };
+
+  Ok(
+    OrTransport::new(quic, upgraded)
+      .map(|output, _| match output {
+        EitherOutput::First(output) | EitherOutput::Second(output) => output,
+      })
+      .boxed(),
+  )
+}
+
+/// Move the peer ID out of the output of whichever security protocol was
+/// negotiated.
+fn either_security<A, B>(
+  output: EitherOutput<(PeerId, A), (PeerId, B)>,
+) -> (PeerId, EitherOutput<A, B>) {
+  match output {
+    EitherOutput::First((peer, stream)) => (peer, EitherOutput::First(stream)),
+    EitherOutput::Second((peer, stream)) => (peer, EitherOutput::Second(stream)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::StreamExt;
+  use libp2p::core::transport::{ListenerEvent, TransportError};
+  use libp2p::relay::v2::client::Client;
+
+  async fn memory_transport(
+    keys: &identity::Keypair,
+    configure: impl FnOnce(&mut TransportConfig),
+  ) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let mut config = TransportConfig::default();
+    config.set_memory(true);
+    configure(&mut config);
+    let (relay_transport, _) = Client::new_transport_and_behaviour(keys.public().to_peer_id());
+    build(keys, relay_transport, config).await
+  }
+
+  /// Connect a dialer with `dialer` settings to a listener with `listener`
+  /// settings, returning the peer IDs each side authenticated.
+  async fn connect(
+    listener: impl FnOnce(&mut TransportConfig),
+    dialer: impl FnOnce(&mut TransportConfig),
+  ) -> (io::Result<PeerId>, io::Result<PeerId>) {
+    let listener_keys = identity::Keypair::generate_ed25519();
+    let dialer_keys = identity::Keypair::generate_ed25519();
+    let listener_transport = memory_transport(&listener_keys, listener).await.unwrap();
+    let dialer_transport = memory_transport(&dialer_keys, dialer).await.unwrap();
+
+    let mut listener = listener_transport
+      .listen_on("/memory/0".parse().unwrap())
+      .unwrap();
+    let addr = match listener.next().await {
+      Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+      _ => panic!("Expected the listener to report its address."),
+    };
+    let accept = async {
+      loop {
+        if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = listener.next().await {
+          return upgrade.await.map(|(peer, _)| peer);
+        }
+      }
+    };
+    let dial = async {
+      dialer_transport
+        .dial(addr)
+        .unwrap()
+        .await
+        .map(|(peer, _)| peer)
+    };
+    let (accepted, dialed) = futures::join!(accept, dial);
+    if let Ok(peer) = &accepted {
+      assert_eq!(*peer, dialer_keys.public().to_peer_id());
+    }
+    if let Ok(peer) = &dialed {
+      assert_eq!(*peer, listener_keys.public().to_peer_id());
+    }
+    (accepted, dialed)
+  }
+
+  #[async_std::test]
+  async fn memory_mode_opens_no_socket() {
+    let keys = identity::Keypair::generate_ed25519();
+    let transport = memory_transport(&keys, |_| {}).await.unwrap();
+    for addr in [
+      "/ip4/127.0.0.1/tcp/0",
+      "/ip4/127.0.0.1/tcp/0/ws",
+      "/ip4/127.0.0.1/udp/0/quic",
+    ] {
+      assert!(
+        matches!(
+          transport.clone().listen_on(addr.parse().unwrap()),
+          Err(TransportError::MultiaddrNotSupported(_))
+        ),
+        "{}",
+        addr
+      );
+    }
+  }
+
+  #[async_std::test]
+  async fn tls_only() {
+    let (accepted, dialed) = connect(
+      |config| {
+        config.set_noise(false);
+      },
+      |config| {
+        config.set_noise(false);
+      },
+    )
+    .await;
+    accepted.unwrap();
+    dialed.unwrap();
+  }
+
+  #[async_std::test]
+  async fn noise_only_with_mplex() {
+    let (accepted, dialed) = connect(
+      |config| {
+        config.set_tls(false).set_yamux(None);
+      },
+      |config| {
+        config.set_tls(false);
+      },
+    )
+    .await;
+    accepted.unwrap();
+    dialed.unwrap();
+  }
+
+  #[async_std::test]
+  async fn falls_back_to_tls() {
+    let (accepted, dialed) = connect(
+      |config| {
+        config.set_noise(false);
+      },
+      |_| {},
+    )
+    .await;
+    accepted.unwrap();
+    dialed.unwrap();
+  }
+
+  #[async_std::test]
+  async fn no_common_security_protocol() {
+    let (accepted, dialed) = connect(
+      |config| {
+        config.set_noise(false);
+      },
+      |config| {
+        config.set_tls(false);
+      },
+    )
+    .await;
+    assert!(accepted.is_err());
+    assert!(dialed.is_err());
+  }
+
+  #[test]
+  fn quic_addresses_need_quic() {
+    let quic: Multiaddr = "/ip4/127.0.0.1/udp/4001/quic".parse().unwrap();
+    let tcp: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+    let mut config = TransportConfig::default();
+    assert_eq!(config.check_address(&quic), Ok(()));
+    config.set_memory(true);
+    assert_eq!(
+      config.check_address(&quic),
+      Err(UnsupportedAddress(quic.clone()))
+    );
+    config.set_memory(false).set_quic(None);
+    assert_eq!(config.check_address(&quic), Err(UnsupportedAddress(quic)));
+    assert_eq!(config.check_address(&tcp), Ok(()));
+  }
+
+  #[async_std::test]
+  async fn dials_over_quic() {
+    let listener_keys = identity::Keypair::generate_ed25519();
+    let dialer_keys = identity::Keypair::generate_ed25519();
+    let (relay_transport, _) = Client::new_transport_and_behaviour(listener_keys.public().into());
+    let listener_transport = build(&listener_keys, relay_transport, TransportConfig::default())
+      .await
+      .unwrap();
+    let (relay_transport, _) = Client::new_transport_and_behaviour(dialer_keys.public().into());
+    let mut config = TransportConfig::default();
+    config.set_tcp(false);
+    let dialer_transport = build(&dialer_keys, relay_transport, config).await.unwrap();
+
+    let mut listener = listener_transport
+      .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+      .unwrap();
+    let addr = match listener.next().await {
+      Some(Ok(ListenerEvent::NewAddress(addr))) => addr,
+      _ => panic!("Expected the listener to report its address."),
+    };
+    let accept = async {
+      loop {
+        if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = listener.next().await {
+          return upgrade.await.unwrap().0;
+        }
+      }
+    };
+    let (accepted, dialed) = futures::join!(accept, dialer_transport.dial(addr).unwrap());
+    assert_eq!(accepted, dialer_keys.public().to_peer_id());
+    assert_eq!(dialed.unwrap().0, listener_keys.public().to_peer_id());
+  }
+
+  #[async_std::test]
+  async fn security_required() {
+    let keys = identity::Keypair::generate_ed25519();
+    let error = memory_transport(&keys, |config| {
+      config.set_noise(false).set_tls(false);
+    })
+    .await
+    .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+  }
+
+  /// Write a self-signed certificate for `localhost` and its key as PEM
+  /// files into a fresh directory, returning their paths.
+  fn write_localhost_certificate(name: &str) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert, certificate.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key, certificate.serialize_private_key_pem()).unwrap();
+    (cert, key)
+  }
+
+  #[test]
+  fn reads_websocket_certificate() {
+    let (cert, key) = write_localhost_certificate("ws-certificate");
+    assert_eq!(read_certificates(&cert).unwrap().len(), 1);
+    read_private_key(&key).unwrap();
+    let mut config = TransportConfig::default();
+    config
+      .set_websocket_certificate(cert.clone(), key.clone())
+      .add_websocket_trust(cert.clone());
+    config.websocket_tls().unwrap();
+
+    // The certificate and the key file mixed up.
+    let error = read_certificates(&key).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    let error = read_private_key(&cert).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    let error = read_certificates(&cert.with_file_name("missing.pem")).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+  }
+
+  #[async_std::test]
+  async fn dials_over_secure_websocket() {
+    let (cert, key) = write_localhost_certificate("wss-dial");
+    let listener_keys = identity::Keypair::generate_ed25519();
+    let dialer_keys = identity::Keypair::generate_ed25519();
+
+    let mut config = TransportConfig::default();
+    config
+      .set_quic(None)
+      .set_websocket_certificate(cert.clone(), key);
+    let (relay_transport, _) = Client::new_transport_and_behaviour(listener_keys.public().into());
+    let listener_transport = build(&listener_keys, relay_transport, config)
+      .await
+      .unwrap();
+
+    let mut config = TransportConfig::default();
+    config.set_quic(None).add_websocket_trust(cert);
+    let (relay_transport, _) = Client::new_transport_and_behaviour(dialer_keys.public().into());
+    let dialer_transport = build(&dialer_keys, relay_transport, config).await.unwrap();
+
+    let mut listener = listener_transport
+      .listen_on("/ip4/127.0.0.1/tcp/0/wss".parse().unwrap())
+      .unwrap();
+    let port = match listener.next().await {
+      Some(Ok(ListenerEvent::NewAddress(addr))) => addr
+        .iter()
+        .find_map(|p| match p {
+          Protocol::Tcp(port) => Some(port),
+          _ => None,
+        })
+        .unwrap(),
+      _ => panic!("Expected the listener to report its address."),
+    };
+    // The certificate is issued for `localhost`.
+    let addr = format!("/dns4/localhost/tcp/{}/wss", port).parse().unwrap();
+    let accept = async {
+      loop {
+        if let Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) = listener.next().await {
+          return upgrade.await.unwrap().0;
+        }
+      }
+    };
+    let (accepted, dialed) = futures::join!(accept, dialer_transport.dial(addr).unwrap());
+    assert_eq!(accepted, dialer_keys.public().to_peer_id());
+    assert_eq!(dialed.unwrap().0, listener_keys.public().to_peer_id());
+  }
+}
@@ -0,0 +1,369 @@
+//! Sharing whole directory trees.
+//!
+//! The provider scans a directory into a [`SharedTree`]: every file is
+//! shared under its own key `<root>/<relative path>` and a [`Manifest`]
+//! listing relative paths, sizes and SHA-256 hashes is shared under the root
+//! key itself. A requester fetches the manifest first and then all files
+//! concurrently, see [`fetch_tree`]. Content fetched under an arbitrary key
+//! is recognized as a manifest by [`Manifest::parse`], see [`mirror_tree`].
+use crate::catalog::CatalogEntry;
+use crate::network::{Client, FileRequestError};
+use crate::reputation::Offense;
+use futures::prelude::*;
+use libp2p::core::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::{fmt, io};
+use walkdir::WalkDir;
+
+/// Value of [`Manifest::format`], telling manifests apart from shared files
+/// that merely happen to be JSON.
+pub const MANIFEST_FORMAT: &str = "libp2p-demo/tree/1";
+
+/// Listing of a shared directory, shared under the root key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+  pub format: String,
+  pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+  /// The manifest `content` is, `None` for any other content.
+  pub fn parse(content: &[u8]) -> Option<Self> {
+    serde_json::from_slice::<Manifest>(content)
+      .ok()
+      .filter(|manifest| manifest.format == MANIFEST_FORMAT)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+  /// Path relative to the shared directory, components separated by `/`.
+  pub path: String,
+  pub size: u64,
+  /// Hex encoded SHA-256 of the content.
+  pub sha256: String,
+  /// Key the file is provided under.
+  pub key: String,
+}
+
+/// A directory shared by the local node.
+#[derive(Debug, Clone)]
+pub struct SharedTree {
+  root: String,
+  entries: Vec<ManifestEntry>,
+  manifest: Vec<u8>,
+  files: HashMap<String, PathBuf>,
+}
+
+impl SharedTree {
+  /// Scan `dir` recursively, hashing every file. Symbolic links are not
+  /// followed.
+  pub fn scan(dir: &Path, root: String) -> io::Result<Self> {
+    let mut entries = Vec::new();
+    let mut files = HashMap::new();
+
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+      let entry = entry?;
+      if !entry.file_type().is_file() {
+        continue;
+      }
+      let relative = entry
+        .path()
+        .strip_prefix(dir)
+        .expect("Walked entries to be below the walked directory.");
+      let path = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+          io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not valid UTF-8", relative.display()),
+          )
+        })?
+        .join("/");
+
+      let content = std::fs::read(entry.path())?;
+      let key = format!("{}/{}", root, path);
+      entries.push(ManifestEntry {
+        path,
+        size: content.len() as u64,
+        sha256: sha256_hex(&content),
+        key: key.clone(),
+      });
+      files.insert(key, entry.into_path());
+    }
+
+    let manifest = Manifest {
+      format: MANIFEST_FORMAT.to_string(),
+      entries,
+    };
+    Ok(Self {
+      root,
+      manifest: serde_json::to_vec(&manifest)?,
+      entries: manifest.entries,
+      files,
+    })
+  }
+
+  /// The key the manifest is provided under.
+  pub fn root(&self) -> &str {
+    &self.root
+  }
+
+  /// All keys to provide: the root followed by one key per file.
+  pub fn keys(&self) -> impl Iterator<Item = &str> {
+    std::iter::once(self.root.as_str()).chain(self.files.keys().map(String::as_str))
+  }
+
+  /// Catalog entries for the manifest and every file of the tree.
+  pub fn catalog_entries(&self) -> impl Iterator<Item = CatalogEntry> + '_ {
+    let manifest = CatalogEntry {
+      key: self.root.clone(),
+      size: self.manifest.len() as u64,
+      sha256: sha256_hex(&self.manifest),
+      mime_type: "application/json".to_string(),
+      description: Some(format!("Directory of {} files", self.entries.len())),
+    };
+    let files = self.entries.iter().map(|entry| CatalogEntry {
+      key: entry.key.clone(),
+      size: entry.size,
+      sha256: entry.sha256.clone(),
+      mime_type: mime_guess::from_path(&entry.path)
+        .first_or_octet_stream()
+        .to_string(),
+      description: None,
+    });
+    std::iter::once(manifest).chain(files)
+  }
+
+  /// Content to answer a request for `key` with, `None` if the key is not
+  /// part of the tree.
+  pub fn read(&self, key: &str) -> Option<io::Result<Vec<u8>>> {
+    if key == self.root {
+      return Some(Ok(self.manifest.clone()));
+    }
+    self.files.get(key).map(std::fs::read)
+  }
+}
+
+/// Error fetching a shared tree.
+#[derive(Debug)]
+pub enum TreeError {
+  Io(io::Error),
+  /// No provider was found for the key.
+  NoProvider(String),
+  /// None of the providers returned the key, the last error is kept.
+  Request(String, FileRequestError),
+  InvalidManifest(serde_json::Error),
+  /// The key is not the root of a shared tree.
+  NotATree(String),
+  /// A manifest path is absolute or escapes the destination directory.
+  InvalidPath(String),
+  /// The content of a file does not match the manifest.
+  HashMismatch(String),
+}
+
+impl fmt::Display for TreeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TreeError::Io(e) => write!(f, "{}", e),
+      TreeError::NoProvider(key) => write!(f, "no provider found for {}", key),
+      TreeError::Request(key, e) => write!(f, "fetching {} failed: {}", key, e),
+      TreeError::InvalidManifest(e) => write!(f, "invalid manifest: {}", e),
+      TreeError::NotATree(key) => write!(f, "{} is not a shared directory", key),
+      TreeError::InvalidPath(path) => write!(f, "invalid path in manifest: {}", path),
+      TreeError::HashMismatch(path) => write!(f, "content of {} does not match its hash", path),
+    }
+  }
+}
+
+impl std::error::Error for TreeError {}
+
+impl From<io::Error> for TreeError {
+  fn from(e: io::Error) -> Self {
+    TreeError::Io(e)
+  }
+}
+
+/// Fetch the tree shared under `root` and mirror it below `dest`, fetching up
+/// to `concurrency` files at a time. Returns the manifest of the tree.
+pub async fn fetch_tree(
+  client: &Client,
+  root: &str,
+  dest: &Path,
+  concurrency: usize,
+) -> Result<Manifest, TreeError> {
+  let (_, manifest) = fetch_key(client.clone(), root.to_string()).await?;
+  let manifest: Manifest = serde_json::from_slice(&manifest).map_err(TreeError::InvalidManifest)?;
+  if manifest.format != MANIFEST_FORMAT {
+    return Err(TreeError::NotATree(root.to_string()));
+  }
+  mirror_tree(client, &manifest, dest, concurrency).await?;
+  Ok(manifest)
+}
+
+/// Fetch the files listed in `manifest` into `dest`, up to `concurrency` at
+/// a time.
+pub async fn mirror_tree(
+  client: &Client,
+  manifest: &Manifest,
+  dest: &Path,
+  concurrency: usize,
+) -> Result<(), TreeError> {
+  // Validate every path before writing anything.
+  let targets = manifest
+    .entries
+    .iter()
+    .map(|entry| Ok((entry, dest.join(safe_relative_path(&entry.path)?))))
+    .collect::<Result<Vec<_>, TreeError>>()?;
+
+  stream::iter(targets.into_iter().map(Ok::<_, TreeError>))
+    .try_for_each_concurrent(concurrency, |(entry, target)| async move {
+      let (provider, content) = fetch_key(client.clone(), entry.key.clone()).await?;
+      if sha256_hex(&content) != entry.sha256 {
+        client
+          .clone()
+          .report_peer(provider, Offense::InvalidFileResponse)
+          .await;
+        return Err(TreeError::HashMismatch(entry.path.clone()));
+      }
+      if let Some(parent) = target.parent() {
+        async_std::fs::create_dir_all(parent).await?;
+      }
+      async_std::fs::write(&target, content).await?;
+      Ok(())
+    })
+    .await
+}
+
+/// Fetch `key` from its providers in ranked order.
+async fn fetch_key(mut client: Client, key: String) -> Result<(PeerId, Vec<u8>), TreeError> {
+  match client.fetch(key.clone()).await {
+    Ok(fetched) => Ok(fetched),
+    Err(mut e) => match e.attempts.pop() {
+      Some(last) => Err(TreeError::Request(key, last.error)),
+      None => Err(TreeError::NoProvider(key)),
+    },
+  }
+}
+
+/// Turn a `/` separated manifest path into a relative path that cannot leave
+/// the destination directory.
+fn safe_relative_path(path: &str) -> Result<PathBuf, TreeError> {
+  // Empty segments are checked on the string, collecting into a `PathBuf`
+  // silently drops them and would turn `/etc` into `etc`.
+  let relative: PathBuf = path.split('/').collect();
+  let safe = path
+    .split('/')
+    .all(|segment| !matches!(segment, "" | "." | ".."))
+    && relative
+      .components()
+      .all(|c| matches!(c, Component::Normal(_)));
+  if safe {
+    Ok(relative)
+  } else {
+    Err(TreeError::InvalidPath(path.to_string()))
+  }
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(content))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    dir
+  }
+
+  #[test]
+  fn scan_lists_files_with_hashes() {
+    let dir = scratch_dir("tree-scan");
+    std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+    std::fs::write(dir.join("sub/b.bin"), b"").unwrap();
+
+    let tree = SharedTree::scan(&dir, "docs".to_string()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(tree.root(), "docs");
+    let mut keys = tree.keys().collect::<Vec<_>>();
+    keys.sort_unstable();
+    assert_eq!(keys, ["docs", "docs/a.txt", "docs/sub/b.bin"]);
+
+    let manifest = Manifest::parse(&tree.read("docs").unwrap().unwrap()).unwrap();
+    assert_eq!(
+      manifest.entries,
+      vec![
+        ManifestEntry {
+          path: "a.txt".to_string(),
+          size: 5,
+          sha256: sha256_hex(b"hello"),
+          key: "docs/a.txt".to_string(),
+        },
+        ManifestEntry {
+          path: "sub/b.bin".to_string(),
+          size: 0,
+          sha256: sha256_hex(b""),
+          key: "docs/sub/b.bin".to_string(),
+        },
+      ]
+    );
+    assert!(tree.read("docs/missing").is_none());
+  }
+
+  #[test]
+  fn catalog_entries_start_with_manifest() {
+    let dir = scratch_dir("tree-catalog");
+    std::fs::write(dir.join("index.html"), b"<html>").unwrap();
+    let tree = SharedTree::scan(&dir, "site".to_string()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let entries = tree.catalog_entries().collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].key, "site");
+    assert_eq!(entries[0].mime_type, "application/json");
+    assert_eq!(entries[1].key, "site/index.html");
+    assert_eq!(entries[1].mime_type, "text/html");
+    assert_eq!(entries[1].size, 6);
+  }
+
+  #[test]
+  fn parse_requires_manifest_format() {
+    let manifest = Manifest {
+      format: MANIFEST_FORMAT.to_string(),
+      entries: Vec::new(),
+    };
+    let bytes = serde_json::to_vec(&manifest).unwrap();
+    assert_eq!(Manifest::parse(&bytes), Some(manifest));
+
+    assert_eq!(
+      Manifest::parse(br#"{"format": "other", "entries": []}"#),
+      None
+    );
+    assert_eq!(Manifest::parse(b"not json"), None);
+  }
+
+  #[test]
+  fn safe_relative_path_rejects_escapes() {
+    assert_eq!(
+      safe_relative_path("a/b.txt").unwrap(),
+      PathBuf::from("a").join("b.txt")
+    );
+    for path in ["", "/etc/passwd", "../x", "a/../../x", "a/./b", "a//b"] {
+      assert!(
+        matches!(safe_relative_path(path), Err(TreeError::InvalidPath(_))),
+        "{}",
+        path
+      );
+    }
+  }
+}
@@ -0,0 +1,119 @@
+//! End-to-end flows over in-memory networks, run with
+//! `cargo test --features test-support`.
+#![cfg(feature = "test-support")]
+
+use futures::future;
+use libp2p_demo::kadmode::KademliaMode;
+use libp2p_demo::network;
+use libp2p_demo::testing::TestNetwork;
+
+#[async_std::test]
+async fn file_exchange_across_hops() {
+  let mut net = TestNetwork::start(4).await;
+  net
+    .assert_file_exchange(0, 3, "report.pdf", b"content")
+    .await;
+}
+
+#[async_std::test]
+async fn record_roundtrip() {
+  let mut net = TestNetwork::start(4).await;
+  net.assert_record_roundtrip(1, 3, "key", b"value").await;
+}
+
+#[async_std::test]
+async fn gossip_reaches_every_node() {
+  let mut net = TestNetwork::start(3).await;
+  net.assert_gossip_propagates(1, "topic", b"hello").await;
+}
+
+#[async_std::test]
+async fn block_exchange() {
+  let mut net = TestNetwork::start(3).await;
+  net.assert_block_exchange(2, 0, b"block").await;
+}
+
+#[async_std::test]
+async fn dag_resolves_across_blocks() {
+  let mut net = TestNetwork::start(3).await;
+  net.assert_dag_resolves(2, 0).await;
+}
+
+#[async_std::test]
+async fn crawl_finds_all_nodes() {
+  let net = TestNetwork::start(4).await;
+  net.assert_crawl_finds_all(0).await;
+}
+
+#[async_std::test]
+async fn concurrent_dials_to_one_peer() {
+  let net = TestNetwork::start(2).await;
+  let target = &net.nodes[0];
+  let mut first = net.client(1);
+  let mut second = net.client(1);
+
+  let (a, b) = future::join(
+    first.dial(target.peer_id, target.addr.clone()),
+    second.dial(target.peer_id, target.addr.clone()),
+  )
+  .await;
+  assert!(a.is_ok(), "First dial failed: {:?}", a.err());
+  assert!(b.is_ok(), "Second dial failed: {:?}", b.err());
+}
+
+#[async_std::test]
+async fn dht_clients_stay_unroutable() {
+  let mut net = TestNetwork::start(2).await;
+  let mut config = network::Config::default();
+  config.set_kademlia_mode(KademliaMode::Client);
+  let client = net.join(config, 0).await;
+  // Auto mode stays a client, AutoNAT never reports in-memory nodes public.
+  let private = net.join(network::Config::default(), 1).await;
+
+  net.assert_dht_client(client, 0).await;
+  net.assert_dht_client(private, 1).await;
+}
+
+#[async_std::test]
+async fn kademlia_protocols_keep_dhts_apart() {
+  let mut config = network::Config::default();
+  config
+    .set_kademlia_mode(KademliaMode::Server)
+    .set_kademlia_protocol(&b"/lab/kad/1.0.0"[..])
+    .set_check_kademlia_protocol(true);
+  let mut net = TestNetwork::start_with_config(1, config.clone()).await;
+  config.set_kademlia_protocol(&b"/other/kad/1.0.0"[..]);
+  let other = net.join(config, 0).await;
+
+  net.assert_separate_dhts(0, other).await;
+}
+
+#[async_std::test]
+async fn bootstrap_is_reported() {
+  let mut net = TestNetwork::start(3).await;
+  net.assert_bootstrap_reported(1).await;
+}
+
+#[async_std::test]
+async fn provider_lookup_events() {
+  let mut net = TestNetwork::start(3).await;
+  net.assert_provider_lookup(2, 0).await;
+}
+
+#[async_std::test]
+async fn record_lookup_events() {
+  let mut net = TestNetwork::start(3).await;
+  net.assert_record_lookup(2, 0).await;
+}
+
+#[async_std::test]
+async fn lookups_time_out() {
+  let mut net = TestNetwork::start(2).await;
+  net.assert_lookups_time_out(1).await;
+}
+
+#[async_std::test]
+async fn dropped_lookups_finish() {
+  let mut net = TestNetwork::start(3).await;
+  net.assert_dropped_lookups_finish(0).await;
+}